@@ -0,0 +1,10 @@
+-- Add migration script here
+CREATE TABLE outbox (
+    id bigserial primary key,
+    kind varchar(8) not null,
+    uuid char(36) not null,
+    author varchar(64),
+    message varchar(1024),
+    likes int,
+    image_updated boolean not null
+);
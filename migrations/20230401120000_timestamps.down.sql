@@ -0,0 +1,7 @@
+-- Add down migration script here
+ALTER TABLE outbox
+    DROP COLUMN client_timestamp,
+    DROP COLUMN server_timestamp;
+ALTER TABLE messages
+    DROP COLUMN client_timestamp,
+    DROP COLUMN server_timestamp;
@@ -0,0 +1,7 @@
+-- Add migration script here
+ALTER TABLE messages
+    ADD COLUMN client_timestamp bigint,
+    ADD COLUMN server_timestamp bigint not null default 0;
+ALTER TABLE outbox
+    ADD COLUMN client_timestamp bigint,
+    ADD COLUMN server_timestamp bigint;
@@ -0,0 +1,4 @@
+-- Add down migration script here
+DROP INDEX messages_search;
+ALTER TABLE messages
+    DROP COLUMN search;
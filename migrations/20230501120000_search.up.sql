@@ -0,0 +1,6 @@
+-- Add migration script here
+ALTER TABLE messages
+    ADD COLUMN search tsvector GENERATED ALWAYS AS (
+        setweight(to_tsvector('english', author), 'A') || setweight(to_tsvector('english', message), 'B')
+    ) STORED;
+CREATE INDEX messages_search ON messages USING GIN (search);
@@ -0,0 +1,3 @@
+-- Add down migration script here
+DROP INDEX messages_likes;
+DROP INDEX messages_author;
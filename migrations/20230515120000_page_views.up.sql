@@ -0,0 +1,3 @@
+-- Add migration script here
+CREATE INDEX messages_likes ON messages (likes, uuid);
+CREATE INDEX messages_author ON messages (author, uuid);
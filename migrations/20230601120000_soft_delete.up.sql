@@ -0,0 +1,4 @@
+-- Add migration script here
+ALTER TABLE messages ADD COLUMN deleted_at BIGINT;
+-- only the soft-deleted rows, found by the purge
+CREATE INDEX messages_deleted_at ON messages (deleted_at) WHERE deleted_at IS NOT NULL;
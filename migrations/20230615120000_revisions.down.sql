@@ -0,0 +1,5 @@
+-- Add down migration script here
+ALTER TABLE outbox
+    DROP COLUMN revision;
+ALTER TABLE messages
+    DROP COLUMN revision;
@@ -0,0 +1,5 @@
+-- Add migration script here
+ALTER TABLE messages
+    ADD COLUMN revision bigint not null default 1;
+ALTER TABLE outbox
+    ADD COLUMN revision bigint;
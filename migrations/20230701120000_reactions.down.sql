@@ -0,0 +1,5 @@
+-- Add down migration script here
+ALTER TABLE outbox
+    DROP COLUMN reactions;
+ALTER TABLE messages
+    DROP COLUMN reactions;
@@ -0,0 +1,5 @@
+-- Add migration script here
+ALTER TABLE messages
+    ADD COLUMN reactions jsonb not null default '{}';
+ALTER TABLE outbox
+    ADD COLUMN reactions jsonb;
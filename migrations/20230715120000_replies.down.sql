@@ -0,0 +1,6 @@
+-- Add down migration script here
+DROP INDEX messages_parent_uuid;
+ALTER TABLE outbox
+    DROP COLUMN parent_uuid;
+ALTER TABLE messages
+    DROP COLUMN parent_uuid;
@@ -0,0 +1,7 @@
+-- Add migration script here
+ALTER TABLE messages
+    ADD COLUMN parent_uuid text;
+ALTER TABLE outbox
+    ADD COLUMN parent_uuid text;
+-- only the replies, paged through by parent
+CREATE INDEX messages_parent_uuid ON messages (parent_uuid, uuid) WHERE parent_uuid IS NOT NULL;
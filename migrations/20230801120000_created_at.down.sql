@@ -0,0 +1,6 @@
+-- Add down migration script here
+DROP INDEX messages_created_at;
+ALTER TABLE outbox
+    DROP COLUMN created_at;
+ALTER TABLE messages
+    DROP COLUMN created_at;
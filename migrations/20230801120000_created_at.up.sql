@@ -0,0 +1,10 @@
+-- Add migration script here
+-- the existing messages were created at their last write at the latest
+ALTER TABLE messages
+    ADD COLUMN created_at bigint;
+UPDATE messages SET created_at = server_timestamp;
+ALTER TABLE messages
+    ALTER COLUMN created_at SET NOT NULL;
+ALTER TABLE outbox
+    ADD COLUMN created_at bigint;
+CREATE INDEX messages_created_at ON messages (created_at, uuid);
@@ -0,0 +1,5 @@
+-- Add down migration script here
+ALTER TABLE outbox
+    DROP COLUMN updated_at;
+ALTER TABLE messages
+    DROP COLUMN updated_at;
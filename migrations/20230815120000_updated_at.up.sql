@@ -0,0 +1,9 @@
+-- Add migration script here
+-- the existing messages were updated at their last write at the latest
+ALTER TABLE messages
+    ADD COLUMN updated_at bigint;
+UPDATE messages SET updated_at = server_timestamp;
+ALTER TABLE messages
+    ALTER COLUMN updated_at SET NOT NULL;
+ALTER TABLE outbox
+    ADD COLUMN updated_at bigint;
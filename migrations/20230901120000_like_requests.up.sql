@@ -0,0 +1,7 @@
+-- Add migration script here
+-- the idempotency keys of the likes applied lately, a retried like isn't counted twice
+CREATE TABLE like_requests (
+    key text PRIMARY KEY,
+    applied_at bigint NOT NULL
+);
+CREATE INDEX like_requests_applied_at ON like_requests (applied_at);
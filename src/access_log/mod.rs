@@ -0,0 +1,66 @@
+use std::{str::FromStr, time::Duration};
+
+use tracing::Level;
+
+use crate::{
+    request::Request,
+    response::{Response, StatusCode},
+};
+
+/// How the access log is written to stdout.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum LogFormat {
+    /// One human readable line per request.
+    #[default]
+    Text,
+    /// One JSON object per request, for log collectors.
+    Json,
+}
+
+impl FromStr for LogFormat {
+    type Err = &'static str;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "text" => Ok(Self::Text),
+            "json" => Ok(Self::Json),
+            _ => Err("Unknown log format"),
+        }
+    }
+}
+
+/// Installs the subscriber writing the access log in `format`. Without it the requests aren't
+/// logged.
+pub fn init(format: LogFormat) {
+    let subscriber = tracing_subscriber::fmt().with_max_level(Level::INFO);
+    match format {
+        LogFormat::Text => subscriber.init(),
+        // the fields at the top level of the object instead of nested in `fields`
+        LogFormat::Json => subscriber.json().flatten_event(true).init(),
+    }
+}
+
+/// Logs `request`, answered with `response` `latency` after it was received. `body_bytes` are
+/// those of the body actually sent, a streamed one included.
+pub(crate) fn record(request: &Request, response: &Response, body_bytes: u64, latency: Duration) {
+    tracing::info!(
+        target: "access",
+        method = %request.method(),
+        path = request.path(),
+        status = response.status_code().code(),
+        body_bytes,
+        latency_ms = latency.as_secs_f64() * 1000.0,
+        "request"
+    );
+}
+
+/// Logs a request rejected with `status` before it could be read, so without a method or path.
+pub(crate) fn record_rejected(status: StatusCode, body_bytes: u64, latency: Duration) {
+    tracing::info!(
+        target: "access",
+        status = status.code(),
+        body_bytes,
+        latency_ms = latency.as_secs_f64() * 1000.0,
+        "rejected request"
+    );
+}
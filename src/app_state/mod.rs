@@ -1,17 +1,92 @@
-use crate::mutation_manager::MutationManager;
+pub mod pagination;
+#[cfg(test)]
+pub(crate) mod testing;
+
+use self::pagination::{Pagination, PaginationMode};
+use crate::metrics::Metrics;
+use crate::{
+    coalescer::Coalescer,
+    cors::Cors,
+    features::FeatureFlags,
+    handlers::PageFormat,
+    image::ImageStorage,
+    journal::Journal,
+    models::IdScheme,
+    mutation_manager::MutationManager,
+    page_tokens::PageTokens,
+    quota::QuotaTracker,
+    repository::{MessageRepository, PageStart, PageView},
+    request::RequestLimits,
+    shard::ShardRouter,
+    tombstones::Tombstones,
+    uploads::UploadManager,
+    wire::Canary,
+};
 use ahash::AHashSet;
-use sqlx::PgPool;
-use std::{path::PathBuf, sync::Arc};
-use tokio::sync::Mutex;
+use bytes::Bytes;
+use std::{num::NonZeroU64, sync::Arc, time::Duration};
+use tokio::sync::{Mutex, Notify};
+
+/// A serialized fresh page and the uuid the next page continues after, if any.
+pub type FreshPage = (Vec<Bytes>, Option<String>);
 
 pub struct AppState {
-    pub pool: Arc<PgPool>,
+    pub messages: Arc<dyn MessageRepository>,
     pub mutations: Mutex<MutationManager>,
     pub pagination_page_size: usize,
-    pub db_pagination_offset: Mutex<usize>,
-    pub pagination_page_number: Mutex<usize>,
-    pub triggered_pagination: Mutex<bool>,
-    pub image_base_path: PathBuf,
+    pub pagination: Mutex<Pagination>,
+    /// How the fresh pages of a pagination session are fetched.
+    pub pagination_mode: PaginationMode,
+    /// Tokens of the served pages, presenting one again replays its page.
+    pub page_tokens: Mutex<PageTokens>,
+    pub images: Arc<dyn ImageStorage>,
     pub all_uuids: Mutex<AHashSet<String>>,
-    pub pages_count: Mutex<usize>,
+    /// Recently deleted uuids, which can't be re-POSTed yet.
+    pub tombstones: Mutex<Tombstones>,
+    /// When set, only GET/pagination endpoints are served (e.g. against a replica database).
+    pub read_only: bool,
+    /// Coalesces concurrent fetches of the same fresh page, keyed by the view, where the page
+    /// starts, its limit and number, whether it has the images and the negotiated format. The serialized
+    /// page is shared as one chunk per message, along with the uuid the next page continues
+    /// after if the page is full.
+    pub fresh_pages: Coalescer<(PageView, PageStart, usize, usize, bool, PageFormat), FreshPage>,
+    /// Sessions of resumable image uploads.
+    pub uploads: Mutex<UploadManager>,
+    /// Wakes the outbox relay after a change to the messages is committed.
+    pub outbox_notify: Notify,
+    /// Set when the dataset is split across several nodes, see `SHARD_PEERS`.
+    pub shard_router: Option<ShardRouter>,
+    /// Writes and stored bytes per api key.
+    pub quotas: Mutex<QuotaTracker>,
+    /// Subsystems enabled through `FEATURES`.
+    pub features: FeatureFlags,
+    /// Requests and latency budget violations per route, with `FEATURES=metrics`.
+    pub metrics: Option<Metrics>,
+    /// Per connection bandwidth of the export and image endpoints, unlimited when `None`.
+    pub download_bytes_per_sec: Option<NonZeroU64>,
+    /// The largest body and header section of the requests.
+    pub request_limits: RequestLimits,
+    /// How long a request may run before its database queries are cancelled.
+    pub request_timeout: Option<Duration>,
+    /// How long a client may take to send a request once it started, it gets a 408 after.
+    pub read_timeout: Duration,
+    /// How long sending a response may take before the connection is dropped, no limit when
+    /// `None`.
+    pub write_timeout: Option<Duration>,
+    /// Whether the admin endpoints are served on the admin listener (`ADMIN_ADDR`) instead of
+    /// the public one.
+    pub admin_listener: bool,
+    /// The bearer token requests to the admin listener must carry, unless the listener
+    /// authenticates clients by their TLS certificate.
+    pub admin_token: Option<String>,
+    /// Cross-origin access for browser clients, disabled when `None`.
+    pub cors: Option<Cors>,
+    /// Shadows a fraction of the page responses with the v2 wire format.
+    pub wire_canary: Canary,
+    /// The write-ahead journal of the writes, disabled when `None`.
+    pub journal: Option<Journal>,
+    /// The format of the message ids clients send.
+    pub id_scheme: IdScheme,
+    /// The kinds of reactions clients may react with, likes aside.
+    pub reaction_kinds: Vec<String>,
 }
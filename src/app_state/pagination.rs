@@ -0,0 +1,551 @@
+use crate::{
+    handlers::{PaginationMetadata, PaginationType},
+    repository::{PageStart, PageView, SortKey},
+    response::StatusCode,
+};
+use serde::Serialize;
+use std::{
+    collections::{BTreeMap, HashMap},
+    fmt,
+    str::FromStr,
+    sync::Arc,
+    time::{Duration, Instant},
+};
+use tokio::sync::Mutex;
+
+/// The header the id of a pagination session is sent in by `GET /api/messages`, and presented
+/// back in to get the pages of that session.
+pub const PAGINATION_SESSION_HEADER: &str = "X-Pagination-Session";
+
+/// How many pagination sessions are kept at once. Past them, a finished or idle session is
+/// dropped for a new one, which is refused while all of them are in use.
+pub const MAX_SESSIONS: usize = 16;
+
+/// How long a session goes without a page claimed before it is idle, and can be dropped for a
+/// new one.
+const SESSION_IDLE_TIMEOUT: Duration = Duration::from_secs(5 * 60);
+
+/// How the fresh pages of a pagination session are fetched, set with `PAGINATION_MODE`.
+#[derive(Serialize, Debug, Clone, Copy, PartialEq, Eq, Default)]
+#[serde(rename_all = "lowercase")]
+pub enum PaginationMode {
+    /// Each page skips the messages of the pages before it, which the database has to walk
+    /// through, getting slower with every page.
+    #[default]
+    Offset,
+    /// Each page continues after the last uuid of the page before it, seeking the index
+    /// directly. Only applies to sessions in uuid order, others fall back to offsets.
+    Keyset,
+}
+
+impl FromStr for PaginationMode {
+    type Err = &'static str;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "offset" => Ok(Self::Offset),
+            "keyset" => Ok(Self::Keyset),
+            _ => Err("Unknown pagination mode"),
+        }
+    }
+}
+
+/// The states a pagination session goes through.
+///
+/// `Triggered` (meta requested) -> `Serving` (pages fetched) -> `Finished` (last page served)
+///
+/// A session over an empty dataset goes `Empty` -> `Finished` on its first page request.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum PaginationState {
+    Triggered {
+        session: u64,
+        kind: PaginationType,
+        total_pages: usize,
+    },
+    Serving {
+        session: u64,
+        kind: PaginationType,
+        page: usize,
+        total_pages: usize,
+    },
+    Finished {
+        session: u64,
+    },
+    /// Triggered with zero pages, the single page request is answered without a query.
+    Empty {
+        session: u64,
+        kind: PaginationType,
+    },
+}
+
+/// A page claimed by [`Pagination::next_page`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Page {
+    pub session: u64,
+    pub kind: PaginationType,
+    /// 1-based page number.
+    pub number: usize,
+    /// Whether this is the last page of the session.
+    pub last: bool,
+    /// Whether the session has no pages at all.
+    pub empty: bool,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum PaginationError {
+    /// A page was requested but no pagination was ever triggered.
+    NotTriggered,
+    /// A page was requested after the last page of the session was served.
+    AlreadyFinished,
+    /// A page was requested from a session that was dropped once finished or idle, or never existed.
+    UnknownSession,
+    /// A session was triggered while every kept session is still in use.
+    Busy,
+}
+
+impl PaginationError {
+    /// The HTTP status this error is surfaced as.
+    pub fn status(&self) -> StatusCode {
+        match self {
+            PaginationError::NotTriggered => StatusCode::Forbidden,
+            PaginationError::AlreadyFinished => StatusCode::Conflict,
+            PaginationError::UnknownSession => StatusCode::Gone,
+            PaginationError::Busy => StatusCode::ServiceUnavailable,
+        }
+    }
+}
+
+impl fmt::Display for PaginationError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            PaginationError::NotTriggered => write!(f, "Pagination not triggered yet."),
+            PaginationError::AlreadyFinished => {
+                write!(f, "Pagination is finished, trigger a new one.")
+            }
+            PaginationError::UnknownSession => {
+                write!(f, "Pagination session not found, trigger a new one.")
+            }
+            PaginationError::Busy => {
+                write!(f, "Every pagination session is in use, retry later.")
+            }
+        }
+    }
+}
+
+impl std::error::Error for PaginationError {}
+
+/// A pagination session, served to one client independently of the sessions of the others.
+#[derive(Debug)]
+struct Session {
+    state: PaginationState,
+    /// The order and filter of the fresh pages of the session.
+    view: PageView,
+    /// The last uuid of each full fresh page served in the session, where the page after it
+    /// continues in keyset mode.
+    continuations: HashMap<usize, String>,
+    /// The last page claimed in the session.
+    last_claimed: Option<Page>,
+    /// When the session was triggered or last had a page claimed.
+    last_active: Instant,
+    /// Held while a fresh page is claimed and served in keyset mode, so the next page is only
+    /// claimed once the one before it recorded where it continues.
+    chain: Arc<Mutex<()>>,
+}
+
+/// The pagination sessions, each with its own cursor, so several clients page at once. A
+/// client presents the id of its session with every page request, requests without one are
+/// served from the latest session.
+#[derive(Debug, Default)]
+pub struct Pagination {
+    sessions: BTreeMap<u64, Session>,
+    last_session: u64,
+}
+
+impl Pagination {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// The ids of the sessions kept, the oldest first.
+    pub fn sessions(&self) -> impl Iterator<Item = u64> + '_ {
+        self.sessions.keys().copied()
+    }
+
+    /// The order and filter of the fresh pages of `session`.
+    pub fn view(&self, session: u64) -> Option<&PageView> {
+        self.sessions.get(&session).map(|session| &session.view)
+    }
+
+    /// Drops a session if [`MAX_SESSIONS`] are kept, so another can be triggered: a finished
+    /// one, or one idle for [`SESSION_IDLE_TIMEOUT`], the least recently active first.
+    ///
+    /// # Errors
+    ///
+    /// This function will return an error if every kept session is still in use.
+    pub fn make_room(&mut self) -> Result<(), PaginationError> {
+        if self.sessions.len() < MAX_SESSIONS {
+            return Ok(());
+        }
+        let idle = self
+            .sessions
+            .iter()
+            .filter(|(_, session)| {
+                matches!(session.state, PaginationState::Finished { .. })
+                    || session.last_active.elapsed() >= SESSION_IDLE_TIMEOUT
+            })
+            .min_by_key(|(_, session)| session.last_active)
+            .map(|(&id, _)| id)
+            .ok_or(PaginationError::Busy)?;
+        self.sessions.remove(&idle);
+        Ok(())
+    }
+
+    /// Starts a new pagination session whose fresh pages are served in `view`, after
+    /// [`Pagination::make_room`] made room for it. `start` is called with the id of the new
+    /// session and returns its metadata, which is passed back to the caller along with the id.
+    ///
+    /// # Errors
+    ///
+    /// This function will return the error of `start`, no session is started then.
+    pub fn trigger<E>(
+        &mut self,
+        view: PageView,
+        start: impl FnOnce(u64) -> Result<PaginationMetadata, E>,
+    ) -> Result<(u64, PaginationMetadata), E> {
+        self.last_session += 1;
+        let id = self.last_session;
+        let meta = start(id)?;
+        let state = match meta.total_pages() {
+            0 => PaginationState::Empty {
+                session: id,
+                kind: meta.kind(),
+            },
+            total_pages => PaginationState::Triggered {
+                session: id,
+                kind: meta.kind(),
+                total_pages,
+            },
+        };
+        self.sessions.insert(
+            id,
+            Session {
+                state,
+                view,
+                continuations: HashMap::new(),
+                last_claimed: None,
+                last_active: Instant::now(),
+                chain: Arc::default(),
+            },
+        );
+        Ok((id, meta))
+    }
+
+    /// The page [`Pagination::next_page`] would claim from `session`, without claiming it.
+    ///
+    /// # Errors
+    ///
+    /// This function will return an error if there is no such session to serve pages from.
+    pub fn peek_page(&self, session: Option<u64>) -> Result<Page, PaginationError> {
+        let id = match session {
+            Some(id) => id,
+            None => *self
+                .sessions
+                .keys()
+                .next_back()
+                .ok_or(PaginationError::NotTriggered)?,
+        };
+        let session = self
+            .sessions
+            .get(&id)
+            .ok_or(PaginationError::UnknownSession)?;
+        let (session, kind, number, total_pages) = match session.state {
+            PaginationState::Finished { .. } => return Err(PaginationError::AlreadyFinished),
+            PaginationState::Empty { session, kind } => {
+                return Ok(Page {
+                    session,
+                    kind,
+                    number: 1,
+                    last: true,
+                    empty: true,
+                })
+            }
+            PaginationState::Triggered {
+                session,
+                kind,
+                total_pages,
+            } => (session, kind, 1, total_pages),
+            PaginationState::Serving {
+                session,
+                kind,
+                page,
+                total_pages,
+            } => (session, kind, page + 1, total_pages),
+        };
+        Ok(Page {
+            session,
+            kind,
+            number,
+            last: number >= total_pages,
+            empty: false,
+        })
+    }
+
+    /// Claims the next page of `session`, of the latest session when `None`.
+    ///
+    /// # Errors
+    ///
+    /// This function will return an error if there is no such session to serve pages from.
+    pub fn next_page(&mut self, session: Option<u64>) -> Result<Page, PaginationError> {
+        let page = self.peek_page(session)?;
+        let session = self
+            .sessions
+            .get_mut(&page.session)
+            .ok_or(PaginationError::UnknownSession)?;
+        session.state = match session.state {
+            PaginationState::Triggered { total_pages, .. }
+            | PaginationState::Serving { total_pages, .. }
+                if !page.last =>
+            {
+                PaginationState::Serving {
+                    session: page.session,
+                    kind: page.kind,
+                    page: page.number,
+                    total_pages,
+                }
+            }
+            _ => PaginationState::Finished {
+                session: page.session,
+            },
+        };
+        session.last_claimed = Some(page);
+        session.last_active = Instant::now();
+        Ok(page)
+    }
+
+    /// Page `number` of `session` again, if it was already claimed and the session is still
+    /// kept, e.g. for a client retrying a page that failed. Doesn't advance the session.
+    pub fn reclaim(&self, session: u64, number: usize) -> Option<Page> {
+        let last = self.sessions.get(&session)?.last_claimed?;
+        if number == 0 || number > last.number {
+            return None;
+        }
+        Some(Page {
+            number,
+            last: last.last && number == last.number,
+            ..last
+        })
+    }
+
+    /// The id of `session`, the latest when `None`, along with the lock its pages are claimed
+    /// and served under when each of them continues after the one before it, i.e. fresh pages in
+    /// keyset mode. `None` if the pages of the session don't chain, or there is no such session.
+    pub fn chain(
+        &self,
+        session: Option<u64>,
+        mode: PaginationMode,
+    ) -> Option<(u64, Arc<Mutex<()>>)> {
+        let (&id, session) = match session {
+            Some(id) => self.sessions.get_key_value(&id)?,
+            None => self.sessions.iter().next_back()?,
+        };
+        let kind = match session.state {
+            PaginationState::Triggered { kind, .. } | PaginationState::Serving { kind, .. } => kind,
+            PaginationState::Finished { .. } | PaginationState::Empty { .. } => return None,
+        };
+        (mode == PaginationMode::Keyset
+            && kind == PaginationType::Fresh
+            && session.view.sort == SortKey::Uuid)
+            .then(|| (id, session.chain.clone()))
+    }
+
+    /// Where the fresh `page` of its session starts in `mode`. In keyset mode it continues after
+    /// the page before it if that one was served already, otherwise it falls back to its offset,
+    /// e.g. when the page before it failed.
+    pub fn start_of(&self, page: &Page, page_size: usize, mode: PaginationMode) -> PageStart {
+        let offset = PageStart::Offset((page.number - 1) * page_size);
+        let Some(session) = self.sessions.get(&page.session) else {
+            return offset;
+        };
+        if mode != PaginationMode::Keyset || session.view.sort != SortKey::Uuid {
+            return offset;
+        }
+        match page.number {
+            1 => PageStart::After(None),
+            number => session
+                .continuations
+                .get(&(number - 1))
+                .map_or(offset, |uuid| PageStart::After(Some(uuid.clone()))),
+        }
+    }
+
+    /// Records `uuid` as the last of the fresh `page` of `session`. Does nothing if the session
+    /// was dropped since.
+    pub fn record_continuation(&mut self, session: u64, page: usize, uuid: String) {
+        if let Some(session) = self.sessions.get_mut(&session) {
+            session.continuations.insert(page, uuid);
+        }
+    }
+
+    /// Finishes `session` early, e.g. when its source ran out of entries. Does nothing if the
+    /// session was dropped since.
+    pub fn finish(&mut self, session: u64) {
+        if let Some(session) = self.sessions.get_mut(&session) {
+            if let PaginationState::Triggered { session: id, .. }
+            | PaginationState::Serving { session: id, .. }
+            | PaginationState::Empty { session: id, .. } = session.state
+            {
+                session.state = PaginationState::Finished { session: id };
+            }
+        }
+    }
+
+    /// Drops every session.
+    pub fn reset(&mut self) {
+        self.sessions.clear();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::convert::Infallible;
+
+    /// Triggers a session over `count` messages, 10 a page.
+    fn trigger(pagination: &mut Pagination, view: PageView, count: usize) -> u64 {
+        pagination
+            .trigger(view, |_| {
+                Ok::<_, Infallible>(PaginationMetadata::new(count, 10, PaginationType::Fresh))
+            })
+            .unwrap()
+            .0
+    }
+
+    #[test]
+    fn empty_session_finishes_on_its_first_page() {
+        let mut pagination = Pagination::new();
+        let session = trigger(&mut pagination, PageView::default(), 0);
+
+        let page = pagination.next_page(Some(session)).unwrap();
+        assert!(page.empty && page.last);
+        assert_eq!(page.number, 1);
+        assert_eq!(
+            pagination.next_page(Some(session)),
+            Err(PaginationError::AlreadyFinished)
+        );
+    }
+
+    #[test]
+    fn pages_after_the_last_are_refused() {
+        let mut pagination = Pagination::new();
+        assert_eq!(
+            pagination.next_page(None),
+            Err(PaginationError::NotTriggered)
+        );
+        let session = trigger(&mut pagination, PageView::default(), 15);
+
+        let first = pagination.next_page(None).unwrap();
+        assert_eq!(
+            (first.session, first.number, first.last),
+            (session, 1, false)
+        );
+        let second = pagination.next_page(Some(session)).unwrap();
+        assert_eq!((second.number, second.last), (2, true));
+        assert_eq!(
+            pagination.next_page(Some(session)),
+            Err(PaginationError::AlreadyFinished)
+        );
+        assert_eq!(
+            pagination.next_page(Some(session + 1)),
+            Err(PaginationError::UnknownSession)
+        );
+        // the finished session still serves its pages again
+        assert_eq!(pagination.reclaim(session, 2), Some(second));
+    }
+
+    #[test]
+    fn errors_are_surfaced_with_their_status() {
+        let codes = [
+            PaginationError::NotTriggered,
+            PaginationError::AlreadyFinished,
+            PaginationError::UnknownSession,
+            PaginationError::Busy,
+        ]
+        .map(|error| error.status().code());
+        assert_eq!(codes, [403, 409, 410, 503]);
+    }
+
+    #[test]
+    fn sessions_past_the_limit_wait_for_one_in_use() {
+        let mut pagination = Pagination::new();
+        for _ in 0..MAX_SESSIONS {
+            pagination.make_room().unwrap();
+            trigger(&mut pagination, PageView::default(), 15);
+        }
+        assert_eq!(pagination.make_room(), Err(PaginationError::Busy));
+
+        // a finished session is dropped for the next one
+        let oldest = pagination.sessions().next().unwrap();
+        pagination.finish(oldest);
+        pagination.make_room().unwrap();
+        assert_eq!(pagination.sessions().count(), MAX_SESSIONS - 1);
+        assert_eq!(
+            pagination.next_page(Some(oldest)),
+            Err(PaginationError::UnknownSession)
+        );
+    }
+
+    #[test]
+    fn idle_sessions_are_reclaimed() {
+        let mut pagination = Pagination::new();
+        for _ in 0..MAX_SESSIONS {
+            trigger(&mut pagination, PageView::default(), 15);
+        }
+        let idle = pagination.sessions().nth(3).unwrap();
+        pagination.next_page(Some(idle)).unwrap();
+        pagination.sessions.get_mut(&idle).unwrap().last_active =
+            Instant::now().checked_sub(SESSION_IDLE_TIMEOUT).unwrap();
+
+        pagination.make_room().unwrap();
+        assert!(pagination.sessions().all(|session| session != idle));
+        assert_eq!(pagination.make_room(), Ok(()));
+        trigger(&mut pagination, PageView::default(), 15);
+        assert_eq!(pagination.make_room(), Err(PaginationError::Busy));
+    }
+
+    #[test]
+    fn keyset_continues_only_in_uuid_order() {
+        let mut pagination = Pagination::new();
+        let by_uuid = trigger(&mut pagination, PageView::default(), 25);
+        let by_likes = trigger(
+            &mut pagination,
+            PageView {
+                sort: SortKey::Likes,
+                ..PageView::default()
+            },
+            25,
+        );
+        let keyset = PaginationMode::Keyset;
+
+        for session in [by_uuid, by_likes] {
+            let page = pagination.next_page(Some(session)).unwrap();
+            pagination.record_continuation(session, page.number, "uuid".to_string());
+        }
+        let next = pagination.peek_page(Some(by_uuid)).unwrap();
+        assert_eq!(
+            pagination.start_of(&next, 10, keyset),
+            PageStart::After(Some("uuid".to_string()))
+        );
+        assert_eq!(
+            pagination.start_of(&next, 10, PaginationMode::Offset),
+            PageStart::Offset(10)
+        );
+        assert!(pagination.chain(Some(by_uuid), keyset).is_some());
+
+        let next = pagination.peek_page(Some(by_likes)).unwrap();
+        assert_eq!(
+            pagination.start_of(&next, 10, keyset),
+            PageStart::Offset(10)
+        );
+        assert!(pagination.chain(Some(by_likes), keyset).is_none());
+    }
+}
@@ -0,0 +1,95 @@
+use super::{pagination::Pagination, AppState};
+use crate::{
+    coalescer::Coalescer,
+    features::FeatureFlags,
+    image::{FsImageStorage, ImageCache},
+    models::{IdScheme, DEFAULT_REACTION_KINDS},
+    mutation_manager::MutationManager,
+    page_tokens::PageTokens,
+    quota::{QuotaLimits, QuotaTracker},
+    repository::InMemoryMessageRepository,
+    request::RequestLimits,
+    tombstones::Tombstones,
+    uploads::UploadManager,
+    wire::Canary,
+};
+use ahash::AHashSet;
+use std::{
+    path::{Path, PathBuf},
+    sync::Arc,
+    time::Duration,
+};
+use tokio::sync::{Mutex, Notify};
+
+/// A directory of its own for a test, removed with everything in it when dropped.
+pub(crate) struct TestDir(PathBuf);
+
+impl TestDir {
+    pub(crate) fn new() -> Self {
+        let path = std::env::temp_dir().join(format!(
+            "server-low-level-test-{:016x}",
+            rand::random::<u64>()
+        ));
+        std::fs::create_dir_all(&path).expect("Failed to create the test directory");
+        Self(path)
+    }
+
+    pub(crate) fn path(&self) -> &Path {
+        &self.0
+    }
+}
+
+impl Drop for TestDir {
+    fn drop(&mut self) {
+        std::fs::remove_dir_all(&self.0).ok();
+    }
+}
+
+/// The state of a server keeping its messages in memory and its files in `dir`, with the
+/// defaults of an unconfigured server and pages of `page_size` messages.
+pub(crate) fn state(dir: &TestDir, page_size: usize) -> Arc<AppState> {
+    let dir = dir.path();
+    let images = dir.join("images");
+    let mutations = dir.join("mutations");
+    std::fs::create_dir_all(&mutations).unwrap();
+
+    Arc::new(AppState {
+        messages: Arc::new(InMemoryMessageRepository::new()),
+        mutations: Mutex::new(MutationManager::open(&mutations, page_size, 1000)),
+        pagination_page_size: page_size,
+        pagination: Mutex::new(Pagination::new()),
+        pagination_mode: Default::default(),
+        page_tokens: Mutex::new(PageTokens::new(None)),
+        images: Arc::new(
+            FsImageStorage::open(
+                images,
+                Arc::new(ImageCache::new(1024 * 1024)),
+                None,
+                u64::MAX,
+            )
+            .unwrap(),
+        ),
+        all_uuids: Mutex::new(AHashSet::new()),
+        tombstones: Mutex::new(Tombstones::new(Duration::ZERO)),
+        read_only: false,
+        fresh_pages: Coalescer::new(),
+        uploads: Mutex::new(UploadManager::new(dir.join("uploads"))),
+        outbox_notify: Notify::new(),
+        shard_router: None,
+        quotas: Mutex::new(QuotaTracker::new(QuotaLimits::default())),
+        features: FeatureFlags::default(),
+        metrics: None,
+        download_bytes_per_sec: None,
+        request_limits: RequestLimits::default(),
+        request_timeout: None,
+        read_timeout: Duration::from_secs(5),
+        write_timeout: None,
+        admin_listener: false,
+        admin_token: None,
+        cors: None,
+        wire_canary: Canary::new(0.0),
+        journal: None,
+        id_scheme: IdScheme::default(),
+        reaction_kinds: DEFAULT_REACTION_KINDS.map(String::from).to_vec(),
+    })
+}
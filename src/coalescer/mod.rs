@@ -0,0 +1,160 @@
+use ahash::AHashMap;
+use std::{
+    future::Future,
+    hash::Hash,
+    sync::{Arc, Mutex},
+};
+use tokio::sync::OnceCell;
+
+type Flight<V> = Arc<OnceCell<Arc<V>>>;
+
+/// Coalesces concurrent requests for the same key: the first caller runs the work, the callers
+/// arriving while it is in flight wait for it and share the same result.
+pub struct Coalescer<K, V> {
+    // never held across an await, so the flight can be retired when the leader is dropped
+    inflight: Mutex<AHashMap<K, Flight<V>>>,
+}
+
+/// Retires the flight of the leader once it is done or dropped, e.g. when its client went away
+/// mid-flight, so the result is never shared with the requests arriving after it.
+struct Retire<'a, K: Hash + Eq, V> {
+    inflight: &'a Mutex<AHashMap<K, Flight<V>>>,
+    key: &'a K,
+    flight: &'a Flight<V>,
+}
+
+impl<K: Hash + Eq, V> Drop for Retire<'_, K, V> {
+    fn drop(&mut self) {
+        let mut inflight = self.inflight.lock().unwrap();
+        // a later flight of the same key isn't this one's to retire
+        if inflight
+            .get(self.key)
+            .is_some_and(|flight| Arc::ptr_eq(flight, self.flight))
+        {
+            inflight.remove(self.key);
+        }
+    }
+}
+
+impl<K: Hash + Eq + Clone, V> Coalescer<K, V> {
+    pub fn new() -> Self {
+        Self {
+            inflight: Mutex::new(AHashMap::new()),
+        }
+    }
+
+    /// Runs `work` for `key` unless the same key is already in flight, in which case the result
+    /// of the in-flight call is shared.
+    ///
+    /// # Errors
+    ///
+    /// This function will return an error if `work` fails. Waiters of a failed flight run
+    /// `work` themselves rather than sharing the error.
+    pub async fn run<F, Fut, E>(&self, key: K, work: F) -> Result<Arc<V>, E>
+    where
+        F: FnOnce() -> Fut,
+        Fut: Future<Output = Result<V, E>>,
+    {
+        let (flight, leader) = {
+            let mut inflight = self.inflight.lock().unwrap();
+            match inflight.get(&key) {
+                Some(flight) => (Arc::clone(flight), false),
+                None => {
+                    let flight = Flight::default();
+                    inflight.insert(key.clone(), Arc::clone(&flight));
+                    (flight, true)
+                }
+            }
+        };
+
+        // the leader is responsible for retiring the flight, later requests start a new one
+        let _retire = leader.then(|| Retire {
+            inflight: &self.inflight,
+            key: &key,
+            flight: &flight,
+        });
+
+        flight
+            .get_or_try_init(|| async { work().await.map(Arc::new) })
+            .await
+            .map(Arc::clone)
+    }
+}
+
+impl<K: Hash + Eq + Clone, V> Default for Coalescer<K, V> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::convert::Infallible;
+
+    #[tokio::test]
+    async fn concurrent_calls_share_the_result() {
+        let coalescer = Coalescer::new();
+        let (tx, rx) = tokio::sync::oneshot::channel::<u32>();
+        let leader = coalescer.run(1, || async { Ok::<_, Infallible>(rx.await.unwrap()) });
+        let waiter = coalescer.run(1, || async { Ok::<_, Infallible>(2) });
+        // only sent once both are in flight
+        let send = async { tx.send(1).unwrap() };
+
+        let (leader, waiter, ()) = tokio::join!(leader, waiter, send);
+        assert_eq!((*leader.unwrap(), *waiter.unwrap()), (1, 1));
+        // the flight is over, the next call runs its own work
+        assert_eq!(
+            *coalescer
+                .run(1, || async { Ok::<_, Infallible>(3) })
+                .await
+                .unwrap(),
+            3
+        );
+    }
+
+    #[tokio::test]
+    async fn cancelled_leader_retires_its_flight() {
+        let coalescer = Arc::new(Coalescer::new());
+        let leader = tokio::spawn({
+            let coalescer = Arc::clone(&coalescer);
+            async move {
+                coalescer
+                    .run(1, std::future::pending::<Result<u32, Infallible>>)
+                    .await
+            }
+        });
+        // wait for the leader to start its flight
+        while coalescer.inflight.lock().unwrap().is_empty() {
+            tokio::task::yield_now().await;
+        }
+        let (tx, rx) = tokio::sync::oneshot::channel::<()>();
+        let waiter = tokio::spawn({
+            let coalescer = Arc::clone(&coalescer);
+            async move {
+                coalescer
+                    .run(1, || async {
+                        rx.await.unwrap();
+                        Ok::<_, Infallible>(2)
+                    })
+                    .await
+            }
+        });
+
+        leader.abort();
+        assert!(leader.await.unwrap_err().is_cancelled());
+        // the waiter takes over the work of the cancelled leader
+        tx.send(()).unwrap();
+        assert_eq!(*waiter.await.unwrap().unwrap(), 2);
+
+        // and its result isn't served to the requests arriving after it
+        assert!(coalescer.inflight.lock().unwrap().is_empty());
+        assert_eq!(
+            *coalescer
+                .run(1, || async { Ok::<_, Infallible>(3) })
+                .await
+                .unwrap(),
+            3
+        );
+    }
+}
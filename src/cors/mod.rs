@@ -0,0 +1,102 @@
+/// The custom response headers of the api, which browsers only let scripts read when they are
+/// listed in `Access-Control-Expose-Headers`.
+pub const DEFAULT_EXPOSE_HEADERS: &[&str] = &[
+    "X-Page-Token",
+    "X-Pagination-Session",
+    "X-Server-Timestamp",
+    "Retry-After",
+    "Range",
+    "Content-Range",
+    "ETag",
+    "X-Next-Cursor",
+    "Location",
+    "X-Image-Width",
+    "X-Image-Height",
+    "X-Image-Size",
+];
+
+/// How long browsers may cache a preflight response, in seconds.
+const PREFLIGHT_MAX_AGE: u32 = 600;
+
+/// Cross-origin access for browser clients served from another origin.
+#[derive(Debug)]
+pub struct Cors {
+    /// The allowed origins, `*` allows any.
+    allowed_origins: Vec<String>,
+    expose_headers: Vec<String>,
+}
+
+impl Cors {
+    /// `allowed_origins` and `expose_headers` are comma separated lists, `expose_headers`
+    /// defaults to [`DEFAULT_EXPOSE_HEADERS`].
+    pub fn new(allowed_origins: &str, expose_headers: Option<&str>) -> Self {
+        Self {
+            allowed_origins: split_list(allowed_origins),
+            expose_headers: match expose_headers {
+                Some(headers) => split_list(headers),
+                None => DEFAULT_EXPOSE_HEADERS
+                    .iter()
+                    .map(|header| header.to_string())
+                    .collect(),
+            },
+        }
+    }
+
+    /// The headers to add to a response to a request from `origin`, none if the origin isn't
+    /// allowed.
+    pub fn response_headers(&self, origin: &str) -> Vec<(&'static str, String)> {
+        let Some(mut headers) = self.allow_origin(origin) else {
+            return Vec::new();
+        };
+        if !self.expose_headers.is_empty() {
+            headers.push((
+                "Access-Control-Expose-Headers",
+                self.expose_headers.join(", "),
+            ));
+        }
+        headers
+    }
+
+    /// The headers of the response to a preflight request from `origin` for a path served with
+    /// `methods`, allowing the headers the browser asked for in `request_headers`.
+    pub fn preflight_headers(
+        &self,
+        origin: &str,
+        methods: &str,
+        request_headers: Option<&str>,
+    ) -> Vec<(&'static str, String)> {
+        let Some(mut headers) = self.allow_origin(origin) else {
+            return Vec::new();
+        };
+        headers.push(("Access-Control-Allow-Methods", methods.to_string()));
+        if let Some(request_headers) = request_headers {
+            headers.push(("Access-Control-Allow-Headers", request_headers.to_string()));
+        }
+        headers.push(("Access-Control-Max-Age", PREFLIGHT_MAX_AGE.to_string()));
+        headers
+    }
+
+    fn allow_origin(&self, origin: &str) -> Option<Vec<(&'static str, String)>> {
+        if self.allowed_origins.iter().any(|allowed| allowed == "*") {
+            return Some(vec![("Access-Control-Allow-Origin", "*".to_string())]);
+        }
+        self.allowed_origins
+            .iter()
+            .any(|allowed| allowed == origin)
+            // the response depends on the origin, caches must keep one per origin
+            .then(|| {
+                vec![
+                    ("Access-Control-Allow-Origin", origin.to_string()),
+                    ("Vary", "Origin".to_string()),
+                ]
+            })
+    }
+}
+
+fn split_list(list: &str) -> Vec<String> {
+    list.split(',')
+        .map(str::trim)
+        .filter(|item| !item.is_empty())
+        .map(str::to_string)
+        .collect()
+}
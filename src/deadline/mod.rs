@@ -0,0 +1,21 @@
+use std::{
+    future::Future,
+    time::{Duration, Instant},
+};
+
+tokio::task_local! {
+    static DEADLINE: Instant;
+}
+
+/// Runs `f` with `deadline` as the deadline of the current request.
+pub async fn scope<F: Future>(deadline: Instant, f: F) -> F::Output {
+    DEADLINE.scope(deadline, f).await
+}
+
+/// The time left until the deadline of the current request, `None` outside of a request or when
+/// requests have no deadline.
+pub fn remaining() -> Option<Duration> {
+    DEADLINE
+        .try_with(|deadline| deadline.saturating_duration_since(Instant::now()))
+        .ok()
+}
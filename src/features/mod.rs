@@ -0,0 +1,24 @@
+use serde::Serialize;
+use std::str::FromStr;
+
+/// Subsystems that ship disabled and are turned on by listing them in `FEATURES`, e.g.
+/// `FEATURES=metrics`. A flag is only added along with the subsystem reading it.
+#[derive(Serialize, Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct FeatureFlags {
+    pub metrics: bool,
+}
+
+impl FromStr for FeatureFlags {
+    type Err = &'static str;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let mut flags = Self::default();
+        for feature in s.split(',').map(str::trim).filter(|f| !f.is_empty()) {
+            match feature {
+                "metrics" => flags.metrics = true,
+                _ => return Err("Unknown feature"),
+            }
+        }
+        Ok(flags)
+    }
+}
@@ -0,0 +1,121 @@
+use std::sync::Arc;
+
+use tokio::io::{AsyncRead, AsyncWrite, BufReader};
+
+use crate::{
+    app_state::AppState,
+    request::{method::Method, Request},
+    response::{Response, ResponseWriter, StatusCode},
+    router::RouteError,
+};
+
+use super::{
+    clear::{clear, clear_filter},
+    connection_header,
+    debug::{handle_config, handle_metrics},
+    delete::handle_purge,
+    import::handle_import,
+    length_required, read_request,
+    replay::handle_replay,
+    route_error_response, routes, send,
+    verify::handle_verify_pagination,
+    wait_for_request, Route,
+};
+
+/// Serves a connection of the admin listener, which exposes clearing and importing the messages
+/// and the debug endpoints behind `Authorization: Bearer <ADMIN_TOKEN>`. A connection whose
+/// client presented a verified certificate is authorized as `identity`, the common name of the
+/// certificate.
+pub async fn handle_admin_connection<S: AsyncRead + AsyncWrite + Unpin>(
+    stream: S,
+    identity: Option<String>,
+    state: Arc<AppState>,
+) {
+    let mut stream = BufReader::new(stream);
+    while wait_for_request(&mut stream).await {
+        let Some(request) = read_request(&mut stream, &state).await else {
+            return;
+        };
+
+        let caller = match &identity {
+            Some(identity) => Some(identity.as_str()),
+            None if has_admin_token(&request, &state) => Some("admin token"),
+            None => None,
+        };
+
+        let response = if caller.is_none() {
+            Response::new()
+                .status(StatusCode::Unauthorized)
+                .header("WWW-Authenticate", "Bearer")
+        } else {
+            match routes().find(*request.method(), request.path()) {
+                Ok((route, _)) => {
+                    route
+                        .with_deadline(&state, route_admin(route, &request, &state))
+                        .await
+                }
+                Err(e) => route_error_response(e, &request),
+            }
+        };
+
+        // HEAD gets the response of GET without its body
+        let response = match request.method() {
+            Method::Head => response.without_body(),
+            _ => response,
+        };
+        // audit log of the admin requests and who made them
+        println!(
+            "Admin {} {} by {}: {}",
+            request.method(),
+            request.path(),
+            caller.unwrap_or("unauthenticated client"),
+            response.status_code()
+        );
+
+        let keep_alive = request.keep_alive();
+        let response = response.header("Connection", connection_header(keep_alive));
+
+        let mut writer = ResponseWriter::new(stream.get_mut());
+        if !send(&mut writer, &response, state.write_timeout).await || !keep_alive {
+            return;
+        }
+    }
+}
+
+/// Routes `request` to the handler of its admin `route`.
+async fn route_admin(route: Route, request: &Request, state: &Arc<AppState>) -> Response {
+    match route {
+        Route::Clear => clear(clear_filter(request), Arc::clone(state)).await,
+        Route::Purge => {
+            handle_purge(request.query_param("older_than_secs"), Arc::clone(state)).await
+        }
+        Route::Import => match request.body() {
+            Some(body) => handle_import(body, Arc::clone(state)).await,
+            None => length_required(),
+        },
+        Route::DebugConfig => handle_config(Arc::clone(state)).await,
+        Route::DebugMetrics => handle_metrics(Arc::clone(state)).await,
+        Route::ReplayMutations => handle_replay(Arc::clone(state)).await,
+        Route::VerifyPagination => handle_verify_pagination(Arc::clone(state)).await,
+        // the message api is only served on the public listener
+        _ => route_error_response(RouteError::NotFound, request),
+    }
+}
+
+fn has_admin_token(request: &Request, state: &AppState) -> bool {
+    let (Some(token), Some(given)) = (
+        &state.admin_token,
+        request
+            .authorization()
+            .and_then(|value| value.strip_prefix("Bearer ")),
+    ) else {
+        return false;
+    };
+    // compare in constant time so the token can't be guessed byte by byte
+    token.len() == given.len()
+        && token
+            .bytes()
+            .zip(given.bytes())
+            .fold(0, |diff, (a, b)| diff | (a ^ b))
+            == 0
+}
@@ -0,0 +1,58 @@
+use std::sync::Arc;
+
+use serde::Serialize;
+#[cfg(feature = "bindings")]
+use ts_rs::TS;
+
+use crate::{
+    app_state::AppState,
+    repository::AuthorCount,
+    response::{Response, StatusCode},
+};
+
+use super::{get::parse_page, PageFormat};
+
+/// A page of the authors, in alphabetical order.
+#[derive(Serialize)]
+#[cfg_attr(feature = "bindings", derive(TS), ts(export))]
+pub struct AuthorsPage {
+    pub page_number: usize,
+    /// How many authors there are, on all pages.
+    pub total: usize,
+    pub authors: Vec<AuthorCount>,
+}
+
+/// `GET /api/authors?page=<n>&size=<size>`, serves a page of the authors with how many messages
+/// each wrote, e.g. for an author filter. `page` defaults to the first and `size` to the
+/// configured page size.
+pub(crate) async fn handle_authors(
+    page: Option<&str>,
+    size: Option<&str>,
+    format: PageFormat,
+    state: Arc<AppState>,
+) -> Response {
+    let page = match parse_page(page, size, state.pagination_page_size) {
+        Ok(page) => page,
+        Err(response) => return response,
+    };
+
+    let (total, authors) = match state.messages.authors(page.size, page.offset).await {
+        Ok(authors) => authors,
+        Err(e) => {
+            eprintln!("Failed to list authors: {}", e);
+            return Response::new().status(StatusCode::InternalServerError);
+        }
+    };
+
+    let page = AuthorsPage {
+        page_number: page.number,
+        total,
+        authors,
+    };
+    let mut body = Vec::new();
+    format.serialize_into(&mut body, &page);
+    Response::new()
+        .header("Content-Type", format.content_type())
+        .header("Vary", "Accept")
+        .body_bytes(body)
+}
@@ -1,22 +1,105 @@
-use crate::{app_state::AppState, image};
+use crate::{
+    app_state::AppState,
+    repository::ClearFilter,
+    request::Request,
+    response::{Response, StatusCode, CONTENT_TYPE_JSON, CONTENT_TYPE_TEXT},
+};
 use std::sync::Arc;
 
-pub(crate) async fn clear(state: Arc<AppState>) -> String {
-    let mut response = crate::response::Response::new();
+/// Reads the filter of a partial clear from the query, e.g. `?author=bob&uuid_prefix=0a`, or
+/// `?created_before=<milliseconds since the epoch>` and `?updated_before=<...>`.
+pub(crate) fn clear_filter(request: &Request) -> Result<ClearFilter, &'static str> {
+    let created_before = match request.query_param("created_before") {
+        Some(created_before) => Some(
+            created_before
+                .parse()
+                .map_err(|_| "created_before must be a timestamp in milliseconds.")?,
+        ),
+        None => None,
+    };
+    let updated_before = match request.query_param("updated_before") {
+        Some(updated_before) => Some(
+            updated_before
+                .parse()
+                .map_err(|_| "updated_before must be a timestamp in milliseconds.")?,
+        ),
+        None => None,
+    };
+    Ok(ClearFilter {
+        author: request.query_param("author").map(str::to_string),
+        uuid_prefix: request.query_param("uuid_prefix").map(str::to_string),
+        created_before,
+        updated_before,
+    })
+}
+
+/// `PATCH /api/messages`, deletes all messages, or only the ones matching `filter`.
+pub(crate) async fn clear(filter: Result<ClearFilter, &str>, state: Arc<AppState>) -> Response {
+    let filter = match filter {
+        Ok(filter) => filter,
+        Err(e) => {
+            return Response::new()
+                .status(StatusCode::BadRequest)
+                .header("Content-Type", CONTENT_TYPE_TEXT)
+                .body(e)
+        }
+    };
+    if !filter.is_empty() {
+        return clear_matching(&filter, state).await;
+    }
 
-    let result = sqlx::query!("DELETE FROM messages")
-        .execute(state.pool.as_ref())
-        .await;
+    let mut response = Response::new();
+
+    let result = state.messages.clear().await;
 
     match result {
         Ok(_) => {
-            image::clear(&state.image_base_path).ok();
-            state.mutations.lock().await.clear();
+            state.images.clear().await.ok();
+            let cleared = state.mutations.lock().await.clear().await;
             state.all_uuids.lock().await.clear();
-            response.set_status_line("HTTP/1.1 204 NO CONTENT");
+            state.tombstones.lock().await.clear();
+            state.pagination.lock().await.reset();
+            state.quotas.lock().await.clear_stored();
+            match cleared {
+                Ok(()) => response.set_status(StatusCode::NoContent),
+                Err(e) => {
+                    eprintln!("Failed to clear the cached mutations: {}", e);
+                    response.set_status(StatusCode::InternalServerError);
+                }
+            }
+        }
+        Err(_) => response.set_status(StatusCode::InternalServerError),
+    }
+
+    response
+}
+
+/// Deletes the messages matching `filter` like individual deletes would, so clients see them
+/// as delete mutations.
+async fn clear_matching(filter: &ClearFilter, state: Arc<AppState>) -> Response {
+    let uuids = match state.messages.clear_matching(filter).await {
+        Ok(uuids) => uuids,
+        Err(e) => {
+            eprintln!("Failed to clear messages: {}", e);
+            return Response::new().status(StatusCode::InternalServerError);
+        }
+    };
+
+    for uuid in &uuids {
+        state.images.remove(uuid).await.ok();
+    }
+    {
+        let mut all_uuids = state.all_uuids.lock().await;
+        let mut tombstones = state.tombstones.lock().await;
+        for uuid in &uuids {
+            all_uuids.remove(uuid);
+            tombstones.bury(uuid);
         }
-        Err(_) => response.set_status_line("HTTP/1.1 500 INTERNAL SERVER ERROR"),
     }
+    state.outbox_notify.notify_one();
 
-    response.to_string()
+    let body = format!("{{\"deleted\":{}}}", uuids.len());
+    Response::new()
+        .header("Content-Type", CONTENT_TYPE_JSON)
+        .body(body)
 }
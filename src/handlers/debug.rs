@@ -0,0 +1,54 @@
+use std::sync::Arc;
+
+use serde::Serialize;
+
+use crate::{
+    app_state::{pagination::PaginationMode, AppState},
+    features::FeatureFlags,
+    models::IdScheme,
+    response::{Response, StatusCode, CONTENT_TYPE_JSON, CONTENT_TYPE_TEXT},
+};
+
+#[derive(Serialize)]
+struct ConfigReport {
+    pagination_page_size: usize,
+    pagination_mode: PaginationMode,
+    read_only: bool,
+    sharded: bool,
+    features: FeatureFlags,
+    id_scheme: IdScheme,
+    reaction_kinds: Vec<String>,
+}
+
+/// `GET /api/debug/config`, reports the running configuration and the enabled features.
+pub(crate) async fn handle_config(state: Arc<AppState>) -> Response {
+    let report = ConfigReport {
+        pagination_page_size: state.pagination_page_size,
+        pagination_mode: state.pagination_mode,
+        read_only: state.read_only,
+        sharded: state.shard_router.is_some(),
+        features: state.features,
+        id_scheme: state.id_scheme,
+        reaction_kinds: state.reaction_kinds.clone(),
+    };
+    let body = serde_json::to_string(&report).unwrap();
+    Response::new()
+        .header("Content-Type", CONTENT_TYPE_JSON)
+        .body(body)
+}
+
+/// `GET /api/debug/metrics`, reports the requests and latency budget violations per route.
+pub(crate) async fn handle_metrics(state: Arc<AppState>) -> Response {
+    let Some(metrics) = &state.metrics else {
+        let body = "Metrics are disabled, enable them with FEATURES=metrics.";
+        return Response::new()
+            .status(StatusCode::NotFound)
+            .header("Content-Type", CONTENT_TYPE_TEXT)
+            .body(body);
+    };
+
+    let body = serde_json::to_string(&metrics.report().await).unwrap();
+    Response::new()
+        .header("Content-Type", CONTENT_TYPE_JSON)
+        .body(body)
+}
@@ -1,36 +1,96 @@
 use std::sync::Arc;
 
-use crate::{app_state::AppState, image, response::Response};
+use crate::{
+    app_state::AppState,
+    models::{timestamp_now, SERVER_TIMESTAMP_HEADER},
+    response::{Response, StatusCode, CONTENT_TYPE_JSON, CONTENT_TYPE_TEXT},
+};
 
-pub(crate) async fn handle_delete(uuid: &str, state: Arc<AppState>) -> String {
+/// `DELETE /api/messages/{uuid}`, soft-deletes a message: it is left out of every read and
+/// clients see it as deleted, but it and its image are kept until they are purged, so it can
+/// still be restored.
+pub(crate) async fn handle_delete(uuid: &str, state: Arc<AppState>) -> Response {
     let mut response = Response::new();
 
     // check for conflicting uuid
     if !state.all_uuids.lock().await.remove(uuid) {
-        return response.status_line("HTTP/1.1 404 NOT FOUND").to_string();
+        return response.status(StatusCode::NotFound);
     }
 
-    let result = sqlx::query!("DELETE FROM messages WHERE uuid = $1", uuid)
-        .execute(state.pool.as_ref())
-        .await;
+    let result = state.messages.delete(uuid, timestamp_now()).await;
 
     match result {
-        Ok(result) => {
-            if result.rows_affected() == 0 {
-                response.set_status_line("HTTP/1.1 404 NOT FOUND");
+        Ok(rows_affected) => {
+            if rows_affected == 0 {
+                response.set_status(StatusCode::NotFound);
             } else {
-                // remove from image store if it exists
-                image::remove(&state.image_base_path, uuid).ok();
-                state
-                    .mutations
-                    .lock()
-                    .await
-                    .add_delete(uuid, &state.image_base_path);
-                response.set_status_line("HTTP/1.1 204 NO CONTENT");
+                state.tombstones.lock().await.bury(uuid);
+                state.outbox_notify.notify_one();
+                response.set_status(StatusCode::NoContent);
             }
         }
-        Err(_) => response.set_status_line("HTTP/1.1 500 INTERNAL SERVER ERROR"),
+        Err(_) => response.set_status(StatusCode::InternalServerError),
     }
 
-    response.to_string()
+    response
+}
+
+/// `POST /api/messages/{uuid}/restore`, brings back a soft-deleted message with its image.
+/// Clients get it as a post.
+pub(crate) async fn handle_restore(uuid: &str, state: Arc<AppState>) -> Response {
+    if state.all_uuids.lock().await.contains(uuid) {
+        let body = format!("Message {uuid} is not deleted.");
+        return Response::new()
+            .status(StatusCode::Conflict)
+            .header("Content-Type", CONTENT_TYPE_TEXT)
+            .body(body);
+    }
+
+    match state.messages.restore(uuid, timestamp_now()).await {
+        Ok(Some(message)) => {
+            state.all_uuids.lock().await.insert(message.uuid);
+            state.outbox_notify.notify_one();
+            Response::new()
+                .status(StatusCode::NoContent)
+                .header(SERVER_TIMESTAMP_HEADER, message.server_timestamp)
+        }
+        Ok(None) => Response::new().status(StatusCode::NotFound),
+        Err(e) => {
+            eprintln!("Failed to restore message: {}", e);
+            Response::new().status(StatusCode::InternalServerError)
+        }
+    }
+}
+
+/// `POST /admin/messages/purge?older_than_secs=<secs>`, permanently removes the messages
+/// soft-deleted at least `older_than_secs` ago, every soft-deleted one by default, along with
+/// their images.
+pub(crate) async fn handle_purge(older_than_secs: Option<&str>, state: Arc<AppState>) -> Response {
+    let older_than_secs = match older_than_secs.map(str::parse::<i64>) {
+        None => 0,
+        Some(Ok(secs)) if secs >= 0 => secs,
+        Some(_) => {
+            return Response::new()
+                .status(StatusCode::BadRequest)
+                .header("Content-Type", CONTENT_TYPE_TEXT)
+                .body("older_than_secs must be a number of seconds.");
+        }
+    };
+
+    let deleted_before = timestamp_now().saturating_sub(older_than_secs.saturating_mul(1000));
+    let uuids = match state.messages.purge_deleted(deleted_before).await {
+        Ok(uuids) => uuids,
+        Err(e) => {
+            eprintln!("Failed to purge messages: {}", e);
+            return Response::new().status(StatusCode::InternalServerError);
+        }
+    };
+    for uuid in &uuids {
+        state.images.remove(uuid).await.ok();
+    }
+
+    let body = format!("{{\"purged\":{}}}", uuids.len());
+    Response::new()
+        .header("Content-Type", CONTENT_TYPE_JSON)
+        .body(body)
 }
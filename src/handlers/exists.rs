@@ -0,0 +1,68 @@
+use std::sync::Arc;
+
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    app_state::AppState,
+    response::{Response, StatusCode, CONTENT_TYPE_JSON, CONTENT_TYPE_TEXT},
+};
+
+/// The most uuids a single existence check may ask for.
+pub const MAX_EXISTS_BATCH: usize = 10_000;
+
+#[derive(Deserialize)]
+struct ExistsRequest {
+    uuids: Vec<String>,
+}
+
+#[derive(Serialize)]
+struct ExistsResponse<'a> {
+    /// The requested uuids that exist, in request order.
+    existing: Vec<&'a str>,
+}
+
+/// `POST /api/messages/exists`, reports which of the given uuids exist. Served from `all_uuids`
+/// without querying the database.
+pub(crate) async fn handle_exists(body: &str, state: Arc<AppState>) -> Response {
+    let ExistsRequest { uuids } = match serde_json::from_str(body) {
+        Ok(v) => v,
+        Err(e) => {
+            let body = e.to_string();
+            return Response::new()
+                .status(StatusCode::BadRequest)
+                .header("Content-Type", CONTENT_TYPE_TEXT)
+                .body(body);
+        }
+    };
+    if uuids.len() > MAX_EXISTS_BATCH {
+        let body = format!("At most {MAX_EXISTS_BATCH} uuids can be checked at once.");
+        return Response::new()
+            .status(StatusCode::PayloadTooLarge)
+            .header("Content-Type", CONTENT_TYPE_TEXT)
+            .body(body);
+    }
+
+    let existing = {
+        let all_uuids = state.all_uuids.lock().await;
+        // reported as the client sent them, found whatever their case
+        uuids
+            .iter()
+            .filter(|uuid| all_uuids.contains(&state.id_scheme.normalize(uuid.to_string())))
+            .map(String::as_str)
+            .collect()
+    };
+    let body = serde_json::to_string(&ExistsResponse { existing }).unwrap();
+    Response::new()
+        .header("Content-Type", CONTENT_TYPE_JSON)
+        .body(body)
+}
+
+/// `GET /api/messages/{uuid}/exists`, 200 if the message exists and 404 otherwise, so a client
+/// generating its uuids can check one for a collision. Answered from `all_uuids`, without the
+/// database or the images.
+pub(crate) async fn handle_uuid_exists(uuid: &str, state: Arc<AppState>) -> Response {
+    match state.all_uuids.lock().await.contains(uuid) {
+        true => Response::new(),
+        false => Response::new().status(StatusCode::NotFound),
+    }
+}
@@ -0,0 +1,194 @@
+use std::{io, str::FromStr, sync::Arc};
+
+use bytes::Bytes;
+use futures_util::StreamExt;
+use serde::{Deserialize, Serialize};
+use tokio::sync::mpsc;
+#[cfg(feature = "bindings")]
+use ts_rs::TS;
+
+use crate::{
+    app_state::AppState,
+    models::{Message, Reactions},
+    response::{Response, StatusCode, CONTENT_TYPE_TEXT},
+};
+
+/// The rows are sent in chunks of about this size, not one by one.
+const EXPORT_CHUNK_BYTES: usize = 64 * 1024;
+
+/// A message as dumped by the export, one per line. The image isn't inlined, a dump of every
+/// image would be huge, it is referenced by the path serving it.
+#[derive(Serialize, Deserialize, Debug)]
+#[cfg_attr(feature = "bindings", derive(TS), ts(export))]
+pub struct ExportRecord {
+    pub uuid: String,
+    pub author: String,
+    pub message: String,
+    /// Missing from dumps taken before threads were added.
+    #[serde(default)]
+    pub parent_uuid: Option<String>,
+    pub likes: i32,
+    pub client_timestamp: Option<i64>,
+    pub server_timestamp: i64,
+    /// Missing from dumps taken before it was stored, the import then takes `server_timestamp`.
+    #[serde(default)]
+    pub created_at: Option<i64>,
+    /// Missing from dumps taken before it was stored, the import then takes `created_at`.
+    #[serde(default)]
+    pub updated_at: Option<i64>,
+    /// The path of the image, `None` if the message has none.
+    pub image: Option<String>,
+    /// Missing from dumps taken before reactions were added.
+    #[serde(default)]
+    pub reactions: Reactions,
+}
+
+impl From<Message> for ExportRecord {
+    fn from(message: Message) -> Self {
+        let image = message
+            .has_image
+            .then(|| format!("/api/messages/{}/image", message.uuid));
+        Self {
+            uuid: message.uuid,
+            author: message.author,
+            message: message.message,
+            parent_uuid: message.parent_uuid,
+            likes: message.likes,
+            client_timestamp: message.client_timestamp,
+            server_timestamp: message.server_timestamp,
+            created_at: Some(message.created_at),
+            updated_at: Some(message.updated_at),
+            image,
+            reactions: message.reactions,
+        }
+    }
+}
+
+/// The format of a dump.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum ExportFormat {
+    /// One JSON object per line.
+    Ndjson,
+    /// RFC 4180 CSV, with a header row.
+    Csv,
+}
+
+impl FromStr for ExportFormat {
+    type Err = &'static str;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "ndjson" => Ok(Self::Ndjson),
+            "csv" => Ok(Self::Csv),
+            _ => Err("Unknown export format, expected ndjson or csv."),
+        }
+    }
+}
+
+impl ExportFormat {
+    fn content_type(self) -> &'static str {
+        match self {
+            ExportFormat::Ndjson => "application/x-ndjson",
+            ExportFormat::Csv => "text/csv; charset=utf-8",
+        }
+    }
+
+    /// What comes before the records.
+    fn header(self) -> &'static str {
+        match self {
+            ExportFormat::Ndjson => "",
+            ExportFormat::Csv => {
+                "uuid,author,message,parent_uuid,likes,client_timestamp,server_timestamp,created_at,updated_at,image,reactions\r\n"
+            }
+        }
+    }
+
+    /// Appends `record` to `out`, line ending included.
+    fn write_record(self, out: &mut Vec<u8>, record: &ExportRecord) {
+        match self {
+            ExportFormat::Ndjson => {
+                serde_json::to_writer(&mut *out, record).unwrap();
+                out.push(b'\n');
+            }
+            ExportFormat::Csv => {
+                let optional =
+                    |value: Option<i64>| value.map(|v| v.to_string()).unwrap_or_default();
+                let fields = [
+                    csv_field(&record.uuid),
+                    csv_field(&record.author),
+                    csv_field(&record.message),
+                    record
+                        .parent_uuid
+                        .as_deref()
+                        .map(csv_field)
+                        .unwrap_or_default(),
+                    record.likes.to_string(),
+                    optional(record.client_timestamp),
+                    record.server_timestamp.to_string(),
+                    optional(record.created_at),
+                    optional(record.updated_at),
+                    record.image.as_deref().map(csv_field).unwrap_or_default(),
+                    // the reactions as a JSON object
+                    csv_field(&serde_json::to_string(&record.reactions).unwrap()),
+                ];
+                out.extend_from_slice(fields.join(",").as_bytes());
+                out.extend_from_slice(b"\r\n");
+            }
+        }
+    }
+}
+
+/// Quotes `value` if it contains a separator, a quote or a line break, doubling its quotes.
+fn csv_field(value: &str) -> String {
+    if value.contains([',', '"', '\r', '\n']) {
+        format!("\"{}\"", value.replace('"', "\"\""))
+    } else {
+        value.to_string()
+    }
+}
+
+/// `GET /api/messages/export?format=ndjson|csv`, dumps every message as `ExportRecord`s, NDJSON
+/// by default. The rows are read from the database a page at a time as they are sent, so the
+/// table is never loaded at once and no connection is held while the client reads; a failure
+/// midway ends the response without its last chunk.
+pub(crate) async fn handle_export(format: Option<&str>, state: Arc<AppState>) -> Response {
+    let format = match format.map(str::parse).unwrap_or(Ok(ExportFormat::Ndjson)) {
+        Ok(format) => format,
+        Err(e) => {
+            return Response::new()
+                .status(StatusCode::BadRequest)
+                .header("Content-Type", CONTENT_TYPE_TEXT)
+                .body(e);
+        }
+    };
+
+    // the channel is bounded, the rows are read only as fast as the client takes them
+    let (sender, receiver) = mpsc::channel(16);
+    tokio::spawn(async move {
+        let mut rows = state.messages.stream_all();
+        let mut chunk = format.header().as_bytes().to_vec();
+        while let Some(row) = rows.next().await {
+            let message = match row {
+                Ok(message) => message,
+                Err(e) => {
+                    eprintln!("Failed to export messages: {}", e);
+                    sender.send(Err(io::Error::other(e))).await.ok();
+                    return;
+                }
+            };
+            format.write_record(&mut chunk, &ExportRecord::from(message));
+            if chunk.len() >= EXPORT_CHUNK_BYTES {
+                let full = Bytes::from(std::mem::take(&mut chunk));
+                if sender.send(Ok(full)).await.is_err() {
+                    // the client is gone
+                    return;
+                }
+            }
+        }
+        sender.send(Ok(Bytes::from(chunk))).await.ok();
+    });
+
+    Response::new()
+        .header("Content-Type", format.content_type())
+        .body_stream(receiver)
+}
@@ -1,39 +1,135 @@
-use crate::{app_state::AppState, image, models::Message, response::Response};
+use crate::{
+    app_state::{
+        pagination::{Page, Pagination, PAGINATION_SESSION_HEADER},
+        AppState,
+    },
+    image::{CachedImage, ImageMetadata, ImageStorage},
+    models::{Message, Reactions},
+    mutation_manager::{MutationError, MutationManager},
+    outbox,
+    page_tokens::{PageTokenError, PAGE_TOKEN_HEADER},
+    repository::{PageStart, PageView, RepositoryResult, SortKey},
+    request::Request,
+    response::{Response, StatusCode, CONTENT_TYPE_JSON, CONTENT_TYPE_TEXT},
+    wire,
+};
+use bytes::Bytes;
 use serde::{Deserialize, Serialize};
-use std::sync::Arc;
+use sha2::{Digest, Sha256};
+use std::{
+    sync::Arc,
+    time::{Duration, Instant},
+};
+#[cfg(feature = "bindings")]
 use ts_rs::TS;
 
-#[derive(Serialize, Debug, Deserialize, TS)]
-#[ts(export)]
+#[derive(Serialize, Debug, Deserialize)]
+#[cfg_attr(feature = "bindings", derive(TS), ts(export))]
 pub struct CompleteMessage {
     pub uuid: String,
     pub author: String,
     pub message: String,
+    /// The message this one replies to, `None` if it starts a thread.
+    pub parent_uuid: Option<String>,
     pub likes: i32,
     pub image: String,
+    /// The type, dimensions and size of `image`, so clients can lay it out without decoding it.
+    /// `None` if the message has no image.
+    pub image_metadata: Option<ImageMetadata>,
+    /// When the client says it last wrote the message, by its own clock. Clients order
+    /// messages by it, falling back to `server_timestamp` when it is missing.
+    pub client_timestamp: Option<i64>,
+    /// When the server received the last write, telling clients how far their clocks are off.
+    /// It is also when the message was last updated.
+    pub server_timestamp: i64,
+    /// When the server received the post of the message.
+    pub created_at: i64,
+    /// When the message was last changed by a `PUT` or `PATCH`, its post time until then.
+    pub updated_at: i64,
+    /// The revision of the message, which a `PUT` can require with `If-Match`.
+    pub revision: i64,
+    /// The count of each kind of reaction, likes aside.
+    pub reactions: Reactions,
 }
 
 impl CompleteMessage {
-    pub fn new(message: Message, image: String) -> Self {
+    pub fn new(message: Message, image: String, image_metadata: Option<ImageMetadata>) -> Self {
         CompleteMessage {
             uuid: message.uuid,
             author: message.author,
             image,
+            image_metadata,
             likes: message.likes,
             message: message.message,
+            parent_uuid: message.parent_uuid,
+            client_timestamp: message.client_timestamp,
+            server_timestamp: message.server_timestamp,
+            created_at: message.created_at,
+            updated_at: message.updated_at,
+            revision: message.revision,
+            reactions: message.reactions,
         }
     }
+
+    /// Completes `rows` with the images of those that have one.
+    pub async fn with_images(rows: Vec<Message>, images: &dyn ImageStorage) -> Vec<Self> {
+        let uuids: Vec<_> = rows
+            .iter()
+            .filter(|m| m.has_image)
+            .map(|m| m.uuid.as_str())
+            .collect();
+        let mut images = images.get_many(&uuids).await.into_iter();
+        rows.into_iter()
+            .map(|message| {
+                let image = match message.has_image {
+                    true => images.next().flatten(),
+                    false => None,
+                };
+                let (image, image_metadata) = image
+                    .map(|image| (image.base64.clone(), image.metadata.clone()))
+                    .unzip();
+                CompleteMessage::new(message, image.unwrap_or_default(), image_metadata)
+            })
+            .collect()
+    }
+}
+
+/// A borrowed view of a [`CompleteMessage`], serialized identically, so a page can be assembled
+/// from the fetched rows and a pooled image buffer without allocating a `String` per field.
+#[derive(Serialize)]
+struct CompleteMessageRef<'a> {
+    uuid: &'a str,
+    author: &'a str,
+    message: &'a str,
+    parent_uuid: Option<&'a str>,
+    likes: i32,
+    image: &'a str,
+    image_metadata: Option<&'a ImageMetadata>,
+    client_timestamp: Option<i64>,
+    server_timestamp: i64,
+    created_at: i64,
+    updated_at: i64,
+    revision: i64,
+    reactions: &'a Reactions,
 }
 
-#[derive(Serialize, Debug)]
+/// Where the pages of a pagination come from. On the wire the kind is its discriminant, a `u32`
+/// in bincode, which must stay stable for clients switching on it.
+#[derive(Serialize, Debug, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "bindings", derive(TS), ts(export))]
+#[repr(u32)]
 pub enum PaginationType {
-    Cache,
-    Fresh,
+    /// Pages of `MutationResults`, the changes since the last pagination.
+    Cache = 0,
+    /// Pages of `DbResults`, every message.
+    Fresh = 1,
 }
 
 #[derive(Serialize)]
+#[cfg_attr(feature = "bindings", derive(TS), ts(export))]
 pub struct PaginationMetadata {
     total_pages: usize,
+    /// Tells the client which page type `get-page` is going to return.
     kind: PaginationType,
 }
 
@@ -44,161 +140,985 @@ impl PaginationMetadata {
             kind,
         }
     }
+
+    pub fn total_pages(&self) -> usize {
+        self.total_pages
+    }
+
+    pub fn kind(&self) -> PaginationType {
+        self.kind
+    }
 }
 
-#[derive(Serialize, Deserialize, TS)]
-#[ts(export)]
+/// The wire format of a fresh page, exported to TypeScript. Pages are serialized message by
+/// message through the borrowed [`CompleteMessageRef`], which lays them out identically.
+#[allow(dead_code)]
+#[derive(Serialize, Deserialize)]
+#[cfg_attr(feature = "bindings", derive(TS), ts(export))]
 pub struct DbResults {
     pub page_number: usize,
     pub messages: Vec<CompleteMessage>,
 }
 
-pub(crate) async fn handle_get(state: Arc<AppState>) -> Vec<u8> {
-    {
-        let triggered_pagination = state.triggered_pagination.lock().await;
-        if !*triggered_pagination {
-            return Response::new()
-                .status_line("HTTP/1.1 403 Forbidden")
-                .body("Pagination not triggered yet.")
-                .to_string()
-                .into_bytes();
+/// The largest page a client may ask for with `?size=`.
+pub const MAX_PAGE_SIZE: usize = 1000;
+
+/// The header carrying the cursor of the next page of a keyset pagination, missing on the last
+/// page.
+pub const NEXT_CURSOR_HEADER: &str = "X-Next-Cursor";
+
+/// Reads the order and filter of fresh pages from the query, e.g.
+/// `?sort=likes&order=desc&author=bob`.
+pub(crate) fn page_view(request: &Request) -> Result<PageView, &'static str> {
+    let sort = match request.query_param("sort") {
+        Some(sort) => sort.parse()?,
+        None => Default::default(),
+    };
+    let descending = match request.query_param("order") {
+        None | Some("asc") => false,
+        Some("desc") => true,
+        Some(_) => return Err("order must be asc or desc."),
+    };
+    Ok(PageView {
+        sort,
+        descending,
+        author: request.query_param("author").map(str::to_string),
+    })
+}
+
+/// Reads from the query whether the messages of fresh pages carry their images, e.g.
+/// `?include_images=false` for list views. Without them `image` is empty, the images shown are
+/// fetched through the image endpoints instead.
+pub(crate) fn include_images(request: &Request) -> Result<bool, &'static str> {
+    match request.query_param("include_images") {
+        None | Some("true") => Ok(true),
+        Some("false") => Ok(false),
+        Some(_) => Err("include_images must be true or false."),
+    }
+}
+
+/// The serialization of pages and pagination metadata, negotiated with the `Accept` header.
+/// Both are laid out the same, JSON with the field names of the exported types.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Default)]
+pub enum PageFormat {
+    /// What the client app decodes.
+    #[default]
+    Bincode,
+    Json,
+}
+
+impl PageFormat {
+    /// JSON if `accept` prefers `application/json` over `application/octet-stream`, bincode
+    /// otherwise.
+    pub fn negotiate(accept: Option<&str>) -> Self {
+        let Some(accept) = accept else {
+            return Self::Bincode;
+        };
+        // the highest quality given to `media`, `None` if it isn't listed
+        let quality = |media: &str| {
+            accept
+                .split(',')
+                .filter_map(|range| {
+                    let mut params = range.split(';').map(str::trim);
+                    if !params.next()?.eq_ignore_ascii_case(media) {
+                        return None;
+                    }
+                    let q = params.find_map(|param| param.strip_prefix("q="));
+                    q.map_or(Some(1.0), |q| q.parse::<f32>().ok())
+                })
+                .reduce(f32::max)
+        };
+        match (
+            quality("application/json"),
+            quality("application/octet-stream"),
+        ) {
+            (Some(json), octet) if json > 0.0 && octet.is_none_or(|octet| json > octet) => {
+                Self::Json
+            }
+            _ => Self::Bincode,
+        }
+    }
+
+    pub fn content_type(self) -> &'static str {
+        match self {
+            PageFormat::Bincode => "application/octet-stream",
+            PageFormat::Json => CONTENT_TYPE_JSON,
+        }
+    }
+
+    pub(crate) fn serialize_into<T: Serialize>(self, buf: &mut Vec<u8>, value: &T) {
+        match self {
+            PageFormat::Bincode => bincode::serialize_into(buf, value).unwrap(),
+            PageFormat::Json => serde_json::to_writer(buf, value).unwrap(),
+        }
+    }
+
+    /// The chunk of the `index`th item of a sequence.
+    fn item<T: Serialize>(self, index: usize, item: &T) -> Bytes {
+        let mut chunk = match self {
+            // a buffer of the exact size avoids reallocations
+            PageFormat::Bincode => {
+                Vec::with_capacity(bincode::serialized_size(item).unwrap() as usize)
+            }
+            PageFormat::Json if index > 0 => vec![b','],
+            PageFormat::Json => Vec::new(),
+        };
+        self.serialize_into(&mut chunk, item);
+        chunk.into()
+    }
+}
+
+/// Serializes a struct field by field, with the items of its sequences in their own chunks, laid
+/// out like serializing the whole struct at once.
+struct ChunkedBody {
+    format: PageFormat,
+    chunks: Vec<Bytes>,
+    /// The bytes written since the last item, sent in the chunk before the next one.
+    pending: Vec<u8>,
+    fields: usize,
+}
+
+impl ChunkedBody {
+    fn new(format: PageFormat, items: usize) -> Self {
+        Self {
+            format,
+            chunks: Vec::with_capacity(items + 2),
+            pending: Vec::new(),
+            fields: 0,
+        }
+    }
+
+    fn field<T: Serialize>(&mut self, name: &str, value: &T) {
+        self.name(name);
+        self.format.serialize_into(&mut self.pending, value);
+    }
+
+    /// Starts the sequence `name` of `len` items, to be added with [`ChunkedBody::items`].
+    fn seq(&mut self, name: &str, len: usize) {
+        self.name(name);
+        match self.format {
+            PageFormat::Bincode => self.format.serialize_into(&mut self.pending, &len),
+            PageFormat::Json => self.pending.push(b'['),
+        }
+    }
+
+    /// Adds item chunks made by [`PageFormat::item`].
+    fn items(&mut self, items: impl IntoIterator<Item = Bytes>) {
+        self.flush();
+        self.chunks.extend(items);
+    }
+
+    fn end_seq(&mut self) {
+        if self.format == PageFormat::Json {
+            self.pending.push(b']');
+        }
+    }
+
+    fn finish(mut self) -> Vec<Bytes> {
+        if self.format == PageFormat::Json {
+            self.pending.push(b'}');
         }
+        self.flush();
+        self.chunks
     }
 
-    let response = Response::new().append_header("Content-Type: application/json");
+    fn name(&mut self, name: &str) {
+        if self.format == PageFormat::Json {
+            self.pending
+                .push(if self.fields == 0 { b'{' } else { b',' });
+            serde_json::to_writer(&mut self.pending, name).unwrap();
+            self.pending.push(b':');
+        }
+        self.fields += 1;
+    }
+
+    fn flush(&mut self) {
+        if !self.pending.is_empty() {
+            self.chunks.push(std::mem::take(&mut self.pending).into());
+        }
+    }
+}
 
-    {
-        let mut mutations = state.mutations.lock().await;
-        if !mutations.is_pagination_empty() {
-            let mut page_number = state.pagination_page_number.lock().await;
+/// `GET /api/messages/get-page`, serves the next page of the pagination with an `X-Page-Token`.
+/// Presenting the token again replays the identical response without advancing the pagination,
+/// so a page can be retried safely after a timeout. A page that failed or is no longer kept is
+/// fetched again by its page number, as long as its session is still kept and, for a cache
+/// page, it wasn't acknowledged. Pages come from the `X-Pagination-Session` given by the
+/// metadata, or from the latest session without one. Fresh pages leave out the images unless
+/// `images`, cache pages always carry the images of the changes.
+///
+/// The next page is compared against `if_none_match` before it is claimed, a client already
+/// holding it gets a 304 and the pagination stays on that page.
+pub(crate) async fn handle_get(
+    page_token: Option<&str>,
+    session: Option<&str>,
+    if_none_match: Option<&str>,
+    images: bool,
+    format: PageFormat,
+    state: Arc<AppState>,
+) -> Response {
+    if let Some(token) = page_token {
+        let replayed = state.page_tokens.lock().await.replay(token);
+        let response = match replayed {
+            Ok(response) => response,
+            Err(PageTokenError::Expired) => match refetch_page(token, images, format, &state).await
+            {
+                Some(response) => response,
+                None => page_token_error(PageTokenError::Expired),
+            },
+            Err(e) => page_token_error(e),
+        };
+        return with_etag(response, if_none_match);
+    }
 
-            let result = mutations.get(*page_number, &state.image_base_path);
-            drop(mutations);
+    let session = match session.map(str::parse) {
+        None => None,
+        Some(Ok(session)) => Some(session),
+        Some(Err(_)) => {
+            return Response::new()
+                .status(StatusCode::BadRequest)
+                .header("Content-Type", CONTENT_TYPE_TEXT)
+                .body("Invalid X-Pagination-Session.");
+        }
+    };
 
-            *page_number += 1;
+    // in keyset mode each page continues after the page before it, so the pages of a session
+    // are claimed and served one at a time
+    let chain = state
+        .pagination
+        .lock()
+        .await
+        .chain(session, state.pagination_mode);
+    let (session, _serving) = match chain {
+        Some((id, chain)) => (Some(id), Some(chain.lock_owned().await)),
+        None => (session, None),
+    };
 
-            // if the pagination is done, reset the flag, and page_number
-            let mut triggered_pagination = state.triggered_pagination.lock().await;
-            if result.done {
-                *triggered_pagination = false;
-                *page_number = 0;
+    // the page is served without claiming it first, to see whether the client holds it already
+    let mut peeked = None;
+    if if_none_match.is_some() {
+        let next = {
+            let pagination = state.pagination.lock().await;
+            pagination.peek_page(session).map(|page| {
+                let start =
+                    pagination.start_of(&page, state.pagination_page_size, state.pagination_mode);
+                let view = pagination.view(page.session).cloned().unwrap_or_default();
+                (page, view, start)
+            })
+        };
+        if let Ok((page, view, start)) = next {
+            let response = page_response(&state, page, &view, start, images, format).await;
+            let checked = with_etag(response.clone(), if_none_match);
+            if checked.status_code() == StatusCode::NotModified {
+                return checked;
             }
+            peeked = Some((page, response));
+        }
+    }
+
+    // the view the session was triggered with
+    let claimed = {
+        let mut pagination = state.pagination.lock().await;
+        pagination.next_page(session).map(|page| {
+            let start =
+                pagination.start_of(&page, state.pagination_page_size, state.pagination_mode);
+            let view = pagination.view(page.session).cloned().unwrap_or_default();
+            (page, view, start)
+        })
+    };
+    let (page, view, start) = match claimed {
+        Ok(claimed) => claimed,
+        Err(e) => {
+            let body = e.to_string();
+            return Response::new()
+                .status(e.status())
+                .header("Content-Type", CONTENT_TYPE_TEXT)
+                .body(body);
+        }
+    };
+
+    let token = state
+        .page_tokens
+        .lock()
+        .await
+        .issue(page.session, page.number);
+    // another request may have claimed the peeked page meanwhile
+    let response = match peeked {
+        Some((peeked, response)) if peeked == page => response,
+        _ => page_response(&state, page, &view, start, images, format).await,
+    };
+    // failed pages are retried with the token too
+    let response = response.header(PAGE_TOKEN_HEADER, &token);
+    // failed pages aren't kept, presenting their token fetches them again
+    if response.status_code().is_success() {
+        state
+            .page_tokens
+            .lock()
+            .await
+            .remember(token, response.clone());
+    }
+    with_etag(response, if_none_match)
+}
 
-            drop(page_number);
-            drop(triggered_pagination);
+/// `POST /api/messages/get-page/ack`, acknowledges the cache page of the `X-Page-Token` and the
+/// pages before it, which can't be fetched again. Their mutations are dropped once the other
+/// sessions are done with them too, rather than kept until the session is released, and served
+/// again after a restart otherwise. Fresh pages need no acknowledgement, acknowledging one does
+/// nothing.
+pub(crate) async fn handle_ack_page(page_token: Option<&str>, state: Arc<AppState>) -> Response {
+    let Some(token) = page_token else {
+        return Response::new()
+            .status(StatusCode::BadRequest)
+            .header("Content-Type", CONTENT_TYPE_TEXT)
+            .body("The X-Page-Token of the page to acknowledge is missing.");
+    };
+    let (session, number) = match state.page_tokens.lock().await.page_of(token) {
+        Ok(page) => page,
+        Err(e) => return page_token_error(e),
+    };
 
-            let body = bincode::serialize(&result).unwrap();
-            let mut res = response
-                .append_header(&format!("Content-Length: {}", body.len()))
-                .to_string()
-                .into_bytes();
-            res.extend(body);
-            return res;
+    // held while acknowledging, so the session isn't released meanwhile
+    let pagination = state.pagination.lock().await;
+    let Some(page) = pagination.reclaim(session, number) else {
+        return page_token_error(PageTokenError::Expired);
+    };
+    if page.kind == PaginationType::Cache {
+        let acked = state
+            .mutations
+            .lock()
+            .await
+            .ack(page.session, page.number - 1);
+        if let Err(e) = acked {
+            eprintln!("Failed to drop the acknowledged mutations: {}", e);
+            return Response::new().status(StatusCode::InternalServerError);
         }
     }
+    Response::new().status(StatusCode::NoContent)
+}
 
-    // pagination in postgres
-    let mut offset = state.db_pagination_offset.lock().await;
-    // get a page of messages
-    let messages = match sqlx::query_as!(
-        Message,
-        "
-        SELECT *
-        FROM messages
-        ORDER BY uuid
-        LIMIT $1
-        OFFSET $2
-        ",
-        state.pagination_page_size as i64,
-        *offset as i64
-    )
-    .map(|m| {
-        let image = {
-            match m.has_image {
-                true => image::get(&state.image_base_path, &m.uuid).unwrap_or("".to_string()),
-                false => "".to_string(),
+/// Fetches the page `token` was issued for again, without advancing the pagination. `None` if
+/// the page isn't one of the claimed pages of a kept session.
+async fn refetch_page(
+    token: &str,
+    images: bool,
+    format: PageFormat,
+    state: &AppState,
+) -> Option<Response> {
+    let (session, number) = state.page_tokens.lock().await.page_of(token).ok()?;
+    let (page, view, start) = {
+        let pagination = state.pagination.lock().await;
+        let page = pagination.reclaim(session, number)?;
+        let start = pagination.start_of(&page, state.pagination_page_size, state.pagination_mode);
+        let view = pagination.view(page.session).cloned().unwrap_or_default();
+        (page, view, start)
+    };
+
+    let response = page_response(state, page, &view, start, images, format)
+        .await
+        .header(PAGE_TOKEN_HEADER, token);
+    if response.status_code().is_success() {
+        state
+            .page_tokens
+            .lock()
+            .await
+            .remember(token.to_string(), response.clone());
+    }
+    Some(response)
+}
+
+fn page_token_error(e: PageTokenError) -> Response {
+    let body = e.to_string();
+    Response::new()
+        .status(e.status())
+        .header("Content-Type", CONTENT_TYPE_TEXT)
+        .body(body)
+}
+
+/// Adds the `ETag` of its body to a page or image `response`, answering 304 without the body
+/// instead when `if_none_match` lists it, i.e. the client already holds the page or image.
+pub(crate) fn with_etag(response: Response, if_none_match: Option<&str>) -> Response {
+    if response.status_code() != StatusCode::Ok {
+        return response;
+    }
+
+    let mut hasher = Sha256::new();
+    for chunk in response.chunks() {
+        hasher.update(chunk);
+    }
+    let etag = format!("\"{}\"", hex::encode(&hasher.finalize()[..16]));
+
+    // weak comparison, a client may have stored the tag as a weak one
+    let matches = if_none_match.is_some_and(|tags| {
+        tags.split(',')
+            .map(|tag| tag.trim())
+            .any(|tag| tag == "*" || tag.trim_start_matches("W/") == etag)
+    });
+    let response = response.header("ETag", &etag);
+    if matches {
+        response
+            .status(StatusCode::NotModified)
+            .body_chunks(Vec::new())
+    } else {
+        response
+    }
+}
+
+/// Responds with `page` of the pagination, a fresh page in `view` from `start`, without its
+/// `X-Page-Token`.
+async fn page_response(
+    state: &AppState,
+    page: Page,
+    view: &PageView,
+    start: PageStart,
+    images: bool,
+    format: PageFormat,
+) -> Response {
+    // nothing to paginate, the run is done without touching the database
+    if page.empty {
+        return Response::new().status(StatusCode::NoContent);
+    }
+
+    if page.kind == PaginationType::Cache {
+        // cache pages are 0-based, their mutation payloads and images are read once the entries
+        // are known, without holding up the other users of the mutations
+        let entries = state
+            .mutations
+            .lock()
+            .await
+            .page(page.session, page.number - 1);
+        let entries = match entries {
+            Ok(entries) => entries,
+            Err(e) => {
+                eprintln!("Failed to read the cache page: {}", e);
+                return Response::new().status(StatusCode::InternalServerError);
             }
         };
-        CompleteMessage::new(m, image)
+        let Some(entries) = entries else {
+            return Response::new()
+                .status(StatusCode::Gone)
+                .header("Content-Type", CONTENT_TYPE_TEXT)
+                .body("The page was acknowledged or released, it can't be fetched again.");
+        };
+        let result = match entries.read(&*state.images).await {
+            Ok(result) => result,
+            Err(e) => {
+                eprintln!("Failed to read the cache page: {}", e);
+                return Response::new().status(StatusCode::InternalServerError);
+            }
+        };
+
+        // the cache may run out before the last page, e.g. when it was cleared
+        if result.done {
+            state.pagination.lock().await.finish(page.session);
+        }
+
+        // serialized like a whole `MutationResults`, the posts with their images in their own
+        // chunks
+        let start = Instant::now();
+        let mut body = ChunkedBody::new(format, result.posts.len());
+        body.seq("posts", result.posts.len());
+        body.items(
+            result
+                .posts
+                .iter()
+                .enumerate()
+                .map(|(i, post)| format.item(i, post)),
+        );
+        body.end_seq();
+        body.field("puts_deletes", &result.puts_deletes);
+        body.field("done", &result.done);
+        body.field("page_number", &result.page_number);
+        let body = body.finish();
+        // the canary compares against the bincode layout
+        if format == PageFormat::Bincode && state.wire_canary.sample() {
+            let len = body.iter().map(Bytes::len).sum();
+            state
+                .wire_canary
+                .shadow("cache page", &result, len, start.elapsed());
+        }
+        return Response::new()
+            .header("Content-Type", format.content_type())
+            .header("Vary", "Accept")
+            .body_chunks(body);
+    }
+
+    let (response, next) = fresh_page_response(
+        state,
+        view,
+        start,
+        state.pagination_page_size,
+        page.number,
+        images,
+        format,
+    )
+    .await;
+    // the next page of the session continues after this one in keyset mode
+    if let Some(uuid) = next {
+        state
+            .pagination
+            .lock()
+            .await
+            .record_continuation(page.session, page.number, uuid);
+    }
+    response
+}
+
+/// `GET /api/messages/get-page?page=<n>&size=<size>`, fetches a specific fresh page without
+/// going through the pagination state, in `view` given by the same query. `size` defaults to
+/// the configured page size.
+pub(crate) async fn handle_get_page_number(
+    page: &str,
+    size: Option<&str>,
+    view: Result<PageView, &str>,
+    images: bool,
+    format: PageFormat,
+    state: Arc<AppState>,
+) -> Response {
+    let view = match view {
+        Ok(view) => view,
+        Err(e) => return bad_view(e),
+    };
+    let page = match parse_page(Some(page), size, state.pagination_page_size) {
+        Ok(page) => page,
+        Err(response) => return response,
+    };
+
+    let start = PageStart::Offset(page.offset);
+    let (response, _) =
+        fresh_page_response(&state, &view, start, page.size, page.number, images, format).await;
+    response
+}
+
+/// A page asked for with `?page=<n>&size=<size>`.
+pub(crate) struct PageQuery {
+    /// The number of the page, counted from 1.
+    pub number: usize,
+    pub size: usize,
+    /// How many rows come before the page.
+    pub offset: usize,
+}
+
+/// Parses `?page=<n>&size=<size>`, `page` defaulting to the first and `size` to `default_size`.
+///
+/// # Errors
+///
+/// This function will return a Bad Request response if `page` is less than 1 or further than
+/// the database can skip, or `size` isn't between 1 and [`MAX_PAGE_SIZE`].
+pub(crate) fn parse_page(
+    page: Option<&str>,
+    size: Option<&str>,
+    default_size: usize,
+) -> Result<PageQuery, Response> {
+    let number = match page {
+        Some(page) => page.parse::<usize>().ok().filter(|page| *page >= 1),
+        None => Some(1),
+    };
+    let (Some(number), Some(size)) = (number, parse_size(size, default_size)) else {
+        let body = format!("page must be at least 1 and size between 1 and {MAX_PAGE_SIZE}.");
+        return Err(Response::new()
+            .status(StatusCode::BadRequest)
+            .header("Content-Type", CONTENT_TYPE_TEXT)
+            .body(body));
+    };
+    let offset = (number - 1)
+        .checked_mul(size)
+        .filter(|offset| i64::try_from(*offset).is_ok());
+    let Some(offset) = offset else {
+        return Err(Response::new()
+            .status(StatusCode::BadRequest)
+            .header("Content-Type", CONTENT_TYPE_TEXT)
+            .body("page is past the last one there can be."));
+    };
+    Ok(PageQuery {
+        number,
+        size,
+        offset,
     })
-    .fetch_all(state.pool.as_ref())
-    .await
-    {
-        Ok(v) => v,
+}
+
+/// Parses `?size=<size>`, `None` if it isn't between 1 and [`MAX_PAGE_SIZE`].
+fn parse_size(size: Option<&str>, default_size: usize) -> Option<usize> {
+    match size {
+        Some(size) => size
+            .parse::<usize>()
+            .ok()
+            .filter(|size| (1..=MAX_PAGE_SIZE).contains(size)),
+        None => Some(default_size),
+    }
+}
+
+/// `GET /api/messages/get-page?cursor=<cursor>&size=<size>`, fetches the fresh page at `cursor`
+/// without going through the pagination state, in `view` given by the same query, which must be
+/// in uuid order. An empty cursor starts at the first page, the `X-Next-Cursor` of the response
+/// continues after it. Unlike `?page=`, the database seeks to the page instead of skipping the
+/// ones before it.
+pub(crate) async fn handle_get_cursor(
+    cursor: &str,
+    size: Option<&str>,
+    view: Result<PageView, &str>,
+    images: bool,
+    format: PageFormat,
+    state: Arc<AppState>,
+) -> Response {
+    let view = match view {
+        Ok(view) if view.sort == SortKey::Uuid => view,
+        Ok(_) => return bad_view("cursors only page through messages sorted by uuid."),
+        Err(e) => return bad_view(e),
+    };
+    let size = parse_size(size, state.pagination_page_size);
+    let (Some((page_number, after)), Some(size)) = (decode_cursor(cursor), size) else {
+        let body = format!("cursor must be one returned in {NEXT_CURSOR_HEADER} or empty and size between 1 and {MAX_PAGE_SIZE}.");
+        return Response::new()
+            .status(StatusCode::BadRequest)
+            .header("Content-Type", CONTENT_TYPE_TEXT)
+            .body(body);
+    };
+
+    let start = PageStart::After(after);
+    let (response, next) =
+        fresh_page_response(&state, &view, start, size, page_number, images, format).await;
+    match next {
+        Some(uuid) => response.header(NEXT_CURSOR_HEADER, encode_cursor(page_number + 1, &uuid)),
+        None => response,
+    }
+}
+
+/// The opaque cursor of the page `page_number`, continuing after `uuid`. Clients pass it back
+/// as it is, so the layout may change.
+fn encode_cursor(page_number: usize, uuid: &str) -> String {
+    base64::encode_config(format!("{page_number}:{uuid}"), base64::URL_SAFE_NO_PAD)
+}
+
+/// The page number and the uuid the page of `cursor` continues after, `None` after no uuid for
+/// the empty cursor of the first page.
+fn decode_cursor(cursor: &str) -> Option<(usize, Option<String>)> {
+    if cursor.is_empty() {
+        return Some((1, None));
+    }
+    let decoded = base64::decode_config(cursor, base64::URL_SAFE_NO_PAD).ok()?;
+    let decoded = String::from_utf8(decoded).ok()?;
+    let (page_number, uuid) = decoded.split_once(':')?;
+    let page_number = page_number
+        .parse::<usize>()
+        .ok()
+        .filter(|page| *page >= 2)?;
+    match uuid.is_empty() {
+        true => None,
+        false => Some((page_number, Some(uuid.to_string()))),
+    }
+}
+
+/// Responds with the fresh page of `limit` messages from `start` in `view`, with their images
+/// if `images`. Also returns the uuid the next page continues after, unless the page is the
+/// last.
+pub(crate) async fn fresh_page_response(
+    state: &AppState,
+    view: &PageView,
+    start: PageStart,
+    limit: usize,
+    page_number: usize,
+    images: bool,
+    format: PageFormat,
+) -> (Response, Option<String>) {
+    // concurrent requests for the same page share a single query and serialization
+    let fetched = state
+        .fresh_pages
+        .run(
+            (
+                view.clone(),
+                start.clone(),
+                limit,
+                page_number,
+                images,
+                format,
+            ),
+            || fetch_fresh_page(state, view, &start, limit, page_number, images, format),
+        )
+        .await;
+    let fetched = match fetched {
+        Ok(fetched) => fetched,
         Err(e) => {
             eprintln!("Error while fetching messages: {}", e);
-            return response
-                .status_line("HTTP/1.1 500 Internal Server Error")
-                .body("Internal Server Error")
-                .to_string()
-                .into_bytes();
+            let response = Response::new()
+                .status(StatusCode::InternalServerError)
+                .header("Content-Type", CONTENT_TYPE_TEXT)
+                .body("Internal Server Error");
+            return (response, None);
         }
     };
+    let (body, next) = &*fetched;
 
-    let mut page_number = state.pagination_page_number.lock().await;
-    *page_number += 1;
-    let mut triggered_pagination = state.triggered_pagination.lock().await;
+    let response = Response::new()
+        .header("Content-Type", format.content_type())
+        .header("Vary", "Accept")
+        .body_chunks(body.to_vec());
+    (response, next.clone())
+}
 
-    let result = DbResults {
-        page_number: *page_number,
-        messages,
+/// Fetches a page of `limit` messages from `start` in `view` from postgres and serializes it,
+/// one chunk per message, with their images if `images`. A full page also gives its last uuid,
+/// for the next page to continue after.
+async fn fetch_fresh_page(
+    state: &AppState,
+    view: &PageView,
+    start: &PageStart,
+    limit: usize,
+    page_number: usize,
+    images: bool,
+    format: PageFormat,
+) -> RepositoryResult<(Vec<Bytes>, Option<String>)> {
+    // get a page of messages
+    let rows = state.messages.page(view, start, limit).await?;
+    let next = match rows.last() {
+        Some(last) if rows.len() == limit => Some(last.uuid.clone()),
+        _ => None,
     };
 
-    if *page_number == *state.pages_count.lock().await {
-        // pagination is done, reset the offset and the flag
-        *offset = 0;
-        *triggered_pagination = false;
-        *page_number = 0;
-    } else {
-        *offset += state.pagination_page_size;
-    }
-
-    // drop the locks so that other threads can access the flag and offset immediately
-    drop(triggered_pagination);
-    drop(offset);
-
-    let body = bincode::serialize(&result).unwrap();
-    let mut res = response
-        .append_header(&format!("Content-Length: {}", body.len()))
-        .to_string()
-        .into_bytes();
-    res.extend(body);
-    res
-}
-
-pub(crate) async fn get_pagination_meta(state: Arc<AppState>) -> Vec<u8> {
-    // trigger pagination
-    *state.triggered_pagination.lock().await = true;
-
-    let response = Response::new().append_header("Content-Type: application/octet-stream");
-
-    // if there are cached mutation updates, return them
-    {
-        let mut mutations = state.mutations.lock().await;
-        if !mutations.is_empty_for_pagination() {
-            let meta = mutations.get_pagination_meta();
-            *state.pages_count.lock().await = meta.total_pages;
-            drop(mutations);
-            let body = bincode::serialize(&meta).unwrap();
-            let res_without_body = response
-                .status_line("HTTP/1.1 200 OK")
-                .append_header(&format!("Content-Length: {}", body.len()))
-                .to_string();
-            let mut res = res_without_body.into_bytes();
-            res.extend(body);
-            return res;
-        }
-    }
-
-    let count = state.all_uuids.lock().await.len();
-    let meta = PaginationMetadata::new(count, state.pagination_page_size, PaginationType::Fresh);
-    *state.pages_count.lock().await = meta.total_pages;
-    let body = bincode::serialize(&meta).unwrap();
-    let mut res = response
-        .status_line("HTTP/1.1 200 OK")
-        .append_header(&format!("Content-Length: {}", body.len()))
-        .to_string()
-        .into_bytes();
-    res.extend(body);
-    res
+    let mut serializer = RowSerializer {
+        images,
+        format,
+        serialized: 0,
+        // the canary compares against the bincode layout
+        shadow: format == PageFormat::Bincode && state.wire_canary.sample(),
+        legacy_time: Duration::ZERO,
+        v2_len: 0,
+        v2_time: Duration::ZERO,
+    };
+
+    // serialized like a whole `DbResults`: the page number and message count, then the messages
+    let mut body = ChunkedBody::new(format, rows.len());
+    body.field("page_number", &page_number);
+    body.seq("messages", rows.len());
+    if serializer.shadow {
+        serializer.v2_len += wire::serialize_v2(&(page_number, rows.len())).len();
+    }
+
+    // serializing blocks, a batch of rows at a time is handed to the blocking pool so a large
+    // page doesn't hold up the other requests of this worker, along with the images of the
+    // batch, or only their metadata, read concurrently beforehand
+    let mut rows = rows.into_iter();
+    loop {
+        let batch: Vec<_> = rows.by_ref().take(ROWS_PER_BATCH).collect();
+        if batch.is_empty() {
+            break;
+        }
+        let uuids: Vec<_> = batch
+            .iter()
+            .filter(|m| m.has_image)
+            .map(|m| m.uuid.as_str())
+            .collect();
+        let batch_images = match images {
+            true => BatchImages::Images(state.images.get_many(&uuids).await),
+            // the metadata is small, and tells clients what they will get from the image
+            // endpoint
+            false => BatchImages::Metadata(state.images.metadata_many(&uuids).await),
+        };
+        let chunks;
+        (serializer, chunks) = tokio::task::spawn_blocking(move || {
+            let chunks = serializer.serialize(&batch, batch_images);
+            (serializer, chunks)
+        })
+        .await?;
+        body.items(chunks);
+    }
+    body.end_seq();
+    let body = body.finish();
+
+    if serializer.shadow {
+        let legacy_len = body.iter().map(Bytes::len).sum();
+        state.wire_canary.report(
+            "fresh page",
+            legacy_len,
+            serializer.legacy_time,
+            serializer.v2_len,
+            serializer.v2_time,
+        );
+    }
+
+    Ok((body, next))
+}
+
+/// How many rows of a fresh page are serialized per blocking task.
+const ROWS_PER_BATCH: usize = 32;
+
+/// The images of the rows of a batch that have one, in order, or only their metadata when the
+/// messages don't carry their images.
+enum BatchImages {
+    Images(Vec<Option<Arc<CachedImage>>>),
+    Metadata(Vec<Option<ImageMetadata>>),
+}
+
+/// Serializes the rows of a fresh page, borrowing the images from the cache rather than copying
+/// them.
+struct RowSerializer {
+    /// Whether the messages carry their images, they only carry their metadata otherwise.
+    images: bool,
+    format: PageFormat,
+    /// How many rows were serialized so far.
+    serialized: usize,
+    /// Whether the page is shadowed by the wire canary.
+    shadow: bool,
+    legacy_time: Duration,
+    v2_len: usize,
+    v2_time: Duration,
+}
+
+impl RowSerializer {
+    /// Serializes `rows`, given the images of those that have one.
+    fn serialize(&mut self, rows: &[Message], images: BatchImages) -> Vec<Bytes> {
+        let mut chunks = Vec::with_capacity(rows.len());
+        let (images, metadata) = match images {
+            BatchImages::Images(images) => (images, Vec::new()),
+            BatchImages::Metadata(metadata) => (Vec::new(), metadata),
+        };
+        let (mut images, mut metadata) = (images.into_iter(), metadata.into_iter());
+        for m in rows {
+            let (image, image_metadata) = match (m.has_image, self.images) {
+                (true, true) => {
+                    let image = images.next().flatten();
+                    let metadata = image.as_ref().map(|image| image.metadata.clone());
+                    (image, metadata)
+                }
+                (true, false) => (None, metadata.next().flatten()),
+                (false, _) => (None, None),
+            };
+            let message = CompleteMessageRef {
+                uuid: &m.uuid,
+                author: &m.author,
+                message: &m.message,
+                parent_uuid: m.parent_uuid.as_deref(),
+                likes: m.likes,
+                image: image.as_ref().map_or("", |image| &image.base64),
+                image_metadata: image_metadata.as_ref(),
+                client_timestamp: m.client_timestamp,
+                server_timestamp: m.server_timestamp,
+                created_at: m.created_at,
+                updated_at: m.updated_at,
+                revision: m.revision,
+                reactions: &m.reactions,
+            };
+
+            let start = Instant::now();
+            chunks.push(self.format.item(self.serialized, &message));
+            self.legacy_time += start.elapsed();
+            self.serialized += 1;
+
+            if self.shadow {
+                let start = Instant::now();
+                self.v2_len += wire::serialize_v2(&message).len();
+                self.v2_time += start.elapsed();
+            }
+        }
+        chunks
+    }
+}
+
+/// `GET /api/messages`, triggers a pagination session and serves its metadata, with the id of
+/// the session in `X-Pagination-Session`. Sessions run side by side, each client pages through
+/// its own with the id, and a new one is refused with 503 while every kept session is in use.
+/// A pagination in another `view` than the default is always fresh, paging through the matching
+/// messages in its order, and leaves the cached mutations for the next default one.
+pub(crate) async fn get_pagination_meta(
+    format: PageFormat,
+    view: Result<PageView, &str>,
+    state: Arc<AppState>,
+) -> Response {
+    let view = match view {
+        Ok(view) => view,
+        Err(e) => return bad_view(e),
+    };
+    let response = Response::new()
+        .header("Content-Type", format.content_type())
+        .header("Vary", "Accept");
+
+    // make sure every committed change is in the mutation manager before paginating
+    if let Err(e) = outbox::relay(&state).await {
+        eprintln!("Failed to relay the outbox: {}", e);
+        let body = "Internal Server Error";
+        return Response::new()
+            .status(StatusCode::InternalServerError)
+            .header("Content-Type", CONTENT_TYPE_TEXT)
+            .body(body);
+    }
+
+    let count = match &view.author {
+        Some(author) => match state.messages.count_by_author(author).await {
+            Ok(count) => count,
+            Err(e) => {
+                eprintln!("Failed to count messages: {}", e);
+                return Response::new().status(StatusCode::InternalServerError);
+            }
+        },
+        None => state.all_uuids.lock().await.len(),
+    };
+    let triggered = {
+        let mut pagination = state.pagination.lock().await;
+        if let Err(e) = pagination.make_room() {
+            let body = e.to_string();
+            return Response::new()
+                .status(e.status())
+                .header("Content-Type", CONTENT_TYPE_TEXT)
+                .body(body);
+        }
+        trigger(
+            &mut pagination,
+            &mut *state.mutations.lock().await,
+            view,
+            count,
+            state.pagination_page_size,
+        )
+    };
+    let (session, meta) = match triggered {
+        Ok(triggered) => triggered,
+        Err(e) => {
+            eprintln!("Failed to start the cache pagination: {}", e);
+            return Response::new().status(StatusCode::InternalServerError);
+        }
+    };
+
+    let mut body = Vec::new();
+    format.serialize_into(&mut body, &meta);
+    response
+        .status(StatusCode::Ok)
+        .header(PAGINATION_SESSION_HEADER, session)
+        .body_bytes(body)
+}
+
+/// Starts a pagination session in `view`, through the cached mutations for the default view
+/// unless there are none or they expired, and through the `count` messages otherwise.
+fn trigger(
+    pagination: &mut Pagination,
+    mutations: &mut MutationManager,
+    view: PageView,
+    count: usize,
+    page_size: usize,
+) -> Result<(u64, PaginationMetadata), MutationError> {
+    // the entries only the released sessions still needed are dropped first
+    mutations.retain_sessions(pagination.sessions())?;
+    // once mutations expired, a fresh pagination gets the clients the changes they missed
+    let cached = view.is_default()
+        && !mutations.needs_resync()
+        && (!mutations.is_empty_for_pagination() || !mutations.is_pagination_empty());
+    if view.is_default() && !cached {
+        mutations.resynced()?;
+    }
+    pagination.trigger(view, |session| {
+        // if there are cached mutation updates, paginate through them
+        if cached {
+            mutations.get_pagination_meta(session)
+        } else {
+            Ok(PaginationMetadata::new(
+                count,
+                page_size,
+                PaginationType::Fresh,
+            ))
+        }
+    })
+}
+
+fn bad_view(e: &str) -> Response {
+    Response::new()
+        .status(StatusCode::BadRequest)
+        .header("Content-Type", CONTENT_TYPE_TEXT)
+        .body(e.to_string())
 }
@@ -0,0 +1,323 @@
+use std::{
+    future::poll_fn,
+    io,
+    pin::Pin,
+    sync::Arc,
+    task::{Context, Poll},
+    time::Instant,
+};
+
+use bytes::Bytes;
+use h2::{
+    server::{self, SendResponse},
+    RecvStream,
+};
+use http::header::CONTENT_LENGTH;
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, ReadBuf};
+
+use crate::{
+    access_log,
+    app_state::AppState,
+    request::{Request, RequestError},
+    response::{Response, StatusCode, CONTENT_TYPE_TEXT},
+};
+
+use super::{body_limit, response_to, routes, KEEP_ALIVE_TIMEOUT};
+
+/// What clients with prior knowledge of HTTP/2 send first on the connection.
+pub(super) const PREFACE: &[u8] = b"PRI * HTTP/2.0\r\n\r\nSM\r\n\r\n";
+
+/// How many streams a client may have open at once on a connection, each of them is served by
+/// a task of its own.
+const MAX_CONCURRENT_STREAMS: u32 = 100;
+
+/// Headers about the connection, which HTTP/2 manages itself and forbids in a response.
+const CONNECTION_HEADERS: [&str; 5] = [
+    "connection",
+    "keep-alive",
+    "proxy-connection",
+    "transfer-encoding",
+    "upgrade",
+];
+
+/// Reads the start of `stream` until it is known whether it is the [`PREFACE`]: until as many
+/// bytes as it has are read, they stop matching it, or the stream ends. Returns whether it is
+/// along with the bytes read, which are still to be served.
+pub(super) async fn sniff<S: AsyncRead + Unpin>(stream: &mut S) -> io::Result<(bool, Vec<u8>)> {
+    let mut read = Vec::with_capacity(PREFACE.len());
+    while read.len() < PREFACE.len() && PREFACE.starts_with(&read) {
+        let mut buf = [0; PREFACE.len()];
+        let n = stream.read(&mut buf[..PREFACE.len() - read.len()]).await?;
+        if n == 0 {
+            break;
+        }
+        read.extend_from_slice(&buf[..n]);
+    }
+    Ok((read == PREFACE, read))
+}
+
+/// A stream whose first bytes were already read, which are served again before the rest.
+pub(super) struct Rewind<S> {
+    read: Vec<u8>,
+    /// How many of `read` were served again.
+    position: usize,
+    inner: S,
+}
+
+impl<S> Rewind<S> {
+    pub(super) fn new(read: Vec<u8>, inner: S) -> Self {
+        Rewind {
+            read,
+            position: 0,
+            inner,
+        }
+    }
+}
+
+impl<S: AsyncRead + Unpin> AsyncRead for Rewind<S> {
+    fn poll_read(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<io::Result<()>> {
+        let this = &mut *self;
+        if this.position < this.read.len() {
+            let n = buf.remaining().min(this.read.len() - this.position);
+            buf.put_slice(&this.read[this.position..this.position + n]);
+            this.position += n;
+            return Poll::Ready(Ok(()));
+        }
+        Pin::new(&mut this.inner).poll_read(cx, buf)
+    }
+}
+
+impl<S: AsyncWrite + Unpin> AsyncWrite for Rewind<S> {
+    fn poll_write(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        Pin::new(&mut self.inner).poll_write(cx, buf)
+    }
+
+    fn poll_flush(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.inner).poll_flush(cx)
+    }
+
+    fn poll_shutdown(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.inner).poll_shutdown(cx)
+    }
+}
+
+/// Serves an HTTP/2 connection, its streams concurrently and with the handlers of HTTP/1.1, so
+/// the bodies are the same, bincode pages included. Bulk downloads aren't paced to
+/// `DOWNLOAD_BYTES_PER_SEC`, the flow control of the client applies instead. Like for
+/// HTTP/1.1, the connection is closed once it has had no stream open for
+/// [`KEEP_ALIVE_TIMEOUT`].
+pub(super) async fn serve<S: AsyncRead + AsyncWrite + Unpin>(stream: S, state: Arc<AppState>) {
+    let mut connection = match server::Builder::new()
+        .max_header_list_size(state.request_limits.max_header_bytes as u32)
+        .max_concurrent_streams(MAX_CONCURRENT_STREAMS)
+        .handshake::<_, Bytes>(stream)
+        .await
+    {
+        Ok(connection) => connection,
+        Err(e) => {
+            eprintln!("Failed HTTP/2 handshake: {}", e);
+            return;
+        }
+    };
+
+    // each stream being served holds a clone, so the connection is idle when only this one is
+    // left
+    let open_streams = Arc::new(());
+    let mut closing = false;
+    loop {
+        // accepting the next stream also drives the connection, sending the responses
+        let stream = match tokio::time::timeout(KEEP_ALIVE_TIMEOUT, connection.accept()).await {
+            Ok(Some(stream)) => stream,
+            Ok(None) => return,
+            Err(_) if Arc::strong_count(&open_streams) > 1 => continue,
+            // the client didn't go away after the GOAWAY
+            Err(_) if closing => return,
+            Err(_) => {
+                // lets the client know not to open more streams, the connection closes once
+                // it is sent
+                connection.graceful_shutdown();
+                closing = true;
+                continue;
+            }
+        };
+        match stream {
+            Ok((request, respond)) => {
+                let open_streams = Arc::clone(&open_streams);
+                let state = Arc::clone(&state);
+                tokio::spawn(async move {
+                    serve_stream(request, respond, state).await;
+                    drop(open_streams);
+                });
+            }
+            Err(e) => {
+                eprintln!("HTTP/2 connection error: {}", e);
+                return;
+            }
+        }
+    }
+}
+
+/// Answers the request of a stream.
+async fn serve_stream(
+    request: http::Request<RecvStream>,
+    respond: SendResponse<Bytes>,
+    state: Arc<AppState>,
+) {
+    let start = Instant::now();
+    let mut body_bytes = 0;
+    let request =
+        match tokio::time::timeout(state.read_timeout, read_request(request, &state)).await {
+            Ok(Ok(request)) => request,
+            Err(_) => {
+                let response = Response::new()
+                    .status(StatusCode::RequestTimeout)
+                    .header("Content-Type", CONTENT_TYPE_TEXT)
+                    .body("The request wasn't received in time.");
+                if let Err(e) = send_response(respond, &response, &mut body_bytes).await {
+                    eprintln!("Failed to send response: {}", e);
+                }
+                access_log::record_rejected(response.status_code(), body_bytes, start.elapsed());
+                return;
+            }
+            Ok(Err(e)) => {
+                let body = e.to_string();
+                let response = Response::new()
+                    .status(e.status())
+                    .header("Content-Type", CONTENT_TYPE_TEXT)
+                    .body(body);
+                if let Err(e) = send_response(respond, &response, &mut body_bytes).await {
+                    eprintln!("Failed to send response: {}", e);
+                }
+                access_log::record_rejected(response.status_code(), body_bytes, start.elapsed());
+                return;
+            }
+        };
+
+    let route = routes()
+        .find(*request.method(), request.path())
+        .ok()
+        .map(|(route, _)| route);
+    let response = response_to(&request, route, &state).await;
+    if let Err(e) = send_response(respond, &response, &mut body_bytes).await {
+        eprintln!("Failed to send response: {}", e);
+    }
+
+    let latency = start.elapsed();
+    access_log::record(&request, &response, body_bytes, latency);
+    if let (Some(metrics), Some(route)) = (&state.metrics, route) {
+        metrics.record(route.name(), latency).await;
+    }
+}
+
+/// Reads the request of a stream, its body within `MAX_BODY_SIZE` and the limit of its route.
+async fn read_request(
+    request: http::Request<RecvStream>,
+    state: &AppState,
+) -> Result<Request, RequestError> {
+    let (parts, mut body) = request.into_parts();
+    let has_body = !body.is_end_stream() || parts.headers.contains_key(CONTENT_LENGTH);
+
+    let headers = parts
+        .headers
+        .iter()
+        .map(|(name, value)| Ok((name.as_str(), value.to_str()?)))
+        .collect::<Result<Vec<_>, http::header::ToStrError>>()
+        .map_err(|_| RequestError::MalformedHeader)?;
+    let uri = parts.uri.path_and_query().map_or("/", |uri| uri.as_str());
+    let mut request = Request::from_parts(parts.method.as_str(), uri, "HTTP/2.0", headers, None)?;
+    if !has_body {
+        return Ok(request);
+    }
+
+    // refused before the body is read when its announced length is over the limit already
+    let limit = body_limit(&request).min(state.request_limits.max_body);
+    let content_length = parts
+        .headers
+        .get(CONTENT_LENGTH)
+        .and_then(|len| len.to_str().ok()?.parse::<usize>().ok());
+    if content_length.is_some_and(|len| len > limit) {
+        return Err(RequestError::PayloadTooLarge { limit });
+    }
+    let mut data = Vec::new();
+    while let Some(chunk) = body.data().await {
+        let chunk = chunk.map_err(|e| RequestError::Io(io::Error::other(e)))?;
+        // let the client send more
+        body.flow_control().release_capacity(chunk.len()).ok();
+        if data.len() + chunk.len() > limit {
+            return Err(RequestError::PayloadTooLarge { limit });
+        }
+        data.extend_from_slice(&chunk);
+    }
+    request.set_received_body(data)?;
+    Ok(request)
+}
+
+/// Sends `response` on the stream, the body chunk by chunk, adding the bytes of body sent to
+/// `body_bytes`.
+async fn send_response(
+    mut respond: SendResponse<Bytes>,
+    response: &Response,
+    body_bytes: &mut u64,
+) -> Result<(), h2::Error> {
+    let mut head = http::Response::builder().status(response.status_code().code());
+    for (name, value) in response.header_fields() {
+        if !CONNECTION_HEADERS
+            .iter()
+            .any(|header| name.eq_ignore_ascii_case(header))
+        {
+            head = head.header(name, value.as_ref());
+        }
+    }
+    let head = match head.body(()) {
+        Ok(head) => head,
+        Err(e) => {
+            eprintln!("Invalid response head: {}", e);
+            respond.send_reset(h2::Reason::INTERNAL_ERROR);
+            return Ok(());
+        }
+    };
+
+    let chunks = response.chunks();
+    let body = response.take_stream();
+    let mut stream = respond.send_response(head, chunks.is_empty() && body.is_none())?;
+    for (i, chunk) in chunks.iter().enumerate() {
+        stream.send_data(chunk.clone(), i + 1 == chunks.len() && body.is_none())?;
+        *body_bytes += chunk.len() as u64;
+    }
+    let Some(mut body) = body else {
+        return Ok(());
+    };
+
+    // a streamed body may be too large to buffer, it is only sent as the client takes it
+    while let Some(chunk) = body.recv().await {
+        let mut chunk = match chunk {
+            Ok(chunk) => chunk,
+            Err(e) => {
+                eprintln!("Streamed body failed: {}", e);
+                stream.send_reset(h2::Reason::INTERNAL_ERROR);
+                return Ok(());
+            }
+        };
+        while !chunk.is_empty() {
+            stream.reserve_capacity(chunk.len());
+            let capacity = match poll_fn(|cx| stream.poll_capacity(cx)).await {
+                Some(capacity) => capacity?,
+                // the client reset the stream
+                None => return Ok(()),
+            };
+            let data = chunk.split_to(capacity.min(chunk.len()));
+            let len = data.len() as u64;
+            stream.send_data(data, false)?;
+            *body_bytes += len;
+        }
+    }
+    stream.send_data(Bytes::new(), true)
+}
@@ -0,0 +1,176 @@
+use std::{io, str::FromStr, sync::Arc};
+
+use bytes::Bytes;
+use serde::{Deserialize, Serialize};
+#[cfg(feature = "bindings")]
+use ts_rs::TS;
+
+use crate::{
+    app_state::AppState,
+    image::{media_type, ImageMetadata},
+    response::{Response, StatusCode, CONTENT_TYPE_TEXT},
+};
+
+/// The most images a single batch may ask for. Images are large, unlike the uuids of an
+/// existence check, so a batch is about a page worth.
+pub const MAX_IMAGE_BATCH: usize = 100;
+
+/// The dimensions of a served image in pixels, left out when they can't be read from it.
+pub const IMAGE_WIDTH_HEADER: &str = "X-Image-Width";
+pub const IMAGE_HEIGHT_HEADER: &str = "X-Image-Height";
+/// The size of a served image decoded, which `Content-Length` isn't when it is served in base64.
+pub const IMAGE_SIZE_HEADER: &str = "X-Image-Size";
+
+#[derive(Deserialize)]
+struct ImageBatchRequest {
+    uuids: Vec<String>,
+}
+
+/// An image of a batch. The response is a bincode `Vec<BatchImage>` with the requested uuids
+/// that exist, in request order.
+#[derive(Serialize)]
+#[cfg_attr(feature = "bindings", derive(TS), ts(export))]
+pub struct BatchImage {
+    pub uuid: String,
+    /// `None` if the message has no image.
+    pub image: Option<String>,
+}
+
+/// How `GET /api/messages/{uuid}/image` serves an image.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum ImageEncoding {
+    /// The bytes as they are stored, with the media type of the image.
+    Raw,
+    /// Encoded in base64, as pages carry it.
+    Base64,
+}
+
+impl FromStr for ImageEncoding {
+    type Err = &'static str;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "raw" => Ok(Self::Raw),
+            "base64" => Ok(Self::Base64),
+            _ => Err("encoding must be raw or base64."),
+        }
+    }
+}
+
+/// `GET /api/messages/{uuid}/image?encoding=raw|base64`, serves the image of a message without
+/// the envelope of a page, with its media type by default so it can be shown as it is. Images
+/// of at least `IMAGE_MMAP_THRESHOLD` bytes are then served from a memory-mapped file instead
+/// of a copy on the heap. `?encoding=base64` serves it encoded like in pages instead.
+pub(crate) async fn handle_image(
+    uuid: &str,
+    encoding: Option<&str>,
+    state: Arc<AppState>,
+) -> Response {
+    let encoding = match encoding.map(str::parse).unwrap_or(Ok(ImageEncoding::Raw)) {
+        Ok(encoding) => encoding,
+        Err(e) => {
+            return Response::new()
+                .status(StatusCode::BadRequest)
+                .header("Content-Type", CONTENT_TYPE_TEXT)
+                .body(e);
+        }
+    };
+    if !state.all_uuids.lock().await.contains(uuid) {
+        return Response::new().status(StatusCode::NotFound);
+    }
+
+    let image = match encoding {
+        // encoded like pages carry it, which the store may have cached
+        ImageEncoding::Base64 => match state.images.get_cached(uuid).await {
+            Some(image) => Ok((
+                CONTENT_TYPE_TEXT,
+                Bytes::from(image.base64.clone()),
+                image.metadata.clone(),
+            )),
+            None => Err(io::Error::from(io::ErrorKind::NotFound)),
+        },
+        ImageEncoding::Raw => match state.images.read(uuid).await {
+            Ok(image) => {
+                let metadata = match state.images.metadata(uuid).await {
+                    Some(metadata) => metadata,
+                    None => ImageMetadata::of(&image),
+                };
+                Ok((media_type(&image), image, metadata))
+            }
+            Err(e) => Err(e),
+        },
+    };
+
+    match image {
+        // the image may change, caches revalidate it with its `ETag`
+        Ok((media_type, image, metadata)) => {
+            let mut response = Response::new()
+                .header("Content-Type", media_type)
+                .header("Cache-Control", "no-cache")
+                .header(IMAGE_SIZE_HEADER, metadata.size);
+            if let (Some(width), Some(height)) = (metadata.width, metadata.height) {
+                response = response
+                    .header(IMAGE_WIDTH_HEADER, width)
+                    .header(IMAGE_HEIGHT_HEADER, height);
+            }
+            response.body_chunks(vec![image])
+        }
+        Err(e) if e.kind() == io::ErrorKind::NotFound => Response::new()
+            .status(StatusCode::NotFound)
+            .header("Content-Type", CONTENT_TYPE_TEXT)
+            .body("The message has no image."),
+        Err(e) => {
+            eprintln!("Failed to read the image: {}", e);
+            Response::new().status(StatusCode::InternalServerError)
+        }
+    }
+}
+
+/// `POST /api/messages/images/batch`, serves the images of the given uuids in one response, so
+/// a client can fill in the images of a page with a single round trip.
+pub(crate) async fn handle_image_batch(body: &str, state: Arc<AppState>) -> Response {
+    let ImageBatchRequest { uuids } = match serde_json::from_str(body) {
+        Ok(v) => v,
+        Err(e) => {
+            return Response::new()
+                .status(StatusCode::BadRequest)
+                .header("Content-Type", CONTENT_TYPE_TEXT)
+                .body(e.to_string());
+        }
+    };
+    if uuids.len() > MAX_IMAGE_BATCH {
+        return Response::new()
+            .status(StatusCode::PayloadTooLarge)
+            .header("Content-Type", CONTENT_TYPE_TEXT)
+            .body(format!(
+                "At most {MAX_IMAGE_BATCH} images can be fetched at once."
+            ));
+    }
+
+    let uuids: Vec<_> = {
+        let all_uuids = state.all_uuids.lock().await;
+        uuids
+            .into_iter()
+            .map(|uuid| state.id_scheme.normalize(uuid))
+            .filter(|uuid| all_uuids.contains(uuid.as_str()))
+            .collect()
+    };
+
+    // one chunk per image like the messages of a page
+    let images = {
+        let uuids: Vec<_> = uuids.iter().map(String::as_str).collect();
+        state.images.get_many(&uuids).await
+    };
+    let mut chunks = Vec::with_capacity(uuids.len() + 1);
+    chunks.push(Bytes::from(bincode::serialize(&uuids.len()).unwrap()));
+    for (uuid, image) in uuids.into_iter().zip(images) {
+        let image = BatchImage {
+            uuid,
+            image: image.map(|image| image.base64.clone()),
+        };
+        chunks.push(bincode::serialize(&image).unwrap().into());
+    }
+    Response::new()
+        .header("Content-Type", "application/octet-stream")
+        .body_chunks(chunks)
+}
@@ -0,0 +1,168 @@
+use std::sync::Arc;
+
+use serde::Serialize;
+
+use crate::{
+    app_state::AppState,
+    models::{Message, MessageId},
+    response::{Response, StatusCode, CONTENT_TYPE_JSON},
+};
+
+use super::export::ExportRecord;
+
+/// The records are inserted this many at a time, each batch in its own statement.
+const IMPORT_BATCH: usize = 1000;
+
+/// The most skipped records whose reason is reported.
+const MAX_IMPORT_ERRORS: usize = 100;
+
+/// Why a record of the dump wasn't imported.
+#[derive(Serialize)]
+struct ImportError {
+    /// The line of the record in the dump, from 1.
+    line: usize,
+    error: String,
+}
+
+#[derive(Serialize)]
+struct ImportReport {
+    imported: usize,
+    skipped: usize,
+    /// The first `MAX_IMPORT_ERRORS` reasons, in dump order.
+    errors: Vec<ImportError>,
+}
+
+impl ImportReport {
+    fn skip(&mut self, line: usize, error: impl Into<String>) {
+        self.skipped += 1;
+        if self.errors.len() < MAX_IMPORT_ERRORS {
+            let error = error.into();
+            self.errors.push(ImportError { line, error });
+        }
+    }
+}
+
+/// `POST /api/messages/import`, the counterpart of the export: inserts the NDJSON dump in the
+/// body, one `ExportRecord` per line, keeping their timestamps. Records that are invalid or
+/// whose uuid is taken are skipped, the rest is inserted in batches, and the response reports
+/// how many were imported and skipped. Images aren't part of a dump, a record keeps its image
+/// only if the file is still on this server, e.g. when the table is restored. An admin endpoint,
+/// served on the admin listener when it is enabled.
+pub(crate) async fn handle_import(body: &str, state: Arc<AppState>) -> Response {
+    let mut report = ImportReport {
+        imported: 0,
+        skipped: 0,
+        errors: Vec::new(),
+    };
+    // the lines are parsed as they are read, a batch at a time, so the dump is never all parsed
+    // at once
+    let mut lines = body
+        .lines()
+        .enumerate()
+        .filter(|(_, line)| !line.trim().is_empty());
+    loop {
+        let mut read = 0;
+        let mut records = Vec::with_capacity(IMPORT_BATCH);
+        for (i, text) in lines.by_ref().take(IMPORT_BATCH) {
+            read += 1;
+            let line = i + 1;
+            // the reasons of the skipped records are reported in dump order, along with the
+            // ones found under the locks
+            let record: ExportRecord = match serde_json::from_str(text) {
+                Ok(record) => record,
+                Err(e) => {
+                    records.push((line, Err(e.to_string())));
+                    continue;
+                }
+            };
+            let uuid = match MessageId::parse(record.uuid.as_str(), state.id_scheme) {
+                Ok(uuid) => uuid.into_inner(),
+                Err(e) => {
+                    records.push((line, Err(e.to_string())));
+                    continue;
+                }
+            };
+            if let Some(router) = state.shard_router.as_ref().filter(|r| !r.is_local(&uuid)) {
+                let error = format!("Owned by {}, import it there.", router.owner(&uuid));
+                records.push((line, Err(error)));
+                continue;
+            }
+            // looked up before the uuids are locked, the other writers don't wait on the disk
+            let has_image = record.image.is_some() && state.images.exists(&uuid).await;
+            records.push((line, Ok((uuid, has_image, record))));
+        }
+        if read == 0 {
+            break;
+        }
+
+        let mut rows = Vec::with_capacity(records.len());
+        {
+            // in the order of clear, which holds both too
+            let mut all_uuids = state.all_uuids.lock().await;
+            let mut tombstones = state.tombstones.lock().await;
+            for (line, record) in records {
+                let (uuid, has_image, record) = match record {
+                    Ok(record) => record,
+                    Err(e) => {
+                        report.skip(line, e);
+                        continue;
+                    }
+                };
+                if tombstones.remaining(&uuid).is_some() {
+                    report.skip(line, "Deleted recently.");
+                    continue;
+                }
+                // also catches uuids repeated within the dump
+                if !all_uuids.insert(uuid.clone()) {
+                    report.skip(line, "A message with this uuid exists.");
+                    continue;
+                }
+                rows.push(Message {
+                    uuid,
+                    author: record.author,
+                    message: record.message,
+                    // the parent may come later in the dump, or have been purged
+                    parent_uuid: record
+                        .parent_uuid
+                        .map(|parent_uuid| state.id_scheme.normalize(parent_uuid)),
+                    likes: record.likes,
+                    has_image,
+                    client_timestamp: record.client_timestamp,
+                    server_timestamp: record.server_timestamp,
+                    created_at: record.created_at.unwrap_or(record.server_timestamp),
+                    updated_at: record
+                        .updated_at
+                        .or(record.created_at)
+                        .unwrap_or(record.server_timestamp),
+                    deleted_at: None,
+                    revision: 1,
+                    reactions: record.reactions,
+                });
+            }
+        }
+        if rows.is_empty() {
+            continue;
+        }
+
+        if let Err(e) = state.messages.insert_many(&rows).await {
+            eprintln!("Failed to import messages: {}", e);
+            let mut all_uuids = state.all_uuids.lock().await;
+            for row in &rows {
+                all_uuids.remove(&row.uuid);
+            }
+            // the earlier batches stay imported
+            let body = serde_json::to_string(&report).unwrap();
+            return Response::new()
+                .status(StatusCode::InternalServerError)
+                .header("Content-Type", CONTENT_TYPE_JSON)
+                .body(body);
+        }
+        report.imported += rows.len();
+        state.outbox_notify.notify_one();
+    }
+
+    let body = serde_json::to_string(&report).unwrap();
+    Response::new()
+        .header("Content-Type", CONTENT_TYPE_JSON)
+        .body(body)
+}
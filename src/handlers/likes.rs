@@ -0,0 +1,64 @@
+use std::sync::Arc;
+
+use serde::Serialize;
+
+use crate::{
+    app_state::AppState,
+    models::{timestamp_now, IDEMPOTENCY_KEY_HEADER, SERVER_TIMESTAMP_HEADER},
+    response::{Response, StatusCode, CONTENT_TYPE_JSON, CONTENT_TYPE_TEXT},
+};
+
+/// The longest `Idempotency-Key` accepted.
+const MAX_IDEMPOTENCY_KEY_LEN: usize = 128;
+
+#[derive(Serialize)]
+struct LikesResponse {
+    likes: i32,
+}
+
+/// `POST /api/messages/{uuid}/like` and `/unlike`, adds `delta` to the likes of a message
+/// atomically, unlike a `PUT` of the count a client read before, and answers with the new
+/// count. A like sent again with the same `Idempotency-Key` is answered with the current count
+/// without being counted again, so a client can retry one safely.
+pub(crate) async fn handle_like(
+    uuid: &str,
+    delta: i32,
+    idempotency_key: Option<&str>,
+    state: Arc<AppState>,
+) -> Response {
+    if idempotency_key.is_some_and(|key| key.is_empty() || key.len() > MAX_IDEMPOTENCY_KEY_LEN) {
+        return Response::new()
+            .status(StatusCode::BadRequest)
+            .header("Content-Type", CONTENT_TYPE_TEXT)
+            .body(format!(
+                "{IDEMPOTENCY_KEY_HEADER} must be 1 to {MAX_IDEMPOTENCY_KEY_LEN} bytes"
+            ));
+    }
+    // the key only names the like among those of the same message and direction
+    let key = idempotency_key.map(|key| format!("{uuid}/{delta}/{key}"));
+
+    if !state.all_uuids.lock().await.contains(uuid) {
+        return Response::new().status(StatusCode::NotFound);
+    }
+
+    let server_timestamp = timestamp_now();
+    match state
+        .messages
+        .add_likes(uuid, delta, key.as_deref(), server_timestamp)
+        .await
+    {
+        Ok(Some(likes)) => {
+            state.outbox_notify.notify_one();
+            let body = serde_json::to_string(&LikesResponse { likes }).unwrap();
+            Response::new()
+                .header(SERVER_TIMESTAMP_HEADER, server_timestamp)
+                .header("Content-Type", CONTENT_TYPE_JSON)
+                .body(body)
+        }
+        Ok(None) => Response::new().status(StatusCode::NotFound),
+        Err(e) => {
+            eprintln!("Failed to update likes: {}", e);
+            Response::new().status(StatusCode::InternalServerError)
+        }
+    }
+}
@@ -0,0 +1,36 @@
+use std::sync::Arc;
+
+use crate::{
+    app_state::AppState,
+    response::{Response, StatusCode},
+};
+
+use super::{put::revision_etag, CompleteMessage, PageFormat};
+
+/// `GET /api/messages/{uuid}`, serves a single message with its image, where the `Location` of
+/// a `POST` points. Its `ETag` is the revision, which `If-Match` takes back.
+pub(crate) async fn handle_message(
+    uuid: &str,
+    format: PageFormat,
+    state: Arc<AppState>,
+) -> Response {
+    let message = match state.messages.get(uuid).await {
+        Ok(Some(message)) => message,
+        Ok(None) => return Response::new().status(StatusCode::NotFound),
+        Err(e) => {
+            eprintln!("Failed to fetch message: {}", e);
+            return Response::new().status(StatusCode::InternalServerError);
+        }
+    };
+
+    let etag = revision_etag(message.revision);
+    let messages = CompleteMessage::with_images(vec![message], &*state.images).await;
+    let mut body = Vec::new();
+    format.serialize_into(&mut body, &messages[0]);
+
+    Response::new()
+        .header("Content-Type", format.content_type())
+        .header("Vary", "Accept")
+        .header("ETag", etag)
+        .body_bytes(body)
+}
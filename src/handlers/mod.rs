@@ -1,88 +1,1340 @@
-use std::sync::Arc;
+use std::{
+    future::Future,
+    io,
+    sync::{Arc, OnceLock},
+    time::{Duration, Instant},
+};
 
 use crate::{
-    app_state::AppState,
-    request::{method::Method, Request},
-    response::Response,
+    access_log,
+    app_state::{pagination::PAGINATION_SESSION_HEADER, AppState},
+    deadline,
+    journal::JournalEntry,
+    models::{IdScheme, MessageId, IDEMPOTENCY_KEY_HEADER},
+    page_tokens::PAGE_TOKEN_HEADER,
+    quota::ANONYMOUS_KEY,
+    request::{method::Method, multipart, Request, RequestError},
+    response::{Response, ResponseWriter, StatusCode, CONTENT_TYPE_JSON, CONTENT_TYPE_TEXT},
+    router::{Params, RouteError, Router},
 };
+use serde::Deserialize;
+use sha2::{Digest, Sha256};
 
 use self::{
-    clear::clear,
-    delete::handle_delete,
-    get::{get_pagination_meta, handle_get},
-    post::handle_post,
-    put::handle_put,
+    authors::handle_authors,
+    clear::{clear, clear_filter},
+    debug::{handle_config, handle_metrics},
+    delete::{handle_delete, handle_purge, handle_restore},
+    exists::{handle_exists, handle_uuid_exists, MAX_EXISTS_BATCH},
+    export::handle_export,
+    get::{
+        get_pagination_meta, handle_ack_page, handle_get, handle_get_cursor,
+        handle_get_page_number, include_images, page_view, with_etag,
+    },
+    images::{handle_image, handle_image_batch, MAX_IMAGE_BATCH},
+    import::handle_import,
+    likes::handle_like,
+    message::handle_message,
+    patch::handle_patch,
+    post::{handle_post, handle_post_batch},
+    put::{handle_put, handle_put_batch},
+    reactions::handle_react,
+    replay::handle_replay,
+    replies::handle_replies,
+    sample::handle_sample,
+    search::handle_search,
+    stats::handle_stats,
+    upload::{
+        handle_commit_upload, handle_create_upload, handle_upload_chunk, handle_upload_progress,
+    },
+    verify::handle_verify_pagination,
 };
 
+mod admin;
+mod authors;
 mod clear;
+mod debug;
 mod delete;
+mod exists;
+mod export;
 mod get;
+#[cfg(feature = "http2")]
+mod http2;
+mod images;
+mod import;
+mod likes;
+mod message;
+mod patch;
 mod post;
 mod put;
+mod reactions;
+mod replay;
+mod replies;
+mod sample;
+mod search;
+mod stats;
+mod upload;
+mod verify;
+
+pub use admin::handle_admin_connection;
+pub use get::{CompleteMessage, PageFormat, PaginationMetadata, PaginationType};
+use tokio::io::{AsyncBufReadExt, AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt, BufReader};
 
-pub use get::{CompleteMessage, PaginationMetadata, PaginationType};
-pub use put::PutMessage;
-use tokio::{io::AsyncWriteExt, net::TcpStream};
-
-pub async fn handle_connection(mut stream: TcpStream, state: Arc<AppState>) {
-    let request = match Request::from_stream(&mut stream).await {
-        Ok(req) => req,
-        Err(e) => {
-            eprintln!("Failed to read from stream: {}", e);
-            let response = Response::new()
-                .status_line("HTTP/1.1 500 INTERNAL SERVER ERROR")
-                .to_string();
-            if let Err(e) = stream.write_all(response.as_bytes()).await {
-                eprintln!("Failed to send response: {}", e);
+/// How long a connection is kept open waiting for its next request.
+const KEEP_ALIVE_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// Serves the requests of a connection of the public listener, HTTP/1.1 or HTTP/2 with prior
+/// knowledge. Any stream works, e.g. a TLS stream or one half of a `tokio::io::duplex` pipe.
+pub async fn handle_connection<S: AsyncRead + AsyncWrite + Unpin>(stream: S, state: Arc<AppState>) {
+    // clients with prior knowledge of HTTP/2 start with its preface instead of a request, which
+    // may come in more than one read
+    #[cfg(feature = "http2")]
+    let stream = {
+        let mut stream = stream;
+        match tokio::time::timeout(KEEP_ALIVE_TIMEOUT, http2::sniff(&mut stream)).await {
+            Ok(Ok((true, read))) => {
+                return http2::serve(http2::Rewind::new(read, stream), state).await
             }
+            Ok(Ok((false, read))) => http2::Rewind::new(read, stream),
+            _ => return,
+        }
+    };
+
+    // the reader outlives each request, so pipelined requests already read from the socket
+    // are served from its buffer
+    let mut stream = BufReader::new(stream);
+    while wait_for_request(&mut stream).await {
+        let Some(request) = read_request(&mut stream, &state).await else {
+            return;
+        };
+
+        if !respond(&mut stream, request, Arc::clone(&state)).await {
             return;
         }
+    }
+}
+
+/// Waits for the next request on `stream`, `false` once the client closed the connection or
+/// sent nothing for [`KEEP_ALIVE_TIMEOUT`]. A pipelined request is already buffered and
+/// doesn't wait for new bytes.
+async fn wait_for_request<S: AsyncRead + Unpin>(stream: &mut BufReader<S>) -> bool {
+    matches!(
+        tokio::time::timeout(KEEP_ALIVE_TIMEOUT, stream.fill_buf()).await,
+        Ok(Ok(buf)) if !buf.is_empty()
+    )
+}
+
+/// The `Connection` header of a response, telling the client whether it may send another
+/// request on the connection.
+fn connection_header(keep_alive: bool) -> &'static str {
+    if keep_alive {
+        "keep-alive"
+    } else {
+        "close"
+    }
+}
+
+/// Reads a request within the `request_limits` from `stream`, answering it with an error if it
+/// is invalid or isn't received within the read timeout. The connection is closed after an
+/// error.
+async fn read_request<S: AsyncRead + AsyncWrite + Unpin>(
+    stream: &mut BufReader<S>,
+    state: &AppState,
+) -> Option<Request> {
+    let start = Instant::now();
+    let unread_body;
+    let read = Request::from_stream(stream, &state.request_limits, body_limit);
+    let response = match tokio::time::timeout(state.read_timeout, read).await {
+        Ok(Ok(request)) => return Some(request),
+        Err(_) => {
+            // the rest of the request may still come in, like an unread body
+            unread_body = true;
+            Response::new()
+                .status(StatusCode::RequestTimeout)
+                .header("Content-Type", CONTENT_TYPE_TEXT)
+                .body("The request wasn't received in time.")
+        }
+        Ok(Err(e)) => {
+            if let RequestError::Io(e) = &e {
+                eprintln!("Failed to read from stream: {}", e);
+            }
+            // the body may still come in, the connection can't be reused
+            unread_body = e.before_body();
+            let body = e.to_string();
+            Response::new()
+                .status(e.status())
+                .header("Content-Type", CONTENT_TYPE_TEXT)
+                .body(body)
+        }
+    };
+    let response = response.header("Connection", connection_header(false));
+    let stream = stream.get_mut();
+    let mut writer = ResponseWriter::new(stream);
+    send(&mut writer, &response, state.write_timeout).await;
+    access_log::record_rejected(response.status_code(), writer.body_bytes(), start.elapsed());
+    drop(writer);
+    if unread_body {
+        linger(stream).await;
+    }
+    None
+}
+
+/// The largest body the route of `request` takes, checked before the body is read. Requests
+/// matching no route are refused before their body matters, `MAX_BODY_SIZE` bounds them.
+fn body_limit(request: &Request) -> usize {
+    routes()
+        .find(*request.method(), request.path())
+        .ok()
+        .and_then(|(route, _)| route.policy().max_body)
+        .unwrap_or(usize::MAX)
+}
+
+/// Sends `response` with `writer`, giving up once `timeout` passes, e.g. when the client stopped
+/// reading. Returns whether the response was sent.
+async fn send<W: AsyncWrite + Unpin>(
+    writer: &mut ResponseWriter<'_, W>,
+    response: &Response,
+    timeout: Option<Duration>,
+) -> bool {
+    let sent = match timeout {
+        Some(timeout) => tokio::time::timeout(timeout, writer.send(response))
+            .await
+            .unwrap_or_else(|_| Err(io::Error::new(io::ErrorKind::TimedOut, "Write timed out"))),
+        None => writer.send(response).await,
+    };
+    if let Err(e) = &sent {
+        eprintln!("Failed to send response: {}", e);
+    }
+    sent.is_ok()
+}
+
+/// Closes `stream` gracefully after an early response: closing it with a body still unread
+/// resets the connection, and clients may lose the response. The body is discarded for a
+/// second at most.
+async fn linger<S: AsyncRead + AsyncWrite + Unpin>(stream: &mut S) {
+    if stream.shutdown().await.is_err() {
+        return;
+    }
+    let mut buf = [0; 8192];
+    let drain = async { while let Ok(1..) = stream.read(&mut buf).await {} };
+    tokio::time::timeout(Duration::from_secs(1), drain)
+        .await
+        .ok();
+}
+
+/// The endpoints of the public listener.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Route {
+    PaginationMeta,
+    Page,
+    AckPage,
+    Search,
+    Sample,
+    Stats,
+    Exists,
+    Message,
+    UuidExists,
+    Replies,
+    Authors,
+    Export,
+    Image,
+    ImageBatch,
+    Post,
+    PostBatch,
+    Import,
+    Put,
+    PutBatch,
+    Patch,
+    Like,
+    Unlike,
+    React,
+    Unreact,
+    Delete,
+    Restore,
+    Clear,
+    Purge,
+    CreateUpload,
+    UploadProgress,
+    UploadChunk,
+    CommitUpload,
+    Usage,
+    DebugConfig,
+    DebugMetrics,
+    ReplayMutations,
+    VerifyPagination,
+}
+
+/// Who may call a route.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Role {
+    Public,
+    /// Served on the admin listener only when it is enabled.
+    Admin,
+}
+
+/// How the traffic of a route is limited.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum RateClass {
+    /// Reads, not limited.
+    Read,
+    /// Changes the messages, counts towards the quotas of the api key and is refused in
+    /// read-only mode.
+    Write,
+    /// Large downloads, paced to `DOWNLOAD_BYTES_PER_SEC`.
+    Bulk,
+}
+
+/// What a route requires, enforced before its handler runs instead of by the handler.
+#[derive(Debug, Clone, Copy)]
+struct Policy {
+    /// The deadline of the database queries, instead of `REQUEST_TIMEOUT_MS`.
+    timeout: Option<Duration>,
+    /// Larger bodies are rejected with 413, `MAX_BODY_SIZE` applies to every route.
+    max_body: Option<usize>,
+    role: Role,
+    rate: RateClass,
+}
+
+impl Policy {
+    const fn new(role: Role, rate: RateClass) -> Self {
+        Self {
+            timeout: None,
+            max_body: None,
+            role,
+            rate,
+        }
+    }
+
+    const fn timeout(mut self, timeout: Duration) -> Self {
+        self.timeout = Some(timeout);
+        self
+    }
+
+    const fn max_body(mut self, max_body: usize) -> Self {
+        self.max_body = Some(max_body);
+        self
+    }
+}
+
+impl Route {
+    /// The requirements of the route, in one place for all routes.
+    fn policy(self) -> Policy {
+        use self::{RateClass::*, Role::*};
+
+        // the routes going through all the messages may take longer than a request should
+        const SWEEP_TIMEOUT: Duration = Duration::from_secs(60);
+        // a uuid of a batch is at most 26 or 36 characters, plus quotes and a comma
+        const BATCH_UUID_BYTES: usize = 40;
+
+        match self {
+            Route::PaginationMeta
+            | Route::Page
+            | Route::AckPage
+            | Route::Search
+            | Route::Sample
+            | Route::Stats
+            | Route::Message
+            | Route::UuidExists
+            | Route::Replies
+            | Route::Authors
+            | Route::UploadProgress
+            | Route::Usage => Policy::new(Public, Read),
+            Route::Exists => {
+                Policy::new(Public, Read).max_body(MAX_EXISTS_BATCH * BATCH_UUID_BYTES + 64)
+            }
+            Route::Export | Route::Image => Policy::new(Public, Bulk),
+            Route::ImageBatch => {
+                Policy::new(Public, Bulk).max_body(MAX_IMAGE_BATCH * BATCH_UUID_BYTES + 64)
+            }
+            Route::Post
+            | Route::PostBatch
+            | Route::Put
+            | Route::PutBatch
+            | Route::Patch
+            | Route::Like
+            | Route::Unlike
+            | Route::React
+            | Route::Unreact
+            | Route::Delete
+            | Route::Restore
+            | Route::CreateUpload
+            | Route::UploadChunk
+            | Route::CommitUpload => Policy::new(Public, Write),
+            Route::Import | Route::Clear | Route::Purge => {
+                Policy::new(Admin, Write).timeout(SWEEP_TIMEOUT)
+            }
+            Route::DebugConfig | Route::DebugMetrics => Policy::new(Admin, Read),
+            Route::ReplayMutations | Route::VerifyPagination => {
+                Policy::new(Admin, Read).timeout(SWEEP_TIMEOUT)
+            }
+        }
+    }
+
+    /// Whether the route changes the messages, as opposed to reading them.
+    fn is_write(self) -> bool {
+        self.policy().rate == RateClass::Write
+    }
+
+    /// Runs `f`, the handling of a request to the route, with the deadline of the route:
+    /// database queries are cancelled once it passes.
+    async fn with_deadline<F: Future>(self, state: &AppState, f: F) -> F::Output {
+        match self.policy().timeout.or(state.request_timeout) {
+            Some(timeout) => deadline::scope(Instant::now() + timeout, f).await,
+            None => f.await,
+        }
+    }
+
+    /// The name of the route in the metrics and in `LATENCY_BUDGETS_MS`.
+    fn name(self) -> &'static str {
+        match self {
+            Route::PaginationMeta => "pagination_meta",
+            Route::Page => "page",
+            Route::AckPage => "ack_page",
+            Route::Search => "search",
+            Route::Sample => "sample",
+            Route::Stats => "stats",
+            Route::Exists => "exists",
+            Route::Message => "message",
+            Route::UuidExists => "uuid_exists",
+            Route::Replies => "replies",
+            Route::Authors => "authors",
+            Route::Export => "export",
+            Route::Image => "image",
+            Route::ImageBatch => "image_batch",
+            Route::Post => "post",
+            Route::PostBatch => "post_batch",
+            Route::Import => "import",
+            Route::Put => "put",
+            Route::PutBatch => "put_batch",
+            Route::Patch => "patch",
+            Route::Like => "like",
+            Route::Unlike => "unlike",
+            Route::React => "react",
+            Route::Unreact => "unreact",
+            Route::Delete => "delete",
+            Route::Restore => "restore",
+            Route::Clear => "clear",
+            Route::Purge => "purge",
+            Route::CreateUpload => "create_upload",
+            Route::UploadProgress => "upload_progress",
+            Route::UploadChunk => "upload_chunk",
+            Route::CommitUpload => "commit_upload",
+            Route::Usage => "usage",
+            Route::DebugConfig => "debug_config",
+            Route::DebugMetrics => "debug_metrics",
+            Route::ReplayMutations => "replay_mutations",
+            Route::VerifyPagination => "verify_pagination",
+        }
+    }
+}
+
+/// The names of the routes, see `LATENCY_BUDGETS_MS`.
+pub fn route_names() -> Vec<&'static str> {
+    routes().values().map(Route::name).collect()
+}
+
+fn routes() -> &'static Router<Route> {
+    static ROUTES: OnceLock<Router<Route>> = OnceLock::new();
+    ROUTES.get_or_init(|| {
+        Router::new()
+            .route(Method::Get, "/api/messages", Route::PaginationMeta)
+            .route(Method::Post, "/api/messages", Route::Post)
+            .route(Method::Patch, "/api/messages", Route::Clear)
+            .route(Method::Get, "/api/messages/get-page", Route::Page)
+            .route(Method::Post, "/api/messages/get-page/ack", Route::AckPage)
+            .route(Method::Get, "/api/messages/search", Route::Search)
+            .route(Method::Get, "/api/messages/sample", Route::Sample)
+            .route(Method::Get, "/api/messages/stats", Route::Stats)
+            .route(Method::Get, "/api/messages/export", Route::Export)
+            .route(Method::Post, "/api/messages/batch", Route::PostBatch)
+            .route(Method::Put, "/api/messages/batch", Route::PutBatch)
+            .route(Method::Post, "/api/messages/import", Route::Import)
+            .route(Method::Post, "/api/messages/exists", Route::Exists)
+            .route(
+                Method::Post,
+                "/api/messages/images/batch",
+                Route::ImageBatch,
+            )
+            .route(Method::Get, "/api/messages/:uuid", Route::Message)
+            .route(Method::Put, "/api/messages/:uuid", Route::Put)
+            .route(Method::Patch, "/api/messages/:uuid", Route::Patch)
+            .route(Method::Delete, "/api/messages/:uuid", Route::Delete)
+            .route(Method::Post, "/api/messages/:uuid/like", Route::Like)
+            .route(Method::Post, "/api/messages/:uuid/unlike", Route::Unlike)
+            .route(Method::Post, "/api/messages/:uuid/react", Route::React)
+            .route(Method::Post, "/api/messages/:uuid/unreact", Route::Unreact)
+            .route(Method::Post, "/api/messages/:uuid/restore", Route::Restore)
+            .route(Method::Get, "/api/messages/:uuid/exists", Route::UuidExists)
+            .route(Method::Get, "/api/messages/:uuid/replies", Route::Replies)
+            .route(Method::Get, "/api/messages/:uuid/image", Route::Image)
+            .route(
+                Method::Post,
+                "/api/messages/:uuid/image/uploads",
+                Route::CreateUpload,
+            )
+            .route(
+                Method::Get,
+                "/api/messages/:uuid/image/uploads/:upload_id",
+                Route::UploadProgress,
+            )
+            .route(
+                Method::Put,
+                "/api/messages/:uuid/image/uploads/:upload_id",
+                Route::UploadChunk,
+            )
+            .route(
+                Method::Post,
+                "/api/messages/:uuid/image/uploads/:upload_id/commit",
+                Route::CommitUpload,
+            )
+            .route(Method::Get, "/api/authors", Route::Authors)
+            .route(Method::Get, "/api/usage", Route::Usage)
+            .route(Method::Get, "/api/debug/config", Route::DebugConfig)
+            .route(Method::Get, "/api/debug/metrics", Route::DebugMetrics)
+            .route(
+                Method::Post,
+                "/admin/mutations/replay",
+                Route::ReplayMutations,
+            )
+            .route(
+                Method::Post,
+                "/admin/verify-pagination",
+                Route::VerifyPagination,
+            )
+            .route(Method::Post, "/admin/messages/purge", Route::Purge)
+    })
+}
+
+/// The response to a request that matched no route.
+fn route_error_response(e: RouteError, request: &Request) -> Response {
+    match e {
+        RouteError::NotFound => {
+            let body = format!("{} uri not found, {}", request.method(), request.path());
+            Response::new()
+                .status(StatusCode::NotFound)
+                .header("Content-Type", CONTENT_TYPE_TEXT)
+                .body(body)
+        }
+        RouteError::MethodNotAllowed { mut allowed } => {
+            // every known path answers OPTIONS with the methods it is served with
+            allowed.push(Method::Options);
+            let allowed: Vec<_> = allowed.iter().map(Method::to_string).collect();
+            let status = match request.method() {
+                Method::Options => StatusCode::NoContent,
+                _ => StatusCode::MethodNotAllowed,
+            };
+            Response::new()
+                .status(status)
+                .header("Allow", allowed.join(", "))
+        }
+    }
+}
+
+/// The response to `request` when the body doesn't match its `X-Content-SHA256`: 422, or 400
+/// when the header isn't a hex SHA-256. A missing body is checked as an empty one.
+fn reject_checksum(request: &Request) -> Option<Response> {
+    let expected = request.content_sha256()?;
+    let (status, body) = match hex::decode(expected.trim()) {
+        Ok(expected) if expected.len() == 32 => {
+            let body = request.sent_body().unwrap_or_default();
+            if Sha256::digest(body).as_slice() == expected {
+                return None;
+            }
+            (
+                StatusCode::UnprocessableEntity,
+                "Request body doesn't match its X-Content-SHA256, it may have been corrupted.",
+            )
+        }
+        _ => (
+            StatusCode::BadRequest,
+            "X-Content-SHA256 must be the hex SHA-256 of the body.",
+        ),
+    };
+    Some(
+        Response::new()
+            .status(status)
+            .header("Content-Type", CONTENT_TYPE_TEXT)
+            .body(body),
+    )
+}
+
+/// The JSON body of a multipart post or put: the JSON object of its `message` part, with the
+/// raw bytes of its `image` part, if any, as the base64 `image` of an image update. Sending the
+/// image as a part of its own spares encoding it.
+fn multipart_json(route: Route, request: &Request, body: &[u8]) -> Result<String, Response> {
+    let bad_request = |e: &str| {
+        Response::new()
+            .status(StatusCode::BadRequest)
+            .header("Content-Type", CONTENT_TYPE_TEXT)
+            .body(e.to_string())
+    };
+    if !matches!(route, Route::Post | Route::Put) {
+        return Err(Response::new()
+            .status(StatusCode::UnsupportedMediaType)
+            .header("Content-Type", CONTENT_TYPE_TEXT)
+            .body("Only posts and puts of a single message may be multipart."));
+    }
+    let boundary = request
+        .header("content-type")
+        .and_then(multipart::boundary)
+        .ok_or_else(|| bad_request("The multipart content type has no boundary."))?;
+    let parts = multipart::parse(body, boundary).map_err(bad_request)?;
+
+    let mut message = None;
+    let mut image = None;
+    for part in parts {
+        match part.name.as_str() {
+            "message" => message = Some(part.data),
+            "image" => image = Some(part.data),
+            _ => return Err(bad_request("The parts must be message and image.")),
+        }
+    }
+    let message = message.ok_or_else(|| bad_request("The message part is missing."))?;
+    let mut message: serde_json::Map<String, serde_json::Value> =
+        serde_json::from_slice(message).map_err(|e| bad_request(&e.to_string()))?;
+    if let Some(image) = image {
+        message.insert("image".to_string(), base64::encode(image).into());
+        message.insert("imageUpdate".to_string(), true.into());
+    }
+    Ok(serde_json::to_string(&message).unwrap())
+}
+
+/// The status and reason a write is refused with when its image can't be saved.
+fn image_error(e: &io::Error) -> (StatusCode, &'static str) {
+    match e.kind() {
+        io::ErrorKind::InvalidData => (StatusCode::BadRequest, "The image must be base64."),
+        io::ErrorKind::StorageFull => (StatusCode::InsufficientStorage, "The image store is full."),
+        _ => {
+            eprintln!("Error saving image: {}", e);
+            (StatusCode::InternalServerError, "Failed to save image.")
+        }
+    }
+}
+
+fn length_required() -> Response {
+    Response::new().status(StatusCode::LengthRequired)
+}
+
+/// Answers `request` on `stream`, returning whether the connection can be reused.
+async fn respond<S: AsyncRead + AsyncWrite + Unpin>(
+    stream: &mut BufReader<S>,
+    request: Request,
+    state: Arc<AppState>,
+) -> bool {
+    let start = Instant::now();
+    let route = routes()
+        .find(*request.method(), request.path())
+        .ok()
+        .map(|(route, _)| route);
+    let mut writer = ResponseWriter::new(stream.get_mut());
+    let response = response_to(&request, route, &state).await;
+    let keep_alive = request.keep_alive();
+    let response = response.header("Connection", connection_header(keep_alive));
+
+    if let (Some(rate), Some(route)) = (state.download_bytes_per_sec, route) {
+        if route.policy().rate == RateClass::Bulk {
+            writer = writer.throttle(rate);
+        }
+    }
+    let sent = send(&mut writer, &response, state.write_timeout).await;
+
+    // the latency includes sending the response, which dominates for large pages
+    let latency = start.elapsed();
+    access_log::record(&request, &response, writer.body_bytes(), latency);
+    if let (Some(metrics), Some(route)) = (&state.metrics, route) {
+        metrics.record(route.name(), latency).await;
+    }
+
+    sent && keep_alive
+}
+
+/// The response to `request`, which matched `route` if any: the answer to a CORS preflight, or
+/// the response of the route within its deadline.
+async fn response_to(request: &Request, route: Option<Route>, state: &Arc<AppState>) -> Response {
+    let response = match preflight_response(request, state) {
+        Some(response) => response,
+        None => {
+            let response = route_response(request, Arc::clone(state));
+            let response = match route {
+                Some(route) => route.with_deadline(state, response).await,
+                None => response.await,
+            };
+            match (&state.cors, request.origin()) {
+                (Some(cors), Some(origin)) => cors
+                    .response_headers(origin)
+                    .into_iter()
+                    .fold(response, |response, (name, value)| {
+                        response.header(name, value)
+                    }),
+                _ => response,
+            }
+        }
     };
 
-    let response = match request.method() {
-        Method::Get => {
-            let uri = request.uri().trim_start_matches("/api/messages");
-            match uri {
-                "" | "/" => get_pagination_meta(state).await,
-                "/get-page" => handle_get(state).await,
-                uri => {
-                    // unknown GET request
-                    let body = format!("GET uri not found, {}", uri);
-                    Response::new()
-                        .status_line("HTTP/1.1 404 NOT FOUND")
-                        .append_header(&format!("Content-Length: {}", body.len()))
-                        .append_header("Content-Type: text/plain")
-                        .body(&body)
-                        .to_string()
-                        .into_bytes()
+    // HEAD gets the response of GET without its body
+    match request.method() {
+        Method::Head => response.without_body(),
+        _ => response,
+    }
+}
+
+/// The response to a CORS preflight request, `None` if `request` isn't one.
+fn preflight_response(request: &Request, state: &AppState) -> Option<Response> {
+    let (Method::Options, Some(cors), Some(origin)) =
+        (request.method(), &state.cors, request.origin())
+    else {
+        return None;
+    };
+    request.header("Access-Control-Request-Method")?;
+    let Err(RouteError::MethodNotAllowed { allowed }) =
+        routes().find(Method::Options, request.path())
+    else {
+        return None;
+    };
+
+    let methods: Vec<_> = allowed.iter().map(Method::to_string).collect();
+    let headers = cors.preflight_headers(
+        origin,
+        &methods.join(", "),
+        request.header("Access-Control-Request-Headers"),
+    );
+    let response = headers.into_iter().fold(
+        Response::new().status(StatusCode::NoContent),
+        |response, (name, value)| response.header(name, value),
+    );
+    Some(response)
+}
+
+/// Routes `request` to its handler.
+async fn route_response(request: &Request, state: Arc<AppState>) -> Response {
+    let (route, params) = match routes().find(*request.method(), request.path()) {
+        // admin endpoints are only served on the admin listener when it is enabled
+        Ok((route, _)) if route.policy().role == Role::Admin && state.admin_listener => {
+            return route_error_response(RouteError::NotFound, request);
+        }
+        Ok(found) => found,
+        Err(e) => return route_error_response(e, request),
+    };
+    if let Some(response) = reject_checksum(request) {
+        return response;
+    }
+
+    // a multipart body is handled as the JSON body it stands for, which is also what is
+    // journaled and forwarded
+    let converted;
+    let request = match request.multipart_body() {
+        Some(body) => match multipart_json(route, request, body) {
+            Ok(json) => {
+                converted = request.with_json_body(json);
+                &converted
+            }
+            Err(response) => return response,
+        },
+        None => request,
+    };
+
+    // HEAD must not change anything, while these GETs move the pagination forward
+    let advances_pagination = match route {
+        Route::PaginationMeta => true,
+        Route::Page => {
+            request.query_param("page").is_none() && request.query_param("cursor").is_none()
+        }
+        _ => false,
+    };
+    if *request.method() == Method::Head && advances_pagination {
+        let Err(RouteError::MethodNotAllowed { mut allowed }) =
+            routes().find(Method::Options, request.path())
+        else {
+            unreachable!("the path of a route is allowed some methods");
+        };
+        allowed.retain(|method| *method != Method::Head);
+        return route_error_response(RouteError::MethodNotAllowed { allowed }, request);
+    }
+
+    // in read-only mode, only GET/pagination and other reading endpoints are served
+    if state.read_only && route.is_write() {
+        let body = "Server is in read-only mode, write endpoints are disabled.";
+        return Response::new()
+            .status(StatusCode::ServiceUnavailable)
+            .header("Content-Type", CONTENT_TYPE_TEXT)
+            .body(body);
+    }
+
+    // the handlers take ownership of the state, the quotas and the journal are updated after
+    let quotas_state = Arc::clone(&state);
+
+    // forward requests for uuids owned by another node of the shard ring
+    if let Some(router) = state
+        .shard_router
+        .as_ref()
+        .filter(|router| !router.is_forwarded(request))
+    {
+        if let Some(uuid) = request_uuid(route, &params, request, state.id_scheme) {
+            if !router.is_local(&uuid) {
+                return match router.forward(router.owner(&uuid), request).await {
+                    Ok(response) => response,
+                    Err(e) => {
+                        eprintln!(
+                            "Failed to forward request to {}: {}",
+                            router.owner(&uuid),
+                            e
+                        );
+                        Response::new().status(StatusCode::BadGateway)
+                    }
+                };
+            }
+        }
+    }
+
+    // ids name the image files, they are validated before a handler sees them
+    let uuid = match params
+        .get("uuid")
+        .map(|uuid| MessageId::parse(uuid, state.id_scheme))
+    {
+        Some(Err(e)) => {
+            return Response::new()
+                .status(StatusCode::BadRequest)
+                .header("Content-Type", CONTENT_TYPE_TEXT)
+                .body(e);
+        }
+        uuid => uuid.and_then(Result::ok),
+    };
+
+    // enforce the quotas of the caller's api key on writes
+    let api_key = request.api_key().unwrap_or(ANONYMOUS_KEY).to_string();
+    let is_write = route.is_write();
+    // upload chunks count towards the stored bytes without replacing the message's size
+    let write_uuid = match route {
+        Route::Post | Route::Put | Route::Delete => {
+            request_uuid(route, &params, request, state.id_scheme)
+        }
+        _ => None,
+    };
+    let write_bytes = match request.method() {
+        Method::Post | Method::Put => request.body().map_or(0, |body| body.len() as u64),
+        _ => 0,
+    };
+    // counted before the write is made, so concurrent writes can't overrun the quotas together
+    let reservation = match is_write {
+        true => {
+            let reserved =
+                state
+                    .quotas
+                    .lock()
+                    .await
+                    .reserve(&api_key, write_uuid.as_deref(), write_bytes);
+            match reserved {
+                Ok(reservation) => Some(reservation),
+                Err(e) => {
+                    let body = serde_json::to_string(&e).unwrap();
+                    return Response::new()
+                        .status(e.status())
+                        .header("Content-Type", CONTENT_TYPE_JSON)
+                        .body(body);
+                }
+            }
+        }
+        false => None,
+    };
+
+    let format = PageFormat::negotiate(request.header("Accept"));
+    // route parameters are always present for the routes declaring them
+    let uuid = uuid.as_deref().unwrap_or_default();
+    let upload_id = params.get("upload_id").unwrap_or_default();
+
+    // writes are journaled before they are processed and marked done once they were committed.
+    // Only those that can be replayed after they were applied without applying them twice are,
+    // reactions add to what is there, likes too unless they carry an Idempotency-Key, and the
+    // bulk inserts aren't checked against it
+    let idempotency_key = request.header(IDEMPOTENCY_KEY_HEADER);
+    let journaled = match route {
+        Route::Post
+        | Route::Put
+        | Route::PutBatch
+        | Route::Patch
+        | Route::Delete
+        | Route::Restore => true,
+        Route::Like | Route::Unlike => idempotency_key.is_some(),
+        _ => false,
+    };
+    let journal_id = match &state.journal {
+        Some(journal) if journaled => {
+            let method = request.method().to_string();
+            let body = request.body().map(String::as_str);
+            match journal
+                .begin(&method, request.uri(), body, idempotency_key)
+                .await
+            {
+                Ok(id) => Some(id),
+                Err(e) => {
+                    eprintln!("Failed to journal the write: {}", e);
+                    if let Some(reservation) = reservation {
+                        state.quotas.lock().await.release(reservation);
+                    }
+                    return Response::new().status(StatusCode::InternalServerError);
+                }
+            }
+        }
+        _ => None,
+    };
+
+    let response = match route {
+        Route::PaginationMeta => get_pagination_meta(format, page_view(request), state).await,
+        Route::Page => match include_images(request) {
+            Ok(images) => {
+                let size = request.query_param("size");
+                // repeated pagination runs skip downloading the pages the client already has
+                let if_none_match = request.header("if-none-match");
+                match (request.query_param("page"), request.query_param("cursor")) {
+                    (Some(page), _) => {
+                        let view = page_view(request);
+                        let response =
+                            handle_get_page_number(page, size, view, images, format, state).await;
+                        with_etag(response, if_none_match)
+                    }
+                    (None, Some(cursor)) => {
+                        let view = page_view(request);
+                        let response =
+                            handle_get_cursor(cursor, size, view, images, format, state).await;
+                        with_etag(response, if_none_match)
+                    }
+                    (None, None) => {
+                        let token = request.header(PAGE_TOKEN_HEADER);
+                        let session = request.header(PAGINATION_SESSION_HEADER);
+                        handle_get(token, session, if_none_match, images, format, state).await
+                    }
                 }
             }
+            Err(e) => Response::new()
+                .status(StatusCode::BadRequest)
+                .header("Content-Type", CONTENT_TYPE_TEXT)
+                .body(e),
+        },
+        Route::AckPage => handle_ack_page(request.header(PAGE_TOKEN_HEADER), state).await,
+        Route::Search => {
+            handle_search(
+                request.query_param("q"),
+                request.query_param("page"),
+                request.query_param("size"),
+                format,
+                state,
+            )
+            .await
+        }
+        Route::Sample => handle_sample(request.query_param("n"), format, state).await,
+        Route::Stats => handle_stats(format, state).await,
+        Route::Exists => match request.body() {
+            Some(body) => handle_exists(body, state).await,
+            None => length_required(),
+        },
+        Route::Message => handle_message(uuid, format, state).await,
+        Route::UuidExists => handle_uuid_exists(uuid, state).await,
+        Route::Replies => {
+            let page = request.query_param("page");
+            let size = request.query_param("size");
+            handle_replies(uuid, page, size, format, state).await
+        }
+        Route::Authors => {
+            let page = request.query_param("page");
+            let size = request.query_param("size");
+            handle_authors(page, size, format, state).await
         }
-        Method::Post => match request.body() {
-            Some(body) => handle_post(body, state).await.into_bytes(),
-            None => Response::new()
-                .status_line("HTTP/1.1 411 LENGTH REQUIRED")
-                .to_string()
-                .into_bytes(),
+        Route::Export => handle_export(request.query_param("format"), state).await,
+        Route::Image => {
+            let response = handle_image(uuid, request.query_param("encoding"), state).await;
+            with_etag(response, request.header("if-none-match"))
+        }
+        Route::ImageBatch => match request.body() {
+            Some(body) => handle_image_batch(body, state).await,
+            None => length_required(),
+        },
+        Route::Post => match request.body() {
+            Some(body) => handle_post(body, state).await,
+            None => length_required(),
+        },
+        Route::PostBatch => match request.body() {
+            Some(body) => handle_post_batch(body, state).await,
+            None => length_required(),
+        },
+        Route::Import => match request.body() {
+            Some(body) => handle_import(body, state).await,
+            None => length_required(),
         },
-        Method::Put => match request.body() {
+        Route::Put => match request.body() {
+            Some(body) => handle_put(uuid, body, request.header("If-Match"), state).await,
+            None => length_required(),
+        },
+        Route::PutBatch => match request.body() {
+            Some(body) => handle_put_batch(body, state).await,
+            None => length_required(),
+        },
+        Route::Patch => match request.body() {
+            Some(body) => handle_patch(uuid, body, request.header("If-Match"), state).await,
+            None => length_required(),
+        },
+        Route::Like => handle_like(uuid, 1, idempotency_key, state).await,
+        Route::Unlike => handle_like(uuid, -1, idempotency_key, state).await,
+        Route::React => match request.body() {
+            Some(body) => handle_react(uuid, body, 1, state).await,
+            None => length_required(),
+        },
+        Route::Unreact => match request.body() {
+            Some(body) => handle_react(uuid, body, -1, state).await,
+            None => length_required(),
+        },
+        Route::Delete => handle_delete(uuid, state).await,
+        Route::Restore => handle_restore(uuid, state).await,
+        Route::Clear => clear(clear_filter(request), state).await,
+        Route::Purge => handle_purge(request.query_param("older_than_secs"), state).await,
+        Route::CreateUpload => handle_create_upload(uuid, state).await,
+        Route::UploadProgress => handle_upload_progress(uuid, upload_id, state).await,
+        Route::UploadChunk => match request.body() {
             Some(body) => {
-                let uuid = request.uri().trim_start_matches("/api/messages/");
-                handle_put(uuid, body, state).await.into_bytes()
+                handle_upload_chunk(uuid, upload_id, request.content_range(), body, state).await
             }
-            None => Response::new()
-                .status_line("HTTP/1.1 411 LENGTH REQUIRED")
-                .to_string()
-                .into_bytes(),
+            None => length_required(),
         },
-        Method::Delete => {
-            let uuid = request.uri().trim_start_matches("/api/messages/");
-            handle_delete(uuid, state).await.into_bytes()
+        Route::CommitUpload => handle_commit_upload(uuid, upload_id, state).await,
+        Route::Usage => handle_usage(&api_key, state).await,
+        Route::DebugConfig => handle_config(state).await,
+        Route::DebugMetrics => handle_metrics(state).await,
+        Route::ReplayMutations => handle_replay(state).await,
+        Route::VerifyPagination => handle_verify_pagination(state).await,
+    };
+
+    if let (Some(journal), Some(id)) = (&quotas_state.journal, journal_id) {
+        let status = response.status_code().code();
+        if let Err(e) = journal.done(id, status).await {
+            eprintln!("Failed to mark journal entry {} as done: {}", id, e);
         }
-        Method::Patch => clear(state).await.into_bytes(),
+    }
+
+    if let Some(reservation) = reservation {
+        let mut quotas = quotas_state.quotas.lock().await;
+        match response.status_code().is_success() {
+            true => quotas.commit(reservation),
+            false => quotas.release(reservation),
+        }
+    }
+
+    response
+}
+
+/// Processes a write of the journal again, e.g. one a crash interrupted, returning the status
+/// it is answered with now.
+///
+/// # Errors
+///
+/// This function will return an error if the entry has an unknown method or an invalid uri.
+pub async fn replay_write(
+    entry: &JournalEntry,
+    state: Arc<AppState>,
+) -> Result<StatusCode, &'static str> {
+    let mut request = Request::default();
+    request.set_method(&entry.method)?;
+    request.set_uri(entry.uri.clone())?;
+    request.set_body(entry.body.clone());
+    if let Some(key) = &entry.idempotency_key {
+        request.append_header(IDEMPOTENCY_KEY_HEADER, key);
+    }
+    Ok(route_response(&request, state).await.status_code())
+}
+
+/// `GET /api/usage`, reports the quota usage of the caller's api key.
+async fn handle_usage(api_key: &str, state: Arc<AppState>) -> Response {
+    let body = serde_json::to_string(&state.quotas.lock().await.report(api_key)).unwrap();
+    Response::new()
+        .header("Content-Type", CONTENT_TYPE_JSON)
+        .body(body)
+}
+
+/// The uuid of the message a request operates on, in the case of `scheme`, used to route it to
+/// its shard.
+fn request_uuid(
+    route: Route,
+    params: &Params,
+    request: &Request,
+    scheme: IdScheme,
+) -> Option<String> {
+    #[derive(Deserialize)]
+    struct WithUuid {
+        uuid: String,
+    }
+
+    let uuid = match route {
+        Route::Post => serde_json::from_str::<WithUuid>(request.body()?)
+            .ok()
+            .map(|m| m.uuid),
+        _ => params.get("uuid").map(str::to_string),
     };
+    uuid.map(|uuid| scheme.normalize(uuid))
+}
 
-    if let Err(e) = stream.write_all(&response).await {
-        eprintln!("Failed to send response: {}", e);
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        app_state::testing::{self, TestDir},
+        journal::Journal,
+    };
+
+    const UUID: &str = "00000000-0000-0000-0000-000000000001";
+
+    fn request(method: &str, uri: &str, body: Option<&str>) -> Request {
+        let mut request = Request::default();
+        request.set_method(method).unwrap();
+        request.set_uri(uri.to_string()).unwrap();
+        request.append_header("Accept", CONTENT_TYPE_JSON);
+        if let Some(body) = body {
+            request.append_header("Content-Type", CONTENT_TYPE_JSON);
+            request.set_body(Some(body.to_string()));
+        }
+        request
+    }
+
+    fn body(response: &Response) -> serde_json::Value {
+        let body: Vec<u8> = response.chunks().concat();
+        serde_json::from_slice(&body).unwrap()
+    }
+
+    fn header(response: &Response, name: &str) -> Option<String> {
+        response
+            .header_fields()
+            .find(|(field, _)| field.eq_ignore_ascii_case(name))
+            .map(|(_, value)| value.into_owned())
+    }
+
+    async fn post(state: &Arc<AppState>, uuid: &str, likes: i32) -> Response {
+        let message = format!(
+            r#"{{"uuid":"{uuid}","author":"author","message":"hello","likes":{likes},"imageUpdate":false,"image":""}}"#
+        );
+        route_response(
+            &request("POST", "/api/messages", Some(&message)),
+            Arc::clone(state),
+        )
+        .await
+    }
+
+    #[tokio::test]
+    async fn journaled_writes_are_marked_done() {
+        let dir = TestDir::new();
+        let path = dir.path().join("journal.jsonl");
+        let Ok(mut state) = Arc::try_unwrap(testing::state(&dir, 10)) else {
+            unreachable!("the state isn't shared yet");
+        };
+        state.journal = Some(Journal::open(&path).unwrap().0);
+        let state = Arc::new(state);
+
+        assert_eq!(
+            post(&state, UUID, 0).await.status_code(),
+            StatusCode::Created
+        );
+        let response = route_response(
+            &request("DELETE", &format!("/api/messages/{UUID}"), None),
+            Arc::clone(&state),
+        )
+        .await;
+        assert!(response.status_code().is_success());
+
+        let (_, incomplete) = Journal::open(&path).unwrap();
+        assert!(incomplete.is_empty());
+        assert_eq!(std::fs::read_to_string(&path).unwrap().lines().count(), 4);
+    }
+
+    #[tokio::test]
+    async fn posted_message_is_served() {
+        let dir = TestDir::new();
+        let state = testing::state(&dir, 10);
+
+        assert!(post(&state, UUID, 3).await.status_code().is_success());
+        let response = route_response(
+            &request("GET", &format!("/api/messages/{UUID}"), None),
+            Arc::clone(&state),
+        )
+        .await;
+
+        assert_eq!(response.status_code(), StatusCode::Ok);
+        let message = body(&response);
+        assert_eq!(message["uuid"], UUID);
+        assert_eq!(message["likes"], 3);
+    }
+
+    #[tokio::test]
+    async fn uuids_are_the_same_message_whatever_their_case() {
+        let dir = TestDir::new();
+        let state = testing::state(&dir, 10);
+        let upper = "0000000A-0000-0000-0000-00000000000B";
+
+        assert!(post(&state, upper, 0).await.status_code().is_success());
+        assert_eq!(
+            post(&state, &upper.to_lowercase(), 0).await.status_code(),
+            StatusCode::Conflict
+        );
+        for uuid in [upper.to_string(), upper.to_lowercase()] {
+            let response = route_response(
+                &request("GET", &format!("/api/messages/{uuid}"), None),
+                Arc::clone(&state),
+            )
+            .await;
+            assert_eq!(response.status_code(), StatusCode::Ok);
+            assert_eq!(body(&response)["uuid"], upper.to_lowercase());
+        }
+    }
+
+    #[tokio::test]
+    async fn posting_a_uuid_twice_conflicts() {
+        let dir = TestDir::new();
+        let state = testing::state(&dir, 10);
+
+        assert!(post(&state, UUID, 0).await.status_code().is_success());
+        assert_eq!(
+            post(&state, UUID, 0).await.status_code(),
+            StatusCode::Conflict
+        );
+    }
+
+    #[tokio::test]
+    async fn unknown_message_is_not_found() {
+        let dir = TestDir::new();
+        let state = testing::state(&dir, 10);
+
+        let response = route_response(
+            &request("GET", &format!("/api/messages/{UUID}"), None),
+            state,
+        )
+        .await;
+        assert_eq!(response.status_code(), StatusCode::NotFound);
+    }
+
+    #[tokio::test]
+    async fn unknown_path_is_not_found_and_known_path_lists_its_methods() {
+        let dir = TestDir::new();
+        let state = testing::state(&dir, 10);
+
+        let response =
+            route_response(&request("GET", "/api/nothing", None), Arc::clone(&state)).await;
+        assert_eq!(response.status_code(), StatusCode::NotFound);
+
+        let response = route_response(&request("POST", "/api/messages/stats", None), state).await;
+        assert_eq!(response.status_code(), StatusCode::MethodNotAllowed);
+        assert!(header(&response, "Allow").unwrap().contains("GET"));
+    }
+
+    #[tokio::test]
+    async fn like_with_the_same_idempotency_key_counts_once() {
+        let dir = TestDir::new();
+        let state = testing::state(&dir, 10);
+        post(&state, UUID, 0).await;
+
+        let uri = format!("/api/messages/{UUID}/like");
+        let mut like = request("POST", &uri, None);
+        like.append_header(IDEMPOTENCY_KEY_HEADER, "retried");
+        for _ in 0..2 {
+            let response = route_response(&like, Arc::clone(&state)).await;
+            assert_eq!(body(&response)["likes"], 1);
+        }
+        let response = route_response(&request("POST", &uri, None), state).await;
+        assert_eq!(body(&response)["likes"], 2);
+    }
+
+    /// Sends `requests` on a connection served by [`handle_connection`] over an in-memory pipe
+    /// and returns all the connection answered with.
+    async fn exchange(state: Arc<AppState>, requests: &str) -> String {
+        let (mut client, server) = tokio::io::duplex(64 * 1024);
+        let served = tokio::spawn(handle_connection(server, state));
+        client.write_all(requests.as_bytes()).await.unwrap();
+        let mut answer = String::new();
+        client.read_to_string(&mut answer).await.unwrap();
+        served.await.unwrap();
+        answer
+    }
+
+    #[tokio::test]
+    async fn connection_answers_pipelined_requests_in_order() {
+        let dir = TestDir::new();
+        let state = testing::state(&dir, 10);
+        let message = format!(
+            r#"{{"uuid":"{UUID}","author":"author","message":"hello","likes":0,"imageUpdate":false,"image":""}}"#
+        );
+        let requests = format!(
+            "POST /api/messages HTTP/1.1\r\nHost: test\r\nContent-Type: application/json\r\n\
+             Content-Length: {}\r\n\r\n{message}\
+             GET /api/messages/{UUID} HTTP/1.1\r\nHost: test\r\nAccept: application/json\r\n\
+             Connection: close\r\n\r\n",
+            message.len()
+        );
+
+        let answer = exchange(state, &requests).await;
+
+        let statuses: Vec<_> = answer
+            .match_indices("HTTP/1.1 ")
+            .map(|(i, _)| &answer[i + 9..i + 12])
+            .collect();
+        assert_eq!(statuses, ["201", "200"]);
+        assert!(answer.contains(r#""message":"hello""#));
+        assert!(answer.contains("Connection: close"));
+    }
+
+    #[tokio::test]
+    async fn connection_rejects_a_malformed_request_and_closes() {
+        let dir = TestDir::new();
+        let state = testing::state(&dir, 10);
+
+        let answer = exchange(state, "GARBAGE\r\n\r\nGET / HTTP/1.1\r\n\r\n").await;
+
+        assert!(answer.starts_with("HTTP/1.1 400 "));
+        assert_eq!(answer.matches("HTTP/1.1 ").count(), 1);
+    }
+
+    #[tokio::test]
+    async fn body_over_the_route_limit_is_refused_before_it_is_sent() {
+        let dir = TestDir::new();
+        let state = testing::state(&dir, 10);
+        let length = Route::Exists.policy().max_body.unwrap() + 1;
+
+        // the body never comes, the head is enough to refuse it
+        let answer = exchange(
+            state,
+            &format!("POST /api/messages/exists HTTP/1.1\r\nContent-Length: {length}\r\n\r\n"),
+        )
+        .await;
+
+        assert!(answer.starts_with("HTTP/1.1 413 "));
+    }
+
+    #[tokio::test]
+    async fn pagination_serves_every_message_once() {
+        let dir = TestDir::new();
+        let state = testing::state(&dir, 2);
+        for i in 1..=3 {
+            post(
+                &state,
+                &format!("00000000-0000-0000-0000-00000000000{i}"),
+                0,
+            )
+            .await;
+        }
+
+        let meta = route_response(&request("GET", "/api/messages", None), Arc::clone(&state)).await;
+        assert_eq!(meta.status_code(), StatusCode::Ok);
+        let session = header(&meta, PAGINATION_SESSION_HEADER).unwrap();
+
+        let mut uuids = Vec::new();
+        loop {
+            let mut page = request("GET", "/api/messages/get-page", None);
+            page.append_header(PAGINATION_SESSION_HEADER, &session);
+            let response = route_response(&page, Arc::clone(&state)).await;
+            assert_eq!(response.status_code(), StatusCode::Ok);
+            let page = body(&response);
+            for message in page["posts"].as_array().unwrap() {
+                uuids.push(message["uuid"].as_str().unwrap().to_string());
+            }
+            if page["done"] == true {
+                break;
+            }
+        }
+        uuids.sort();
+        uuids.dedup();
+        assert_eq!(uuids.len(), 3);
     }
 }
@@ -0,0 +1,149 @@
+use std::sync::Arc;
+
+use serde::Deserialize;
+
+use crate::{
+    app_state::AppState,
+    models::{timestamp_now, Maybe, SERVER_TIMESTAMP_HEADER},
+    repository::{MessagePatch, UpdateOutcome},
+    response::{Response, StatusCode, CONTENT_TYPE_TEXT},
+};
+
+use super::{
+    image_error,
+    put::{expected_revision, revision_etag},
+};
+
+/// The fields of a `PATCH`, any of them may be left out. Unknown fields are rejected, so a typo
+/// isn't silently ignored.
+#[derive(Deserialize, Default)]
+#[serde(default, deny_unknown_fields)]
+struct PatchMessage {
+    author: Maybe<String>,
+    message: Maybe<String>,
+    likes: Maybe<i32>,
+    /// Replaces the image, an empty string removes it.
+    image: Maybe<String>,
+    /// `null` clears the client timestamp.
+    clientTimestamp: Maybe<Option<i64>>,
+}
+
+/// `PATCH /api/messages/{uuid}`, changes only the fields present in the body, e.g.
+/// `{"likes": 5}`, instead of replacing the whole message like `PUT`. `If-Match` requires a
+/// revision like for `PUT`.
+pub(crate) async fn handle_patch(
+    uuid: &str,
+    body: &str,
+    if_match: Option<&str>,
+    state: Arc<AppState>,
+) -> Response {
+    let mut response = Response::new();
+
+    if !state.all_uuids.lock().await.contains(uuid) {
+        return response.status(StatusCode::NotFound);
+    }
+
+    let Ok(expected_revision) = expected_revision(if_match) else {
+        return response
+            .status(StatusCode::PreconditionFailed)
+            .header("Content-Type", CONTENT_TYPE_TEXT)
+            .body("If-Match must be a revision of the message.");
+    };
+
+    let payload: PatchMessage = match serde_json::from_str(body) {
+        Ok(v) => v,
+        Err(e) => {
+            return response
+                .status(StatusCode::BadRequest)
+                .header("Content-Type", CONTENT_TYPE_TEXT)
+                .body(e.to_string());
+        }
+    };
+    let changes_nothing = !payload.author.is_present()
+        && !payload.message.is_present()
+        && !payload.likes.is_present()
+        && !payload.image.is_present()
+        && !payload.clientTimestamp.is_present();
+    if changes_nothing {
+        return response
+            .status(StatusCode::BadRequest)
+            .header("Content-Type", CONTENT_TYPE_TEXT)
+            .body("The patch changes no field.");
+    }
+
+    // a new image is saved before the message is patched, so it is there once the message says
+    // so, and the image it replaced is put back if the message isn't patched. A removed image is
+    // only removed once the message is patched.
+    let mut replaced_image = None;
+    let has_image = match payload.image {
+        Maybe::Absent => Maybe::Absent,
+        Maybe::Present(image) if image.is_empty() => Maybe::Present(false),
+        Maybe::Present(image) => {
+            replaced_image = Some(state.images.get(uuid).await);
+            if let Err(e) = state.images.save(uuid, &image).await {
+                let (status, reason) = image_error(&e);
+                return response
+                    .status(status)
+                    .header("Content-Type", CONTENT_TYPE_TEXT)
+                    .body(reason);
+            }
+            Maybe::Present(true)
+        }
+    };
+
+    let patch = MessagePatch {
+        author: payload.author,
+        message: payload.message,
+        likes: payload.likes,
+        has_image,
+        client_timestamp: payload.clientTimestamp,
+        server_timestamp: timestamp_now(),
+        expected_revision,
+    };
+    let outcome = state.messages.patch(uuid, &patch).await;
+    match (&outcome, replaced_image) {
+        (Ok(UpdateOutcome::Updated { .. }), _) => {
+            if patch.has_image == Maybe::Present(false) {
+                state.images.remove(uuid).await.ok();
+            }
+        }
+        (_, Some(replaced_image)) => restore_image(uuid, replaced_image, &state).await,
+        (_, None) => (),
+    }
+    match outcome {
+        Ok(UpdateOutcome::Updated { revision }) => {
+            state.outbox_notify.notify_one();
+            response = response
+                .status(StatusCode::NoContent)
+                .header(SERVER_TIMESTAMP_HEADER, patch.server_timestamp)
+                .header("ETag", revision_etag(revision));
+        }
+        Ok(UpdateOutcome::NotFound) => response.set_status(StatusCode::NotFound),
+        Ok(UpdateOutcome::Stale { revision }) => {
+            let body = format!("The message was changed, it is at revision {revision}.");
+            response = response
+                .status(StatusCode::PreconditionFailed)
+                .header("ETag", revision_etag(revision))
+                .header("Content-Type", CONTENT_TYPE_TEXT)
+                .body(body);
+        }
+        Err(e) => {
+            eprintln!("Failed to patch message: {}", e);
+            response.set_status(StatusCode::InternalServerError);
+        }
+    }
+
+    response
+}
+
+/// Puts `image` back as the image of `uuid` after a patch that replaced it failed, removing
+/// the new one if there was none before.
+async fn restore_image(uuid: &str, image: Option<String>, state: &AppState) {
+    let restored = match image {
+        Some(image) => state.images.save(uuid, &image).await,
+        None => state.images.remove(uuid).await,
+    };
+    if let Err(e) = restored {
+        eprintln!("Failed to restore the image of {}: {}", uuid, e);
+    }
+}
@@ -2,9 +2,13 @@ use std::sync::Arc;
 
 use serde::{Deserialize, Serialize};
 
-use crate::{app_state::AppState, image, response::Response};
+use crate::{
+    app_state::AppState,
+    models::{timestamp_now, Message, MessageId, Reactions, SERVER_TIMESTAMP_HEADER},
+    response::{Response, StatusCode, CONTENT_TYPE_JSON, CONTENT_TYPE_TEXT},
+};
 
-use super::CompleteMessage;
+use super::{image_error, put::revision_etag, replies::parent_error, CompleteMessage};
 
 #[derive(Deserialize, Serialize)]
 pub struct PostMessage {
@@ -14,9 +18,17 @@ pub struct PostMessage {
     likes: i32,
     imageUpdate: bool,
     image: String,
+    /// When the message was written by the client's clock, in milliseconds since the epoch.
+    #[serde(default)]
+    clientTimestamp: Option<i64>,
+    /// The message this one replies to, if any.
+    #[serde(default)]
+    parentUuid: Option<String>,
 }
 
-pub async fn handle_post(body: &str, state: Arc<AppState>) -> String {
+/// `POST /api/messages`, creates a message and answers with it as stored, along with its
+/// `Location`, so clients don't have to echo their own payload.
+pub async fn handle_post(body: &str, state: Arc<AppState>) -> Response {
     let mut response = Response::new();
 
     let PostMessage {
@@ -25,21 +37,63 @@ pub async fn handle_post(body: &str, state: Arc<AppState>) -> String {
         message,
         likes,
         imageUpdate,
-        mut image,
+        image,
+        clientTimestamp,
+        parentUuid,
     } = match serde_json::from_str(body) {
         Ok(v) => v,
         Err(e) => {
             let body = format!("{e} {body}");
             return response
-                .status_line("HTTP/1.1 400 BAD REQUEST")
-                .body(&body)
-                .to_string();
+                .status(StatusCode::BadRequest)
+                .header("Content-Type", CONTENT_TYPE_TEXT)
+                .body(body);
+        }
+    };
+
+    let uuid = match MessageId::parse(uuid, state.id_scheme) {
+        Ok(uuid) => uuid.into_inner(),
+        Err(e) => {
+            return response
+                .status(StatusCode::BadRequest)
+                .header("Content-Type", CONTENT_TYPE_TEXT)
+                .body(e);
         }
     };
+    let parentUuid = parentUuid.map(|parent_uuid| state.id_scheme.normalize(parent_uuid));
+
+    // a recently deleted uuid can't be reused yet
+    if let Some(remaining) = state.tombstones.lock().await.remaining(&uuid) {
+        let secs = remaining.as_secs() + 1;
+        let body = format!(
+            "Message {uuid} was deleted recently, its uuid can be reused in {secs} seconds."
+        );
+        return response
+            .status(StatusCode::Conflict)
+            .header("Retry-After", secs)
+            .header("Content-Type", CONTENT_TYPE_TEXT)
+            .body(body);
+    }
+
+    if let Some(parent_uuid) = &parentUuid {
+        match parent_error(&uuid, parent_uuid, &state).await {
+            Ok(None) => (),
+            Ok(Some(e)) => {
+                return response
+                    .status(StatusCode::BadRequest)
+                    .header("Content-Type", CONTENT_TYPE_TEXT)
+                    .body(e);
+            }
+            Err(e) => {
+                eprintln!("Error checking the parent: {}", e);
+                return response.status(StatusCode::InternalServerError);
+            }
+        }
+    }
 
     // check for conflicting uuid
     if !state.all_uuids.lock().await.insert(uuid.clone()) {
-        return response.status_line("HTTP/1.1 409 CONFLICT").to_string();
+        return response.status(StatusCode::Conflict);
     }
 
     // if let (true, "") = (imageUpdate, image) {
@@ -51,50 +105,265 @@ pub async fn handle_post(body: &str, state: Arc<AppState>) -> String {
     //         .to_string();
     // }
     // }
-    if imageUpdate {
-        if !image.is_empty() {
-            if let Err(e) = image::save(&state.image_base_path, &image, &uuid) {
-                eprintln!("Error saving image: {}", e);
-                return response
-                    .status_line("HTTP/1.1 500 Internal Server Error")
-                    .body("Failed to save image.")
-                    .to_string();
-            }
-        } else {
-            image = String::new();
+    if imageUpdate && !image.is_empty() {
+        if let Err(e) = state.images.save(&uuid, &image).await {
+            state.all_uuids.lock().await.remove(&uuid);
+            let (status, reason) = image_error(&e);
+            return response
+                .status(status)
+                .header("Content-Type", CONTENT_TYPE_TEXT)
+                .body(reason);
         }
+    } else {
+        // left behind if the uuid was of a deleted message, which the post replaces
+        state.images.remove(&uuid).await.ok();
     }
 
-    let result = sqlx::query!(
-        "INSERT INTO messages (uuid, author, message, likes, has_image) VALUES ($1, $2, $3, $4, $5)",
+    let server_timestamp = timestamp_now();
+    let row = Message {
         uuid,
         author,
         message,
+        parent_uuid: parentUuid,
         likes,
-        imageUpdate
-    )
-    .execute(state.pool.as_ref())
-    .await;
+        has_image: imageUpdate,
+        client_timestamp: clientTimestamp,
+        server_timestamp,
+        created_at: server_timestamp,
+        updated_at: server_timestamp,
+        deleted_at: None,
+        revision: 1,
+        reactions: Reactions::default(),
+    };
+    let result = state.messages.insert(&row).await;
 
     match result {
         Ok(_) => {
-            state.mutations.lock().await.add_post(
-                CompleteMessage {
-                    uuid,
-                    author,
-                    message,
-                    likes,
-                    image,
-                },
-                &state.image_base_path,
-                imageUpdate,
-            );
-            response.set_status_line("HTTP/1.1 201 OK");
+            state.outbox_notify.notify_one();
+            let location = format!("/api/messages/{}", row.uuid);
+            let etag = revision_etag(row.revision);
+            let (image, image_metadata) = if row.has_image {
+                (image, state.images.metadata(&row.uuid).await)
+            } else {
+                (String::new(), None)
+            };
+            let body =
+                serde_json::to_string(&CompleteMessage::new(row, image, image_metadata)).unwrap();
+            response = response
+                .status(StatusCode::Created)
+                .header("Location", location)
+                .header(SERVER_TIMESTAMP_HEADER, server_timestamp)
+                .header("ETag", etag)
+                .header("Content-Type", CONTENT_TYPE_JSON)
+                .body(body);
         }
         Err(_) => {
-            response.set_status_line("HTTP/1.1 409 CONFLICT");
+            response.set_status(StatusCode::Conflict);
+        }
+    }
+
+    response
+}
+
+/// The most messages a single batch may post.
+const MAX_POST_BATCH: usize = 10_000;
+
+/// The outcome of a message of a batch, in request order.
+#[derive(Serialize)]
+struct BatchPostResult {
+    /// Empty if the item has no uuid.
+    uuid: String,
+    /// The status a single `POST /api/messages` of the message is answered with.
+    status: u16,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    error: Option<String>,
+    /// When the message was stored, for created messages.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    serverTimestamp: Option<i64>,
+}
+
+impl BatchPostResult {
+    fn rejected(uuid: String, status: StatusCode, error: impl Into<String>) -> Self {
+        Self {
+            uuid,
+            status: status.code(),
+            error: Some(error.into()),
+            serverTimestamp: None,
+        }
+    }
+}
+
+/// `POST /api/messages/batch`, posts a JSON array of messages, e.g. to seed a large data set.
+/// The accepted messages are inserted in a single statement along with their mutations, all or
+/// none, and the response reports the outcome of every message.
+pub(crate) async fn handle_post_batch(body: &str, state: Arc<AppState>) -> Response {
+    let items: Vec<serde_json::Value> = match serde_json::from_str(body) {
+        Ok(v) => v,
+        Err(e) => {
+            let body = e.to_string();
+            return Response::new()
+                .status(StatusCode::BadRequest)
+                .header("Content-Type", CONTENT_TYPE_TEXT)
+                .body(body);
+        }
+    };
+    if items.len() > MAX_POST_BATCH {
+        let body = format!("At most {MAX_POST_BATCH} messages can be posted at once.");
+        return Response::new()
+            .status(StatusCode::PayloadTooLarge)
+            .header("Content-Type", CONTENT_TYPE_TEXT)
+            .body(body);
+    }
+
+    let mut results = Vec::with_capacity(items.len());
+    // the messages that passed validation, with the index of their result
+    let mut accepted = Vec::with_capacity(items.len());
+    {
+        // in the order of clear, which holds both too
+        let mut all_uuids = state.all_uuids.lock().await;
+        let mut tombstones = state.tombstones.lock().await;
+        for item in items {
+            let uuid = item
+                .get("uuid")
+                .and_then(|uuid| uuid.as_str())
+                .unwrap_or_default()
+                .to_string();
+            let mut post: PostMessage = match serde_json::from_value(item) {
+                Ok(post) => post,
+                Err(e) => {
+                    results.push(BatchPostResult::rejected(
+                        uuid,
+                        StatusCode::BadRequest,
+                        e.to_string(),
+                    ));
+                    continue;
+                }
+            };
+            let uuid = match MessageId::parse(uuid.as_str(), state.id_scheme) {
+                Ok(id) => id.into_inner(),
+                Err(e) => {
+                    results.push(BatchPostResult::rejected(uuid, StatusCode::BadRequest, e));
+                    continue;
+                }
+            };
+            post.uuid = uuid.clone();
+            post.parentUuid = post
+                .parentUuid
+                .map(|parent_uuid| state.id_scheme.normalize(parent_uuid));
+            // single posts are forwarded to the owner, a batch only takes this node's messages
+            if let Some(router) = state.shard_router.as_ref().filter(|r| !r.is_local(&uuid)) {
+                let error = format!("Owned by {}, post it there.", router.owner(&uuid));
+                results.push(BatchPostResult::rejected(
+                    uuid,
+                    StatusCode::BadRequest,
+                    error,
+                ));
+                continue;
+            }
+            if let Some(remaining) = tombstones.remaining(&uuid) {
+                let secs = remaining.as_secs() + 1;
+                let error = format!("Deleted recently, can be reused in {secs} seconds.");
+                results.push(BatchPostResult::rejected(uuid, StatusCode::Conflict, error));
+                continue;
+            }
+            // parents may be posted earlier in the batch
+            if post
+                .parentUuid
+                .as_ref()
+                .is_some_and(|parent_uuid| !all_uuids.contains(parent_uuid))
+            {
+                results.push(BatchPostResult::rejected(
+                    uuid,
+                    StatusCode::BadRequest,
+                    "The parent message doesn't exist.",
+                ));
+                continue;
+            }
+            // also catches uuids repeated within the batch
+            if !all_uuids.insert(uuid.clone()) {
+                results.push(BatchPostResult::rejected(
+                    uuid,
+                    StatusCode::Conflict,
+                    "A message with this uuid exists.",
+                ));
+                continue;
+            }
+            accepted.push((results.len(), post));
+            results.push(BatchPostResult {
+                uuid,
+                status: StatusCode::Created.code(),
+                error: None,
+                serverTimestamp: None,
+            });
+        }
+    }
+
+    let server_timestamp = timestamp_now();
+    let mut rows = Vec::with_capacity(accepted.len());
+    let mut failed = Vec::new();
+    // the uuids whose image was saved, removed again if the insert fails
+    let mut saved_images = Vec::new();
+    for (i, post) in accepted {
+        if post.imageUpdate && !post.image.is_empty() {
+            if let Err(e) = state.images.save(&post.uuid, &post.image).await {
+                let (status, reason) = image_error(&e);
+                results[i] = BatchPostResult::rejected(post.uuid.clone(), status, reason);
+                failed.push(post.uuid);
+                continue;
+            }
+            saved_images.push(post.uuid.clone());
+        } else {
+            state.images.remove(&post.uuid).await.ok();
         }
+        results[i].serverTimestamp = Some(server_timestamp);
+        rows.push(Message {
+            uuid: post.uuid,
+            author: post.author,
+            message: post.message,
+            parent_uuid: post.parentUuid,
+            likes: post.likes,
+            has_image: post.imageUpdate,
+            client_timestamp: post.clientTimestamp,
+            server_timestamp,
+            created_at: server_timestamp,
+            updated_at: server_timestamp,
+            deleted_at: None,
+            revision: 1,
+            reactions: Reactions::default(),
+        });
+    }
+
+    let inserted = match rows.is_empty() {
+        true => Ok(()),
+        false => state.messages.insert_many(&rows).await,
+    };
+    if let Err(e) = &inserted {
+        eprintln!("Failed to insert the batch: {}", e);
+        // no message owns them, and the blobs they share with others are released
+        for uuid in &saved_images {
+            if let Err(e) = state.images.remove(uuid).await {
+                eprintln!("Failed to remove the image of {}: {}", uuid, e);
+            }
+        }
+        failed.extend(rows.into_iter().map(|row| row.uuid));
+    } else if !rows.is_empty() {
+        state.outbox_notify.notify_one();
+    }
+    if !failed.is_empty() {
+        let mut all_uuids = state.all_uuids.lock().await;
+        for uuid in &failed {
+            all_uuids.remove(uuid);
+        }
+    }
+    if inserted.is_err() {
+        return Response::new()
+            .status(StatusCode::InternalServerError)
+            .header("Content-Type", CONTENT_TYPE_TEXT)
+            .body("Failed to store the messages, none was posted.");
     }
 
-    response.to_string()
+    let body = serde_json::to_string(&results).unwrap();
+    Response::new()
+        .header("Content-Type", CONTENT_TYPE_JSON)
+        .body(body)
 }
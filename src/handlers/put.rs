@@ -1,7 +1,14 @@
-use crate::{app_state::AppState, image, mutation_manager::ServerPutUpdate, response::Response};
+use crate::{
+    app_state::AppState,
+    models::{timestamp_now, MessageId, SERVER_TIMESTAMP_HEADER},
+    repository::{MessageUpdate, UpdateOutcome},
+    response::{Response, StatusCode, CONTENT_TYPE_JSON, CONTENT_TYPE_TEXT},
+};
 use serde::{Deserialize, Serialize};
 use std::sync::Arc;
 
+use super::{image_error, replies::parent_error};
+
 #[derive(Deserialize, Serialize, Default, Debug)]
 pub struct PutMessage {
     pub author: String,
@@ -9,100 +16,364 @@ pub struct PutMessage {
     pub likes: i32,
     pub imageUpdate: bool,
     pub image: String,
+    /// When the update was written by the client's clock, in milliseconds since the epoch.
+    #[serde(default)]
+    pub clientTimestamp: Option<i64>,
+    /// The message it replies to, left out to make it start a thread.
+    #[serde(default)]
+    pub parentUuid: Option<String>,
+}
+
+/// The `ETag` of a message at `revision`, which `If-Match` takes back.
+pub(crate) fn revision_etag(revision: i64) -> String {
+    format!("\"{revision}\"")
+}
+
+/// The revision an `If-Match` header requires, quoted like an `ETag` or not. `Ok(None)` if any
+/// revision will do, `Err` if no revision matches the header.
+pub(crate) fn expected_revision(if_match: Option<&str>) -> Result<Option<i64>, ()> {
+    match if_match.map(str::trim) {
+        None | Some("*") => Ok(None),
+        Some(tag) => tag.trim_matches('"').parse().map(Some).map_err(|_| ()),
+    }
 }
 
-pub async fn handle_put(uuid: &str, body: &str, state: Arc<AppState>) -> String {
+/// `PUT /api/messages/{uuid}`, replaces the fields of a message. With `If-Match: <revision>`
+/// the message is only replaced if it is still at that revision, and a stale client gets 412
+/// instead of overwriting writes it hasn't seen.
+pub async fn handle_put(
+    uuid: &str,
+    body: &str,
+    if_match: Option<&str>,
+    state: Arc<AppState>,
+) -> Response {
     let mut response = Response::new();
 
     // check for conflicting uuid
     if !state.all_uuids.lock().await.contains(uuid) {
-        return response.status_line("HTTP/1.1 404 NOT FOUND").to_string();
+        return response.status(StatusCode::NotFound);
     }
 
-    let payload: PutMessage = match serde_json::from_str(body) {
+    let Ok(expected_revision) = expected_revision(if_match) else {
+        return response
+            .status(StatusCode::PreconditionFailed)
+            .header("Content-Type", CONTENT_TYPE_TEXT)
+            .body("If-Match must be a revision of the message.");
+    };
+
+    let mut payload: PutMessage = match serde_json::from_str(body) {
         Ok(v) => v,
         Err(e) => {
             return response
-                .status_line("HTTP/1.1 400 BAD REQUEST")
-                .body(&format!("{}", e))
-                .to_string();
+                .status(StatusCode::BadRequest)
+                .header("Content-Type", CONTENT_TYPE_TEXT)
+                .body(format!("{}", e));
         }
     };
 
-    // There are 3 cases for `image_to_client`:
-    // 1. No update to image, meaning the client will not get an image (null or absent in the response)
-    // 2. Update image with new content, meaning the client will get the new image in the response
-    // 3. Remove image, meaning the client will get an `empty` string in the response
-    let mut image_to_client = None;
+    payload.parentUuid = payload
+        .parentUuid
+        .map(|parent_uuid| state.id_scheme.normalize(parent_uuid));
+    if let Some(parent_uuid) = &payload.parentUuid {
+        match parent_error(uuid, parent_uuid, &state).await {
+            Ok(None) => (),
+            Ok(Some(e)) => {
+                return response
+                    .status(StatusCode::BadRequest)
+                    .header("Content-Type", CONTENT_TYPE_TEXT)
+                    .body(e);
+            }
+            Err(e) => {
+                eprintln!("Error checking the parent: {}", e);
+                return response.status(StatusCode::InternalServerError);
+            }
+        }
+    }
 
-    let result = if payload.imageUpdate {
+    let has_image = if payload.imageUpdate {
         if !payload.image.is_empty() {
             // update image
-            if let Err(e) = image::save(&state.image_base_path, &payload.image, uuid) {
-                eprintln!("Error saving image: {}", e);
+            if let Err(e) = state.images.save(uuid, &payload.image).await {
+                let (status, reason) = image_error(&e);
                 return response
-                    .status_line("HTTP/1.1 500 Internal Server Error")
-                    .body("Failed to save image.")
-                    .to_string();
+                    .status(status)
+                    .header("Content-Type", CONTENT_TYPE_TEXT)
+                    .body(reason);
             }
 
-            image_to_client = Some(payload.image);
-            sqlx::query!(
-                "UPDATE messages SET author = $1, message = $2, likes = $3, has_image = $4 WHERE uuid = $5",
-                payload.author,
-                payload.message,
-                payload.likes,
-                true,
-                uuid
-            )
+            Some(true)
         } else {
             // remove image
-            image::remove(&state.image_base_path, uuid).ok();
-            image_to_client = Some("".to_string());
-            sqlx::query!(
-                "UPDATE messages SET author = $1, message = $2, likes = $3, has_image = $4 WHERE uuid = $5",
-                payload.author,
-                payload.message,
-                payload.likes,
-                false,
-                uuid
-            )
+            state.images.remove(uuid).await.ok();
+            Some(false)
         }
     } else {
-        sqlx::query!(
-            "UPDATE messages SET author = $1, message = $2, likes = $3 WHERE uuid = $4",
-            payload.author,
-            payload.message,
-            payload.likes,
-            uuid
-        )
-    }
-    .execute(state.pool.as_ref())
-    .await;
+        None
+    };
+
+    let update = MessageUpdate {
+        author: payload.author,
+        message: payload.message,
+        parent_uuid: payload.parentUuid,
+        likes: payload.likes,
+        has_image,
+        client_timestamp: payload.clientTimestamp,
+        server_timestamp: timestamp_now(),
+        expected_revision,
+    };
+    let result = state.messages.update(uuid, &update).await;
 
     match result {
-        Ok(result) => {
-            if result.rows_affected() == 0 {
-                response.set_status_line("HTTP/1.1 404 Not Found");
-            } else {
-                state.mutations.lock().await.add_put(
+        Ok(UpdateOutcome::Updated { revision }) => {
+            state.outbox_notify.notify_one();
+            response = response
+                .status(StatusCode::NoContent)
+                .header(SERVER_TIMESTAMP_HEADER, update.server_timestamp)
+                .header("ETag", revision_etag(revision));
+        }
+        Ok(UpdateOutcome::NotFound) => response.set_status(StatusCode::NotFound),
+        Ok(UpdateOutcome::Stale { revision }) => {
+            let body = format!("The message was changed, it is at revision {revision}.");
+            response = response
+                .status(StatusCode::PreconditionFailed)
+                .header("ETag", revision_etag(revision))
+                .header("Content-Type", CONTENT_TYPE_TEXT)
+                .body(body);
+        }
+        Err(_) => {
+            response.set_status(StatusCode::InternalServerError);
+        }
+    }
+
+    response
+}
+
+/// The most messages a single batch may update.
+const MAX_PUT_BATCH: usize = 1000;
+
+/// The fields a batch replaces, those of a `PUT` but the image: image files can't be written
+/// in the transaction of the batch.
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+struct BatchPutFields {
+    author: String,
+    message: String,
+    likes: i32,
+    #[serde(default)]
+    clientTimestamp: Option<i64>,
+    #[serde(default)]
+    parentUuid: Option<String>,
+}
+
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+struct BatchPutItem {
+    uuid: String,
+    fields: BatchPutFields,
+    /// The update only applies to this revision, like `If-Match`.
+    #[serde(default)]
+    revision: Option<i64>,
+}
+
+/// The outcome of an update of a batch, in request order.
+#[derive(Serialize)]
+struct BatchPutResult {
+    /// Empty if the item has no uuid.
+    uuid: String,
+    /// The status a single `PUT` of the update is answered with, 409 for the updates that
+    /// weren't applied because another one failed.
+    status: u16,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    error: Option<String>,
+    /// The revision the message was updated to, or is at for a stale update.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    revision: Option<i64>,
+}
+
+impl BatchPutResult {
+    fn rejected(uuid: String, status: StatusCode, error: impl Into<String>) -> Self {
+        Self {
+            uuid,
+            status: status.code(),
+            error: Some(error.into()),
+            revision: None,
+        }
+    }
+
+    fn not_applied(uuid: String) -> Self {
+        let error = "Not applied, another update of the batch failed.";
+        Self::rejected(uuid, StatusCode::Conflict, error)
+    }
+}
+
+/// `PUT /api/messages/batch`, applies a JSON array of `{uuid, fields, revision}` updates in a
+/// single transaction, all of them or none, so sync tools can push many edits at once. The
+/// response reports the outcome of every update, with the status of the batch: 200 if all were
+/// applied, 400 if one is invalid and 409 if one failed.
+pub(crate) async fn handle_put_batch(body: &str, state: Arc<AppState>) -> Response {
+    let items: Vec<serde_json::Value> = match serde_json::from_str(body) {
+        Ok(v) => v,
+        Err(e) => {
+            let body = e.to_string();
+            return Response::new()
+                .status(StatusCode::BadRequest)
+                .header("Content-Type", CONTENT_TYPE_TEXT)
+                .body(body);
+        }
+    };
+    if items.len() > MAX_PUT_BATCH {
+        let body = format!("At most {MAX_PUT_BATCH} messages can be updated at once.");
+        return Response::new()
+            .status(StatusCode::PayloadTooLarge)
+            .header("Content-Type", CONTENT_TYPE_TEXT)
+            .body(body);
+    }
+
+    let server_timestamp = timestamp_now();
+    let mut results = Vec::with_capacity(items.len());
+    let mut updates = Vec::with_capacity(items.len());
+    for item in items {
+        let uuid = item
+            .get("uuid")
+            .and_then(|uuid| uuid.as_str())
+            .unwrap_or_default()
+            .to_string();
+        let mut item: BatchPutItem = match serde_json::from_value(item) {
+            Ok(item) => item,
+            Err(e) => {
+                let error = e.to_string();
+                results.push(Some(BatchPutResult::rejected(
+                    uuid,
+                    StatusCode::BadRequest,
+                    error,
+                )));
+                continue;
+            }
+        };
+        let uuid = match MessageId::parse(uuid.as_str(), state.id_scheme) {
+            Ok(id) => id.into_inner(),
+            Err(e) => {
+                results.push(Some(BatchPutResult::rejected(
                     uuid,
-                    ServerPutUpdate {
-                        author: payload.author,
-                        message: payload.message,
-                        likes: payload.likes,
-                        image: image_to_client,
-                        image_updated: payload.imageUpdate,
-                    },
-                    &state.image_base_path,
-                );
-                response.set_status_line("HTTP/1.1 204 No Content");
+                    StatusCode::BadRequest,
+                    e,
+                )));
+                continue;
             }
+        };
+        item.uuid = uuid.clone();
+        item.fields.parentUuid = item
+            .fields
+            .parentUuid
+            .map(|parent_uuid| state.id_scheme.normalize(parent_uuid));
+        // single puts are forwarded to the owner, a batch only takes this node's messages
+        if let Some(router) = state.shard_router.as_ref().filter(|r| !r.is_local(&uuid)) {
+            let error = format!("Owned by {}, update it there.", router.owner(&uuid));
+            results.push(Some(BatchPutResult::rejected(
+                uuid,
+                StatusCode::BadRequest,
+                error,
+            )));
+            continue;
         }
-        Err(_) => {
-            response.set_status_line("HTTP/1.1 500 Internal Server Error");
+        if let Some(parent_uuid) = &item.fields.parentUuid {
+            match parent_error(&uuid, parent_uuid, &state).await {
+                Ok(None) => (),
+                Ok(Some(e)) => {
+                    results.push(Some(BatchPutResult::rejected(
+                        uuid,
+                        StatusCode::BadRequest,
+                        e,
+                    )));
+                    continue;
+                }
+                Err(e) => {
+                    eprintln!("Error checking the parent: {}", e);
+                    return Response::new().status(StatusCode::InternalServerError);
+                }
+            }
         }
+        results.push(None);
+        let update = MessageUpdate {
+            author: item.fields.author,
+            message: item.fields.message,
+            parent_uuid: item.fields.parentUuid,
+            likes: item.fields.likes,
+            has_image: None,
+            client_timestamp: item.fields.clientTimestamp,
+            server_timestamp,
+            expected_revision: item.revision,
+        };
+        updates.push((item.uuid, update));
+    }
+
+    // nothing is applied if an update is invalid
+    if results.iter().any(Option::is_some) {
+        let mut updates = updates.into_iter();
+        let results: Vec<_> = results
+            .into_iter()
+            .map(|result| {
+                result.unwrap_or_else(|| {
+                    let (uuid, _) = updates.next().expect("an update per valid item");
+                    BatchPutResult::not_applied(uuid)
+                })
+            })
+            .collect();
+        let body = serde_json::to_string(&results).unwrap();
+        return Response::new()
+            .status(StatusCode::BadRequest)
+            .header("Content-Type", CONTENT_TYPE_JSON)
+            .body(body);
     }
 
-    response.to_string()
+    let outcomes = match state.messages.update_many(&updates).await {
+        Ok(outcomes) => outcomes,
+        Err(e) => {
+            eprintln!("Failed to update the batch: {}", e);
+            return Response::new()
+                .status(StatusCode::InternalServerError)
+                .header("Content-Type", CONTENT_TYPE_TEXT)
+                .body("Failed to store the updates, none was applied.");
+        }
+    };
+    let applied = outcomes
+        .iter()
+        .all(|outcome| matches!(outcome, UpdateOutcome::Updated { .. }));
+    let results: Vec<_> = updates
+        .into_iter()
+        .zip(outcomes)
+        .map(|((uuid, _), outcome)| match outcome {
+            UpdateOutcome::Updated { revision } if applied => BatchPutResult {
+                uuid,
+                status: StatusCode::NoContent.code(),
+                error: None,
+                revision: Some(revision),
+            },
+            UpdateOutcome::Updated { .. } => BatchPutResult::not_applied(uuid),
+            UpdateOutcome::NotFound => {
+                BatchPutResult::rejected(uuid, StatusCode::NotFound, "No message with this uuid.")
+            }
+            UpdateOutcome::Stale { revision } => BatchPutResult {
+                revision: Some(revision),
+                ..BatchPutResult::rejected(
+                    uuid,
+                    StatusCode::PreconditionFailed,
+                    "The message was changed since the revision.",
+                )
+            },
+        })
+        .collect();
+
+    let body = serde_json::to_string(&results).unwrap();
+    if !applied {
+        return Response::new()
+            .status(StatusCode::Conflict)
+            .header("Content-Type", CONTENT_TYPE_JSON)
+            .body(body);
+    }
+    state.outbox_notify.notify_one();
+    Response::new()
+        .header(SERVER_TIMESTAMP_HEADER, server_timestamp)
+        .header("Content-Type", CONTENT_TYPE_JSON)
+        .body(body)
 }
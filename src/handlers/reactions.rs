@@ -0,0 +1,75 @@
+use std::sync::Arc;
+
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    app_state::AppState,
+    models::{timestamp_now, Reactions, SERVER_TIMESTAMP_HEADER},
+    response::{Response, StatusCode, CONTENT_TYPE_JSON, CONTENT_TYPE_TEXT},
+};
+
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+struct ReactRequest {
+    kind: String,
+}
+
+#[derive(Serialize)]
+struct ReactionsResponse {
+    reactions: Reactions,
+}
+
+/// `POST /api/messages/{uuid}/react` and `/unreact`, adds `delta` to the count of the reaction
+/// `{"kind": ...}` of a message atomically, like a like, and answers with all its reactions.
+/// The kind must be one of `REACTION_KINDS`.
+pub(crate) async fn handle_react(
+    uuid: &str,
+    body: &str,
+    delta: i32,
+    state: Arc<AppState>,
+) -> Response {
+    let kind = match serde_json::from_str::<ReactRequest>(body) {
+        Ok(request) if state.reaction_kinds.contains(&request.kind) => request.kind,
+        Ok(_) => {
+            let body = format!(
+                "Unknown reaction kind, expected one of {}.",
+                state.reaction_kinds.join(", ")
+            );
+            return Response::new()
+                .status(StatusCode::BadRequest)
+                .header("Content-Type", CONTENT_TYPE_TEXT)
+                .body(body);
+        }
+        Err(e) => {
+            return Response::new()
+                .status(StatusCode::BadRequest)
+                .header("Content-Type", CONTENT_TYPE_TEXT)
+                .body(e.to_string());
+        }
+    };
+
+    if !state.all_uuids.lock().await.contains(uuid) {
+        return Response::new().status(StatusCode::NotFound);
+    }
+
+    let server_timestamp = timestamp_now();
+    match state
+        .messages
+        .add_reaction(uuid, &kind, delta, server_timestamp)
+        .await
+    {
+        Ok(Some(reactions)) => {
+            state.outbox_notify.notify_one();
+            let body = serde_json::to_string(&ReactionsResponse { reactions }).unwrap();
+            Response::new()
+                .header(SERVER_TIMESTAMP_HEADER, server_timestamp)
+                .header("Content-Type", CONTENT_TYPE_JSON)
+                .body(body)
+        }
+        Ok(None) => Response::new().status(StatusCode::NotFound),
+        Err(e) => {
+            eprintln!("Failed to update reactions: {}", e);
+            Response::new().status(StatusCode::InternalServerError)
+        }
+    }
+}
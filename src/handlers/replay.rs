@@ -0,0 +1,39 @@
+use std::sync::Arc;
+
+use crate::{
+    app_state::AppState,
+    outbox::relay_locked,
+    response::{Response, StatusCode, CONTENT_TYPE_JSON},
+};
+
+/// `POST /admin/mutations/replay`, enqueues every message as a post for the next cache
+/// pagination, so a new client can bootstrap through delta sync instead of a fresh pagination.
+pub(crate) async fn handle_replay(state: Arc<AppState>) -> Response {
+    // the replayed posts must come after every committed change, and before those committed
+    // after the snapshot, which are only relayed once the lock is released
+    let mut mutations = state.mutations.lock().await;
+    if let Err(e) = relay_locked(&state, &mut mutations).await {
+        eprintln!("Failed to relay the outbox: {}", e);
+        return Response::new().status(StatusCode::InternalServerError);
+    }
+
+    let messages = match state.messages.all().await {
+        Ok(messages) => messages,
+        Err(e) => {
+            eprintln!("Failed to fetch messages: {}", e);
+            return Response::new().status(StatusCode::InternalServerError);
+        }
+    };
+
+    // the posts get payloads of the next epoch, the running sessions keep reading
+    // theirs
+    let replayed = messages.len();
+    if let Err(e) = mutations.replay_posts(messages).await {
+        eprintln!("Failed to replay the messages: {}", e);
+        return Response::new().status(StatusCode::InternalServerError);
+    }
+
+    Response::new()
+        .header("Content-Type", CONTENT_TYPE_JSON)
+        .body(format!("{{\"replayed\":{replayed}}}"))
+}
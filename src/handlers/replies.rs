@@ -0,0 +1,96 @@
+use std::sync::Arc;
+
+use ahash::AHashSet;
+use serde::Serialize;
+#[cfg(feature = "bindings")]
+use ts_rs::TS;
+
+use crate::{
+    app_state::AppState,
+    repository::RepositoryResult,
+    response::{Response, StatusCode},
+};
+
+use super::{get::parse_page, CompleteMessage, PageFormat};
+
+/// A page of the replies to a message, in uuid order.
+#[derive(Serialize)]
+#[cfg_attr(feature = "bindings", derive(TS), ts(export))]
+pub struct Replies {
+    pub page_number: usize,
+    /// How many replies the message has, on all pages.
+    pub total: usize,
+    pub messages: Vec<CompleteMessage>,
+}
+
+/// Why `uuid` can't reply to `parent_uuid`, if it can't. The parent must be a message of this
+/// node, and neither `uuid` nor one of its replies, which would make a thread without a start.
+pub(crate) async fn parent_error(
+    uuid: &str,
+    parent_uuid: &str,
+    state: &AppState,
+) -> RepositoryResult<Option<&'static str>> {
+    if !state.all_uuids.lock().await.contains(parent_uuid) {
+        return Ok(Some("The parent message doesn't exist."));
+    }
+
+    // walks up the thread of the parent
+    let mut seen = AHashSet::new();
+    let mut ancestor = Some(parent_uuid.to_string());
+    while let Some(current) = ancestor {
+        if current == uuid {
+            return Ok(Some("A message can't reply to itself or to its replies."));
+        }
+        if !seen.insert(current.clone()) {
+            break;
+        }
+        ancestor = state
+            .messages
+            .get(&current)
+            .await?
+            .and_then(|message| message.parent_uuid);
+    }
+    Ok(None)
+}
+
+/// `GET /api/messages/{uuid}/replies?page=<n>&size=<size>`, serves a page of the direct replies
+/// to a message, so clients can unfold a thread level by level. `page` defaults to the first and
+/// `size` to the configured page size.
+pub(crate) async fn handle_replies(
+    uuid: &str,
+    page: Option<&str>,
+    size: Option<&str>,
+    format: PageFormat,
+    state: Arc<AppState>,
+) -> Response {
+    let page = match parse_page(page, size, state.pagination_page_size) {
+        Ok(page) => page,
+        Err(response) => return response,
+    };
+
+    if !state.all_uuids.lock().await.contains(uuid) {
+        return Response::new().status(StatusCode::NotFound);
+    }
+
+    let (total, rows) = match state.messages.replies(uuid, page.size, page.offset).await {
+        Ok(replies) => replies,
+        Err(e) => {
+            eprintln!("Failed to fetch replies: {}", e);
+            return Response::new().status(StatusCode::InternalServerError);
+        }
+    };
+
+    let messages = CompleteMessage::with_images(rows, &*state.images).await;
+    let replies = Replies {
+        page_number: page.number,
+        total,
+        messages,
+    };
+    let mut body = Vec::new();
+    format.serialize_into(&mut body, &replies);
+
+    Response::new()
+        .header("Content-Type", format.content_type())
+        .header("Vary", "Accept")
+        .body_bytes(body)
+}
@@ -0,0 +1,66 @@
+use std::sync::Arc;
+
+use serde::Serialize;
+#[cfg(feature = "bindings")]
+use ts_rs::TS;
+
+use crate::{
+    app_state::AppState,
+    response::{Response, StatusCode, CONTENT_TYPE_TEXT},
+};
+
+use super::{CompleteMessage, PageFormat};
+
+/// How many messages a sample has unless `n` says otherwise.
+const DEFAULT_SAMPLE_SIZE: usize = 100;
+
+/// The largest sample a client may ask for, picking one scans the whole table.
+const MAX_SAMPLE_SIZE: usize = 1000;
+
+/// Messages picked at random.
+#[derive(Serialize)]
+#[cfg_attr(feature = "bindings", derive(TS), ts(export))]
+pub struct Sample {
+    /// Fewer than asked for when there aren't enough messages.
+    pub messages: Vec<CompleteMessage>,
+}
+
+/// `GET /api/messages/sample?n=<n>`, serves `n` messages picked at random, 100 by default, so
+/// their integrity can be spot-checked without paginating through all of them.
+pub(crate) async fn handle_sample(
+    n: Option<&str>,
+    format: PageFormat,
+    state: Arc<AppState>,
+) -> Response {
+    let n = match n {
+        Some(n) => n
+            .parse::<usize>()
+            .ok()
+            .filter(|n| (1..=MAX_SAMPLE_SIZE).contains(n)),
+        None => Some(DEFAULT_SAMPLE_SIZE),
+    };
+    let Some(n) = n else {
+        let body = format!("n must be between 1 and {MAX_SAMPLE_SIZE}.");
+        return Response::new()
+            .status(StatusCode::BadRequest)
+            .header("Content-Type", CONTENT_TYPE_TEXT)
+            .body(body);
+    };
+
+    let rows = match state.messages.sample(n).await {
+        Ok(rows) => rows,
+        Err(e) => {
+            eprintln!("Failed to sample messages: {}", e);
+            return Response::new().status(StatusCode::InternalServerError);
+        }
+    };
+
+    let messages = CompleteMessage::with_images(rows, &*state.images).await;
+    let mut body = Vec::new();
+    format.serialize_into(&mut body, &Sample { messages });
+
+    Response::new()
+        .header("Content-Type", format.content_type())
+        .header("Vary", "Accept")
+        .body_bytes(body)
+}
@@ -0,0 +1,67 @@
+use std::sync::Arc;
+
+use serde::Serialize;
+#[cfg(feature = "bindings")]
+use ts_rs::TS;
+
+use crate::{
+    app_state::AppState,
+    response::{Response, StatusCode, CONTENT_TYPE_TEXT},
+};
+
+use super::{get::parse_page, CompleteMessage, PageFormat};
+
+/// A page of search results, most relevant first.
+#[derive(Serialize)]
+#[cfg_attr(feature = "bindings", derive(TS), ts(export))]
+pub struct SearchResults {
+    pub page_number: usize,
+    /// How many messages match, on all pages.
+    pub total: usize,
+    pub messages: Vec<CompleteMessage>,
+}
+
+/// `GET /api/messages/search?q=<query>&page=<n>&size=<size>`, serves a page of the messages
+/// whose author or text match `q`, ranked by relevance. `page` defaults to the first and `size`
+/// to the configured page size.
+pub(crate) async fn handle_search(
+    query: Option<&str>,
+    page: Option<&str>,
+    size: Option<&str>,
+    format: PageFormat,
+    state: Arc<AppState>,
+) -> Response {
+    let query = query.map(str::trim).filter(|query| !query.is_empty());
+    let Some(query) = query else {
+        return Response::new()
+            .status(StatusCode::BadRequest)
+            .header("Content-Type", CONTENT_TYPE_TEXT)
+            .body("q must not be empty.");
+    };
+    let page = match parse_page(page, size, state.pagination_page_size) {
+        Ok(page) => page,
+        Err(response) => return response,
+    };
+
+    let (total, rows) = match state.messages.search(query, page.size, page.offset).await {
+        Ok(results) => results,
+        Err(e) => {
+            eprintln!("Failed to search messages: {}", e);
+            return Response::new().status(StatusCode::InternalServerError);
+        }
+    };
+
+    let messages = CompleteMessage::with_images(rows, &*state.images).await;
+    let results = SearchResults {
+        page_number: page.number,
+        total,
+        messages,
+    };
+    let mut body = Vec::new();
+    format.serialize_into(&mut body, &results);
+
+    Response::new()
+        .header("Content-Type", format.content_type())
+        .header("Vary", "Accept")
+        .body_bytes(body)
+}
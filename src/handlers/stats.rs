@@ -0,0 +1,57 @@
+use std::sync::Arc;
+
+use serde::Serialize;
+#[cfg(feature = "bindings")]
+use ts_rs::TS;
+
+use crate::{
+    app_state::AppState,
+    response::{Response, StatusCode},
+};
+
+use super::PageFormat;
+
+/// The `MessageStats` of the messages followed by the disk usage of their images.
+#[derive(Serialize)]
+#[cfg_attr(feature = "bindings", derive(TS), ts(export))]
+pub struct Stats {
+    pub total: usize,
+    pub total_likes: i64,
+    /// How many messages have an image.
+    pub with_image: usize,
+    pub distinct_authors: usize,
+    /// The bytes the images take on disk, identical images counting once.
+    pub image_bytes: u64,
+    /// The bytes the images may take, `None` if they are only limited by the disk.
+    pub image_quota: Option<u64>,
+}
+
+/// `GET /api/messages/stats`, serves the number of messages, their likes, how many have an
+/// image, how many authors wrote them and how much space the images take, as `Stats` in the
+/// negotiated format, so dashboards don't page through every message to compute them.
+pub(crate) async fn handle_stats(format: PageFormat, state: Arc<AppState>) -> Response {
+    let stats = match state.messages.stats().await {
+        Ok(stats) => {
+            let usage = state.images.usage();
+            Stats {
+                total: stats.total,
+                total_likes: stats.total_likes,
+                with_image: stats.with_image,
+                distinct_authors: stats.distinct_authors,
+                image_bytes: usage.bytes,
+                image_quota: usage.quota,
+            }
+        }
+        Err(e) => {
+            eprintln!("Failed to aggregate messages: {}", e);
+            return Response::new().status(StatusCode::InternalServerError);
+        }
+    };
+
+    let mut body = Vec::new();
+    format.serialize_into(&mut body, &stats);
+    Response::new()
+        .header("Content-Type", format.content_type())
+        .header("Vary", "Accept")
+        .body_bytes(body)
+}
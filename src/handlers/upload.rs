@@ -0,0 +1,144 @@
+use crate::{
+    app_state::AppState,
+    models::timestamp_now,
+    repository::MessageUpdate,
+    response::{Response, StatusCode, CONTENT_TYPE_JSON, CONTENT_TYPE_TEXT},
+    uploads::{ContentRange, UploadError, UploadSession},
+};
+use std::sync::Arc;
+
+fn error_response(e: UploadError) -> Response {
+    let body = e.to_string();
+    // tell the client where to resume from
+    let range = match e {
+        UploadError::RangeMismatch { received } => Some(received_range(received)),
+        _ => None,
+    };
+    let mut response = Response::new()
+        .status(e.status())
+        .header("Content-Type", CONTENT_TYPE_TEXT);
+    if let Some(range) = range {
+        response = response.header("Range", range);
+    }
+    response.body(body)
+}
+
+/// The value of the `Range` header telling the client how many bytes were received.
+fn received_range(received: usize) -> String {
+    match received {
+        0 => "bytes=0-0".to_string(),
+        received => format!("bytes=0-{}", received - 1),
+    }
+}
+
+fn progress_response(status: StatusCode, session: &UploadSession) -> Response {
+    let body = serde_json::to_string(session).unwrap();
+    Response::new()
+        .status(status)
+        .header("Content-Type", CONTENT_TYPE_JSON)
+        .header("Range", received_range(session.received))
+        .body(body)
+}
+
+/// `POST /api/messages/{uuid}/image/uploads`, creates an upload session.
+pub(crate) async fn handle_create_upload(uuid: &str, state: Arc<AppState>) -> Response {
+    if !state.all_uuids.lock().await.contains(uuid) {
+        return Response::new().status(StatusCode::NotFound);
+    }
+
+    match state.uploads.lock().await.create(uuid) {
+        Ok(upload_id) => Response::new()
+            .status(StatusCode::Created)
+            .header(
+                "Location",
+                format!("/api/messages/{uuid}/image/uploads/{upload_id}"),
+            )
+            .header("Content-Type", CONTENT_TYPE_TEXT)
+            .body(upload_id),
+        Err(e) => error_response(e.into()),
+    }
+}
+
+/// `GET /api/messages/{uuid}/image/uploads/{upload_id}`, reports how many bytes were received,
+/// so an interrupted client knows where to resume.
+pub(crate) async fn handle_upload_progress(
+    uuid: &str,
+    upload_id: &str,
+    state: Arc<AppState>,
+) -> Response {
+    match state.uploads.lock().await.progress(upload_id, uuid) {
+        Ok(session) => progress_response(StatusCode::Ok, session),
+        Err(e) => error_response(e),
+    }
+}
+
+/// `PUT /api/messages/{uuid}/image/uploads/{upload_id}` with a `Content-Range`, appends a chunk.
+pub(crate) async fn handle_upload_chunk(
+    uuid: &str,
+    upload_id: &str,
+    content_range: Option<&str>,
+    chunk: &str,
+    state: Arc<AppState>,
+) -> Response {
+    let range = match content_range.and_then(ContentRange::parse) {
+        Some(range) => range,
+        None => return error_response(UploadError::InvalidRange),
+    };
+
+    match state
+        .uploads
+        .lock()
+        .await
+        .append(upload_id, uuid, range, chunk.as_bytes())
+    {
+        Ok(session) => progress_response(StatusCode::Ok, session),
+        Err(e) => error_response(e),
+    }
+}
+
+/// `POST /api/messages/{uuid}/image/uploads/{upload_id}/commit`, swaps the message's image for
+/// the uploaded one.
+pub(crate) async fn handle_commit_upload(
+    uuid: &str,
+    upload_id: &str,
+    state: Arc<AppState>,
+) -> Response {
+    let message = match state.messages.get(uuid).await {
+        Ok(Some(message)) => message,
+        Ok(None) => return Response::new().status(StatusCode::NotFound),
+        Err(e) => {
+            eprintln!("Error fetching message: {}", e);
+            return Response::new().status(StatusCode::InternalServerError);
+        }
+    };
+
+    if let Err(e) = state
+        .uploads
+        .lock()
+        .await
+        .commit(upload_id, uuid, state.images.as_ref())
+        .await
+    {
+        return error_response(e);
+    }
+
+    let update = MessageUpdate {
+        author: message.author,
+        message: message.message,
+        parent_uuid: message.parent_uuid,
+        likes: message.likes,
+        has_image: Some(true),
+        // the upload only completes the image of the client's last write
+        client_timestamp: message.client_timestamp,
+        server_timestamp: timestamp_now(),
+        expected_revision: None,
+    };
+    if let Err(e) = state.messages.update(uuid, &update).await {
+        eprintln!("Error updating message: {}", e);
+        return Response::new().status(StatusCode::InternalServerError);
+    }
+
+    state.outbox_notify.notify_one();
+
+    Response::new().status(StatusCode::NoContent)
+}
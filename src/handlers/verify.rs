@@ -0,0 +1,310 @@
+use std::{
+    collections::hash_map::DefaultHasher,
+    hash::{Hash, Hasher},
+    sync::Arc,
+};
+
+use ahash::AHashMap;
+use serde::Serialize;
+
+use crate::{
+    app_state::{pagination::PaginationMode, AppState},
+    handlers::{PageFormat, PaginationMetadata, PaginationType},
+    models::Message,
+    mutation_manager::PendingMutation,
+    repository::{PageStart, PageView},
+    response::{Response, StatusCode, CONTENT_TYPE_JSON},
+};
+
+use super::{
+    get::{fresh_page_response, DbResults},
+    CompleteMessage,
+};
+
+#[derive(Serialize, Default)]
+struct FreshReport {
+    pages: usize,
+    received: usize,
+    /// In the database but on no page.
+    missing: Vec<String>,
+    /// On more than one page.
+    duplicates: Vec<String>,
+    /// On a page but not in the database.
+    unexpected: Vec<String>,
+    /// On a page with other contents than in the database.
+    mismatched: Vec<String>,
+}
+
+#[derive(Serialize, Default)]
+struct CacheReport {
+    mutations: usize,
+    /// Posted or put but not in the database.
+    missing: Vec<String>,
+    /// Posted or put with other contents than in the database.
+    mismatched: Vec<String>,
+    /// Deleted but still in the database.
+    undeleted: Vec<String>,
+    /// Changed in the database since, the change is still to be relayed from the outbox.
+    unrelayed: Vec<String>,
+}
+
+#[derive(Serialize)]
+struct VerifyReport {
+    consistent: bool,
+    messages: usize,
+    fresh: FreshReport,
+    cache: CacheReport,
+}
+
+/// `POST /admin/verify-pagination`, goes through the pages a fresh pagination serves and the
+/// mutations the next cache pagination serves, and compares what a client receives against a
+/// scan of the database. The pages are served like to clients, in bincode and starting where
+/// the pagination mode starts them, but neither pagination moves forward and the pending
+/// mutations stay queued.
+///
+/// A write landing during the pass may be reported as a mismatch, run it again to tell those
+/// apart.
+pub(crate) async fn handle_verify_pagination(state: Arc<AppState>) -> Response {
+    let messages = match state.messages.all().await {
+        Ok(messages) => messages,
+        Err(e) => {
+            eprintln!("Failed to fetch messages: {}", e);
+            return Response::new().status(StatusCode::InternalServerError);
+        }
+    };
+    let scanned: AHashMap<&str, &Message> = messages
+        .iter()
+        .map(|message| (message.uuid.as_str(), message))
+        .collect();
+
+    let fresh = match verify_fresh(&state, &scanned).await {
+        Ok(fresh) => fresh,
+        Err(e) => {
+            eprintln!("Failed to fetch the fresh pages: {}", e);
+            return Response::new().status(StatusCode::InternalServerError);
+        }
+    };
+
+    let pending = state.mutations.lock().await.pending();
+    let pending = match pending {
+        Ok(pending) => pending.read().await,
+        Err(e) => Err(e),
+    };
+    let pending = match pending {
+        Ok(pending) => pending,
+        Err(e) => {
+            eprintln!("Failed to read the cached mutations: {}", e);
+            return Response::new().status(StatusCode::InternalServerError);
+        }
+    };
+    let cache = verify_cache(&pending, &scanned);
+
+    let consistent = [
+        &fresh.missing,
+        &fresh.duplicates,
+        &fresh.unexpected,
+        &fresh.mismatched,
+        &cache.missing,
+        &cache.mismatched,
+        &cache.undeleted,
+    ]
+    .iter()
+    .all(|uuids| uuids.is_empty());
+    let report = VerifyReport {
+        consistent,
+        messages: messages.len(),
+        fresh,
+        cache,
+    };
+    let body = serde_json::to_string(&report).unwrap();
+    Response::new()
+        .header("Content-Type", CONTENT_TYPE_JSON)
+        .body(body)
+}
+
+/// Fetches every page of a fresh pagination, as many as its metadata announces, through the
+/// handler serving them.
+///
+/// # Errors
+///
+/// This function will return an error if a page couldn't be served or decoded.
+async fn verify_fresh(
+    state: &AppState,
+    scanned: &AHashMap<&str, &Message>,
+) -> Result<FreshReport, String> {
+    let count = state.all_uuids.lock().await.len();
+    let page_size = state.pagination_page_size;
+    let pages = PaginationMetadata::new(count, page_size, PaginationType::Fresh).total_pages();
+
+    let mut report = FreshReport {
+        pages,
+        ..Default::default()
+    };
+    let mut received: AHashMap<String, u64> = AHashMap::with_capacity(count);
+    let mut after = None;
+    for page_number in 1..=pages {
+        let start = match state.pagination_mode {
+            PaginationMode::Offset => PageStart::Offset((page_number - 1) * page_size),
+            PaginationMode::Keyset => PageStart::After(after.take()),
+        };
+        let (response, next) = fresh_page_response(
+            state,
+            &PageView::default(),
+            start,
+            page_size,
+            page_number,
+            false,
+            PageFormat::Bincode,
+        )
+        .await;
+        if response.status_code() != StatusCode::Ok {
+            return Err(format!(
+                "page {page_number} was served with {}",
+                response.status_code()
+            ));
+        }
+        let body = response.chunks().concat();
+        let page: DbResults = bincode::deserialize(&body)
+            .map_err(|e| format!("page {page_number} couldn't be decoded: {e}"))?;
+        after = next;
+        for message in page.messages {
+            report.received += 1;
+            let digest = served_digest(&message);
+            if received.insert(message.uuid.clone(), digest).is_some() {
+                report.duplicates.push(message.uuid);
+            }
+        }
+    }
+
+    for (uuid, received_digest) in &received {
+        match scanned.get(uuid.as_str()) {
+            None => report.unexpected.push(uuid.clone()),
+            Some(message) if digest(message) != *received_digest => {
+                report.mismatched.push(uuid.clone())
+            }
+            Some(_) => (),
+        }
+    }
+    report.missing = scanned
+        .keys()
+        .filter(|uuid| !received.contains_key(**uuid))
+        .map(|uuid| uuid.to_string())
+        .collect();
+    Ok(report)
+}
+
+/// Compares the pending mutations against the database. A client applies them in order, so only
+/// the last of each message is compared, and only against the revision it carries: a message
+/// changed since is left out, its change is still in the outbox.
+fn verify_cache(pending: &[PendingMutation], scanned: &AHashMap<&str, &Message>) -> CacheReport {
+    let mut report = CacheReport {
+        mutations: pending.len(),
+        ..Default::default()
+    };
+    let mut last: AHashMap<&str, &PendingMutation> = AHashMap::with_capacity(pending.len());
+    for mutation in pending {
+        let uuid = match mutation {
+            PendingMutation::Post(post) => &post.uuid,
+            PendingMutation::Put { uuid, .. } | PendingMutation::Delete(uuid) => uuid,
+        };
+        last.insert(uuid, mutation);
+    }
+    for mutation in last.into_values() {
+        match mutation {
+            PendingMutation::Post(post) => match scanned.get(post.uuid.as_str()) {
+                None => report.missing.push(post.uuid.clone()),
+                Some(message) if message.revision > post.revision => {
+                    report.unrelayed.push(post.uuid.clone())
+                }
+                Some(message) => {
+                    let matches = message.author == post.author
+                        && message.message == post.message
+                        && message.parent_uuid == post.parent_uuid
+                        && message.likes == post.likes
+                        && message.client_timestamp == post.client_timestamp
+                        && message.server_timestamp == post.server_timestamp
+                        && message.created_at == post.created_at
+                        && message.updated_at == post.updated_at
+                        && message.revision == post.revision
+                        && message.reactions == post.reactions;
+                    if !matches {
+                        report.mismatched.push(post.uuid.clone());
+                    }
+                }
+            },
+            PendingMutation::Put { uuid, update } => match scanned.get(uuid.as_str()) {
+                None => report.missing.push(uuid.clone()),
+                Some(message) if message.revision > update.revision => {
+                    report.unrelayed.push(uuid.clone())
+                }
+                Some(message) => {
+                    // a likes-only put carries no text, nor parent
+                    let matches = update.author.as_ref().is_none_or(|a| *a == message.author)
+                        && (update.author.is_none() || update.parent_uuid == message.parent_uuid)
+                        && update
+                            .message
+                            .as_ref()
+                            .is_none_or(|m| *m == message.message)
+                        && message.likes == update.likes
+                        && message.client_timestamp == update.client_timestamp
+                        && message.server_timestamp == update.server_timestamp
+                        && update.updated_at.is_none_or(|u| u == message.updated_at)
+                        && message.revision == update.revision
+                        && update
+                            .reactions
+                            .as_ref()
+                            .is_none_or(|r| *r == message.reactions);
+                    if !matches {
+                        report.mismatched.push(uuid.clone());
+                    }
+                }
+            },
+            PendingMutation::Delete(uuid) => {
+                if scanned.contains_key(uuid.as_str()) {
+                    report.undeleted.push(uuid.clone());
+                }
+            }
+        }
+    }
+    report
+}
+
+/// A digest of what a client receives of `message`, its image aside.
+fn digest(message: &Message) -> u64 {
+    let mut hasher = DefaultHasher::new();
+    (
+        &message.author,
+        &message.message,
+        &message.parent_uuid,
+        message.likes,
+        message.has_image,
+        message.client_timestamp,
+        message.server_timestamp,
+        message.created_at,
+        message.updated_at,
+        message.revision,
+        &message.reactions,
+    )
+        .hash(&mut hasher);
+    hasher.finish()
+}
+
+/// [`digest`] of a served message, which has image metadata when the message has an image.
+fn served_digest(message: &CompleteMessage) -> u64 {
+    let mut hasher = DefaultHasher::new();
+    (
+        &message.author,
+        &message.message,
+        &message.parent_uuid,
+        message.likes,
+        message.image_metadata.is_some(),
+        message.client_timestamp,
+        message.server_timestamp,
+        message.created_at,
+        message.updated_at,
+        message.revision,
+        &message.reactions,
+    )
+        .hash(&mut hasher);
+    hasher.finish()
+}
@@ -0,0 +1,119 @@
+use super::ImageMetadata;
+use ahash::AHashMap;
+use std::{
+    collections::BTreeMap,
+    sync::{Arc, Mutex},
+};
+
+/// The images read last, encoded in base64 as pages carry them and with their metadata, so
+/// repeated paginations don't read and encode the same files again. The least recently used
+/// images are evicted once the cached images are larger than the byte budget, a budget of 0
+/// caches nothing.
+///
+/// The lock is a std one, the cache is used both by handlers and by serializers on the blocking
+/// pool and is never held across an await.
+pub struct ImageCache {
+    budget: usize,
+    entries: Mutex<Entries>,
+}
+
+/// An image as pages carry it.
+pub struct CachedImage {
+    pub base64: String,
+    pub metadata: ImageMetadata,
+}
+
+#[derive(Default)]
+struct Entries {
+    /// The image of each uuid and when it was last used.
+    images: AHashMap<String, (Arc<CachedImage>, u64)>,
+    /// The uuids by when they were last used, the least recently used first.
+    by_use: BTreeMap<u64, String>,
+    next_use: u64,
+    /// The total length of the cached images.
+    bytes: usize,
+    /// Counts the invalidations, an image read before one may be stale and isn't cached.
+    epoch: u64,
+}
+
+impl Entries {
+    fn remove(&mut self, uuid: &str) {
+        if let Some((image, used)) = self.images.remove(uuid) {
+            self.by_use.remove(&used);
+            self.bytes -= image.base64.len();
+        }
+    }
+
+    fn touch(&mut self, uuid: &str) -> Option<Arc<CachedImage>> {
+        let next_use = self.next_use;
+        let (image, used) = self.images.get_mut(uuid)?;
+        let uuid = self.by_use.remove(used).unwrap_or_default();
+        *used = next_use;
+        let image = Arc::clone(image);
+        self.by_use.insert(next_use, uuid);
+        self.next_use += 1;
+        Some(image)
+    }
+}
+
+impl ImageCache {
+    pub fn new(budget: usize) -> Self {
+        Self {
+            budget,
+            entries: Mutex::default(),
+        }
+    }
+
+    /// The cached image of `uuid`, which becomes the most recently used.
+    pub fn get(&self, uuid: &str) -> Option<Arc<CachedImage>> {
+        self.entries.lock().unwrap().touch(uuid)
+    }
+
+    /// The current epoch, to be taken before reading an image that is then inserted.
+    pub fn epoch(&self) -> u64 {
+        self.entries.lock().unwrap().epoch
+    }
+
+    /// Caches the image of `uuid` read at `epoch`, evicting the least recently used images it
+    /// doesn't fit next to. An image larger than the whole budget isn't cached, nor is one read
+    /// before an invalidation, which may have replaced it while it was read.
+    pub fn insert(&self, uuid: &str, image: Arc<CachedImage>, epoch: u64) {
+        if image.base64.len() > self.budget {
+            return;
+        }
+        let mut entries = self.entries.lock().unwrap();
+        if entries.epoch != epoch {
+            return;
+        }
+        entries.remove(uuid);
+        while entries.bytes + image.base64.len() > self.budget {
+            let Some((_, evicted)) = entries.by_use.pop_first() else {
+                break;
+            };
+            if let Some((evicted, _)) = entries.images.remove(&evicted) {
+                entries.bytes -= evicted.base64.len();
+            }
+        }
+        let used = entries.next_use;
+        entries.next_use += 1;
+        entries.bytes += image.base64.len();
+        entries.by_use.insert(used, uuid.to_string());
+        entries.images.insert(uuid.to_string(), (image, used));
+    }
+
+    /// Drops the image of `uuid`, after it was replaced or removed.
+    pub fn invalidate(&self, uuid: &str) {
+        let mut entries = self.entries.lock().unwrap();
+        entries.remove(uuid);
+        entries.epoch += 1;
+    }
+
+    pub fn clear(&self) {
+        let mut entries = self.entries.lock().unwrap();
+        let epoch = entries.epoch + 1;
+        *entries = Entries {
+            epoch,
+            ..Entries::default()
+        };
+    }
+}
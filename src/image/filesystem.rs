@@ -0,0 +1,420 @@
+use super::{
+    cached, decode, file_path, map, metadata, metadata_path, save_metadata, tmp_path,
+    write_replacing, CachedImage, ImageCache, ImageMetadata, ImageStorage, ImageUsage,
+    BINARY_MARKER,
+};
+use ahash::AHashMap;
+use async_trait::async_trait;
+use bytes::Bytes;
+use sha2::{Digest, Sha256};
+use std::{
+    fs::Metadata,
+    io,
+    path::PathBuf,
+    sync::{Arc, Mutex},
+};
+
+/// The directory of the images directory holding one blob per distinct image.
+const BLOBS_DIR: &str = ".blobs";
+
+/// Runs the blocking `f` on the blocking pool, so the file I/O doesn't stall the worker awaiting
+/// it.
+async fn unblock<T, F>(f: F) -> io::Result<T>
+where
+    T: Send + 'static,
+    F: FnOnce() -> io::Result<T> + Send + 'static,
+{
+    tokio::task::spawn_blocking(f)
+        .await
+        .map_err(io::Error::other)?
+}
+
+/// Stores each image decoded in a file of a directory, named after the uuid of its message.
+/// Reads go through the cache, which is shared with the page serializers.
+///
+/// Images are content-addressed, so the many messages sharing e.g. a stock image store it once:
+/// the file of each message is a hard link to a blob of `.blobs` named after the SHA-256 of the
+/// image, which is removed with the last file linking to it. The metadata of each image is
+/// stored next to its file, in `.<uuid>.json`.
+///
+/// With a quota, images that would take the blobs past it aren't saved. Images of at least
+/// `mmap_threshold` bytes are read decoded from a memory-mapped file rather than copied into the
+/// heap.
+pub struct FsImageStorage(Arc<Store>);
+
+struct Store {
+    base_path: PathBuf,
+    cache: Arc<ImageCache>,
+    quota: Option<u64>,
+    mmap_threshold: u64,
+    blobs: Mutex<Blobs>,
+}
+
+/// Which blob each image links to, and how many images link to each blob.
+#[derive(Default)]
+struct Blobs {
+    hashes: AHashMap<String, String>,
+    /// The number of links to each blob, and its size.
+    refs: AHashMap<String, (usize, u64)>,
+    /// The total size of the blobs.
+    bytes: u64,
+}
+
+impl Blobs {
+    fn link(&mut self, uuid: &str, hash: String, size: u64) -> Option<String> {
+        let (refs, _) = self.refs.entry(hash.clone()).or_insert_with(|| {
+            self.bytes += size;
+            (0, size)
+        });
+        *refs += 1;
+        self.hashes.insert(uuid.to_string(), hash)
+    }
+
+    /// Drops a link to the blob of `hash`, returning whether it was the last.
+    fn unlink(&mut self, hash: &str) -> bool {
+        let Some((refs, size)) = self.refs.get_mut(hash) else {
+            return false;
+        };
+        *refs -= 1;
+        if *refs > 0 {
+            return false;
+        }
+        self.bytes -= *size;
+        self.refs.remove(hash);
+        true
+    }
+
+    /// The bytes freed by relinking `uuid` elsewhere, the size of its blob if it is the last
+    /// image linking to it.
+    fn freed_by_relinking(&self, uuid: &str) -> u64 {
+        match self.hashes.get(uuid).and_then(|hash| self.refs.get(hash)) {
+            Some(&(1, size)) => size,
+            _ => 0,
+        }
+    }
+}
+
+impl FsImageStorage {
+    /// Opens the images of `base_path`, e.g. at startup. On unix, the images linking to a blob
+    /// are told by their inode, only those that don't link to one yet, like those stored before
+    /// images were deduplicated, are read and linked to the blob of their content. Elsewhere,
+    /// every image is read and hashed. Blobs no image links to anymore are removed. Images
+    /// without metadata get it, and metadata without an image is removed. Images aren't saved
+    /// once the blobs would take more than `quota` bytes, those already stored are kept even if
+    /// they do.
+    ///
+    /// # Errors
+    ///
+    /// This function will return an error if the directory can't be read or an image can't be
+    /// linked.
+    pub fn open(
+        base_path: PathBuf,
+        cache: Arc<ImageCache>,
+        quota: Option<u64>,
+        mmap_threshold: u64,
+    ) -> io::Result<Self> {
+        let blobs_path = base_path.join(BLOBS_DIR);
+        std::fs::create_dir_all(&blobs_path)?;
+        let store = Store {
+            base_path,
+            cache,
+            quota,
+            mmap_threshold,
+            blobs: Mutex::default(),
+        };
+
+        // the blobs are named after their hash, the images linking to them share their inode
+        let mut blob_inodes = AHashMap::new();
+        for entry in std::fs::read_dir(&blobs_path)? {
+            let entry = entry?;
+            let Ok(hash) = entry.file_name().into_string() else {
+                continue;
+            };
+            if let Some(inode) = inode(&entry.metadata()?) {
+                blob_inodes.insert(inode, hash);
+            }
+        }
+
+        let mut blobs = Blobs::default();
+        for entry in std::fs::read_dir(&store.base_path)? {
+            let entry = entry?;
+            let name = entry.file_name();
+            let Some(uuid) = name.to_str().filter(|name| !name.starts_with('.')) else {
+                continue;
+            };
+            if !entry.file_type()?.is_file() || file_path(&store.base_path, uuid).is_err() {
+                continue;
+            }
+            let file = entry.metadata()?;
+            let has_metadata = metadata_path(&store.base_path, uuid)?.exists();
+            let hash = match inode(&file).and_then(|inode| blob_inodes.get(&inode)) {
+                Some(hash) => {
+                    if !has_metadata {
+                        let image = std::fs::read(entry.path())?;
+                        save_metadata(&store.base_path, uuid, &ImageMetadata::of(&image))?;
+                    }
+                    hash.clone()
+                }
+                None => {
+                    let image = std::fs::read(entry.path())?;
+                    if !has_metadata {
+                        save_metadata(&store.base_path, uuid, &ImageMetadata::of(&image))?;
+                    }
+                    let hash = hash(&image);
+                    let blob = store.blob_path(&hash);
+                    if blob.exists() {
+                        store.link(&hash, uuid)?;
+                    } else {
+                        std::fs::hard_link(entry.path(), blob)?;
+                    }
+                    hash
+                }
+            };
+            blobs.link(uuid, hash, file.len());
+        }
+
+        // left behind by a crash between writing a blob and linking it
+        for entry in std::fs::read_dir(&blobs_path)? {
+            let entry = entry?;
+            let name = entry.file_name();
+            if !name
+                .to_str()
+                .is_some_and(|hash| blobs.refs.contains_key(hash))
+            {
+                std::fs::remove_file(entry.path())?;
+            }
+        }
+
+        // left behind by a crash between removing an image and its metadata
+        for entry in std::fs::read_dir(&store.base_path)? {
+            let entry = entry?;
+            let name = entry.file_name();
+            let Some(uuid) = name
+                .to_str()
+                .and_then(|name| name.strip_prefix('.')?.strip_suffix(".json"))
+            else {
+                continue;
+            };
+            if !blobs.hashes.contains_key(uuid) {
+                std::fs::remove_file(entry.path())?;
+            }
+        }
+
+        *store.blobs.lock().unwrap() = blobs;
+        Ok(Self(Arc::new(store)))
+    }
+}
+
+/// The device and inode of a file, which the links to the same blob share.
+#[cfg(unix)]
+fn inode(metadata: &Metadata) -> Option<(u64, u64)> {
+    use std::os::unix::fs::MetadataExt;
+    Some((metadata.dev(), metadata.ino()))
+}
+
+/// Files can't be told apart by inode, the images are hashed instead.
+#[cfg(not(unix))]
+fn inode(_: &Metadata) -> Option<(u64, u64)> {
+    None
+}
+
+/// The name of the blob of `image`.
+fn hash(image: &[u8]) -> String {
+    hex::encode(Sha256::digest(image))
+}
+
+impl Store {
+    fn blob_path(&self, hash: &str) -> PathBuf {
+        self.base_path.join(BLOBS_DIR).join(hash)
+    }
+
+    /// Replaces the file of `uuid` with a link to the blob of `hash`.
+    fn link(&self, hash: &str, uuid: &str) -> io::Result<()> {
+        let path = file_path(&self.base_path, uuid)?;
+        let tmp_path = tmp_path(&path);
+        std::fs::hard_link(self.blob_path(hash), &tmp_path)?;
+        let renamed = std::fs::rename(&tmp_path, path);
+        // renaming onto a link to the same file does nothing, leaving the temporary link behind
+        std::fs::remove_file(&tmp_path).ok();
+        renamed
+    }
+
+    fn save(&self, uuid: &str, image: &[u8]) -> io::Result<()> {
+        let hash = hash(image);
+        // held while the files change, so a blob isn't removed while it is being linked to
+        let mut blobs = self.blobs.lock().unwrap();
+        if blobs.hashes.get(uuid) == Some(&hash) {
+            return Ok(());
+        }
+        let is_new = !blobs.refs.contains_key(&hash);
+        if let Some(quota) = self.quota {
+            let size = image.len() as u64;
+            // a deduplicated image takes no space, and is saved even past the quota
+            if is_new && blobs.bytes + size > quota + blobs.freed_by_relinking(uuid) {
+                return Err(io::Error::new(
+                    io::ErrorKind::StorageFull,
+                    "The image store is full.",
+                ));
+            }
+        }
+        if is_new {
+            write_replacing(&self.blob_path(&hash), image)?;
+        }
+        if let Err(e) = self.link(&hash, uuid) {
+            if is_new {
+                std::fs::remove_file(self.blob_path(&hash)).ok();
+            }
+            return Err(e);
+        }
+        if let Some(old) = blobs.link(uuid, hash, image.len() as u64) {
+            if blobs.unlink(&old) {
+                std::fs::remove_file(self.blob_path(&old)).ok();
+            }
+        }
+        save_metadata(&self.base_path, uuid, &ImageMetadata::of(image))
+    }
+
+    fn remove(&self, uuid: &str) -> io::Result<()> {
+        let mut blobs = self.blobs.lock().unwrap();
+        std::fs::remove_file(file_path(&self.base_path, uuid)?)?;
+        std::fs::remove_file(metadata_path(&self.base_path, uuid)?).ok();
+        if let Some(hash) = blobs.hashes.remove(uuid) {
+            if blobs.unlink(&hash) {
+                std::fs::remove_file(self.blob_path(&hash)).ok();
+            }
+        }
+        Ok(())
+    }
+
+    fn clear(&self) -> io::Result<()> {
+        let mut blobs = self.blobs.lock().unwrap();
+        std::fs::remove_dir_all(&self.base_path)?;
+        std::fs::create_dir(&self.base_path)?;
+        std::fs::create_dir(self.base_path.join(BLOBS_DIR))?;
+        std::fs::write(self.base_path.join(BINARY_MARKER), "")?;
+        *blobs = Blobs::default();
+        Ok(())
+    }
+}
+
+#[async_trait]
+impl ImageStorage for FsImageStorage {
+    async fn save(&self, uuid: &str, image: &str) -> io::Result<()> {
+        let (store, uuid, image) = (Arc::clone(&self.0), uuid.to_string(), image.to_string());
+        unblock(move || {
+            let saved = decode(&image).and_then(|image| store.save(&uuid, &image));
+            // invalidated once the new image is in place, or if writing it failed halfway
+            store.cache.invalidate(&uuid);
+            saved
+        })
+        .await
+    }
+
+    async fn get(&self, uuid: &str) -> Option<String> {
+        self.get_cached(uuid)
+            .await
+            .map(|image| image.base64.clone())
+    }
+
+    async fn get_cached(&self, uuid: &str) -> Option<Arc<CachedImage>> {
+        let (store, uuid) = (Arc::clone(&self.0), uuid.to_string());
+        unblock(move || cached(&store.base_path, &store.cache, &uuid))
+            .await
+            .ok()
+    }
+
+    async fn read(&self, uuid: &str) -> io::Result<Bytes> {
+        let (store, uuid) = (Arc::clone(&self.0), uuid.to_string());
+        unblock(move || {
+            let path = file_path(&store.base_path, &uuid)?;
+            if path.metadata()?.len() >= store.mmap_threshold {
+                map(&store.base_path, &uuid).map(Bytes::from_owner)
+            } else {
+                std::fs::read(path).map(Bytes::from)
+            }
+        })
+        .await
+    }
+
+    async fn remove(&self, uuid: &str) -> io::Result<()> {
+        let (store, uuid) = (Arc::clone(&self.0), uuid.to_string());
+        unblock(move || {
+            let removed = store.remove(&uuid);
+            store.cache.invalidate(&uuid);
+            removed
+        })
+        .await
+    }
+
+    async fn clear(&self) -> io::Result<()> {
+        let store = Arc::clone(&self.0);
+        unblock(move || {
+            let cleared = store.clear();
+            store.cache.clear();
+            cleared
+        })
+        .await
+    }
+
+    async fn exists(&self, uuid: &str) -> bool {
+        let (store, uuid) = (Arc::clone(&self.0), uuid.to_string());
+        unblock(move || Ok(file_path(&store.base_path, &uuid)?.exists()))
+            .await
+            .unwrap_or(false)
+    }
+
+    fn usage(&self) -> ImageUsage {
+        ImageUsage {
+            bytes: self.0.blobs.lock().unwrap().bytes,
+            quota: self.0.quota,
+        }
+    }
+
+    async fn metadata(&self, uuid: &str) -> Option<ImageMetadata> {
+        let (store, uuid) = (Arc::clone(&self.0), uuid.to_string());
+        unblock(move || Ok(metadata(&store.base_path, &store.cache, &uuid)))
+            .await
+            .ok()
+            .flatten()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::app_state::testing::TestDir;
+
+    fn open(dir: &TestDir) -> FsImageStorage {
+        let cache = Arc::new(ImageCache::new(1024));
+        FsImageStorage::open(dir.path().to_path_buf(), cache, None, u64::MAX).unwrap()
+    }
+
+    #[tokio::test]
+    async fn reopened_images_keep_their_blobs() {
+        let dir = TestDir::new();
+        let images = open(&dir);
+        // "hello" and "bye"
+        images.save("a", "aGVsbG8=").await.unwrap();
+        images.save("b", "aGVsbG8=").await.unwrap();
+        images.save("c", "Ynll").await.unwrap();
+        drop(images);
+        // stored before images were deduplicated
+        std::fs::write(dir.path().join("d"), "hello").unwrap();
+
+        let images = open(&dir);
+        assert_eq!(images.usage().bytes, 8);
+        assert_eq!(&images.read("d").await.unwrap()[..], b"hello");
+        assert_eq!(
+            std::fs::read_dir(dir.path().join(BLOBS_DIR))
+                .unwrap()
+                .count(),
+            2
+        );
+
+        // the last link to a blob removes it, however the image was linked
+        for uuid in ["a", "b", "d"] {
+            images.remove(uuid).await.unwrap();
+        }
+        assert_eq!(images.usage().bytes, 3);
+    }
+}
@@ -0,0 +1,129 @@
+use serde::{Deserialize, Serialize};
+#[cfg(feature = "bindings")]
+use ts_rs::TS;
+
+/// What clients want to know about an image without decoding it, e.g. to lay it out before it
+/// is loaded. Stored next to each image when it is saved.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+#[cfg_attr(feature = "bindings", derive(TS), ts(export))]
+pub struct ImageMetadata {
+    /// The media type told by the first bytes of the image, `application/octet-stream` if it
+    /// isn't one of the known formats.
+    pub content_type: String,
+    /// In pixels, `None` when the dimensions can't be read from the header of the image.
+    pub width: Option<u32>,
+    pub height: Option<u32>,
+    /// The size of the decoded image in bytes.
+    pub size: u64,
+}
+
+impl ImageMetadata {
+    pub fn of(image: &[u8]) -> Self {
+        let dimensions = dimensions(image);
+        Self {
+            content_type: media_type(image).to_string(),
+            width: dimensions.map(|(width, _)| width),
+            height: dimensions.map(|(_, height)| height),
+            size: image.len() as u64,
+        }
+    }
+}
+
+/// The media type of an image, told by its first bytes rather than by what the client claimed,
+/// so nothing is served as a type that browsers would run.
+pub fn media_type(image: &[u8]) -> &'static str {
+    match image {
+        [0x89, b'P', b'N', b'G', ..] => "image/png",
+        [0xFF, 0xD8, 0xFF, ..] => "image/jpeg",
+        [b'G', b'I', b'F', b'8', ..] => "image/gif",
+        [b'R', b'I', b'F', b'F', _, _, _, _, b'W', b'E', b'B', b'P', ..] => "image/webp",
+        [b'B', b'M', ..] => "image/bmp",
+        _ => "application/octet-stream",
+    }
+}
+
+fn u16_be(image: &[u8], at: usize) -> Option<u32> {
+    let bytes = image.get(at..at + 2)?;
+    Some(u16::from_be_bytes([bytes[0], bytes[1]]).into())
+}
+
+fn u16_le(image: &[u8], at: usize) -> Option<u32> {
+    let bytes = image.get(at..at + 2)?;
+    Some(u16::from_le_bytes([bytes[0], bytes[1]]).into())
+}
+
+fn u24_le(image: &[u8], at: usize) -> Option<u32> {
+    let bytes = image.get(at..at + 3)?;
+    Some(u32::from_le_bytes([bytes[0], bytes[1], bytes[2], 0]))
+}
+
+fn u32_be(image: &[u8], at: usize) -> Option<u32> {
+    let bytes = image.get(at..at + 4)?;
+    Some(u32::from_be_bytes(bytes.try_into().ok()?))
+}
+
+fn u32_le(image: &[u8], at: usize) -> Option<u32> {
+    let bytes = image.get(at..at + 4)?;
+    Some(u32::from_le_bytes(bytes.try_into().ok()?))
+}
+
+/// The width and height of an image of a known format, read from its header.
+fn dimensions(image: &[u8]) -> Option<(u32, u32)> {
+    match media_type(image) {
+        // the IHDR chunk comes first
+        "image/png" => Some((u32_be(image, 16)?, u32_be(image, 20)?)),
+        "image/jpeg" => jpeg_dimensions(image),
+        // the logical screen descriptor
+        "image/gif" => Some((u16_le(image, 6)?, u16_le(image, 8)?)),
+        "image/webp" => webp_dimensions(image),
+        "image/bmp" => match u32_le(image, 14)? {
+            // the OS/2 header has 16 bit dimensions
+            12 => Some((u16_le(image, 18)?, u16_le(image, 20)?)),
+            // a negative height is a top-down bitmap
+            _ => Some((
+                (u32_le(image, 18)? as i32).unsigned_abs(),
+                (u32_le(image, 22)? as i32).unsigned_abs(),
+            )),
+        },
+        _ => None,
+    }
+}
+
+/// The dimensions of the start of frame segment, found by skipping the segments before it.
+fn jpeg_dimensions(image: &[u8]) -> Option<(u32, u32)> {
+    let mut at = 2;
+    loop {
+        if *image.get(at)? != 0xFF {
+            return None;
+        }
+        match *image.get(at + 1)? {
+            // fill bytes before a marker
+            0xFF => at += 1,
+            // markers without a segment
+            0x01 | 0xD0..=0xD8 => at += 2,
+            // start of frame, except DHT, JPG and DAC which share the range
+            0xC0..=0xCF if !matches!(image[at + 1], 0xC4 | 0xC8 | 0xCC) => {
+                return Some((u16_be(image, at + 7)?, u16_be(image, at + 5)?));
+            }
+            // the image data starts before any frame
+            0xD9 | 0xDA => return None,
+            _ => at += 2 + u16_be(image, at + 2)? as usize,
+        }
+    }
+}
+
+/// The dimensions of the canvas of the first chunk, whose layout depends on the codec.
+fn webp_dimensions(image: &[u8]) -> Option<(u32, u32)> {
+    match image.get(12..16)? {
+        // lossy, after the frame tag and start code
+        b"VP8 " => Some((u16_le(image, 26)? & 0x3FFF, u16_le(image, 28)? & 0x3FFF)),
+        // lossless, 14 bits each after the signature byte
+        b"VP8L" => {
+            let bits = u32_le(image, 21)?;
+            Some(((bits & 0x3FFF) + 1, ((bits >> 14) & 0x3FFF) + 1))
+        }
+        // extended, 24 bits each after the flags
+        b"VP8X" => Some((u24_le(image, 24)? + 1, u24_le(image, 27)? + 1)),
+        _ => None,
+    }
+}
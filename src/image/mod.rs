@@ -1,27 +1,245 @@
-use std::{io, path::PathBuf};
+mod cache;
+mod filesystem;
+mod metadata;
 
-pub fn file_path(base_path: &PathBuf, user_id: &str) -> PathBuf {
-    std::fs::canonicalize(base_path)
-        .expect("Base path is not a valid path")
-        .join(user_id)
+pub use cache::{CachedImage, ImageCache};
+pub use filesystem::FsImageStorage;
+pub use metadata::{media_type, ImageMetadata};
+
+use async_trait::async_trait;
+use bytes::Bytes;
+use futures_util::{stream, StreamExt};
+use memmap2::Mmap;
+use std::{
+    io,
+    path::{Path, PathBuf},
+    sync::Arc,
+};
+
+/// The file marking an images directory whose images are stored decoded, see [`migrate`].
+const BINARY_MARKER: &str = ".binary";
+
+/// How many images [`ImageStorage::get_many`] and [`ImageStorage::metadata_many`] read at once
+/// by default.
+const GET_MANY_CONCURRENCY: usize = 8;
+
+/// Storage of the images of the messages, keyed by their uuids and injected through `AppState`
+/// like the messages, so handlers don't depend on where the images are kept. Images go in as
+/// the base64 clients send and come out as the base64 pages carry, or decoded for the raw
+/// image endpoint. Every reader of the images goes through it, the pages, the mutation manager
+/// and the image endpoints alike.
+#[async_trait]
+pub trait ImageStorage: Send + Sync {
+    /// Replaces the image of `uuid`.
+    ///
+    /// # Errors
+    ///
+    /// This function will return an error of kind `InvalidData` if the image isn't base64, and
+    /// of kind `StorageFull` if it would take the images past their quota.
+    async fn save(&self, uuid: &str, image: &str) -> io::Result<()>;
+
+    /// Returns the image of `uuid` in base64, `None` if it has none.
+    async fn get(&self, uuid: &str) -> Option<String>;
+
+    /// Returns the image of `uuid` as pages carry it, in base64 along with its metadata, `None`
+    /// if it has none.
+    async fn get_cached(&self, uuid: &str) -> Option<Arc<CachedImage>>;
+
+    /// [`ImageStorage::get_cached`] for each of `uuids`, in the same order. They are read
+    /// [`GET_MANY_CONCURRENCY`] at a time, so e.g. a page waits on its slowest images rather
+    /// than on each of them in turn.
+    async fn get_many(&self, uuids: &[&str]) -> Vec<Option<Arc<CachedImage>>> {
+        let reads: Vec<_> = uuids.iter().map(|uuid| self.get_cached(uuid)).collect();
+        stream::iter(reads)
+            .buffered(GET_MANY_CONCURRENCY)
+            .collect()
+            .await
+    }
+
+    /// Returns the decoded image of `uuid`, to serve it as it is.
+    ///
+    /// # Errors
+    ///
+    /// This function will return an error of kind `NotFound` if `uuid` has no image.
+    async fn read(&self, uuid: &str) -> io::Result<Bytes>;
+
+    /// Removes the image of `uuid`.
+    async fn remove(&self, uuid: &str) -> io::Result<()>;
+
+    /// Removes every image.
+    async fn clear(&self) -> io::Result<()>;
+
+    /// Whether `uuid` has an image.
+    async fn exists(&self, uuid: &str) -> bool;
+
+    /// Returns the metadata of the image of `uuid`, `None` if it has none.
+    async fn metadata(&self, uuid: &str) -> Option<ImageMetadata>;
+
+    /// [`ImageStorage::metadata`] for each of `uuids`, in the same order, read like
+    /// [`ImageStorage::get_many`].
+    async fn metadata_many(&self, uuids: &[&str]) -> Vec<Option<ImageMetadata>> {
+        let reads: Vec<_> = uuids.iter().map(|uuid| self.metadata(uuid)).collect();
+        stream::iter(reads)
+            .buffered(GET_MANY_CONCURRENCY)
+            .collect()
+            .await
+    }
+
+    /// How much space the images take.
+    fn usage(&self) -> ImageUsage;
 }
 
-pub fn save(base_path: &PathBuf, image: &str, user_id: &str) -> io::Result<()> {
-    std::fs::write(file_path(base_path, user_id), image)
+/// The space taken by the stored images, and how much they may take. Once an image doesn't fit
+/// in the quota anymore, [`ImageStorage::save`] fails with an error of kind `StorageFull`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ImageUsage {
+    pub bytes: u64,
+    /// `None` when the images are only limited by the disk.
+    pub quota: Option<u64>,
 }
 
-pub fn remove(base_path: &PathBuf, user_id: &str) -> std::io::Result<()> {
-    std::fs::remove_file(file_path(base_path, user_id))
+/// The longest id an image file may be named after, longer than both id schemes.
+const MAX_ID_LEN: usize = 64;
+
+/// The path of the image file of `user_id` in `base_path`.
+///
+/// # Errors
+///
+/// This function will return an error of kind `InvalidInput` if `user_id` isn't made of the
+/// letters, digits and hyphens of the id schemes, or the path would escape `base_path`, e.g.
+/// through a symlink, and the error of the canonicalization if `base_path` can't be resolved.
+pub fn file_path(base_path: &PathBuf, user_id: &str) -> io::Result<PathBuf> {
+    let invalid = || io::Error::new(io::ErrorKind::InvalidInput, "Invalid image id.");
+
+    let is_id = !user_id.is_empty()
+        && user_id.len() <= MAX_ID_LEN
+        && user_id
+            .bytes()
+            .all(|c| c.is_ascii_alphanumeric() || c == b'-');
+    if !is_id {
+        return Err(invalid());
+    }
+    let base_path = std::fs::canonicalize(base_path)?;
+    let path = base_path.join(user_id);
+    match std::fs::canonicalize(&path) {
+        Ok(real) if !real.starts_with(&base_path) => Err(invalid()),
+        _ => Ok(path),
+    }
+}
+
+/// Decodes an image as clients send it, base64 behind the `data:<type>;base64,` prefix of a
+/// data URL or not.
+///
+/// # Errors
+///
+/// This function will return an error of kind `InvalidData` if the image isn't base64.
+pub fn decode(image: &str) -> io::Result<Vec<u8>> {
+    let data = match image.strip_prefix("data:") {
+        Some(url) => url.split_once(";base64,").map_or(image, |(_, data)| data),
+        None => image,
+    };
+    base64::decode(data.trim())
+        .map_err(|_| io::Error::new(io::ErrorKind::InvalidData, "The image must be base64."))
+}
+
+/// A hidden path next to `path` to write its replacement to.
+fn tmp_path(path: &Path) -> PathBuf {
+    let name = path.file_name().unwrap_or_default().to_string_lossy();
+    path.with_file_name(format!(".{name}.{:016x}", rand::random::<u64>()))
+}
+
+/// Writes `contents` to a new file that then replaces the one at `path`, so a mapped image file
+/// is never truncated while it is being served.
+fn write_replacing(path: &Path, contents: &[u8]) -> io::Result<()> {
+    let tmp_path = tmp_path(path);
+    std::fs::write(&tmp_path, contents)?;
+    std::fs::rename(&tmp_path, path).inspect_err(|_| {
+        std::fs::remove_file(&tmp_path).ok();
+    })
+}
+
+/// The image of `user_id` from `cache`, read from its file and cached on a miss.
+fn cached(base_path: &PathBuf, cache: &ImageCache, user_id: &str) -> io::Result<Arc<CachedImage>> {
+    if let Some(image) = cache.get(user_id) {
+        return Ok(image);
+    }
+    let epoch = cache.epoch();
+    let image = std::fs::read(file_path(base_path, user_id)?)?;
+    let image = Arc::new(CachedImage {
+        metadata: ImageMetadata::of(&image),
+        base64: base64::encode(image),
+    });
+    cache.insert(user_id, Arc::clone(&image), epoch);
+    Ok(image)
+}
+
+/// The path of the metadata stored next to the image of `user_id`.
+fn metadata_path(base_path: &PathBuf, user_id: &str) -> io::Result<PathBuf> {
+    let path = file_path(base_path, user_id)?;
+    Ok(path.with_file_name(format!(".{user_id}.json")))
+}
+
+/// Stores the metadata of the image of `user_id`.
+fn save_metadata(base_path: &PathBuf, user_id: &str, metadata: &ImageMetadata) -> io::Result<()> {
+    let metadata = serde_json::to_vec(metadata).map_err(io::Error::other)?;
+    write_replacing(&metadata_path(base_path, user_id)?, &metadata)
 }
 
-pub fn get(base_path: &PathBuf, user_id: &str) -> Option<String> {
-    match std::fs::read_to_string(file_path(base_path, user_id)) {
-        Ok(image) => Some(image),
-        Err(_) => None,
+/// The metadata of the image of `user_id`, from `cache` or else as stored next to the image, so
+/// the image itself isn't read. `None` if it has no image.
+fn metadata(base_path: &PathBuf, cache: &ImageCache, user_id: &str) -> Option<ImageMetadata> {
+    if let Some(image) = cache.get(user_id) {
+        return Some(image.metadata.clone());
     }
+    let metadata = std::fs::read(metadata_path(base_path, user_id).ok()?).ok()?;
+    serde_json::from_slice(&metadata).ok()
 }
 
-pub fn clear(base_path: &PathBuf) -> std::io::Result<()> {
-    std::fs::remove_dir_all(base_path)?;
-    std::fs::create_dir(base_path)
+/// Memory-maps the decoded image of `user_id`, so a large image is served without copying it
+/// into the heap.
+fn map(base_path: &PathBuf, user_id: &str) -> io::Result<Mmap> {
+    let file = std::fs::File::open(file_path(base_path, user_id)?)?;
+    // SAFETY: image files are only ever replaced, never modified in place (see
+    // `write_replacing`), so the mapped contents don't change under the mapping
+    unsafe { Mmap::map(&file) }
+}
+
+/// Decodes the images of a directory that stored them as the base64 text clients sent, once,
+/// returning how many were decoded. Images that aren't base64 are left as they are, and are
+/// served as if they were their decoded bytes.
+///
+/// # Errors
+///
+/// This function will return an error if the directory can't be read or an image can't be
+/// rewritten.
+pub fn migrate(base_path: &PathBuf) -> io::Result<usize> {
+    let marker = base_path.join(BINARY_MARKER);
+    if marker.exists() {
+        return Ok(0);
+    }
+    let mut decoded = 0;
+    for entry in std::fs::read_dir(base_path)? {
+        let entry = entry?;
+        let name = entry.file_name();
+        let Some(user_id) = name.to_str().filter(|name| !name.starts_with('.')) else {
+            continue;
+        };
+        if !entry.file_type()?.is_file() {
+            continue;
+        }
+        let Ok(path) = file_path(base_path, user_id) else {
+            eprintln!("{user_id} isn't named after a message id, it is left as it is.");
+            continue;
+        };
+        let image = std::fs::read(&path)?;
+        match std::str::from_utf8(&image).map(decode) {
+            Ok(Ok(image)) => {
+                write_replacing(&path, &image)?;
+                decoded += 1;
+            }
+            _ => eprintln!("The image of {user_id} isn't base64, it is left as it is."),
+        }
+    }
+    std::fs::write(marker, "")?;
+    Ok(decoded)
 }
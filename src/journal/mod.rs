@@ -0,0 +1,159 @@
+use serde::{Deserialize, Serialize};
+use std::{
+    fs::{File, OpenOptions},
+    io::{self, BufRead, BufReader, Write},
+    path::Path,
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc,
+    },
+};
+
+/// A write request as it was received.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct JournalEntry {
+    pub id: u64,
+    pub method: String,
+    pub uri: String,
+    pub body: Option<String>,
+    /// The `Idempotency-Key` the write was sent with, replayed with it.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub idempotency_key: Option<String>,
+}
+
+/// A line of the journal file.
+#[derive(Serialize, Deserialize)]
+#[serde(tag = "kind", rename_all = "snake_case")]
+enum Record {
+    /// Appended before the write is processed.
+    Begin(JournalEntry),
+    /// Appended once the write was processed, with the status it was answered with.
+    Done { id: u64, status: u16 },
+}
+
+/// A write-ahead journal of the write requests, enabled with `JOURNAL_PATH`. Every write is
+/// appended before it is processed and marked done after, so the writes a crash interrupted
+/// can be accounted for, or replayed, at the next start.
+///
+/// Records are appended by a single `write` to a file opened for appending, so concurrent
+/// writes don't need a lock, and each waits for its own record to be on disk. The file I/O runs
+/// on the blocking pool.
+pub struct Journal {
+    file: Arc<File>,
+    next_id: AtomicU64,
+}
+
+impl Journal {
+    /// Opens the journal at `path`, returning it with the entries a previous run began but never
+    /// finished. They stay in the journal until the caller reported or replayed them and calls
+    /// [`Journal::truncate`], so a crash before then finds them again.
+    ///
+    /// # Errors
+    ///
+    /// This function will return an error if the journal can't be read or opened for appending.
+    pub fn open(path: &Path) -> io::Result<(Self, Vec<JournalEntry>)> {
+        let mut incomplete: Vec<JournalEntry> = Vec::new();
+        let mut next_id = 1;
+        match File::open(path) {
+            Ok(file) => {
+                for line in BufReader::new(file).lines() {
+                    // the last line is torn when the crash happened while appending it
+                    let Ok(record) = serde_json::from_str(&line?) else {
+                        continue;
+                    };
+                    match record {
+                        Record::Begin(entry) => {
+                            next_id = next_id.max(entry.id + 1);
+                            incomplete.push(entry);
+                        }
+                        Record::Done { id, .. } => incomplete.retain(|entry| entry.id != id),
+                    }
+                }
+            }
+            Err(e) if e.kind() == io::ErrorKind::NotFound => {}
+            Err(e) => return Err(e),
+        }
+
+        let file = OpenOptions::new().create(true).append(true).open(path)?;
+        let journal = Self {
+            file: Arc::new(file),
+            next_id: AtomicU64::new(next_id),
+        };
+        Ok((journal, incomplete))
+    }
+
+    /// Empties the journal, once the entries [`Journal::open`] returned are accounted for.
+    ///
+    /// # Errors
+    ///
+    /// This function will return an error if the journal couldn't be truncated.
+    pub fn truncate(&self) -> io::Result<()> {
+        self.file.set_len(0)?;
+        self.file.sync_data()
+    }
+
+    /// Records a write before it is processed, returning the id to mark it done with.
+    ///
+    /// # Errors
+    ///
+    /// This function will return an error if the entry couldn't be persisted, the write must not
+    /// be processed then.
+    pub async fn begin(
+        &self,
+        method: &str,
+        uri: &str,
+        body: Option<&str>,
+        idempotency_key: Option<&str>,
+    ) -> io::Result<u64> {
+        let id = self.next_id.fetch_add(1, Ordering::Relaxed);
+        let line = line(&Record::Begin(JournalEntry {
+            id,
+            method: method.to_string(),
+            uri: uri.to_string(),
+            body: body.map(str::to_string),
+            idempotency_key: idempotency_key.map(str::to_string),
+        }))?;
+        self.unblock(move |file| {
+            append(file, &line)?;
+            // the record must survive a crash right after the call
+            file.sync_data()
+        })
+        .await?;
+        Ok(id)
+    }
+
+    /// Marks the write `id` as processed, answered with `status`. The record isn't synced, a
+    /// crash losing it only replays a write that is safe to apply again.
+    ///
+    /// # Errors
+    ///
+    /// This function will return an error if the record couldn't be appended.
+    pub async fn done(&self, id: u64, status: u16) -> io::Result<()> {
+        let line = line(&Record::Done { id, status })?;
+        self.unblock(move |file| append(file, &line)).await
+    }
+
+    /// Runs the blocking `f` on the file on the blocking pool, so the worker awaiting it isn't
+    /// held up by the disk.
+    async fn unblock<F>(&self, f: F) -> io::Result<()>
+    where
+        F: FnOnce(&File) -> io::Result<()> + Send + 'static,
+    {
+        let file = Arc::clone(&self.file);
+        tokio::task::spawn_blocking(move || f(&file))
+            .await
+            .map_err(io::Error::other)?
+    }
+}
+
+/// The line of the journal file holding `record`.
+fn line(record: &Record) -> io::Result<Vec<u8>> {
+    let mut line = serde_json::to_vec(record)?;
+    line.push(b'\n');
+    Ok(line)
+}
+
+fn append(mut file: &File, line: &[u8]) -> io::Result<()> {
+    // a single write, records of concurrent writes aren't interleaved
+    file.write_all(line)
+}
@@ -1,25 +1,46 @@
 #![allow(non_snake_case)]
 use std::path::Path;
 
+pub mod access_log;
 pub mod app_state;
+pub mod coalescer;
+pub mod cors;
+pub mod deadline;
+pub mod features;
 mod handlers;
 pub mod image;
-mod models;
+pub mod journal;
+pub mod listener;
+pub mod metrics;
+pub mod models;
 pub mod mutation_manager;
+pub mod outbox;
+pub mod page_tokens;
+pub mod quota;
+pub mod repository;
 mod request;
-mod response;
+pub mod response;
+mod router;
+pub mod seed;
+pub mod shard;
+pub mod throttle;
+#[cfg(feature = "tls")]
+pub mod tls;
+pub mod tombstones;
+pub mod uploads;
+pub mod wire;
 
-pub use handlers::handle_connection;
+pub use handlers::{handle_admin_connection, handle_connection, replay_write, route_names};
+pub use request::RequestLimits;
 
 pub fn try_write_perm(path: &Path) {
     let test_file_path = path.join("test_file.txt");
-    std::fs::write(&test_file_path, "test").expect(
-        format!(
+    std::fs::write(&test_file_path, "test").unwrap_or_else(|_| {
+        panic!(
             "Failed to write to {}. Try `sudo chmod 777 {}",
             path.display(),
             path.display()
         )
-        .as_str(),
-    );
+    });
     std::fs::remove_file(&test_file_path).unwrap();
 }
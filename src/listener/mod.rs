@@ -0,0 +1,68 @@
+use socket2::{Domain, Protocol, SockRef, Socket, TcpKeepalive, Type};
+use std::{io, net::SocketAddr, time::Duration};
+use tokio::net::{TcpListener, TcpStream};
+
+/// The socket options of the listeners and of the connections they accept. The defaults are the
+/// ones of [`TcpListener::bind`].
+#[derive(Debug, Clone, Copy)]
+pub struct SocketOptions {
+    /// `SO_REUSEADDR`, so the server can bind again right after a restart while the connections
+    /// of the previous run are in `TIME_WAIT`.
+    pub reuse_address: bool,
+    /// `SO_REUSEPORT`, so several listeners bind the same address and the kernel spreads the
+    /// incoming connections across them. Only available on unix.
+    pub reuse_port: bool,
+    /// How many connections the kernel queues until they are accepted, more are dropped.
+    pub backlog: u32,
+    /// `TCP_NODELAY` on the accepted connections, so small responses aren't held back to be
+    /// coalesced.
+    pub nodelay: bool,
+    /// How long an accepted connection is idle before TCP keepalive probes are sent, no probes
+    /// when `None`.
+    pub keepalive: Option<Duration>,
+}
+
+impl Default for SocketOptions {
+    fn default() -> Self {
+        Self {
+            reuse_address: true,
+            reuse_port: false,
+            backlog: 1024,
+            nodelay: false,
+            keepalive: None,
+        }
+    }
+}
+
+impl SocketOptions {
+    /// Binds a listener to `addr`.
+    ///
+    /// # Errors
+    ///
+    /// This function will return an error if the socket can't be created or bound, e.g. when the
+    /// address is in use.
+    pub fn bind(&self, addr: SocketAddr) -> io::Result<TcpListener> {
+        let socket = Socket::new(Domain::for_address(addr), Type::STREAM, Some(Protocol::TCP))?;
+        socket.set_reuse_address(self.reuse_address)?;
+        #[cfg(unix)]
+        socket.set_reuse_port(self.reuse_port)?;
+        socket.set_nonblocking(true)?;
+        socket.bind(&addr.into())?;
+        // the kernel caps the backlog to `net.core.somaxconn`
+        socket.listen(self.backlog.try_into().unwrap_or(i32::MAX))?;
+        TcpListener::from_std(socket.into())
+    }
+
+    /// Applies the options of the accepted connections to `stream`.
+    ///
+    /// # Errors
+    ///
+    /// This function will return an error if an option can't be set.
+    pub fn configure(&self, stream: &TcpStream) -> io::Result<()> {
+        stream.set_nodelay(self.nodelay)?;
+        if let Some(idle) = self.keepalive {
+            SockRef::from(stream).set_tcp_keepalive(&TcpKeepalive::new().with_time(idle))?;
+        }
+        Ok(())
+    }
+}
@@ -1,130 +1,586 @@
 use ahash::AHashSet;
 use dotenv::dotenv;
-use futures_util::stream::StreamExt;
+use server_low_level::metrics::{LatencyBudgets, Metrics};
+#[cfg(not(feature = "postgres"))]
+use server_low_level::repository::InMemoryMessageRepository;
+#[cfg(feature = "postgres")]
+use server_low_level::repository::PgMessageRepository;
+#[cfg(feature = "tls")]
+use server_low_level::tls::ClientCertAuth;
 use server_low_level::{
-    app_state::AppState, handle_connection, mutation_manager::MutationManager, try_write_perm,
+    access_log::{self, LogFormat},
+    app_state::{pagination::Pagination, AppState},
+    coalescer::Coalescer,
+    cors::Cors,
+    features::FeatureFlags,
+    handle_admin_connection, handle_connection,
+    image::{self, FsImageStorage, ImageCache},
+    journal::Journal,
+    listener::SocketOptions,
+    models::DEFAULT_REACTION_KINDS,
+    mutation_manager::{spawn_sweeper, MutationManager},
+    outbox::spawn_relay,
+    page_tokens::PageTokens,
+    quota::{QuotaLimits, QuotaTracker},
+    replay_write,
+    repository::MessageRepository,
+    route_names,
+    seed::seed_from_dir,
+    shard::ShardRouter,
+    tombstones::Tombstones,
+    try_write_perm,
+    uploads::{self, UploadManager},
+    wire::Canary,
+    RequestLimits,
 };
+#[cfg(feature = "postgres")]
 use sqlx::postgres::PgPoolOptions;
-use std::{net::SocketAddr, sync::Arc};
+use std::{
+    io,
+    net::{IpAddr, SocketAddr},
+    path::Path,
+    sync::Arc,
+    time::Duration,
+};
 use tokio::{
-    net::TcpListener,
+    net::lookup_host,
     signal,
-    sync::{mpsc, Mutex},
+    sync::{broadcast, Mutex, Notify},
 };
 
+/// How long an admin client has to complete the TLS handshake, so clients that stall in it
+/// don't pile up.
+#[cfg(feature = "tls")]
+const ADMIN_TLS_HANDSHAKE_TIMEOUT: Duration = Duration::from_secs(10);
+
 #[tokio::main]
 async fn main() {
     dotenv().ok();
 
-    println!("Connecting to database...");
-    let db_pool = Arc::new(
-        match PgPoolOptions::new()
-            .min_connections(90)
-            .max_connections(100)
-            .connect(&std::env::var("DATABASE_URL").expect("DATABASE_URL is not set"))
-            .await
-        {
-            Ok(pool) => {
-                println!("Connected to database.");
-                pool
-            }
-            Err(e) => {
-                panic!("Failed to connect to database: {}", e);
-            }
-        },
-    );
+    // every request is logged to stdout unless ACCESS_LOG=false
+    let access_log = std::env::var("ACCESS_LOG")
+        .map(|v| v.parse().expect("ACCESS_LOG must be true or false"))
+        .unwrap_or(true);
+    if access_log {
+        let format: LogFormat = std::env::var("LOG_FORMAT")
+            .map(|v| v.parse().expect("LOG_FORMAT must be text or json"))
+            .unwrap_or_default();
+        access_log::init(format);
+    }
+
+    #[cfg(feature = "postgres")]
+    let db_pool = {
+        println!("Connecting to database...");
+        Arc::new(
+            match PgPoolOptions::new()
+                .min_connections(90)
+                .max_connections(100)
+                .connect(&std::env::var("DATABASE_URL").expect("DATABASE_URL is not set"))
+                .await
+            {
+                Ok(pool) => {
+                    println!("Connected to database.");
+                    pool
+                }
+                Err(e) => {
+                    panic!("Failed to connect to database: {}", e);
+                }
+            },
+        )
+    };
+    #[cfg(feature = "postgres")]
     let db_pool_cloned = Arc::clone(&db_pool);
 
-    // channel to send shutdown signal to the tcp listener
-    let (shutdown_send, mut shutdown_recv) = mpsc::unbounded_channel();
+    // channel to send shutdown signal to the accept loops
+    let (shutdown_send, _) = broadcast::channel(1);
 
     let pagination_page_size: usize = std::env::var("PAGINATION_PAGE_SIZE")
         .expect("PAGINATION_PAGE_SIZE is not set")
         .parse()
         .expect("PAGINATION_PAGE_SIZE must be a number");
 
+    let features: FeatureFlags = std::env::var("FEATURES")
+        .map(|v| {
+            v.parse()
+                .expect("FEATURES must be a comma separated list of features")
+        })
+        .unwrap_or_default();
+
+    // admin endpoints get their own listener, e.g. on localhost, when this is set
+    let admin_addr = std::env::var("ADMIN_ADDR").ok();
+    // the admin listener requires client certificates signed by ADMIN_TLS_CLIENT_CA when set
+    let admin_client_ca = std::env::var("ADMIN_TLS_CLIENT_CA").ok();
+    #[cfg(not(feature = "tls"))]
+    if admin_client_ca.is_some() {
+        panic!("ADMIN_TLS_CLIENT_CA is set, but the server was built without the tls feature");
+    }
+    #[cfg(feature = "tls")]
+    let admin_tls = admin_client_ca.as_ref().map(|client_ca| {
+        let cert = std::env::var("ADMIN_TLS_CERT")
+            .expect("ADMIN_TLS_CERT must be set when ADMIN_TLS_CLIENT_CA is set");
+        let key = std::env::var("ADMIN_TLS_KEY")
+            .expect("ADMIN_TLS_KEY must be set when ADMIN_TLS_CLIENT_CA is set");
+        ClientCertAuth::new(Path::new(&cert), Path::new(&key), Path::new(client_ca))
+            .expect("Failed to load the admin TLS certificates")
+    });
+
+    // writes interrupted by a crash are reported, and replayed with JOURNAL_REPLAY, at startup
+    let (journal, incomplete_writes) = match std::env::var("JOURNAL_PATH") {
+        Ok(path) => {
+            let (journal, incomplete) =
+                Journal::open(Path::new(&path)).expect("Failed to open the JOURNAL_PATH journal");
+            (Some(journal), incomplete)
+        }
+        Err(_) => (None, Vec::new()),
+    };
+
     // setting up the tcp listener
 
+    #[cfg(feature = "postgres")]
+    let messages: Arc<dyn MessageRepository> = Arc::new(PgMessageRepository::new(db_pool));
+    #[cfg(not(feature = "postgres"))]
+    let messages: Arc<dyn MessageRepository> = {
+        // a server meant to store the messages in Postgres mustn't lose them on restart
+        if std::env::var("DATABASE_URL").is_ok() {
+            panic!("DATABASE_URL is set, but the server was built without the postgres feature");
+        }
+        println!("Built without the postgres feature, the messages are kept in memory.");
+        Arc::new(InMemoryMessageRepository::new())
+    };
+
+    let image_base_path = {
+        let path = std::env::var("IMAGES_BASE_PATH").expect("IMAGES_BASE_PATH must be set");
+        let path = std::path::Path::new(&path);
+        // check if this path directory exists
+        if !std::path::Path::new(&path).exists() {
+            panic!("IMAGES_BASE_PATH directory does not exist, the given path is {path:#?}.");
+        }
+        // try writing and deleting a file to check if we have write permissions
+        try_write_perm(path);
+        path.to_path_buf()
+    };
+
+    // the base64 of the images read last is kept in memory, up to IMAGE_CACHE_BYTES
+    let image_cache = Arc::new(ImageCache::new(
+        std::env::var("IMAGE_CACHE_BYTES")
+            .map(|v| v.parse().expect("IMAGE_CACHE_BYTES must be a number"))
+            .unwrap_or(64 * 1024 * 1024),
+    ));
+
+    // images used to be stored as the base64 text clients sent, decode them once
+    let decoded = image::migrate(&image_base_path).expect("Failed to decode the images");
+    if decoded > 0 {
+        println!("Decoded {decoded} images stored as base64.");
+    }
+    // the images may take up to IMAGES_QUOTA_BYTES, unlimited when unset
+    let images_quota = std::env::var("IMAGES_QUOTA_BYTES")
+        .ok()
+        .map(|v| v.parse().expect("IMAGES_QUOTA_BYTES must be a number"));
+    // images of at least IMAGE_MMAP_THRESHOLD bytes are served from a memory-mapped file
+    let image_mmap_threshold = std::env::var("IMAGE_MMAP_THRESHOLD")
+        .map(|v| v.parse().expect("IMAGE_MMAP_THRESHOLD must be a number"))
+        .unwrap_or(1024 * 1024);
+    // images are deduplicated by content, those stored before they were are linked to their
+    // blob at startup
+    let images = FsImageStorage::open(
+        image_base_path,
+        image_cache,
+        images_quota,
+        image_mmap_threshold,
+    )
+    .expect("Failed to open the images");
+
+    // the entries of the cache paginations past MUTATION_QUEUE_MEMORY_ENTRIES are spilled to disk
+    let mutation_queue_memory_entries = std::env::var("MUTATION_QUEUE_MEMORY_ENTRIES")
+        .map(|v| {
+            v.parse()
+                .expect("MUTATION_QUEUE_MEMORY_ENTRIES must be a number")
+        })
+        .unwrap_or(100_000);
+
+    // cached mutations no client paginated through within MUTATION_TTL_SECS are dropped
+    let mutation_ttl = std::env::var("MUTATION_TTL_SECS")
+        .ok()
+        .map(|v| Duration::from_secs(v.parse().expect("MUTATION_TTL_SECS must be a number")));
+
+    // upload sessions that got no chunk within UPLOAD_TTL_SECS are ended, a day by default
+    let upload_ttl = Duration::from_secs(
+        std::env::var("UPLOAD_TTL_SECS")
+            .map(|v| v.parse().expect("UPLOAD_TTL_SECS must be a number"))
+            .unwrap_or(24 * 60 * 60),
+    );
+
     // the state of the tcp listener server
     let state = Arc::new(AppState {
-        pool: db_pool,
-        mutations: Mutex::new(MutationManager::new(pagination_page_size)),
+        mutations: Mutex::new(MutationManager::new(
+            pagination_page_size,
+            mutation_queue_memory_entries,
+        )),
         pagination_page_size,
-        db_pagination_offset: Mutex::new(0),
-        triggered_pagination: Mutex::new(false),
-        image_base_path: {
-            let path = std::env::var("IMAGES_BASE_PATH").expect("IMAGES_BASE_PATH must be set");
-            let path = std::path::Path::new(&path);
-            // check if this path directory exists
-            if !std::path::Path::new(&path).exists() {
-                panic!("IMAGES_BASE_PATH directory does not exist, the given path is {path:#?}.");
-            }
-            // try writing and deleting a file to check if we have write permissions
-            try_write_perm(&path);
-            path.to_path_buf()
-        },
+        pagination: Mutex::new(Pagination::new()),
+        page_tokens: Mutex::new(PageTokens::new(
+            std::env::var("PAGE_TOKEN_SECRET")
+                .ok()
+                .as_deref()
+                .map(str::as_bytes),
+        )),
+        images: Arc::new(images),
+        messages: Arc::clone(&messages),
         all_uuids: {
             let mut uuids = AHashSet::with_capacity(50_000usize.next_power_of_two());
-            let mut stream = sqlx::query!("SELECT uuid FROM messages")
-                .map(|row| row.uuid)
-                .fetch(db_pool_cloned.as_ref());
-            while let Some(uuid) = stream.next().await {
-                let uuid = uuid.expect("Failed to fetch uuid from database");
-                uuids.insert(uuid);
-            }
+            uuids.extend(
+                messages
+                    .all_uuids()
+                    .await
+                    .expect("Failed to fetch uuids from database"),
+            );
             println!("Fetched all {} uuids from database.", uuids.len());
             Mutex::new(uuids)
         },
-        pagination_page_number: Mutex::new(0),
-        pages_count: Mutex::new(0),
+        tombstones: Mutex::new(Tombstones::new(Duration::from_secs(
+            std::env::var("TOMBSTONE_WINDOW_SECS")
+                .map(|v| v.parse().expect("TOMBSTONE_WINDOW_SECS must be a number"))
+                .unwrap_or(0),
+        ))),
+        read_only: std::env::var("READ_ONLY")
+            .map(|v| v.parse().expect("READ_ONLY must be true or false"))
+            .unwrap_or(false),
+        pagination_mode: std::env::var("PAGINATION_MODE")
+            .map(|v| v.parse().expect("PAGINATION_MODE must be offset or keyset"))
+            .unwrap_or_default(),
+        fresh_pages: Coalescer::new(),
+        uploads: Mutex::new(UploadManager::new(
+            std::env::var("UPLOADS_BASE_PATH")
+                .unwrap_or("./data/uploads".to_string())
+                .into(),
+        )),
+        outbox_notify: Notify::new(),
+        shard_router: std::env::var("SHARD_PEERS").ok().map(|peers| {
+            let self_addr = std::env::var("SHARD_SELF")
+                .expect("SHARD_SELF must be set when SHARD_PEERS is set");
+            // authenticates the requests the nodes forward to each other, sent as a header value
+            let secret = std::env::var("SHARD_SECRET")
+                .expect("SHARD_SECRET must be set when SHARD_PEERS is set");
+            if secret.is_empty() || secret.bytes().any(|b| !b.is_ascii_graphic()) {
+                panic!("SHARD_SECRET must be non-empty printable ascii without spaces");
+            }
+            let peers = peers
+                .split(',')
+                .map(str::trim)
+                .filter(|peer| !peer.is_empty())
+                .map(str::to_string)
+                .collect();
+            ShardRouter::new(self_addr, peers, secret)
+        }),
+        quotas: Mutex::new(QuotaTracker::new(QuotaLimits {
+            daily_writes: std::env::var("QUOTA_DAILY_WRITES")
+                .ok()
+                .map(|v| v.parse().expect("QUOTA_DAILY_WRITES must be a number")),
+            stored_bytes: std::env::var("QUOTA_STORED_BYTES")
+                .ok()
+                .map(|v| v.parse().expect("QUOTA_STORED_BYTES must be a number")),
+        })),
+        features,
+        metrics: features.metrics.then(|| {
+            Metrics::new(
+                std::env::var("LATENCY_BUDGETS_MS")
+                    .map(|v| {
+                        let budgets: LatencyBudgets = v.parse().expect(
+                            "LATENCY_BUDGETS_MS must be a comma separated list of route=ms",
+                        );
+                        let unknown = budgets.unknown_routes(&route_names());
+                        if !unknown.is_empty() {
+                            panic!(
+                                "LATENCY_BUDGETS_MS names unknown routes: {}",
+                                unknown.join(", ")
+                            );
+                        }
+                        budgets
+                    })
+                    .unwrap_or_default(),
+            )
+        }),
+        download_bytes_per_sec: std::env::var("DOWNLOAD_BYTES_PER_SEC").ok().map(|v| {
+            v.parse()
+                .expect("DOWNLOAD_BYTES_PER_SEC must be a positive number")
+        }),
+        request_limits: {
+            let defaults = RequestLimits::default();
+            RequestLimits {
+                max_body: std::env::var("MAX_BODY_SIZE")
+                    .map(|v| v.parse().expect("MAX_BODY_SIZE must be a number"))
+                    .unwrap_or(defaults.max_body),
+                max_headers: std::env::var("MAX_HEADER_COUNT")
+                    .map(|v| v.parse().expect("MAX_HEADER_COUNT must be a number"))
+                    .unwrap_or(defaults.max_headers),
+                max_header_bytes: std::env::var("MAX_HEADER_BYTES")
+                    .map(|v| v.parse().expect("MAX_HEADER_BYTES must be a number"))
+                    .unwrap_or(defaults.max_header_bytes),
+            }
+        },
+        request_timeout: std::env::var("REQUEST_TIMEOUT_MS").ok().map(|v| {
+            Duration::from_millis(v.parse().expect("REQUEST_TIMEOUT_MS must be a number"))
+        }),
+        read_timeout: Duration::from_millis(
+            std::env::var("READ_TIMEOUT_MS")
+                .map(|v| v.parse().expect("READ_TIMEOUT_MS must be a number"))
+                .unwrap_or(30_000),
+        ),
+        write_timeout: std::env::var("WRITE_TIMEOUT_MS")
+            .ok()
+            .map(|v| Duration::from_millis(v.parse().expect("WRITE_TIMEOUT_MS must be a number"))),
+        admin_listener: admin_addr.is_some(),
+        admin_token: admin_addr
+            .as_ref()
+            .and_then(|_| match std::env::var("ADMIN_TOKEN") {
+                Ok(token) => Some(token),
+                Err(_) if admin_client_ca.is_some() => None,
+                Err(_) => panic!(
+                    "ADMIN_TOKEN must be set when ADMIN_ADDR is set without ADMIN_TLS_CLIENT_CA"
+                ),
+            }),
+        cors: std::env::var("CORS_ALLOW_ORIGIN").ok().map(|origins| {
+            Cors::new(
+                &origins,
+                std::env::var("CORS_EXPOSE_HEADERS").ok().as_deref(),
+            )
+        }),
+        wire_canary: Canary::new(
+            std::env::var("WIRE_CANARY_FRACTION")
+                .map(|v| v.parse().expect("WIRE_CANARY_FRACTION must be a number"))
+                .unwrap_or(0.0),
+        ),
+        journal,
+        id_scheme: std::env::var("ID_SCHEME")
+            .map(|v| v.parse().expect("ID_SCHEME must be uuid or ulid"))
+            .unwrap_or_default(),
+        reaction_kinds: match std::env::var("REACTION_KINDS") {
+            Ok(kinds) => kinds
+                .split(',')
+                .map(str::trim)
+                .filter(|kind| !kind.is_empty())
+                .map(String::from)
+                .collect(),
+            Err(_) => DEFAULT_REACTION_KINDS.map(String::from).to_vec(),
+        },
     });
 
-    // the address to bind to
-    let addr = SocketAddr::from((
-        [0, 0, 0, 0],
-        std::env::var("PORT")
-            .unwrap_or("3000".to_string())
-            .parse()
-            .expect("PORT must be a number"),
-    ));
+    if state.read_only {
+        println!("Running in read-only mode, write endpoints are disabled.");
+    }
 
-    // the tcp listener
-    let listener = match TcpListener::bind(addr).await {
-        Ok(listener) => {
-            println!("Listening on {}", listener.local_addr().unwrap());
-            listener
+    // pre-populate an empty database with the fixtures of SEED_DIR, e.g. for demos
+    if let (Ok(dir), false) = (std::env::var("SEED_DIR"), state.read_only) {
+        if state.all_uuids.lock().await.is_empty() {
+            let seeded = seed_from_dir(Path::new(&dir), &state)
+                .await
+                .expect("Failed to import the fixtures of SEED_DIR");
+            println!("Imported {seeded} messages from {dir}.");
+        } else {
+            println!("Database is not empty, skipping the fixtures of {dir}.");
         }
-        Err(e) => {
-            panic!("Failed to bind to {}: {}", addr, e);
+    }
+
+    // relay the mutations committed to the outbox to the mutation manager
+    spawn_relay(Arc::clone(&state));
+    if let Some(ttl) = mutation_ttl {
+        spawn_sweeper(Arc::clone(&state), ttl);
+    }
+    uploads::spawn_sweeper(Arc::clone(&state), upload_ttl);
+
+    if !incomplete_writes.is_empty() {
+        let replay = std::env::var("JOURNAL_REPLAY")
+            .map(|v| v.parse().expect("JOURNAL_REPLAY must be true or false"))
+            .unwrap_or(false);
+        println!(
+            "The journal has {} writes that were never completed:",
+            incomplete_writes.len()
+        );
+        for entry in &incomplete_writes {
+            if replay {
+                match replay_write(entry, Arc::clone(&state)).await {
+                    Ok(status) => println!(
+                        "  #{} {} {}: replayed, {status}",
+                        entry.id, entry.method, entry.uri
+                    ),
+                    Err(e) => println!(
+                        "  #{} {} {}: not replayed, {e}",
+                        entry.id, entry.method, entry.uri
+                    ),
+                }
+            } else {
+                println!("  #{} {} {}", entry.id, entry.method, entry.uri);
+            }
+        }
+    }
+    // the entries of the previous runs are accounted for, the journal starts over
+    if let Some(journal) = &state.journal {
+        journal
+            .truncate()
+            .expect("Failed to truncate the JOURNAL_PATH journal");
+    }
+
+    // the address to bind to
+    let port = std::env::var("PORT")
+        .unwrap_or("3000".to_string())
+        .parse()
+        .expect("PORT must be a number");
+    let bind_addr = std::env::var("BIND_ADDR").unwrap_or("0.0.0.0".to_string());
+    let addr = match resolve_bind_addr(&bind_addr, port).await {
+        Ok(addr) => addr,
+        Err(e) => panic!("Failed to resolve BIND_ADDR {bind_addr:?}: {e}"),
+    };
+
+    // listeners accepting the connections of the public address in parallel
+    let accept_loops: usize = std::env::var("ACCEPT_LOOPS")
+        .map(|v| v.parse().expect("ACCEPT_LOOPS must be a number"))
+        .unwrap_or(1);
+    assert!(accept_loops >= 1, "ACCEPT_LOOPS must be at least 1");
+    if accept_loops > 1 && cfg!(not(unix)) {
+        panic!("ACCEPT_LOOPS above 1 needs SO_REUSEPORT, which is only available on unix");
+    }
+
+    // the options of the listeners and of the connections they accept
+    let socket_options = {
+        let defaults = SocketOptions::default();
+        SocketOptions {
+            reuse_address: std::env::var("TCP_REUSEADDR")
+                .map(|v| v.parse().expect("TCP_REUSEADDR must be true or false"))
+                .unwrap_or(defaults.reuse_address),
+            // the accept loops each bind their own listener
+            reuse_port: accept_loops > 1,
+            backlog: std::env::var("TCP_BACKLOG")
+                .map(|v| v.parse().expect("TCP_BACKLOG must be a number"))
+                .unwrap_or(defaults.backlog),
+            nodelay: std::env::var("TCP_NODELAY")
+                .map(|v| v.parse().expect("TCP_NODELAY must be true or false"))
+                .unwrap_or(defaults.nodelay),
+            keepalive: std::env::var("TCP_KEEPALIVE_SECS").ok().map(|v| {
+                Duration::from_secs(v.parse().expect("TCP_KEEPALIVE_SECS must be a number"))
+            }),
         }
     };
 
-    // the main task that listens for incoming HTTP requests
-    let listener_task = async move {
-        loop {
-            // select between the listener accepting a new connection and the shutdown signal
-            tokio::select! {
-                // a new connection has been accepted
-                result = listener.accept() => {
-                    match result {
-                        Ok((stream, _)) => {
-                            tokio::spawn(handle_connection(stream, Arc::clone(&state)));
+    // the tcp listeners, the others bind the address of the first, whose port may have been
+    // picked by the system
+    let mut listeners = Vec::with_capacity(accept_loops);
+    let mut addr = addr;
+    for _ in 0..accept_loops {
+        match socket_options.bind(addr) {
+            Ok(listener) => {
+                addr = listener.local_addr().unwrap();
+                listeners.push(listener);
+            }
+            Err(e) => {
+                panic!("Failed to bind to {}: {}", addr, e);
+            }
+        }
+    }
+    if accept_loops > 1 {
+        println!("Listening on {addr} with {accept_loops} accept loops");
+    } else {
+        println!("Listening on {addr}");
+    }
+
+    // the listener for admin requests
+    if let Some(admin_addr) = admin_addr {
+        let addr = match resolve_bind_addr(&admin_addr, port).await {
+            Ok(addr) => addr,
+            Err(e) => panic!("Failed to resolve ADMIN_ADDR {admin_addr:?}: {e}"),
+        };
+        // a single accept loop, no other listener may share its address
+        let admin_options = SocketOptions {
+            reuse_port: false,
+            ..socket_options
+        };
+        let admin_listener = match admin_options.bind(addr) {
+            Ok(listener) => {
+                println!("Admin listening on {}", listener.local_addr().unwrap());
+                listener
+            }
+            Err(e) => {
+                panic!("Failed to bind to {}: {}", addr, e);
+            }
+        };
+        let state = Arc::clone(&state);
+        #[cfg(feature = "tls")]
+        let admin_tls = admin_tls.map(Arc::new);
+        tokio::spawn(async move {
+            loop {
+                match admin_listener.accept().await {
+                    Ok((stream, addr)) => {
+                        if let Err(e) = socket_options.configure(&stream) {
+                            eprintln!("Failed to configure admin connection: {}", e);
+                        }
+                        let state = Arc::clone(&state);
+                        #[cfg(feature = "tls")]
+                        if let Some(admin_tls) = admin_tls.clone() {
+                            tokio::spawn(async move {
+                                let handshake = tokio::time::timeout(
+                                    ADMIN_TLS_HANDSHAKE_TIMEOUT,
+                                    admin_tls.accept(stream),
+                                );
+                                match handshake.await {
+                                    Ok(Ok((stream, identity))) => {
+                                        handle_admin_connection(stream, Some(identity), state).await
+                                    }
+                                    Ok(Err(e)) => {
+                                        eprintln!("Rejected admin connection from {addr}: {e}")
+                                    }
+                                    Err(_) => eprintln!(
+                                        "Admin connection from {addr} timed out in the TLS handshake"
+                                    ),
+                                }
+                            });
+                            continue;
                         }
-                        Err(e) => {
-                            eprintln!("Failed to accept connection: {}", e);
-                        },
+                        #[cfg(not(feature = "tls"))]
+                        let _ = addr;
+                        tokio::spawn(handle_admin_connection(stream, None, state));
                     }
+                    Err(e) => eprintln!("Failed to accept admin connection: {}", e),
                 }
-                // the shutdown signal has been received
-                _ = shutdown_recv.recv() => {
-                    break;
+            }
+        });
+    }
+
+    // the tasks that listen for incoming HTTP requests
+    let listener_tasks: Vec<_> = listeners
+        .into_iter()
+        .map(|listener| {
+            let state = Arc::clone(&state);
+            let mut shutdown_recv = shutdown_send.subscribe();
+            async move {
+                loop {
+                    // select between the listener accepting a new connection and the shutdown
+                    // signal
+                    tokio::select! {
+                        // a new connection has been accepted
+                        result = listener.accept() => {
+                            match result {
+                                Ok((stream, _)) => {
+                                    if let Err(e) = socket_options.configure(&stream) {
+                                        eprintln!("Failed to configure connection: {}", e);
+                                    }
+                                    tokio::spawn(handle_connection(stream, Arc::clone(&state)));
+                                }
+                                Err(e) => {
+                                    eprintln!("Failed to accept connection: {}", e);
+                                },
+                            }
+                        }
+                        // the shutdown signal has been received
+                        _ = shutdown_recv.recv() => {
+                            break;
+                        }
+                    }
                 }
             }
-        }
-    };
+        })
+        .collect();
 
-    // spawn the tcp listener thread
-    let tcp_listener_thread = tokio::spawn(listener_task);
+    // spawn the tcp listener threads
+    let tcp_listener_threads: Vec<_> = listener_tasks.into_iter().map(tokio::spawn).collect();
 
     // leaving main thread to handle shutdown signal
 
@@ -154,14 +610,38 @@ async fn main() {
     println!("Shutting down...");
 
     // close the database connection
-    db_pool_cloned.close().await;
-    println!("Database connection closed.");
+    #[cfg(feature = "postgres")]
+    {
+        db_pool_cloned.close().await;
+        println!("Database connection closed.");
+    }
 
-    // send shutdown signal to the tcp listener
+    // send shutdown signal to the tcp listeners
     shutdown_send.send(()).ok();
 
-    // wait for the tcp listener to finish
-    tcp_listener_thread
-        .await
-        .expect("Failed to join server task");
+    // wait for the tcp listeners to finish
+    for tcp_listener_thread in tcp_listener_threads {
+        tcp_listener_thread
+            .await
+            .expect("Failed to join server task");
+    }
+}
+
+/// Resolves `BIND_ADDR`, which is either a full socket address (`[::1]:3000`, `host:3000`) or a
+/// hostname or ip literal (`::`, `localhost`) that is bound on `port`.
+async fn resolve_bind_addr(bind_addr: &str, port: u16) -> io::Result<SocketAddr> {
+    if let Ok(addr) = bind_addr.parse() {
+        return Ok(addr);
+    }
+    let host = bind_addr.trim_start_matches('[').trim_end_matches(']');
+    if let Ok(ip) = host.parse::<IpAddr>() {
+        return Ok(SocketAddr::new(ip, port));
+    }
+
+    // a hostname, with or without a port
+    let addrs = match bind_addr.rsplit_once(':') {
+        Some((_, p)) if p.parse::<u16>().is_ok() => lookup_host(bind_addr).await?.next(),
+        _ => lookup_host((bind_addr, port)).await?.next(),
+    };
+    addrs.ok_or_else(|| io::Error::new(io::ErrorKind::NotFound, "host resolved to no addresses"))
 }
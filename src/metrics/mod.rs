@@ -0,0 +1,152 @@
+use ahash::AHashMap;
+use serde::Serialize;
+use std::{
+    collections::BTreeMap,
+    str::FromStr,
+    sync::atomic::{AtomicU64, Ordering},
+    time::Duration,
+};
+use tokio::sync::Mutex;
+
+/// Target latencies per route, parsed from `route=ms` pairs, e.g. `page=50,post=20`.
+#[derive(Debug, Clone, Default)]
+pub struct LatencyBudgets(AHashMap<String, Duration>);
+
+impl FromStr for LatencyBudgets {
+    type Err = &'static str;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        s.split(',')
+            .map(str::trim)
+            .filter(|pair| !pair.is_empty())
+            .map(|pair| {
+                let (route, ms) = pair.split_once('=').ok_or("Expected route=ms")?;
+                let ms = ms
+                    .trim()
+                    .parse()
+                    .map_err(|_| "Budget must be milliseconds")?;
+                Ok((route.trim().to_string(), Duration::from_millis(ms)))
+            })
+            .collect::<Result<_, _>>()
+            .map(Self)
+    }
+}
+
+impl LatencyBudgets {
+    /// The route names of the budgets that aren't among `routes`, a typo would otherwise leave
+    /// the route without a budget.
+    pub fn unknown_routes(&self, routes: &[&str]) -> Vec<&str> {
+        let mut unknown: Vec<_> = self
+            .0
+            .keys()
+            .map(String::as_str)
+            .filter(|route| !routes.contains(route))
+            .collect();
+        unknown.sort_unstable();
+        unknown
+    }
+}
+
+#[derive(Debug, Default)]
+struct RouteStats {
+    requests: u64,
+    total: Duration,
+    max: Duration,
+    over_budget: u64,
+}
+
+#[derive(Serialize, Debug)]
+pub struct RouteReport {
+    requests: u64,
+    mean_ms: f64,
+    max_ms: f64,
+    budget_ms: Option<u128>,
+    over_budget: u64,
+    /// The fraction of the requests over budget.
+    violation_rate: f64,
+}
+
+/// The stats of every route requested so far by route name, next to the server-wide counters.
+#[derive(Serialize, Debug)]
+pub struct Report {
+    #[serde(flatten)]
+    routes: BTreeMap<&'static str, RouteReport>,
+    /// How many cached mutations expired before a client paginated through them.
+    expired_mutations: u64,
+}
+
+/// Request counts and latencies per route, and how often each route misses its budget.
+pub struct Metrics {
+    budgets: LatencyBudgets,
+    routes: Mutex<AHashMap<&'static str, RouteStats>>,
+    expired_mutations: AtomicU64,
+}
+
+impl Metrics {
+    pub fn new(budgets: LatencyBudgets) -> Self {
+        Self {
+            budgets,
+            routes: Mutex::new(AHashMap::new()),
+            expired_mutations: AtomicU64::new(0),
+        }
+    }
+
+    /// Records that `count` cached mutations expired.
+    pub fn record_expired_mutations(&self, count: u64) {
+        self.expired_mutations.fetch_add(count, Ordering::Relaxed);
+    }
+
+    /// Records a request to `route` answered in `elapsed`.
+    pub async fn record(&self, route: &'static str, elapsed: Duration) {
+        let mut routes = self.routes.lock().await;
+        let stats = routes.entry(route).or_default();
+        stats.requests += 1;
+        stats.total += elapsed;
+        stats.max = stats.max.max(elapsed);
+        if self
+            .budgets
+            .0
+            .get(route)
+            .is_some_and(|budget| elapsed > *budget)
+        {
+            stats.over_budget += 1;
+        }
+    }
+
+    /// The stats of every route requested so far, and the counters.
+    pub async fn report(&self) -> Report {
+        let routes = self
+            .routes
+            .lock()
+            .await
+            .iter()
+            .map(|(route, stats)| {
+                let report = RouteReport {
+                    requests: stats.requests,
+                    mean_ms: stats.total.as_secs_f64() * 1000.0 / stats.requests as f64,
+                    max_ms: stats.max.as_secs_f64() * 1000.0,
+                    budget_ms: self.budgets.0.get(*route).map(Duration::as_millis),
+                    over_budget: stats.over_budget,
+                    violation_rate: stats.over_budget as f64 / stats.requests as f64,
+                };
+                (*route, report)
+            })
+            .collect();
+        Report {
+            routes,
+            expired_mutations: self.expired_mutations.load(Ordering::Relaxed),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::route_names;
+
+    #[test]
+    fn budgets_of_unknown_routes_are_reported() {
+        let budgets: LatencyBudgets = "page=50, pgae=20,post=20,Put=5".parse().unwrap();
+        assert_eq!(budgets.unknown_routes(&route_names()), ["Put", "pgae"]);
+    }
+}
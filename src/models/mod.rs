@@ -1,11 +1,254 @@
 use serde::{Deserialize, Serialize};
+#[cfg(feature = "postgres")]
+use sqlx::{
+    encode::IsNull,
+    error::BoxDynError,
+    postgres::{PgArgumentBuffer, PgHasArrayType, PgTypeInfo, PgValueRef},
+    types::Json,
+    Decode, Encode, Postgres, Type,
+};
+use std::{
+    collections::BTreeMap,
+    fmt,
+    ops::Deref,
+    str::FromStr,
+    time::{SystemTime, UNIX_EPOCH},
+};
+#[cfg(feature = "bindings")]
+use ts_rs::TS;
 
-#[derive(Serialize, Deserialize)]
+#[derive(Serialize, Deserialize, Debug, Clone)]
+#[cfg_attr(feature = "postgres", derive(sqlx::FromRow))]
 /// The model of the `messages` table.
 pub struct Message {
     pub uuid: String,
     pub author: String,
     pub message: String,
+    /// The message this one replies to, `None` for a message that starts a thread.
+    pub parent_uuid: Option<String>,
     pub likes: i32,
     pub has_image: bool,
+    /// When the client says it last wrote the message, in milliseconds since the epoch, by its
+    /// own possibly wrong clock.
+    pub client_timestamp: Option<i64>,
+    /// When the server received the last write of the message, in milliseconds since the epoch,
+    /// likes and reactions included.
+    pub server_timestamp: i64,
+    /// When the server received the post of the message, in milliseconds since the epoch.
+    pub created_at: i64,
+    /// When the message was last changed by a `PUT` or `PATCH`, in milliseconds since the epoch,
+    /// its post time until then. Likes and reactions leave it be.
+    pub updated_at: i64,
+    /// When the message was deleted, in milliseconds since the epoch, `None` while it is live.
+    /// Deleted messages are left out of every read until they are restored or purged.
+    pub deleted_at: Option<i64>,
+    /// Counts the writes of the message from 1, so clients can tell their copy is stale.
+    pub revision: i64,
+    /// The reactions other than likes, which keep their own column.
+    pub reactions: Reactions,
+}
+
+impl Message {
+    /// Whether the message wasn't soft-deleted.
+    pub fn is_live(&self) -> bool {
+        self.deleted_at.is_none()
+    }
+}
+
+/// The kinds of reactions clients may react with unless `REACTION_KINDS` says otherwise.
+pub const DEFAULT_REACTION_KINDS: [&str; 5] = ["love", "laugh", "wow", "sad", "angry"];
+
+/// The count of each kind of reaction to a message, stored as a JSON object. Kinds nobody
+/// reacted with are left out, never 0.
+#[derive(Serialize, Deserialize, Debug, Clone, Default, PartialEq, Eq, Hash)]
+#[cfg_attr(feature = "bindings", derive(TS), ts(export))]
+pub struct Reactions(pub BTreeMap<String, i32>);
+
+impl Reactions {
+    /// Adds `delta` to the count of `kind`, which doesn't go below 0, returning the new count.
+    pub fn add(&mut self, kind: &str, delta: i32) -> i32 {
+        let count = self.0.get(kind).copied().unwrap_or(0);
+        let count = count.saturating_add(delta).max(0);
+        match count {
+            0 => self.0.remove(kind),
+            _ => self.0.insert(kind.to_string(), count),
+        };
+        count
+    }
+}
+
+#[cfg(feature = "postgres")]
+impl Type<Postgres> for Reactions {
+    fn type_info() -> PgTypeInfo {
+        Json::<Self>::type_info()
+    }
+}
+
+#[cfg(feature = "postgres")]
+impl PgHasArrayType for Reactions {
+    fn array_type_info() -> PgTypeInfo {
+        Json::<Self>::array_type_info()
+    }
+}
+
+#[cfg(feature = "postgres")]
+impl Encode<'_, Postgres> for Reactions {
+    fn encode_by_ref(&self, buf: &mut PgArgumentBuffer) -> IsNull {
+        Json(self).encode_by_ref(buf)
+    }
+}
+
+#[cfg(feature = "postgres")]
+impl Decode<'_, Postgres> for Reactions {
+    fn decode(value: PgValueRef<'_>) -> Result<Self, BoxDynError> {
+        Ok(Json::<Self>::decode(value)?.0)
+    }
+}
+
+/// A field of a partial update, `Absent` when the client left it out. `Maybe<Option<T>>` tells
+/// a field left out apart from one set to `null`, which `Option<Option<T>>` can't with serde.
+///
+/// Fields of this type need `#[serde(default)]` to be optional.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum Maybe<T> {
+    #[default]
+    Absent,
+    Present(T),
+}
+
+impl<T> Maybe<T> {
+    pub fn is_present(&self) -> bool {
+        matches!(self, Self::Present(_))
+    }
+
+    /// The value if present, `None` if absent.
+    pub fn present(self) -> Option<T> {
+        match self {
+            Self::Absent => None,
+            Self::Present(value) => Some(value),
+        }
+    }
+}
+
+impl<'de, T: Deserialize<'de>> Deserialize<'de> for Maybe<T> {
+    fn deserialize<D: serde::Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        // only called for fields that are present, absent ones get the default
+        T::deserialize(deserializer).map(Self::Present)
+    }
+}
+
+/// The header write responses carry the `server_timestamp` of the write in, so clients can
+/// measure how far their clock is off.
+pub const SERVER_TIMESTAMP_HEADER: &str = "X-Server-Timestamp";
+
+/// The header a client names a like with, so a retry of it isn't counted twice.
+pub const IDEMPOTENCY_KEY_HEADER: &str = "Idempotency-Key";
+
+/// The current time in milliseconds since the epoch, as stored in `server_timestamp`.
+pub fn timestamp_now() -> i64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_millis() as i64
+}
+
+/// The format message ids must have, set with `ID_SCHEME`. Ids are also the names of the image
+/// files, neither scheme can escape the images directory.
+#[derive(Serialize, Debug, Clone, Copy, Default, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum IdScheme {
+    /// Hyphenated UUIDs, e.g. `67e55044-10b1-426f-9247-bb680e5fe0c8`, in either case. They are
+    /// kept in lowercase, so an id is the same message whatever its case.
+    #[default]
+    Uuid,
+    /// Canonical (uppercase) ULIDs, e.g. `01ARZ3NDEKTSV4RRFFQ69G5FAV`. They start with their
+    /// creation time, so pages ordered by id are in creation order.
+    Ulid,
+}
+
+impl IdScheme {
+    /// `id` in the case the messages are kept with: lowercase for UUIDs, ULIDs are only valid
+    /// in uppercase already.
+    pub fn normalize(self, id: String) -> String {
+        match self {
+            Self::Uuid if id.bytes().any(|c| c.is_ascii_uppercase()) => id.to_ascii_lowercase(),
+            _ => id,
+        }
+    }
+
+    fn is_valid(self, id: &str) -> bool {
+        let id = id.as_bytes();
+        match self {
+            Self::Uuid => {
+                id.len() == 36
+                    && id.iter().enumerate().all(|(i, c)| match i {
+                        8 | 13 | 18 | 23 => *c == b'-',
+                        _ => c.is_ascii_hexdigit(),
+                    })
+            }
+            Self::Ulid => {
+                // Crockford's base32 without I, L, O and U, the first character only holds 3
+                // bits of the 48 bit timestamp
+                id.len() == 26
+                    && id[0] <= b'7'
+                    && id.iter().all(|c| {
+                        matches!(c, b'0'..=b'9' | b'A'..=b'Z')
+                            && !matches!(c, b'I' | b'L' | b'O' | b'U')
+                    })
+            }
+        }
+    }
+}
+
+impl FromStr for IdScheme {
+    type Err = &'static str;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "uuid" => Ok(Self::Uuid),
+            "ulid" => Ok(Self::Ulid),
+            _ => Err("Unknown id scheme"),
+        }
+    }
+}
+
+/// A message id that is valid in the configured [`IdScheme`]. It derefs to the `str` the
+/// storage layers keep it as.
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub struct MessageId(String);
+
+impl MessageId {
+    /// Validates `id` against `scheme`, normalizing its case.
+    ///
+    /// # Errors
+    ///
+    /// This function will return an error if `id` isn't in the format of `scheme`.
+    pub fn parse(id: impl Into<String>, scheme: IdScheme) -> Result<Self, &'static str> {
+        let id = id.into();
+        if !scheme.is_valid(&id) {
+            return Err(match scheme {
+                IdScheme::Uuid => "Message ids must be hyphenated UUIDs.",
+                IdScheme::Ulid => "Message ids must be canonical uppercase ULIDs.",
+            });
+        }
+        Ok(Self(scheme.normalize(id)))
+    }
+
+    pub fn into_inner(self) -> String {
+        self.0
+    }
+}
+
+impl Deref for MessageId {
+    type Target = str;
+
+    fn deref(&self) -> &str {
+        &self.0
+    }
+}
+
+impl fmt::Display for MessageId {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(&self.0)
+    }
 }
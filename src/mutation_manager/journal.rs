@@ -0,0 +1,182 @@
+use super::Kind;
+use crate::models::timestamp_now;
+use serde::{Deserialize, Serialize};
+use std::{
+    fs::{File, OpenOptions},
+    io::{self, BufRead, BufReader},
+    path::{Path, PathBuf},
+};
+use tokio::io::AsyncWriteExt;
+
+/// The file of the mutation directory holding the journal. Mutation files are named after
+/// uuids, hidden files are left to the journal.
+const JOURNAL_FILE: &str = ".journal.jsonl";
+
+/// A journal holding fewer records than this isn't compacted.
+const COMPACTION_MIN_RECORDS: usize = 4096;
+
+/// A change to the queues of the [`MutationManager`](super::MutationManager), a line of the
+/// journal. The times are in milliseconds since the epoch, records journaled before they were
+/// get the time they are replayed at.
+#[derive(Serialize, Deserialize, Debug)]
+#[serde(tag = "op", rename_all = "snake_case")]
+pub enum Record {
+    /// A post was enqueued, replacing a pending put of its uuid.
+    Post {
+        uuid: String,
+        #[serde(default = "timestamp_now")]
+        at: i64,
+    },
+    /// A put was enqueued, those collapsed into the pending mutation of their uuid aren't
+    /// journaled.
+    Put {
+        uuid: String,
+        #[serde(default = "timestamp_now")]
+        at: i64,
+    },
+    /// A delete was enqueued, dropping the pending mutation of its uuid.
+    Delete {
+        uuid: String,
+        #[serde(default = "timestamp_now")]
+        at: i64,
+    },
+    /// A pagination queued the pending mutations, starting the next epoch.
+    Paginate {
+        #[serde(default = "timestamp_now")]
+        at: i64,
+    },
+    /// The first `count` queued mutations were served and dropped.
+    Served { count: usize },
+    /// The mutations that were pending or queued since before `before` expired, see
+    /// [`MutationManager::expire`](super::MutationManager::expire).
+    Expire { before: i64 },
+    /// A fresh pagination served the changes of the expired mutations.
+    Resync,
+    /// A mutation queued by a pagination, written by compaction.
+    Queued {
+        kind: Kind,
+        uuid: String,
+        epoch: u64,
+        #[serde(default = "timestamp_now")]
+        at: i64,
+    },
+    /// The epoch of the pending mutations and whether mutations expired since the last resync,
+    /// written by compaction.
+    Epoch {
+        epoch: u64,
+        #[serde(default)]
+        resync: bool,
+    },
+    /// The mutations were cleared.
+    Clear,
+    /// The outbox was relayed up to the entry `id`, see
+    /// [`MutationManager::relayed`](super::MutationManager::relayed).
+    Relayed { id: i64 },
+}
+
+/// An append-only journal of the changes to the queues of the mutation manager, which only
+/// live in memory otherwise. It is replayed at startup, so clients are still served the
+/// mutations whose pages they haven't acknowledged after a restart.
+///
+/// Records are buffered and only appended by [`MutationJournal::sync`], which the outbox relay
+/// calls before acknowledging what it relayed. A crash thus loses changes that are either
+/// relayed again, or pages acknowledged since the last sync, which are served again.
+pub struct MutationJournal {
+    path: PathBuf,
+    file: tokio::fs::File,
+    pending: Vec<u8>,
+    /// How many records the journal holds, the pending ones included.
+    records: usize,
+}
+
+impl MutationJournal {
+    /// Opens the journal of `mutation_dir`, returning it with the records of the previous runs.
+    ///
+    /// # Errors
+    ///
+    /// This function will return an error if the journal can't be read or opened for appending.
+    pub fn open(mutation_dir: &Path) -> io::Result<(Self, Vec<Record>)> {
+        let path = mutation_dir.join(JOURNAL_FILE);
+        let mut records = Vec::new();
+        match File::open(&path) {
+            Ok(file) => {
+                for line in BufReader::new(file).lines() {
+                    // the last line is torn when the crash happened while appending it
+                    let Ok(record) = serde_json::from_str(&line?) else {
+                        continue;
+                    };
+                    records.push(record);
+                }
+            }
+            Err(e) if e.kind() == io::ErrorKind::NotFound => {}
+            Err(e) => return Err(e),
+        }
+
+        let file = OpenOptions::new().create(true).append(true).open(&path)?;
+        let journal = Self {
+            path,
+            file: tokio::fs::File::from_std(file),
+            pending: Vec::new(),
+            records: records.len(),
+        };
+        Ok((journal, records))
+    }
+
+    /// Buffers `record` until the next [`MutationJournal::sync`].
+    pub fn append(&mut self, record: &Record) {
+        serde_json::to_writer(&mut self.pending, record).unwrap();
+        self.pending.push(b'\n');
+        self.records += 1;
+    }
+
+    /// Appends the buffered records and waits for them to be on disk.
+    ///
+    /// # Errors
+    ///
+    /// This function will return an error if the records couldn't be persisted, they are
+    /// appended again by the next sync.
+    pub async fn sync(&mut self) -> io::Result<()> {
+        if self.pending.is_empty() {
+            return Ok(());
+        }
+        self.file.write_all(&self.pending).await?;
+        self.file.sync_data().await?;
+        self.pending.clear();
+        Ok(())
+    }
+
+    /// Whether the journal holds many more records than the `live` ones that rebuild the
+    /// queues as they are.
+    pub fn needs_compaction(&self, live: usize) -> bool {
+        self.records >= COMPACTION_MIN_RECORDS && self.records > 2 * live
+    }
+
+    /// Replaces the journal with `records`, which rebuild the queues as they are, dropping the
+    /// buffered records they supersede.
+    ///
+    /// # Errors
+    ///
+    /// This function will return an error if the new journal couldn't be written, the old one
+    /// is kept then.
+    pub async fn compact(&mut self, records: Vec<Record>) -> io::Result<()> {
+        let mut contents = Vec::new();
+        for record in &records {
+            serde_json::to_writer(&mut contents, record).unwrap();
+            contents.push(b'\n');
+        }
+
+        let tmp_path = self.path.with_extension("tmp");
+        let mut tmp = tokio::fs::File::create(&tmp_path).await?;
+        tmp.write_all(&contents).await?;
+        tmp.sync_data().await?;
+        tokio::fs::rename(&tmp_path, &self.path).await?;
+
+        self.file = tokio::fs::OpenOptions::new()
+            .append(true)
+            .open(&self.path)
+            .await?;
+        self.pending.clear();
+        self.records = records.len();
+        Ok(())
+    }
+}
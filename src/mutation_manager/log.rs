@@ -0,0 +1,446 @@
+use ahash::{AHashMap, AHashSet};
+use serde::de::DeserializeOwned;
+use sha2::{Digest, Sha256};
+use std::{
+    collections::{BTreeMap, BTreeSet},
+    fs::{File, OpenOptions},
+    io,
+    os::unix::fs::FileExt,
+    path::{Path, PathBuf},
+    sync::Arc,
+};
+
+/// The extension of the segment files of the mutation directory, named after their number.
+const SEGMENT_EXTENSION: &str = "segment";
+
+/// Payloads are appended to a new segment once the last one is this large.
+pub(super) const SEGMENT_BYTES: u64 = 8 * 1024 * 1024;
+
+/// A record is the length of its key, the length of its payload, a checksum of both, the key
+/// and the payload.
+const HEADER_LEN: usize = 1 + 4 + 4;
+
+/// Runs the blocking `f` on the blocking pool, so the file I/O doesn't stall the worker awaiting
+/// it.
+async fn unblock<T, F>(f: F) -> io::Result<T>
+where
+    T: Send + 'static,
+    F: FnOnce() -> io::Result<T> + Send + 'static,
+{
+    tokio::task::spawn_blocking(f)
+        .await
+        .map_err(io::Error::other)?
+}
+
+/// The first bytes of the SHA-256 of a record, a record torn by a crash doesn't match it.
+fn checksum(key: &[u8], payload: &[u8]) -> [u8; 4] {
+    let digest = Sha256::new()
+        .chain_update(key)
+        .chain_update(payload)
+        .finalize();
+    digest[..4].try_into().unwrap()
+}
+
+fn encode(key: &str, payload: &[u8], record: &mut Vec<u8>) {
+    let key = key.as_bytes();
+    assert!(
+        key.len() <= u8::MAX as usize,
+        "keys are shorter than 256 bytes"
+    );
+    record.push(key.len() as u8);
+    record.extend_from_slice(&(payload.len() as u32).to_le_bytes());
+    record.extend_from_slice(&checksum(key, payload));
+    record.extend_from_slice(key);
+    record.extend_from_slice(payload);
+}
+
+/// The key and payload of the record at the start of `data`, `None` if it is torn.
+fn decode(data: &[u8]) -> Option<(&str, &[u8])> {
+    let header = data.get(..HEADER_LEN)?;
+    let key_len = header[0] as usize;
+    let payload_len = u32::from_le_bytes(header[1..5].try_into().unwrap()) as usize;
+    let key = data.get(HEADER_LEN..HEADER_LEN + key_len)?;
+    let payload = data.get(HEADER_LEN + key_len..HEADER_LEN + key_len + payload_len)?;
+    if header[5..9] != checksum(key, payload) {
+        return None;
+    }
+    Some((std::str::from_utf8(key).ok()?, payload))
+}
+
+struct Segment {
+    file: Arc<File>,
+    len: u64,
+    /// The length of the records the index points to, the others were superseded or removed.
+    live: u64,
+}
+
+/// Where the payload of a key is.
+#[derive(Clone, Copy)]
+struct Location {
+    segment: u64,
+    /// The offset of the record in the segment.
+    offset: u64,
+    len: u32,
+}
+
+impl Location {
+    /// The length of the record, whose key is `key_len` long.
+    fn record_len(&self, key_len: usize) -> u64 {
+        (HEADER_LEN + key_len) as u64 + self.len as u64
+    }
+}
+
+/// A payload of the log, read without the log, e.g. once the lock of the mutation manager is
+/// released. It stays readable after being removed or compacted away, as it holds its segment
+/// open.
+#[derive(Clone)]
+pub struct Payload {
+    file: Arc<File>,
+    offset: u64,
+    len: u32,
+}
+
+impl Payload {
+    /// Reads the payload, blocking.
+    pub fn read(&self) -> io::Result<Vec<u8>> {
+        let mut payload = vec![0; self.len as usize];
+        self.file.read_exact_at(&mut payload, self.offset)?;
+        Ok(payload)
+    }
+
+    /// Reads and deserializes the payload, blocking.
+    pub fn decode<T: DeserializeOwned>(&self) -> io::Result<T> {
+        bincode::deserialize(&self.read()?)
+            .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))
+    }
+}
+
+/// The payloads of the mutations, appended to the segment files of the mutation directory
+/// rather than written to a file each, and found through an index of their keys kept in
+/// memory. A payload written again supersedes the previous one, which is left in its segment.
+///
+/// The index is rebuilt at startup by reading the segments in order, the last record of a key
+/// winning. Segments left mostly unused once the payloads of a pagination are removed are
+/// compacted: their live records are appended to the last segment and they are removed.
+pub struct MutationLog {
+    dir: PathBuf,
+    segments: BTreeMap<u64, Segment>,
+    /// The segment appended to, the last one.
+    active: u64,
+    index: AHashMap<String, Location>,
+    /// The segments appended to since the last [`MutationLog::sync`].
+    unsynced: BTreeSet<u64>,
+}
+
+impl MutationLog {
+    /// Opens the log of `mutation_dir`, indexing the records of its segments. The torn record a
+    /// crash left at the end of a segment is cut off. The mutation files of the layout before
+    /// the log, one per mutation named after its key, are moved into the log.
+    ///
+    /// # Errors
+    ///
+    /// This function will return an error if a segment or a mutation file can't be read, or the
+    /// segment to append to can't be created.
+    pub fn open(mutation_dir: &Path) -> io::Result<Self> {
+        let mut segment_ids = Vec::new();
+        let mut legacy = Vec::new();
+        for entry in std::fs::read_dir(mutation_dir)? {
+            let entry = entry?;
+            let name = entry.file_name();
+            // the hidden files are the journal's and the queue's
+            let Some(name) = name.to_str().filter(|name| !name.starts_with('.')) else {
+                continue;
+            };
+            if !entry.file_type()?.is_file() {
+                continue;
+            }
+            match name
+                .strip_suffix(SEGMENT_EXTENSION)
+                .and_then(|id| id.strip_suffix('.')?.parse().ok())
+            {
+                Some(id) => segment_ids.push(id),
+                None if name.len() <= u8::MAX as usize => legacy.push(name.to_string()),
+                None => (),
+            }
+        }
+        segment_ids.sort_unstable();
+
+        let mut log = Self {
+            dir: mutation_dir.to_path_buf(),
+            segments: BTreeMap::new(),
+            active: 0,
+            index: AHashMap::new(),
+            unsynced: BTreeSet::new(),
+        };
+        for id in segment_ids {
+            let path = log.path(id);
+            let data = std::fs::read(&path)?;
+            let file = OpenOptions::new().read(true).write(true).open(&path)?;
+            log.segments.insert(
+                id,
+                Segment {
+                    file: Arc::new(file),
+                    len: 0,
+                    live: 0,
+                },
+            );
+            let mut offset = 0;
+            while let Some((key, payload)) = decode(&data[offset..]) {
+                let location = Location {
+                    segment: id,
+                    offset: offset as u64,
+                    len: payload.len() as u32,
+                };
+                offset += location.record_len(key.len()) as usize;
+                log.index(key.to_string(), location);
+            }
+            if offset < data.len() {
+                eprintln!("The mutation segment {id} ends with a torn record, it is cut off.");
+                log.segments[&id].file.set_len(offset as u64)?;
+            }
+            log.segments.get_mut(&id).unwrap().len = offset as u64;
+            log.active = id;
+        }
+        log.roll()?;
+
+        for key in &legacy {
+            let path = log.dir.join(key);
+            let payload = std::fs::read(&path)?;
+            let mut record = Vec::new();
+            encode(key, &payload, &mut record);
+            let segment = &log.segments[&log.active];
+            segment.file.write_all_at(&record, segment.len)?;
+            log.appended(vec![(key.clone(), payload.len() as u32)]);
+        }
+        if !legacy.is_empty() {
+            // the files are only removed once their payloads are on disk
+            log.segments[&log.active].file.sync_data()?;
+            for key in &legacy {
+                std::fs::remove_file(log.dir.join(key))?;
+            }
+        }
+        Ok(log)
+    }
+
+    fn path(&self, id: u64) -> PathBuf {
+        self.dir.join(format!("{id:08}.{SEGMENT_EXTENSION}"))
+    }
+
+    /// Appends to a new segment from now on.
+    fn roll(&mut self) -> io::Result<()> {
+        let id = self.active + 1;
+        let file = OpenOptions::new()
+            .read(true)
+            .write(true)
+            .create(true)
+            .truncate(true)
+            .open(self.path(id))?;
+        self.segments.insert(
+            id,
+            Segment {
+                file: Arc::new(file),
+                len: 0,
+                live: 0,
+            },
+        );
+        self.active = id;
+        Ok(())
+    }
+
+    /// Points `key` to `location`, the payload it had before becoming unused.
+    fn index(&mut self, key: String, location: Location) {
+        let key_len = key.len();
+        if let Some(segment) = self.segments.get_mut(&location.segment) {
+            segment.live += location.record_len(key_len);
+        }
+        if let Some(old) = self.index.insert(key, location) {
+            if let Some(segment) = self.segments.get_mut(&old.segment) {
+                segment.live -= old.record_len(key_len);
+            }
+        }
+    }
+
+    /// Indexes the records of the keys and payload lengths of `records`, just written at the
+    /// end of the active segment.
+    fn appended(&mut self, records: Vec<(String, u32)>) {
+        for (key, len) in records {
+            let location = Location {
+                segment: self.active,
+                offset: self.segments[&self.active].len,
+                len,
+            };
+            self.segments.get_mut(&self.active).unwrap().len += location.record_len(key.len());
+            self.index(key, location);
+        }
+        self.unsynced.insert(self.active);
+    }
+
+    /// Appends the `payloads` of their keys in a single write, superseding the payloads they
+    /// had.
+    ///
+    /// # Errors
+    ///
+    /// This function will return an error if the payloads couldn't be written, the keys keep
+    /// the payloads they had then.
+    pub async fn append(&mut self, payloads: Vec<(String, Vec<u8>)>) -> io::Result<()> {
+        if self.segments[&self.active].len >= SEGMENT_BYTES {
+            self.roll()?;
+        }
+        let mut records = Vec::new();
+        for (key, payload) in &payloads {
+            encode(key, payload, &mut records);
+        }
+        let segment = &self.segments[&self.active];
+        let (file, offset) = (Arc::clone(&segment.file), segment.len);
+        // a failed write is overwritten by the next one, as the length isn't advanced
+        unblock(move || file.write_all_at(&records, offset)).await?;
+        self.appended(
+            payloads
+                .into_iter()
+                .map(|(key, payload)| (key, payload.len() as u32))
+                .collect(),
+        );
+        Ok(())
+    }
+
+    /// Appends `payload` as the payload of `key`.
+    ///
+    /// # Errors
+    ///
+    /// This function will return an error if the payload couldn't be written.
+    pub async fn put(&mut self, key: String, payload: Vec<u8>) -> io::Result<()> {
+        self.append(vec![(key, payload)]).await
+    }
+
+    /// The payload of `key`, `None` if it has none.
+    pub fn get(&self, key: &str) -> Option<Payload> {
+        let location = self.index.get(key)?;
+        Some(Payload {
+            file: Arc::clone(&self.segments[&location.segment].file),
+            offset: location.offset + (HEADER_LEN + key.len()) as u64,
+            len: location.len,
+        })
+    }
+
+    /// Removes the payload of `key`, left in its segment until the segment is compacted.
+    pub fn remove(&mut self, key: &str) {
+        if let Some(location) = self.index.remove(key) {
+            if let Some(segment) = self.segments.get_mut(&location.segment) {
+                segment.live -= location.record_len(key.len());
+            }
+        }
+    }
+
+    /// Keeps the payloads of the `used` keys, e.g. those the journal refers to at startup, and
+    /// removes the segments that only hold the others.
+    ///
+    /// # Errors
+    ///
+    /// This function will return an error if an unused segment can't be removed.
+    pub fn retain(&mut self, used: &AHashSet<String>) -> io::Result<()> {
+        let unused: Vec<_> = self
+            .index
+            .keys()
+            .filter(|key| !used.contains(*key))
+            .cloned()
+            .collect();
+        for key in unused {
+            self.remove(&key);
+        }
+        let empty: Vec<_> = self
+            .segments
+            .iter()
+            .filter(|(&id, segment)| id != self.active && segment.live == 0)
+            .map(|(&id, _)| id)
+            .collect();
+        for id in empty {
+            self.segments.remove(&id);
+            std::fs::remove_file(self.path(id))?;
+        }
+        Ok(())
+    }
+
+    /// Waits for the records appended since the last call to be on disk.
+    ///
+    /// # Errors
+    ///
+    /// This function will return an error if a segment couldn't be synced, it is synced again
+    /// by the next call.
+    pub async fn sync(&mut self) -> io::Result<()> {
+        let files: Vec<_> = self
+            .unsynced
+            .iter()
+            .filter_map(|id| self.segments.get(id))
+            .map(|segment| Arc::clone(&segment.file))
+            .collect();
+        if files.is_empty() {
+            return Ok(());
+        }
+        unblock(move || files.iter().try_for_each(|file| file.sync_data())).await?;
+        self.unsynced.clear();
+        Ok(())
+    }
+
+    /// Compacts the segments before the active one that are at least half unused, and the
+    /// active one once it is entirely unused: their live records are appended to the active
+    /// segment, and once they are on disk the segments are removed. Payloads read meanwhile are still read from the removed segments.
+    ///
+    /// # Errors
+    ///
+    /// This function will return an error if the live records couldn't be moved, the segments
+    /// are compacted again by the next call then.
+    pub async fn compact(&mut self) -> io::Result<()> {
+        // an active segment holding only unused records is removed too
+        let active = &self.segments[&self.active];
+        if active.live == 0 && active.len > 0 {
+            self.roll()?;
+        }
+        let compacted: AHashSet<u64> = self
+            .segments
+            .iter()
+            .filter(|(&id, segment)| id != self.active && segment.live * 2 <= segment.len)
+            .map(|(&id, _)| id)
+            .collect();
+        if compacted.is_empty() {
+            return Ok(());
+        }
+
+        let live: Vec<_> = self
+            .index
+            .iter()
+            .filter(|(_, location)| compacted.contains(&location.segment))
+            .filter_map(|(key, _)| Some((key.clone(), self.get(key)?)))
+            .collect();
+        if !live.is_empty() {
+            let payloads = unblock(move || {
+                live.into_iter()
+                    .map(|(key, payload)| Ok((key, payload.read()?)))
+                    .collect::<io::Result<Vec<_>>>()
+            })
+            .await?;
+            self.append(payloads).await?;
+            self.sync().await?;
+        }
+
+        let paths: Vec<_> = compacted
+            .into_iter()
+            .filter_map(|id| self.segments.remove(&id).map(|_| self.path(id)))
+            .collect();
+        unblock(move || paths.iter().try_for_each(std::fs::remove_file)).await
+    }
+
+    /// Removes every payload and segment.
+    ///
+    /// # Errors
+    ///
+    /// This function will return an error if a segment couldn't be removed or the segment to
+    /// append to can't be created.
+    pub async fn clear(&mut self) -> io::Result<()> {
+        let paths: Vec<_> = self.segments.keys().map(|&id| self.path(id)).collect();
+        self.segments.clear();
+        self.index.clear();
+        self.unsynced.clear();
+        unblock(move || paths.iter().try_for_each(std::fs::remove_file)).await?;
+        self.roll()
+    }
+}
@@ -1,13 +1,26 @@
 use crate::{
     handlers::{CompleteMessage, PaginationMetadata, PaginationType},
-    image, try_write_perm,
+    image::ImageStorage,
+    models::{timestamp_now, Message, Reactions},
+    try_write_perm,
 };
-use ahash::AHashSet;
-use serde::{Deserialize, Serialize};
-use std::{collections::VecDeque, fmt, path::PathBuf};
+use ahash::{AHashMap, AHashSet};
+use journal::{MutationJournal, Record};
+use log::{MutationLog, Payload};
+use queue::SpillQueue;
+use serde::{de::DeserializeOwned, Deserialize, Serialize};
+use std::{fmt, io, path::Path, time::Duration};
+#[cfg(feature = "bindings")]
 use ts_rs::TS;
 
-#[derive(Serialize, Debug)]
+mod journal;
+mod log;
+mod queue;
+mod sweeper;
+
+pub use sweeper::spawn_sweeper;
+
+#[derive(Serialize, Deserialize, Debug, Clone, Copy)]
 enum Kind {
     #[serde(rename = "post")]
     Post,
@@ -27,22 +40,127 @@ impl fmt::Display for Kind {
     }
 }
 
-#[derive(Serialize, Debug)]
+#[derive(Serialize, Debug, Clone)]
 pub struct Entry {
     kind: Kind,
     uuid: String,
+    /// The epoch of the payload, see [`MutationManager::epoch`].
+    epoch: u64,
+    /// When the entry was queued, in milliseconds since the epoch.
+    at: i64,
+}
+
+/// The key of the payload of the mutation of `uuid` in `epoch` in the log.
+fn key(uuid: &str, epoch: u64) -> String {
+    format!("{uuid}.{epoch}")
+}
+
+impl Entry {
+    /// The key of the payload of the entry, `None` for a delete.
+    fn key(&self) -> Option<String> {
+        match self.kind {
+            Kind::Delete => None,
+            Kind::Post | Kind::Put => Some(key(&self.uuid, self.epoch)),
+        }
+    }
+
+    /// Reads the mutation of the entry from its `payload`, blocking. `None` if the entry has
+    /// no payload anymore, e.g. because the cache was cleared, and
+    /// [`MutationError::Corrupt`] if the payload doesn't deserialize.
+    fn read(self, payload: Option<&Payload>) -> Result<Option<PendingMutation>, MutationError> {
+        Ok(match (self.kind, payload) {
+            (Kind::Delete, _) => Some(PendingMutation::Delete(self.uuid)),
+            (_, None) => None,
+            (Kind::Post, Some(payload)) => {
+                Some(PendingMutation::Post(decode(payload, &self.uuid)?))
+            }
+            (Kind::Put, Some(payload)) => Some(PendingMutation::Put {
+                update: decode(payload, &self.uuid)?,
+                uuid: self.uuid,
+            }),
+        })
+    }
+}
+
+/// Deserializes `payload`, the payload of a mutation of `uuid`, blocking.
+fn decode<T: DeserializeOwned>(payload: &Payload, uuid: &str) -> Result<T, MutationError> {
+    payload.decode().map_err(|e| match e.kind() {
+        io::ErrorKind::InvalidData => MutationError::Corrupt {
+            uuid: uuid.to_string(),
+        },
+        _ => MutationError::Io(e),
+    })
+}
+
+/// Reads the mutations of `entries` from their payloads on the blocking pool, in the same order.
+/// Entries without a payload are left out, and so are those whose payload is corrupt, which are
+/// logged.
+///
+/// # Errors
+///
+/// This function will return an error if a payload couldn't be read.
+async fn read_entries(
+    entries: Vec<(Entry, Option<Payload>)>,
+) -> Result<Vec<PendingMutation>, MutationError> {
+    let read = tokio::task::spawn_blocking(move || {
+        entries
+            .into_iter()
+            .map(|(entry, payload)| entry.read(payload.as_ref()))
+            .collect::<Vec<_>>()
+    })
+    .await
+    .map_err(|e| MutationError::Io(io::Error::other(e)))?;
+
+    let mut mutations = Vec::with_capacity(read.len());
+    for mutation in read {
+        match mutation {
+            Ok(Some(mutation)) => mutations.push(mutation),
+            Ok(None) => (),
+            Err(e @ MutationError::Corrupt { .. }) => eprintln!("Skipping a mutation: {e}"),
+            Err(e) => return Err(e),
+        }
+    }
+    Ok(mutations)
 }
 
-#[derive(Serialize, Debug, TS)]
-#[ts(export)]
+/// An error of the mutation manager, which fails the request it happened in rather than the
+/// server.
+#[derive(Debug)]
+pub enum MutationError {
+    /// The payload of the mutation of `uuid` doesn't deserialize, e.g. because it was corrupted
+    /// on disk. Reading it again won't help, the mutation is skipped.
+    Corrupt { uuid: String },
+    /// The payloads or the queue couldn't be read or written.
+    Io(io::Error),
+}
+
+impl fmt::Display for MutationError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            MutationError::Corrupt { uuid } => write!(f, "The mutation of {uuid} is corrupt."),
+            MutationError::Io(e) => write!(f, "Mutation I/O error: {e}"),
+        }
+    }
+}
+
+impl std::error::Error for MutationError {}
+
+impl From<io::Error> for MutationError {
+    fn from(e: io::Error) -> Self {
+        MutationError::Io(e)
+    }
+}
+
+#[derive(Serialize, Debug)]
+#[cfg_attr(feature = "bindings", derive(TS), ts(export))]
 pub struct PutDeleteUpdate {
     uuid: String,
     put: Option<ClientPutUpdate>,
     delete: bool,
 }
 
-#[derive(Serialize, Debug, TS)]
-#[ts(export)]
+#[derive(Serialize, Debug)]
+#[cfg_attr(feature = "bindings", derive(TS), ts(export))]
 pub struct MutationResults {
     pub posts: Vec<CompleteMessage>,
     pub puts_deletes: Vec<PutDeleteUpdate>,
@@ -70,35 +188,63 @@ impl Default for MutationResults {
 #[derive(Serialize, Debug, Deserialize)]
 /// The update that is saved to the mutation directory
 pub struct ServerPutUpdate {
-    pub author: String,
-    pub message: String,
+    /// `None` when only the likes or the image changed.
+    pub author: Option<String>,
+    pub message: Option<String>,
+    /// Set along with the author and message, `None` then meaning no parent.
+    pub parent_uuid: Option<String>,
     pub likes: i32,
     pub image_updated: bool,
-    pub image: Option<String>,
+    pub client_timestamp: Option<i64>,
+    pub server_timestamp: i64,
+    /// `None` for likes and reactions, which don't update the message.
+    pub updated_at: Option<i64>,
+    pub revision: i64,
+    /// `None` when the reactions didn't change.
+    pub reactions: Option<Reactions>,
 }
 
 #[derive(Serialize, Debug, Deserialize)]
 pub struct ServerPutUpdateWithoutImage {
-    pub author: String,
-    pub message: String,
+    pub author: Option<String>,
+    pub message: Option<String>,
+    pub parent_uuid: Option<String>,
     pub likes: i32,
     pub image_updated: bool,
+    /// How many puts were collapsed into this one.
+    pub change_count: u32,
+    /// The timestamps and revision of the last of the collapsed puts.
+    pub client_timestamp: Option<i64>,
+    pub server_timestamp: i64,
+    /// The update time of the last of the collapsed puts that set one.
+    pub updated_at: Option<i64>,
+    pub revision: i64,
+    /// The reactions of the last of the collapsed puts that changed them.
+    pub reactions: Option<Reactions>,
 }
 
 impl ServerPutUpdateWithoutImage {
-    fn update(&mut self, other: ServerPutUpdate, base_image_path: &PathBuf, uuid: &str) {
-        self.author = other.author;
-        self.message = other.message;
+    fn update(&mut self, other: ServerPutUpdate) {
+        // a likes-only put keeps the text of an earlier one
+        if other.author.is_some() {
+            self.author = other.author;
+            self.parent_uuid = other.parent_uuid;
+        }
+        if other.message.is_some() {
+            self.message = other.message;
+        }
+        if other.reactions.is_some() {
+            self.reactions = other.reactions;
+        }
         self.likes = other.likes;
         self.image_updated = other.image_updated || self.image_updated;
-        if other.image_updated {
-            if let Some(image) = other.image {
-                image::save(base_image_path, &image, uuid).ok();
-            } else {
-                // image is removed
-                image::remove(base_image_path, uuid).ok();
-            }
+        self.change_count += 1;
+        self.client_timestamp = other.client_timestamp;
+        self.server_timestamp = other.server_timestamp;
+        if other.updated_at.is_some() {
+            self.updated_at = other.updated_at;
         }
+        self.revision = other.revision;
     }
 }
 
@@ -107,52 +253,215 @@ pub struct MessageWithoutImage {
     pub uuid: String,
     pub author: String,
     pub message: String,
+    pub parent_uuid: Option<String>,
     pub likes: i32,
+    pub client_timestamp: Option<i64>,
+    pub server_timestamp: i64,
+    pub created_at: i64,
+    pub updated_at: i64,
+    pub revision: i64,
+    pub reactions: Reactions,
 }
 
 impl MessageWithoutImage {
-    pub fn update(&mut self, put: ServerPutUpdate, image_base_path: &PathBuf) {
-        self.author = put.author;
-        self.message = put.message;
+    pub fn update(&mut self, put: ServerPutUpdate) {
+        if let Some(author) = put.author {
+            self.author = author;
+            self.parent_uuid = put.parent_uuid;
+        }
+        if let Some(message) = put.message {
+            self.message = message;
+        }
+        if let Some(reactions) = put.reactions {
+            self.reactions = reactions;
+        }
         self.likes = put.likes;
-        if put.image_updated {
-            if let Some(image) = put.image {
-                image::save(image_base_path, &image, &self.uuid).ok();
-            } else {
-                image::remove(image_base_path, &self.uuid).ok();
-            }
-        };
+        self.client_timestamp = put.client_timestamp;
+        self.server_timestamp = put.server_timestamp;
+        if let Some(updated_at) = put.updated_at {
+            self.updated_at = updated_at;
+        }
+        self.revision = put.revision;
     }
 }
 
-#[derive(Serialize, Debug, Deserialize, TS)]
-#[ts(export)]
-/// The update that the client sees.
+#[derive(Serialize, Debug, Deserialize)]
+#[cfg_attr(feature = "bindings", derive(TS), ts(export))]
+/// The update that the client sees. `author` and `message` are `None` when only the likes or
+/// the image changed since the last sync, `reactions` when they didn't change.
 pub struct ClientPutUpdate {
-    pub author: String,
-    pub message: String,
+    pub author: Option<String>,
+    pub message: Option<String>,
+    /// Sent along with `author` and `message`, `None` then meaning the message has no parent.
+    pub parent_uuid: Option<String>,
     pub likes: i32,
     pub image: Option<String>,
+    /// How many puts were collapsed into this update.
+    pub change_count: u32,
+    /// The timestamps and revision of the last of the collapsed puts, see [`CompleteMessage`].
+    pub client_timestamp: Option<i64>,
+    pub server_timestamp: i64,
+    /// When the last of the collapsed puts that changed the message was made, `None` when they
+    /// only changed the likes or reactions.
+    pub updated_at: Option<i64>,
+    pub revision: i64,
+    pub reactions: Option<Reactions>,
 }
 
 impl ClientPutUpdate {
-    fn new(update: ServerPutUpdateWithoutImage, image_base_path: &PathBuf, uuid: &str) -> Self {
-        let image = if update.image_updated {
-            let image = image::get(image_base_path, uuid);
-            if let Some(image) = image {
-                Some(image)
-            } else {
-                // image is removed
-                Some("".to_string())
-            }
-        } else {
-            None
-        };
+    /// `image` is the image of the message if the update changed it, empty if it was removed.
+    fn new(update: ServerPutUpdateWithoutImage, image: Option<String>) -> Self {
         Self {
             author: update.author,
             likes: update.likes,
             message: update.message,
+            parent_uuid: update.parent_uuid,
             image,
+            change_count: update.change_count,
+            client_timestamp: update.client_timestamp,
+            server_timestamp: update.server_timestamp,
+            updated_at: update.updated_at,
+            revision: update.revision,
+            reactions: update.reactions,
+        }
+    }
+}
+
+/// A mutation waiting to be served by a cache pagination, see [`MutationManager::pending`].
+#[derive(Debug)]
+pub enum PendingMutation {
+    Post(MessageWithoutImage),
+    Put {
+        uuid: String,
+        update: ServerPutUpdateWithoutImage,
+    },
+    Delete(String),
+}
+
+/// The mutations pending at some point, whose payloads are read once the lock of the manager
+/// is released, see [`MutationManager::pending`].
+pub struct PendingEntries {
+    entries: Vec<(Entry, Option<Payload>)>,
+}
+
+impl PendingEntries {
+    /// Reads the mutations, leaving out those without a payload anymore or with a corrupt one.
+    ///
+    /// # Errors
+    ///
+    /// This function will return an error if a payload couldn't be read.
+    pub async fn read(self) -> Result<Vec<PendingMutation>, MutationError> {
+        read_entries(self.entries).await
+    }
+}
+
+/// The entries of a cache page, whose payloads are read once the lock of the manager is
+/// released, see [`MutationManager::page`].
+pub struct PageEntries {
+    entries: Vec<(Entry, Option<Payload>)>,
+    page_number: usize,
+    done: bool,
+}
+
+impl PageEntries {
+    /// Reads the mutations of the page along with the images of its posts and of the puts that
+    /// changed theirs, which are read concurrently. Entries without a payload, e.g. because the
+    /// cache was cleared, are left out, and so are those with a corrupt one.
+    ///
+    /// # Errors
+    ///
+    /// This function will return an error if a payload couldn't be read.
+    pub async fn read(self, images: &dyn ImageStorage) -> Result<MutationResults, MutationError> {
+        let mutations = read_entries(self.entries).await?;
+
+        let uuids: Vec<_> = mutations
+            .iter()
+            .filter_map(|mutation| match mutation {
+                PendingMutation::Post(message) => Some(message.uuid.as_str()),
+                PendingMutation::Put { uuid, update } if update.image_updated => {
+                    Some(uuid.as_str())
+                }
+                _ => None,
+            })
+            .collect();
+        let mut images = images.get_many(&uuids).await.into_iter();
+
+        let mut result = MutationResults {
+            page_number: self.page_number,
+            done: self.done,
+            ..Default::default()
+        };
+        for mutation in mutations {
+            match mutation {
+                PendingMutation::Post(message_without_image) => {
+                    let image = images.next().flatten();
+                    let complete_message = CompleteMessage {
+                        author: message_without_image.author,
+                        image: image
+                            .as_ref()
+                            .map_or_else(String::new, |image| image.base64.clone()),
+                        image_metadata: image.map(|image| image.metadata.clone()),
+                        likes: message_without_image.likes,
+                        message: message_without_image.message,
+                        parent_uuid: message_without_image.parent_uuid,
+                        uuid: message_without_image.uuid,
+                        client_timestamp: message_without_image.client_timestamp,
+                        server_timestamp: message_without_image.server_timestamp,
+                        created_at: message_without_image.created_at,
+                        updated_at: message_without_image.updated_at,
+                        revision: message_without_image.revision,
+                        reactions: message_without_image.reactions,
+                    };
+                    result.posts.push(complete_message);
+                }
+                PendingMutation::Put { uuid, update } => {
+                    let image = update.image_updated.then(|| {
+                        images
+                            .next()
+                            .flatten()
+                            .map_or_else(String::new, |image| image.base64.clone())
+                    });
+                    result.puts_deletes.push(PutDeleteUpdate {
+                        put: Some(ClientPutUpdate::new(update, image)),
+                        uuid,
+                        delete: false,
+                    });
+                }
+                PendingMutation::Delete(uuid) => {
+                    result.puts_deletes.push(PutDeleteUpdate {
+                        uuid,
+                        put: None,
+                        delete: true,
+                    });
+                }
+            }
+        }
+
+        Ok(result)
+    }
+}
+
+/// The part of the queue a cache pagination session pages through, in positions counted from
+/// the first entry queued since startup, which don't change as entries are dropped.
+struct Cursor {
+    /// The first entry of the session.
+    from: usize,
+    /// The entry after the last one of the session.
+    to: usize,
+    /// The entries before this one were acknowledged.
+    acked: usize,
+    /// The entries before this one were served.
+    served: usize,
+}
+
+impl Cursor {
+    /// The first entry the session may still serve.
+    fn needed_from(&self) -> usize {
+        // the pages of a finished session are only re-fetched until its entries are dropped
+        if self.served >= self.to {
+            self.to
+        } else {
+            self.acked
         }
     }
 }
@@ -161,40 +470,420 @@ pub struct MutationManager {
     updates_post: AHashSet<String>,
     updates_put: AHashSet<String>,
     updates_delete: Vec<String>,
-    mutation_dir: PathBuf,
-    updates_all: VecDeque<Entry>,
+    /// When each uuid with a pending mutation first changed since the last pagination, in
+    /// milliseconds since the epoch.
+    pending_at: AHashMap<String, i64>,
+    /// The entries of the cache paginations, only the first of which are kept in memory.
+    updates_all: SpillQueue,
+    /// The position of the first entry of `updates_all`.
+    base: usize,
+    /// The entries before this position were covered by a session, those after it were
+    /// restored from the journal and wait for the next session.
+    covered: usize,
+    /// The cursors of the cache pagination sessions by id, which share the queue. An entry is
+    /// dropped once every session covering it is finished or acknowledged it.
+    cursors: AHashMap<u64, Cursor>,
+    /// The keys of the payloads of the dropped entries, removed by the next sync of the journal.
+    dropped: Vec<String>,
+    /// Counts the paginations. The payloads of the pending mutations are keyed by the current
+    /// epoch, which a pagination freezes for its pages: mutations arriving meanwhile get
+    /// payloads of the next epoch instead of changing the pages.
+    epoch: u64,
+    /// The payloads of the mutations.
+    log: MutationLog,
+    /// Whether mutations expired since the last fresh default pagination, which clients need to
+    /// get the changes they missed.
+    resync: bool,
     page_size: usize,
+    /// The id of the last outbox entry relayed, see [`MutationManager::relayed`].
+    relayed: i64,
+    /// The id `relayed` was last journaled at.
+    journaled_relayed: i64,
+    /// The changes to the queues above, replayed at startup.
+    journal: MutationJournal,
 }
 
 impl MutationManager {
-    pub fn new(page_size: usize) -> Self {
-        let s = Self {
+    /// Creates the manager of the mutations of `MUTATIONS_BASE_PATH`, rebuilding the queues of
+    /// the previous run from its journal. Payloads no queue refers to, e.g. left behind by a
+    /// crash, are dropped from the log. The entries of the cache paginations past the first
+    /// `queue_memory_entries` are spilled to the mutation directory.
+    pub fn new(page_size: usize, queue_memory_entries: usize) -> Self {
+        let mutation_dir = {
+            let path =
+                std::env::var("MUTATIONS_BASE_PATH").expect("MUTATIONS_BASE_PATH must be set");
+            let path = std::path::Path::new(&path).to_path_buf();
+            // check if this path directory exists
+            if !std::path::Path::new(&path).exists() {
+                panic!("MUTATIONS_BASE_PATH directory does not exist, the given path is {path:?}.");
+            }
+            // try writing and deleting a file to check if we have write permissions
+            try_write_perm(&path);
+            path
+        };
+        Self::open(&mutation_dir, page_size, queue_memory_entries)
+    }
+
+    /// Creates the manager of the mutations of `mutation_dir`, like [`MutationManager::new`]
+    /// does for `MUTATIONS_BASE_PATH`.
+    pub fn open(mutation_dir: &Path, page_size: usize, queue_memory_entries: usize) -> Self {
+        let (journal, records) =
+            MutationJournal::open(mutation_dir).expect("Failed to open the mutation journal");
+        let updates_all = SpillQueue::new(mutation_dir, queue_memory_entries)
+            .expect("Failed to open the mutation queue");
+        let log = MutationLog::open(mutation_dir).expect("Failed to open the mutation log");
+        let mut s = Self {
             updates_post: AHashSet::with_capacity(50_000usize.next_power_of_two()),
             updates_put: AHashSet::with_capacity(10_000usize.next_power_of_two()),
             updates_delete: Vec::with_capacity(10_000usize.next_power_of_two()),
-            mutation_dir: {
-                let path =
-                    std::env::var("MUTATIONS_BASE_PATH").expect("MUTATIONS_BASE_PATH must be set");
-                let path = std::path::Path::new(&path).to_path_buf();
-                // check if this path directory exists
-                if !std::path::Path::new(&path).exists() {
-                    panic!(
-                        "MUTATIONS_BASE_PATH directory does not exist, the given path is {path:?}."
-                    );
-                }
-                // try writing and deleting a file to check if we have write permissions
-                try_write_perm(&path);
-                path
-            },
-            updates_all: VecDeque::with_capacity(50_000usize.next_power_of_two()),
+            pending_at: AHashMap::new(),
+            updates_all,
+            // the sessions of before the restart are gone, their entries are served again
+            base: 0,
+            covered: 0,
+            cursors: AHashMap::new(),
+            dropped: Vec::new(),
+            epoch: 0,
+            log,
+            resync: false,
             page_size,
+            relayed: 0,
+            journaled_relayed: 0,
+            journal,
         };
-        MutationManager::clear_dir(&s.mutation_dir).ok();
+        for record in &records {
+            s.apply(record)
+                .expect("Failed to rebuild the mutation queue from its journal");
+        }
+        s.journaled_relayed = s.relayed;
+        let used = s
+            .used_keys()
+            .expect("Failed to read the mutation queue back");
+        s.log
+            .retain(&used)
+            .expect("Failed to drop the unused mutation payloads");
         s
     }
 
+    /// Applies `record` to the queues, both when the change is made and when the journal is
+    /// replayed.
+    ///
+    /// # Errors
+    ///
+    /// This function will return an error if the queued entries couldn't be spilled or read
+    /// back, the queues are left as they were then.
+    fn apply(&mut self, record: &Record) -> io::Result<()> {
+        match record {
+            Record::Post { uuid, at } => {
+                // the post superseded the payload of the put
+                self.updates_put.remove(uuid);
+                self.updates_post.insert(uuid.clone());
+                self.pending_at.entry(uuid.clone()).or_insert(*at);
+            }
+            Record::Put { uuid, at } => {
+                self.updates_put.insert(uuid.clone());
+                self.pending_at.entry(uuid.clone()).or_insert(*at);
+            }
+            Record::Delete { uuid, at } => {
+                // remove from updates_put if it exists, the image stays until the message is
+                // purged
+                self.updates_put.remove(uuid);
+
+                // remove from updates_post if it exists, the delete of a message deleted before
+                // it was posted again stays pending
+                if self.updates_post.remove(uuid) {
+                    if !self.updates_delete.contains(uuid) {
+                        self.pending_at.remove(uuid);
+                    }
+                } else {
+                    self.updates_delete.push(uuid.clone());
+                    self.pending_at.entry(uuid.clone()).or_insert(*at);
+                }
+            }
+            Record::Paginate { at } => {
+                self.paginate(*at)?;
+                self.epoch += 1;
+            }
+            Record::Epoch { epoch, resync } => {
+                self.epoch = *epoch;
+                self.resync = *resync;
+            }
+            Record::Served { count } => self.updates_all.drop_front(*count),
+            Record::Expire { before } => {
+                let queued = self.queued_before(*before)?;
+                self.updates_all.drop_front(queued);
+
+                let expired: AHashSet<String> = self
+                    .pending_at
+                    .iter()
+                    .filter(|(_, at)| **at < *before)
+                    .map(|(uuid, _)| uuid.clone())
+                    .collect();
+                self.pending_at.retain(|uuid, _| !expired.contains(uuid));
+                self.updates_post.retain(|uuid| !expired.contains(uuid));
+                self.updates_put.retain(|uuid| !expired.contains(uuid));
+                self.updates_delete.retain(|uuid| !expired.contains(uuid));
+                self.resync = true;
+            }
+            Record::Resync => self.resync = false,
+            Record::Queued {
+                kind,
+                uuid,
+                epoch,
+                at,
+            } => self.updates_all.push_back(Entry {
+                kind: *kind,
+                uuid: uuid.clone(),
+                epoch: *epoch,
+                at: *at,
+            })?,
+            Record::Clear => {
+                self.updates_post.clear();
+                self.updates_put.clear();
+                self.updates_delete.clear();
+                self.pending_at.clear();
+                self.updates_all.clear();
+            }
+            Record::Relayed { id } => self.relayed = *id,
+        }
+        Ok(())
+    }
+
+    /// Applies `record` and journals it.
+    ///
+    /// # Errors
+    ///
+    /// This function will return an error if the record couldn't be applied, it isn't journaled
+    /// then.
+    fn commit(&mut self, record: Record) -> io::Result<()> {
+        self.apply(&record)?;
+        self.journal.append(&record);
+        Ok(())
+    }
+
+    /// How many of the queued entries were queued before `before`, the first ones as snapshots
+    /// are queued in order.
+    fn queued_before(&self, before: i64) -> io::Result<usize> {
+        let mut count = 0;
+        for entry in self.updates_all.iter() {
+            if entry?.at >= before {
+                break;
+            }
+            count += 1;
+        }
+        Ok(count)
+    }
+
+    /// Persists the payloads and the changes journaled since the last call, compacting the
+    /// journal once it is mostly made of superseded changes, then removes the payloads of the
+    /// dropped entries and compacts the segments they leave mostly unused. Called by the outbox
+    /// relay, at least every second.
+    ///
+    /// # Errors
+    ///
+    /// This function will return an error if the log or the journal couldn't be written, or the
+    /// queued entries couldn't be read back to compact the journal.
+    pub async fn sync_journal(&mut self) -> io::Result<()> {
+        // the journal never refers to a payload that isn't on disk
+        self.log.sync().await?;
+        if self.relayed != self.journaled_relayed {
+            // persisted along with the mutations the entries enqueued
+            self.journal.append(&Record::Relayed { id: self.relayed });
+            self.journaled_relayed = self.relayed;
+        }
+        self.write_journal().await?;
+        // only once the drops are persisted, a replayed entry must still find its payload
+        if !self.dropped.is_empty() {
+            for key in self.dropped.drain(..) {
+                self.log.remove(&key);
+            }
+            // the entries of a finished pagination, or expired ones, were dropped
+            self.log.compact().await?;
+        }
+        Ok(())
+    }
+
+    async fn write_journal(&mut self) -> io::Result<()> {
+        let live = self.updates_all.len()
+            + self.updates_post.len()
+            + self.updates_put.len()
+            + self.updates_delete.len();
+        if !self.journal.needs_compaction(live) {
+            return self.journal.sync().await;
+        }
+
+        let epoch = Record::Epoch {
+            epoch: self.epoch,
+            resync: self.resync,
+        };
+        let queued: Vec<_> = self
+            .updates_all
+            .iter()
+            .map(|entry| {
+                entry.map(|entry| Record::Queued {
+                    kind: entry.kind,
+                    uuid: entry.uuid,
+                    epoch: entry.epoch,
+                    at: entry.at,
+                })
+            })
+            .collect::<io::Result<_>>()?;
+        let at = |uuid: &String| {
+            self.pending_at
+                .get(uuid)
+                .copied()
+                .unwrap_or_else(timestamp_now)
+        };
+        let posts = self.updates_post.iter().map(|uuid| Record::Post {
+            uuid: uuid.clone(),
+            at: at(uuid),
+        });
+        let puts = self.updates_put.iter().map(|uuid| Record::Put {
+            uuid: uuid.clone(),
+            at: at(uuid),
+        });
+        let deletes = self.updates_delete.iter().map(|uuid| Record::Delete {
+            uuid: uuid.clone(),
+            at: at(uuid),
+        });
+        let relayed = Record::Relayed { id: self.relayed };
+        let records = [epoch, relayed]
+            .into_iter()
+            .chain(queued)
+            .chain(posts)
+            .chain(puts)
+            .chain(deletes)
+            .collect();
+        self.journal.compact(records).await
+    }
+
+    /// The keys of the payloads the queues refer to.
+    fn used_keys(&self) -> io::Result<AHashSet<String>> {
+        let mut used: AHashSet<String> = self
+            .updates_post
+            .iter()
+            .chain(&self.updates_put)
+            .map(|uuid| key(uuid, self.epoch))
+            .collect();
+        for entry in self.updates_all.iter() {
+            used.extend(entry?.key());
+        }
+        Ok(used)
+    }
+
+    /// Drops the first `count` queued entries, which were served. Their payloads are removed by
+    /// the next sync of the journal.
+    fn drop_served(&mut self, count: usize) -> Result<(), MutationError> {
+        let count = count.min(self.updates_all.len());
+        if count == 0 {
+            return Ok(());
+        }
+        let dropped = self.payload_keys(count);
+        self.commit(Record::Served { count })?;
+        self.dropped.extend(dropped);
+        self.base += count;
+        // the entries after the dropped ones are moved back to memory
+        self.updates_all.refill()?;
+        Ok(())
+    }
+
+    /// The keys of the payloads of the first `count` queued entries, to be removed once they
+    /// are dropped. Those whose entries can't be read back are left out, and removed at the
+    /// next startup along with the other payloads no entry uses.
+    fn payload_keys(&self, count: usize) -> Vec<String> {
+        match self.updates_all.range(0, count) {
+            Ok(entries) => entries.iter().filter_map(Entry::key).collect(),
+            Err(e) => {
+                eprintln!("Failed to read the dropped mutations back: {e}");
+                Vec::new()
+            }
+        }
+    }
+
+    /// Drops the entries every session is done with, keeping those no session covered yet.
+    fn collect_garbage(&mut self) -> Result<(), MutationError> {
+        let needed = self
+            .cursors
+            .values()
+            .map(Cursor::needed_from)
+            .fold(self.covered, usize::min);
+        self.drop_served(needed.saturating_sub(self.base))
+    }
+
+    /// Forgets the cursors of the sessions that aren't in `sessions` anymore, and drops the
+    /// entries the remaining ones are done with.
+    ///
+    /// # Errors
+    ///
+    /// This function will return an error if the spilled entries couldn't be moved back to
+    /// memory once the entries were dropped.
+    pub fn retain_sessions(
+        &mut self,
+        sessions: impl Iterator<Item = u64>,
+    ) -> Result<(), MutationError> {
+        let sessions: AHashSet<u64> = sessions.collect();
+        self.cursors.retain(|id, _| sessions.contains(id));
+        self.collect_garbage()
+    }
+
+    /// Drops the mutations that were pending or queued for longer than `ttl`, e.g. because no
+    /// client paginated since, along with their payloads, returning how many expired. Queued
+    /// entries expire `ttl` after the pagination queuing them, the pages of a session still
+    /// paging through them are gone then. The next default pagination is fresh once mutations
+    /// expired, see [`MutationManager::needs_resync`].
+    ///
+    /// # Errors
+    ///
+    /// This function will return an error if the queued entries couldn't be read back, nothing
+    /// expires then.
+    pub fn expire(&mut self, ttl: Duration) -> Result<usize, MutationError> {
+        let before = timestamp_now().saturating_sub(ttl.as_millis() as i64);
+        let queued = self.queued_before(before)?;
+        let pending: Vec<_> = self
+            .pending_at
+            .iter()
+            .filter(|(_, at)| **at < before)
+            .map(|(uuid, _)| uuid.clone())
+            .collect();
+        if queued == 0 && pending.is_empty() {
+            return Ok(0);
+        }
+
+        let mut dropped = self.payload_keys(queued);
+        for uuid in &pending {
+            if self.updates_post.contains(uuid) || self.updates_put.contains(uuid) {
+                dropped.push(key(uuid, self.epoch));
+            }
+        }
+        self.commit(Record::Expire { before })?;
+        self.dropped.extend(dropped);
+        self.base += queued;
+        self.updates_all.refill()?;
+        Ok(queued + pending.len())
+    }
+
+    /// Whether mutations expired since the last fresh default pagination, the clients that
+    /// missed them get the whole dataset from the next one.
+    pub fn needs_resync(&self) -> bool {
+        self.resync
+    }
+
+    /// Records that a fresh default pagination was triggered after mutations expired.
+    ///
+    /// # Errors
+    ///
+    /// This function will return an error like the other changes to the queues, though clearing
+    /// the flag doesn't touch them.
+    pub fn resynced(&mut self) -> Result<(), MutationError> {
+        if self.resync {
+            self.commit(Record::Resync)?;
+        }
+        Ok(())
+    }
+
+    /// Whether no entry is queued, e.g. once every session is done with them.
     pub fn is_pagination_empty(&self) -> bool {
-        self.updates_all.is_empty()
+        self.updates_all.len() == 0
     }
 
     pub fn is_empty_for_pagination(&self) -> bool {
@@ -203,70 +892,75 @@ impl MutationManager {
             && self.updates_delete.is_empty()
     }
 
-    pub fn add_post(
-        &mut self,
-        message: CompleteMessage,
-        image_base_path: &PathBuf,
-        image_updated: bool,
-    ) {
-        // save the message to the mutation directory
-        let path = self.get_mutation_file_path(&message.uuid);
-        if image_updated {
-            image::save(image_base_path, &message.image, &message.uuid).ok();
-        };
+    /// Enqueues a post. Its image, like those of puts, is left to the image store, where it is
+    /// read from when the page is served.
+    ///
+    /// # Errors
+    ///
+    /// This function will return an error if the payload couldn't be written, the post isn't
+    /// enqueued then.
+    pub async fn add_post(&mut self, message: CompleteMessage) -> Result<(), MutationError> {
         let message_without_image = MessageWithoutImage {
             author: message.author,
             likes: message.likes,
             message: message.message,
+            parent_uuid: message.parent_uuid,
             uuid: message.uuid,
+            client_timestamp: message.client_timestamp,
+            server_timestamp: message.server_timestamp,
+            created_at: message.created_at,
+            updated_at: message.updated_at,
+            revision: message.revision,
+            reactions: message.reactions,
         };
-        let encoded = bincode::serialize(&message_without_image).unwrap();
-        std::fs::write(path, encoded).unwrap();
-        self.updates_post.insert(message_without_image.uuid);
+        self.write_pending(&message_without_image.uuid, &message_without_image)
+            .await?;
+        self.commit(Record::Post {
+            uuid: message_without_image.uuid,
+            at: timestamp_now(),
+        })?;
+        Ok(())
     }
 
-    pub fn add_delete(&mut self, uuid: &str, image_base_path: &PathBuf) {
-        // remove from updates_put if it exists
-        self.updates_put.remove(uuid);
-
-        // remove image file if any
-        image::remove(image_base_path, uuid).ok();
-
-        // remove from updates_post if it exists
-        if !self.updates_post.remove(uuid) {
-            self.updates_delete.push(uuid.to_string());
-        }
+    /// Enqueues a delete, dropping the pending post or put of `uuid`.
+    ///
+    /// # Errors
+    ///
+    /// This function will return an error like the other changes to the queues, though a delete
+    /// doesn't touch the queued entries.
+    pub fn add_delete(&mut self, uuid: &str) -> Result<(), MutationError> {
+        self.commit(Record::Delete {
+            uuid: uuid.to_string(),
+            at: timestamp_now(),
+        })?;
+        Ok(())
     }
 
-    pub fn add_put(&mut self, uuid: &str, put: ServerPutUpdate, image_base_path: &PathBuf) {
-        let path = self.get_mutation_file_path(uuid);
-
+    /// Enqueues a put, collapsed into the pending post or put of `uuid` if it has one.
+    ///
+    /// # Errors
+    ///
+    /// This function will return an error if the pending mutation of `uuid` is corrupt or
+    /// couldn't be read, or the payload couldn't be written, the put isn't enqueued then.
+    pub async fn add_put(&mut self, uuid: &str, put: ServerPutUpdate) -> Result<(), MutationError> {
         // if there's a post update of this uuid, modify it rather than adding to updates_put
         if self.updates_post.contains(uuid) {
-            // retrieve the message from the file
-            let file_content = std::fs::read(&path).expect("Failed to read put mutation file");
-            let mut message_without_image: MessageWithoutImage =
-                bincode::deserialize(&file_content).expect("Failed to deserialize message");
+            // retrieve the message from the log
+            let mut message_without_image: MessageWithoutImage = self.read_pending(uuid).await?;
 
             // overwrite the message with the new values
-            message_without_image.update(put, image_base_path);
+            message_without_image.update(put);
 
-            // write back to the file
-            let encoded = bincode::serialize(&message_without_image).unwrap();
-            std::fs::write(&path, encoded).unwrap();
-            return;
+            // write it back, superseding the old payload
+            return self.write_pending(uuid, &message_without_image).await;
         }
 
         if self.updates_put.contains(uuid) {
-            // retrieve the message from the file
-            let file_content = std::fs::read(&path).expect("Failed to read put mutation file");
-            let mut update: ServerPutUpdateWithoutImage =
-                bincode::deserialize(&file_content).expect("Failed to deserialize message");
-            update.update(put, image_base_path, uuid);
-            // write back to the file
-            let encoded = bincode::serialize(&update).unwrap();
-            std::fs::write(&path, encoded).unwrap();
-            return;
+            // retrieve the update from the log
+            let mut update: ServerPutUpdateWithoutImage = self.read_pending(uuid).await?;
+            update.update(put);
+            // write it back, superseding the old payload
+            return self.write_pending(uuid, &update).await;
         }
 
         let put_without_image = ServerPutUpdateWithoutImage {
@@ -274,155 +968,611 @@ impl MutationManager {
             image_updated: put.image_updated,
             likes: put.likes,
             message: put.message,
+            parent_uuid: put.parent_uuid,
+            change_count: 1,
+            client_timestamp: put.client_timestamp,
+            server_timestamp: put.server_timestamp,
+            updated_at: put.updated_at,
+            revision: put.revision,
+            reactions: put.reactions,
         };
-        if put.image_updated {
-            if let Some(image) = put.image {
-                image::save(image_base_path, &image, uuid).ok();
-            } else {
-                // image is removed
-                image::remove(image_base_path, uuid).ok();
-            }
-        }
 
-        // create new file for this uuid
-        let encoded = bincode::serialize(&put_without_image).unwrap();
-        std::fs::write(path, encoded).unwrap();
+        // the first payload of this uuid in the epoch
+        self.write_pending(uuid, &put_without_image).await?;
 
         // add to updates_put
-        self.updates_put.insert(uuid.to_string());
+        self.commit(Record::Put {
+            uuid: uuid.to_string(),
+            at: timestamp_now(),
+        })?;
+        Ok(())
     }
 
-    pub fn get_pagination_meta(&mut self) -> PaginationMetadata {
-        let mut posts: Vec<_> = self
+    /// Enqueues `messages` as posts, replacing their pending puts, so the next cache pagination
+    /// carries the whole dataset. Their images are already in the image store.
+    ///
+    /// # Errors
+    ///
+    /// This function will return an error if a payload couldn't be written, the messages before
+    /// it are enqueued.
+    pub async fn replay_posts(&mut self, messages: Vec<Message>) -> Result<(), MutationError> {
+        for message in messages {
+            self.add_post(CompleteMessage::new(message, String::new(), None))
+                .await?;
+        }
+        Ok(())
+    }
+
+    /// The id of the last outbox entry relayed. The outbox relay skips the entries up to it, so
+    /// those relayed but not acknowledged, e.g. before a crash, aren't enqueued twice.
+    pub fn relayed(&self) -> i64 {
+        self.relayed
+    }
+
+    /// Records that the outbox entry `id` was relayed, which is journaled by the next
+    /// [`MutationManager::sync_journal`] along with the mutations it enqueued.
+    pub fn mark_relayed(&mut self, id: i64) {
+        self.relayed = id;
+    }
+
+    /// The mutations the next cache pages serve, including those queued for the running
+    /// pagination, without consuming them. A uuid appears once, as its latest mutation, in no
+    /// particular order.
+    ///
+    /// # Errors
+    ///
+    /// This function will return an error if the spilled entries couldn't be read back.
+    pub fn pending(&self) -> Result<PendingEntries, MutationError> {
+        let pending = |kind, uuid: &String| Entry {
+            kind,
+            uuid: uuid.clone(),
+            epoch: self.epoch,
+            at: self.pending_at.get(uuid).copied().unwrap_or_default(),
+        };
+        let posts = self
             .updates_post
-            .drain()
-            .map(|uuid| Entry {
-                kind: Kind::Post,
-                uuid,
-            })
-            .collect();
-        // sort posts by uuid
-        posts.sort_by(|a, b| a.uuid.cmp(&b.uuid));
-        self.updates_all.extend(posts);
-
-        let mut puts_deletes =
-            Vec::with_capacity(self.updates_put.len() + self.updates_delete.len());
-        let puts: Vec<_> = self
-            .updates_put
-            .drain()
-            .map(|uuid| Entry {
-                kind: Kind::Put,
-                uuid,
-            })
-            .collect();
-        puts_deletes.extend(puts);
-        let del: Vec<_> = self
+            .iter()
+            .map(|uuid| pending(Kind::Post, uuid));
+        let puts = self.updates_put.iter().map(|uuid| pending(Kind::Put, uuid));
+        let deletes = self
             .updates_delete
             .iter()
-            .map(|uuid| Entry {
-                kind: Kind::Delete,
-                uuid: uuid.to_string(),
-            })
+            .map(|uuid| pending(Kind::Delete, uuid));
+
+        // a later mutation of a uuid supersedes an earlier one, and the post of a uuid deleted
+        // and posted again its delete
+        let latest: AHashMap<String, Entry> = self
+            .updates_all
+            .iter()
+            .chain(deletes.chain(posts).chain(puts).map(Ok))
+            .map(|entry| entry.map(|entry| (entry.uuid.clone(), entry)))
+            .collect::<io::Result<_>>()?;
+
+        let entries = latest
+            .into_values()
+            .map(|entry| self.locate(entry))
             .collect();
-        self.updates_delete.clear();
-        puts_deletes.extend(del);
-        // sort puts_deletes by uuid
-        puts_deletes.sort_by(|a, b| a.uuid.cmp(&b.uuid));
-        self.updates_all.extend(puts_deletes);
+        Ok(PendingEntries { entries })
+    }
 
-        PaginationMetadata::new(
+    /// Starts the cache pagination `session`, over the entries still queued and the pending
+    /// mutations, which are queued as a new snapshot.
+    ///
+    /// # Errors
+    ///
+    /// This function will return an error if the snapshot couldn't be spilled, the session
+    /// isn't started and the mutations stay pending then.
+    pub fn get_pagination_meta(
+        &mut self,
+        session: u64,
+    ) -> Result<PaginationMetadata, MutationError> {
+        self.commit(Record::Paginate {
+            at: timestamp_now(),
+        })?;
+        let end = self.base + self.updates_all.len();
+        self.covered = end;
+        self.cursors.insert(
+            session,
+            Cursor {
+                from: self.base,
+                to: end,
+                acked: self.base,
+                served: self.base,
+            },
+        );
+        Ok(PaginationMetadata::new(
             self.updates_all.len(),
             self.page_size,
             PaginationType::Cache,
-        )
-    }
-
-    pub fn get(&mut self, page_number: usize, image_base_path: &PathBuf) -> MutationResults {
-        let mut result = MutationResults::default();
-        result.page_number = page_number;
-
-        // extract `page_size` updates from `updates_all` add them to `result`
-        for _ in 0..self.page_size {
-            if let Some(entry) = self.updates_all.pop_front() {
-                let path = self.get_mutation_file_path(&entry.uuid);
-                match entry.kind {
-                    Kind::Post => {
-                        let message_without_image =
-                            std::fs::read(&path).expect("Failed to read post mutation file");
-                        let message_without_image: MessageWithoutImage =
-                            bincode::deserialize(&message_without_image)
-                                .expect("Failed to parse post mutation file");
-                        let complete_message = CompleteMessage {
-                            author: message_without_image.author,
-                            image: image::get(image_base_path, &message_without_image.uuid)
-                                .unwrap_or("".to_string()),
-                            likes: message_without_image.likes,
-                            message: message_without_image.message,
-                            uuid: message_without_image.uuid,
-                        };
-                        result.posts.push(complete_message);
-                    }
-                    Kind::Put => {
-                        let server_update =
-                            std::fs::read(&path).expect("Failed to read put mutation file");
-                        let server_update: ServerPutUpdateWithoutImage =
-                            bincode::deserialize(&server_update)
-                                .expect("Failed to parse put mutation file");
-                        result.puts_deletes.push(PutDeleteUpdate {
-                            put: Some(ClientPutUpdate::new(
-                                server_update,
-                                image_base_path,
-                                &entry.uuid,
-                            )),
-                            uuid: entry.uuid,
-                            delete: false,
-                        });
-                    }
-                    Kind::Delete => {
-                        result.puts_deletes.push(PutDeleteUpdate {
-                            uuid: entry.uuid,
-                            put: None,
-                            delete: true,
-                        });
-                    }
-                }
-            } else {
-                // pagination is done
-                result.done = true;
-                let dir = self.mutation_dir.clone();
-                tokio::spawn(async move {
-                    MutationManager::clear_dir(&dir).ok();
-                });
-                break;
-            }
-        }
-
-        result.done = result.done || self.updates_all.is_empty();
-        result
+        ))
     }
 
-    pub fn clear(&mut self) {
+    /// Queues the pending mutations for the pagination started `at`, the posts first. They
+    /// stay pending if they couldn't be queued.
+    fn paginate(&mut self, at: i64) -> io::Result<()> {
+        let entry = |kind, uuid: &String| Entry {
+            kind,
+            uuid: uuid.clone(),
+            epoch: self.epoch,
+            at,
+        };
+        // the post of a uuid deleted and posted again, e.g. restored, supersedes its delete
+        let deletes: Vec<_> = self
+            .updates_delete
+            .iter()
+            .filter(|uuid| !self.updates_post.contains(*uuid))
+            .map(|uuid| entry(Kind::Delete, uuid))
+            .collect();
+
+        let mut posts: Vec<_> = self
+            .updates_post
+            .iter()
+            .map(|uuid| entry(Kind::Post, uuid))
+            .collect();
+        // sort posts by uuid
+        posts.sort_by(|a, b| a.uuid.cmp(&b.uuid));
+
+        let mut puts_deletes = Vec::with_capacity(self.updates_put.len() + deletes.len());
+        puts_deletes.extend(self.updates_put.iter().map(|uuid| entry(Kind::Put, uuid)));
+        puts_deletes.extend(deletes);
+        // sort puts_deletes by uuid
+        puts_deletes.sort_by(|a, b| a.uuid.cmp(&b.uuid));
+        self.updates_all
+            .extend(posts.into_iter().chain(puts_deletes))?;
+
+        self.pending_at.clear();
         self.updates_post.clear();
         self.updates_put.clear();
         self.updates_delete.clear();
-        self.updates_all.clear();
-        MutationManager::clear_dir(&self.mutation_dir).ok();
+        Ok(())
     }
 
-    fn clear_dir(dir: &PathBuf) -> std::io::Result<()> {
-        // remove all files under mutation_dir
-        for entry in std::fs::read_dir(dir)? {
-            let entry = entry?;
-            let path = entry.path();
-            if path.is_file() {
-                std::fs::remove_file(path)?;
-            }
+    /// The entries of the 0-based cache page `page_number` of `session`, without consuming
+    /// them, so the page can be fetched again. `None` if the session is unknown, or the page was
+    /// acknowledged or dropped once the session finished. The page is read with
+    /// [`PageEntries::read`], once the lock of the manager is released.
+    ///
+    /// # Errors
+    ///
+    /// This function will return an error if the spilled entries of the page can't be read
+    /// back, the page isn't counted as served then.
+    pub fn page(
+        &mut self,
+        session: u64,
+        page_number: usize,
+    ) -> Result<Option<PageEntries>, MutationError> {
+        let Some(cursor) = self.cursors.get(&session) else {
+            return Ok(None);
+        };
+        let start = cursor.from + page_number * self.page_size;
+        if start < cursor.acked.max(self.base) {
+            return Ok(None);
         }
+        let start = start.min(cursor.to);
+        let end = (start + self.page_size).min(cursor.to);
+        // the session is done once its last entry is served
+        let done = end == cursor.to;
+        let entries = self
+            .updates_all
+            .range(start - self.base, end - self.base)?
+            .into_iter()
+            .map(|entry| self.locate(entry))
+            .collect();
+        if let Some(cursor) = self.cursors.get_mut(&session) {
+            cursor.served = cursor.served.max(end);
+        }
+        Ok(Some(PageEntries {
+            page_number,
+            done,
+            entries,
+        }))
+    }
+
+    /// Acknowledges the served 0-based cache page `page_number` of `session` and the pages
+    /// before it, which can't be fetched again from then on. Their entries are dropped once no
+    /// other session needs them.
+    ///
+    /// # Errors
+    ///
+    /// This function will return an error if the spilled entries couldn't be moved back to
+    /// memory once the entries were dropped, the page is acknowledged nonetheless.
+    pub fn ack(&mut self, session: u64, page_number: usize) -> Result<(), MutationError> {
+        if let Some(cursor) = self.cursors.get_mut(&session) {
+            let end = (cursor.from + (page_number + 1) * self.page_size).min(cursor.served);
+            cursor.acked = cursor.acked.max(end);
+        }
+        self.collect_garbage()
+    }
+
+    /// Drops every mutation, along with the sessions paging through them.
+    ///
+    /// # Errors
+    ///
+    /// This function will return an error if the log or the spill file couldn't be cleared,
+    /// the queues are empty but payloads may be left, which are removed at the next startup.
+    pub async fn clear(&mut self) -> Result<(), MutationError> {
+        self.commit(Record::Clear)?;
+        self.base = 0;
+        self.covered = 0;
+        self.cursors.clear();
+        self.dropped.clear();
+        self.log.clear().await?;
+        self.updates_all.refill()?;
+        Ok(())
+    }
+
+    /// `entry` along with its payload, `None` for a delete.
+    fn locate(&self, entry: Entry) -> (Entry, Option<Payload>) {
+        let payload = entry.key().and_then(|key| self.log.get(&key));
+        (entry, payload)
+    }
+
+    /// Reads the payload of the pending mutation of `uuid`, in the current epoch.
+    async fn read_pending<T: DeserializeOwned + Send + 'static>(
+        &self,
+        uuid: &str,
+    ) -> Result<T, MutationError> {
+        let payload = self
+            .log
+            .get(&key(uuid, self.epoch))
+            .ok_or_else(|| io::Error::from(io::ErrorKind::NotFound))?;
+        let uuid = uuid.to_string();
+        tokio::task::spawn_blocking(move || decode(&payload, &uuid))
+            .await
+            .map_err(|e| MutationError::Io(io::Error::other(e)))?
+    }
+
+    /// Writes the payload of the pending mutation of `uuid`, in the current epoch, superseding
+    /// the one it had.
+    async fn write_pending(
+        &mut self,
+        uuid: &str,
+        value: &impl Serialize,
+    ) -> Result<(), MutationError> {
+        let payload = bincode::serialize(value).unwrap();
+        self.log.put(key(uuid, self.epoch), payload).await?;
         Ok(())
     }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        app_state::testing::{self, TestDir},
+        image::{FsImageStorage, ImageCache},
+    };
+    use log::SEGMENT_BYTES;
+    use std::{path::PathBuf, sync::Arc};
+
+    fn uuid(i: usize) -> String {
+        format!("00000000-0000-0000-0000-{i:012}")
+    }
+
+    fn message(i: usize, text: &str) -> CompleteMessage {
+        CompleteMessage {
+            uuid: uuid(i),
+            author: "author".to_string(),
+            message: text.to_string(),
+            parent_uuid: None,
+            likes: 0,
+            image: String::new(),
+            image_metadata: None,
+            client_timestamp: None,
+            server_timestamp: 1,
+            created_at: 1,
+            updated_at: 1,
+            revision: 1,
+            reactions: Default::default(),
+        }
+    }
+
+    fn put(likes: i32) -> ServerPutUpdate {
+        ServerPutUpdate {
+            author: None,
+            message: None,
+            parent_uuid: None,
+            likes,
+            image_updated: false,
+            client_timestamp: None,
+            server_timestamp: 2,
+            updated_at: None,
+            revision: 2,
+            reactions: None,
+        }
+    }
+
+    fn open(dir: &TestDir, page_size: usize, queue_memory_entries: usize) -> MutationManager {
+        let path = dir.path().join("mutations");
+        std::fs::create_dir_all(&path).unwrap();
+        MutationManager::open(&path, page_size, queue_memory_entries)
+    }
+
+    fn images(dir: &TestDir) -> FsImageStorage {
+        let cache = Arc::new(ImageCache::new(1024));
+        FsImageStorage::open(dir.path().join("images"), cache, None, u64::MAX).unwrap()
+    }
+
+    /// The mutations as `<kind> <uuid number> <message or likes>`.
+    fn describe(mutation: &PendingMutation) -> String {
+        let number = |uuid: &str| uuid[24..].trim_start_matches('0').to_string();
+        match mutation {
+            PendingMutation::Post(message) => {
+                format!("post {} {}", number(&message.uuid), message.message)
+            }
+            PendingMutation::Put { uuid, update } => {
+                format!("put {} {}", number(uuid), update.likes)
+            }
+            PendingMutation::Delete(uuid) => format!("delete {}", number(uuid)),
+        }
+    }
+
+    async fn pending(mutations: &MutationManager) -> Vec<String> {
+        let mut pending: Vec<_> = mutations
+            .pending()
+            .unwrap()
+            .read()
+            .await
+            .unwrap()
+            .iter()
+            .map(describe)
+            .collect();
+        pending.sort();
+        pending
+    }
+
+    /// The page `page_number` of `session`, `None` if it can't be fetched.
+    async fn page(
+        mutations: &mut MutationManager,
+        images: &FsImageStorage,
+        session: u64,
+        page_number: usize,
+    ) -> Option<Vec<String>> {
+        let page = mutations.page(session, page_number).unwrap()?;
+        let results = page.read(images).await.unwrap();
+        let number = |uuid: &str| uuid[24..].trim_start_matches('0').to_string();
+        let posts = results
+            .posts
+            .iter()
+            .map(|post| format!("post {} {}", number(&post.uuid), post.message));
+        let puts_deletes = results.puts_deletes.iter().map(|update| match &update.put {
+            Some(put) => format!("put {} {}", number(&update.uuid), put.likes),
+            None => format!("delete {}", number(&update.uuid)),
+        });
+        Some(posts.chain(puts_deletes).collect())
+    }
+
+    fn segments(dir: &TestDir) -> Vec<PathBuf> {
+        let mut segments: Vec<_> = std::fs::read_dir(dir.path().join("mutations"))
+            .unwrap()
+            .map(|entry| entry.unwrap().path())
+            .filter(|path| path.extension().is_some_and(|ext| ext == "segment"))
+            .collect();
+        segments.sort();
+        segments
+    }
+
+    #[tokio::test]
+    async fn synced_mutations_survive_a_crash() {
+        let dir = TestDir::new();
+        let mut mutations = open(&dir, 10, 100);
+        mutations.add_post(message(1, "one")).await.unwrap();
+        mutations.add_post(message(2, "two")).await.unwrap();
+        mutations.add_put(&uuid(2), put(5)).await.unwrap();
+        mutations.add_put(&uuid(3), put(7)).await.unwrap();
+        mutations.add_delete(&uuid(1)).unwrap();
+        mutations.sync_journal().await.unwrap();
+        // never synced, lost with the crash
+        mutations.add_post(message(4, "four")).await.unwrap();
+        drop(mutations);
+
+        let mutations = open(&dir, 10, 100);
+        assert_eq!(pending(&mutations).await, ["post 2 two", "put 3 7"]);
+    }
+
+    #[tokio::test]
+    async fn torn_record_of_the_last_segment_is_cut_off() {
+        let dir = TestDir::new();
+        let mut mutations = open(&dir, 10, 100);
+        mutations.add_post(message(1, "one")).await.unwrap();
+        mutations.add_post(message(2, "two")).await.unwrap();
+        mutations.sync_journal().await.unwrap();
+        drop(mutations);
+
+        // the crash happened while the payload of the second post was appended
+        let segment = segments(&dir).pop().unwrap();
+        let len = std::fs::metadata(&segment).unwrap().len();
+        let file = std::fs::OpenOptions::new()
+            .write(true)
+            .open(&segment)
+            .unwrap();
+        file.set_len(len - 3).unwrap();
+
+        let mut mutations = open(&dir, 10, 100);
+        assert_eq!(pending(&mutations).await, ["post 1 one"]);
+        // the log appends after the last whole record
+        mutations.add_post(message(3, "three")).await.unwrap();
+        mutations.sync_journal().await.unwrap();
+        drop(mutations);
+        let mutations = open(&dir, 10, 100);
+        assert_eq!(pending(&mutations).await, ["post 1 one", "post 3 three"]);
+    }
+
+    #[tokio::test]
+    async fn spilled_entries_are_served_in_order_and_refilled() {
+        let dir = TestDir::new();
+        let images = images(&dir);
+        let mut mutations = open(&dir, 2, 2);
+        for i in 1..=5 {
+            mutations.add_post(message(i, "m")).await.unwrap();
+        }
+        mutations.get_pagination_meta(1).unwrap();
+        mutations.sync_journal().await.unwrap();
+
+        assert_eq!(
+            page(&mut mutations, &images, 1, 0).await.unwrap(),
+            ["post 1 m", "post 2 m"]
+        );
+        mutations.ack(1, 0).unwrap();
+        // the acknowledged entries are dropped, the spilled ones move back to memory
+        assert_eq!(mutations.updates_all.len(), 3);
+        assert_eq!(
+            page(&mut mutations, &images, 1, 1).await.unwrap(),
+            ["post 3 m", "post 4 m"]
+        );
+
+        // the whole queue is rebuilt at startup, spilled entries included
+        mutations.sync_journal().await.unwrap();
+        drop(mutations);
+        let mut mutations = open(&dir, 2, 2);
+        mutations.get_pagination_meta(2).unwrap();
+        let mut served = Vec::new();
+        for page_number in 0..2 {
+            served.extend(page(&mut mutations, &images, 2, page_number).await.unwrap());
+        }
+        assert_eq!(served, ["post 3 m", "post 4 m", "post 5 m"]);
+    }
+
+    #[tokio::test]
+    async fn segments_of_dropped_payloads_are_compacted() {
+        let dir = TestDir::new();
+        let images = images(&dir);
+        let mut mutations = open(&dir, 10, 100);
+        // large enough for the next payload to start a new segment
+        let large = "x".repeat(SEGMENT_BYTES as usize / 2 + 1);
+        mutations.add_post(message(1, &large)).await.unwrap();
+        mutations.add_post(message(2, &large)).await.unwrap();
+        mutations.get_pagination_meta(1).unwrap();
+        // pending for the next pagination, in a new segment
+        mutations.add_post(message(3, "three")).await.unwrap();
+        mutations.sync_journal().await.unwrap();
+        let first = segments(&dir).remove(0);
+        assert_eq!(segments(&dir).len(), 2);
+
+        assert_eq!(page(&mut mutations, &images, 1, 0).await.unwrap().len(), 2);
+        mutations.ack(1, 0).unwrap();
+        mutations.sync_journal().await.unwrap();
+
+        assert!(!first.exists());
+        assert_eq!(segments(&dir).len(), 1);
+        drop(mutations);
+        let mutations = open(&dir, 10, 100);
+        assert_eq!(pending(&mutations).await, ["post 3 three"]);
+    }
+
+    #[tokio::test]
+    async fn pages_of_a_session_keep_the_epoch_they_were_queued_in() {
+        let dir = TestDir::new();
+        let images = images(&dir);
+        let mut mutations = open(&dir, 10, 100);
+        mutations.add_put(&uuid(1), put(5)).await.unwrap();
+        mutations.get_pagination_meta(1).unwrap();
+        // arrives while the session pages through the first put
+        mutations.add_put(&uuid(1), put(9)).await.unwrap();
+
+        assert_eq!(
+            page(&mut mutations, &images, 1, 0).await.unwrap(),
+            ["put 1 5"]
+        );
+        mutations.ack(1, 0).unwrap();
+        mutations.get_pagination_meta(2).unwrap();
+        assert_eq!(
+            page(&mut mutations, &images, 2, 0).await.unwrap(),
+            ["put 1 9"]
+        );
+    }
+
+    #[tokio::test]
+    async fn sessions_acknowledge_their_pages_independently() {
+        let dir = TestDir::new();
+        let images = images(&dir);
+        let mut mutations = open(&dir, 1, 100);
+        mutations.add_post(message(1, "one")).await.unwrap();
+        mutations.add_post(message(2, "two")).await.unwrap();
+        mutations.get_pagination_meta(1).unwrap();
+        mutations.get_pagination_meta(2).unwrap();
+
+        for page_number in 0..2 {
+            page(&mut mutations, &images, 1, page_number).await.unwrap();
+            mutations.ack(1, page_number).unwrap();
+        }
+        // acknowledged by the first session only
+        assert_eq!(page(&mut mutations, &images, 1, 0).await, None);
+        assert_eq!(
+            page(&mut mutations, &images, 2, 0).await.unwrap(),
+            ["post 1 one"]
+        );
+        assert!(!mutations.is_pagination_empty());
+
+        mutations.ack(2, 0).unwrap();
+        assert_eq!(
+            page(&mut mutations, &images, 2, 1).await.unwrap(),
+            ["post 2 two"]
+        );
+        mutations.ack(2, 1).unwrap();
+        assert!(mutations.is_pagination_empty());
+    }
+
+    #[tokio::test]
+    async fn finished_sessions_are_collected() {
+        let dir = TestDir::new();
+        let images = images(&dir);
+        let mut mutations = open(&dir, 10, 100);
+        mutations.add_post(message(1, "one")).await.unwrap();
+        mutations.get_pagination_meta(1).unwrap();
+        page(&mut mutations, &images, 1, 0).await.unwrap();
+
+        // the session was dropped without acknowledging its page
+        mutations.retain_sessions(std::iter::empty()).unwrap();
+        assert!(mutations.is_pagination_empty());
+    }
+
+    #[tokio::test]
+    async fn post_of_a_deleted_message_supersedes_its_delete() {
+        let dir = TestDir::new();
+        let images = images(&dir);
+        let mut mutations = open(&dir, 10, 100);
+        mutations.add_delete(&uuid(1)).unwrap();
+        mutations.add_post(message(1, "again")).await.unwrap();
+        mutations.add_delete(&uuid(2)).unwrap();
+        assert_eq!(pending(&mutations).await, ["delete 2", "post 1 again"]);
+
+        mutations.get_pagination_meta(1).unwrap();
+        assert_eq!(
+            page(&mut mutations, &images, 1, 0).await.unwrap(),
+            ["post 1 again", "delete 2"]
+        );
+    }
+
+    #[tokio::test]
+    async fn deleting_a_reposted_message_keeps_its_delete() {
+        let dir = TestDir::new();
+        let mut mutations = open(&dir, 10, 100);
+        mutations.add_delete(&uuid(1)).unwrap();
+        mutations.add_post(message(1, "again")).await.unwrap();
+        mutations.add_delete(&uuid(1)).unwrap();
+        assert_eq!(pending(&mutations).await, ["delete 1"]);
+    }
+
+    #[tokio::test]
+    async fn sweeper_expires_old_mutations() {
+        let dir = TestDir::new();
+        let state = testing::state(&dir, 10);
+        state
+            .mutations
+            .lock()
+            .await
+            .add_post(message(1, "one"))
+            .await
+            .unwrap();
+        // the expired mutations are those older than the ttl, in milliseconds
+        tokio::time::sleep(Duration::from_millis(5)).await;
 
-    fn get_mutation_file_path(&self, uuid: &str) -> PathBuf {
-        // mutation_dur/uuid
-        std::path::Path::new(&self.mutation_dir).join(uuid)
+        spawn_sweeper(Arc::clone(&state), Duration::ZERO);
+        // the sweeper sweeps at most every second
+        tokio::time::sleep(Duration::from_millis(1500)).await;
+        let mutations = state.mutations.lock().await;
+        assert!(mutations.is_empty_for_pagination());
+        assert!(mutations.needs_resync());
     }
 }
@@ -0,0 +1,295 @@
+use super::{Entry, Kind};
+use std::{
+    collections::{vec_deque, VecDeque},
+    fs::{File, OpenOptions},
+    io::{self, Read, Seek, SeekFrom, Write},
+    iter,
+    path::Path,
+};
+
+/// The file of the mutation directory holding the spilled entries, hidden like the journal.
+const SPILL_FILE: &str = ".queue.spill";
+
+/// The longest uuid a spilled entry may have, longer than both id schemes.
+const MAX_UUID_LEN: usize = 64;
+
+/// A spilled entry is its kind, its epoch, when it was queued, the length of its uuid and its
+/// uuid padded to [`MAX_UUID_LEN`], so the spilled entries are indexed like an array.
+const RECORD_LEN: usize = 18 + MAX_UUID_LEN;
+
+/// How many spilled entries are read at once when iterating.
+const READ_CHUNK: usize = 4096;
+
+/// The queue of the entries of the cache paginations, which only keeps its first
+/// `memory_entries` entries in memory. The entries after them are spilled to a file of the
+/// mutation directory and read back as the entries before them are dropped, or when a page
+/// reaches them.
+///
+/// The file only holds what doesn't fit in memory, the journal rebuilds the whole queue at
+/// startup. A failed write leaves the queue as it was, and dropping entries never touches the
+/// file, which is caught up by [`SpillQueue::refill`].
+pub struct SpillQueue {
+    head: VecDeque<Entry>,
+    memory_entries: usize,
+    /// Opened on the first spill.
+    file: Option<File>,
+    spill_path: std::path::PathBuf,
+    /// The record of the file holding the first spilled entry.
+    spilled_from: usize,
+    spilled: usize,
+}
+
+impl Kind {
+    fn to_byte(self) -> u8 {
+        match self {
+            Kind::Post => 0,
+            Kind::Put => 1,
+            Kind::Delete => 2,
+        }
+    }
+
+    fn from_byte(byte: u8) -> Option<Self> {
+        match byte {
+            0 => Some(Kind::Post),
+            1 => Some(Kind::Put),
+            2 => Some(Kind::Delete),
+            _ => None,
+        }
+    }
+}
+
+fn encode(entry: &Entry, record: &mut Vec<u8>) {
+    let uuid = entry.uuid.as_bytes();
+    assert!(uuid.len() <= MAX_UUID_LEN, "ids are shorter than a record");
+    record.push(entry.kind.to_byte());
+    record.extend_from_slice(&entry.epoch.to_le_bytes());
+    record.extend_from_slice(&entry.at.to_le_bytes());
+    record.push(uuid.len() as u8);
+    record.extend_from_slice(uuid);
+    record.resize(record.len() + MAX_UUID_LEN - uuid.len(), 0);
+}
+
+fn decode(record: &[u8]) -> io::Result<Entry> {
+    let invalid = || io::Error::new(io::ErrorKind::InvalidData, "Invalid spilled entry.");
+    let kind = Kind::from_byte(record[0]).ok_or_else(invalid)?;
+    let epoch = u64::from_le_bytes(record[1..9].try_into().unwrap());
+    let at = i64::from_le_bytes(record[9..17].try_into().unwrap());
+    let uuid = record
+        .get(18..18 + record[17] as usize)
+        .ok_or_else(invalid)?;
+    let uuid = String::from_utf8(uuid.to_vec()).map_err(|_| invalid())?;
+    Ok(Entry {
+        kind,
+        uuid,
+        epoch,
+        at,
+    })
+}
+
+impl SpillQueue {
+    /// An empty queue spilling to `mutation_dir`, whose spill file of the previous run is
+    /// dropped.
+    ///
+    /// # Errors
+    ///
+    /// This function will return an error if the old spill file can't be removed.
+    pub fn new(mutation_dir: &Path, memory_entries: usize) -> io::Result<Self> {
+        let spill_path = mutation_dir.join(SPILL_FILE);
+        match std::fs::remove_file(&spill_path) {
+            Err(e) if e.kind() != io::ErrorKind::NotFound => return Err(e),
+            _ => (),
+        }
+        Ok(Self {
+            head: VecDeque::with_capacity(memory_entries.min(50_000usize.next_power_of_two())),
+            memory_entries,
+            file: None,
+            spill_path,
+            spilled_from: 0,
+            spilled: 0,
+        })
+    }
+
+    pub fn len(&self) -> usize {
+        self.head.len() + self.spilled
+    }
+
+    /// Appends `entry`, see [`SpillQueue::extend`].
+    ///
+    /// # Errors
+    ///
+    /// This function will return an error if the entry had to be spilled and couldn't be.
+    pub fn push_back(&mut self, entry: Entry) -> io::Result<()> {
+        self.extend(iter::once(entry))
+    }
+
+    /// Appends `entries`, spilling those that don't fit in memory in a single write.
+    ///
+    /// # Errors
+    ///
+    /// This function will return an error if the entries that don't fit in memory couldn't be
+    /// spilled, none of `entries` is appended then.
+    pub fn extend(&mut self, entries: impl IntoIterator<Item = Entry>) -> io::Result<()> {
+        let head_len = self.head.len();
+        let mut spilled = Vec::new();
+        let mut count = 0;
+        for entry in entries {
+            // entries are only kept in memory ahead of the spilled ones
+            if self.spilled + count == 0 && self.head.len() < self.memory_entries {
+                self.head.push_back(entry);
+            } else {
+                encode(&entry, &mut spilled);
+                count += 1;
+            }
+        }
+        if count > 0 {
+            if let Err(e) = self.write_spilled(&spilled) {
+                self.head.truncate(head_len);
+                return Err(e);
+            }
+            self.spilled += count;
+        }
+        Ok(())
+    }
+
+    fn write_spilled(&mut self, records: &[u8]) -> io::Result<()> {
+        let file = match &mut self.file {
+            Some(file) => file,
+            file => file.insert(
+                OpenOptions::new()
+                    .read(true)
+                    .write(true)
+                    .create(true)
+                    .truncate(true)
+                    .open(&self.spill_path)?,
+            ),
+        };
+        let end = (self.spilled_from + self.spilled) * RECORD_LEN;
+        file.seek(SeekFrom::Start(end as u64))?;
+        file.write_all(records)
+    }
+
+    /// Reads `count` spilled entries from the `from`th one.
+    fn read_spilled(&self, from: usize, count: usize) -> io::Result<Vec<Entry>> {
+        let (Some(mut file), true) = (self.file.as_ref(), count > 0) else {
+            return Ok(Vec::new());
+        };
+        let mut records = vec![0; count * RECORD_LEN];
+        file.seek(SeekFrom::Start(
+            ((self.spilled_from + from) * RECORD_LEN) as u64,
+        ))?;
+        file.read_exact(&mut records)?;
+        records.chunks(RECORD_LEN).map(decode).collect()
+    }
+
+    /// Drops the first `count` entries, the spilled ones are moved back to memory by
+    /// [`SpillQueue::refill`].
+    pub fn drop_front(&mut self, count: usize) {
+        let from_head = count.min(self.head.len());
+        self.head.drain(..from_head);
+        let from_spilled = (count - from_head).min(self.spilled);
+        self.spilled_from += from_spilled;
+        self.spilled -= from_spilled;
+        if self.spilled == 0 {
+            // the next spill overwrites the file from its start
+            self.spilled_from = 0;
+        }
+    }
+
+    /// Moves spilled entries back to memory once half of it is free, and empties the file once
+    /// nothing is spilled anymore.
+    ///
+    /// # Errors
+    ///
+    /// This function will return an error if the spilled entries can't be read back or the file
+    /// can't be truncated, the queue is unchanged then and the next call tries again.
+    pub fn refill(&mut self) -> io::Result<()> {
+        if self.spilled > 0 && self.head.len() <= self.memory_entries / 2 {
+            let count = (self.memory_entries - self.head.len()).min(self.spilled);
+            let entries = self.read_spilled(0, count)?;
+            self.head.extend(entries);
+            self.spilled_from += count;
+            self.spilled -= count;
+        }
+        match &self.file {
+            Some(file) if self.spilled == 0 => {
+                self.spilled_from = 0;
+                file.set_len(0)
+            }
+            _ => Ok(()),
+        }
+    }
+
+    /// The entries from `start` to `end`, read back from the file for those that are spilled.
+    ///
+    /// # Errors
+    ///
+    /// This function will return an error if the spilled entries can't be read back.
+    pub fn range(&self, start: usize, end: usize) -> io::Result<Vec<Entry>> {
+        let end = end.min(self.len());
+        let start = start.min(end);
+        let in_head = end.min(self.head.len());
+        let mut entries: Vec<_> = self
+            .head
+            .range(start.min(in_head)..in_head)
+            .cloned()
+            .collect();
+        let spilled_start = start.saturating_sub(self.head.len());
+        let spilled_end = end.saturating_sub(self.head.len());
+        entries.extend(self.read_spilled(spilled_start, spilled_end - spilled_start)?);
+        Ok(entries)
+    }
+
+    /// The entries in order, the spilled ones read back [`READ_CHUNK`] at a time. The iteration
+    /// ends after the first error reading them back.
+    pub fn iter(&self) -> Iter<'_> {
+        Iter {
+            queue: self,
+            head: self.head.iter(),
+            next_spilled: 0,
+            chunk: Vec::new().into_iter(),
+        }
+    }
+
+    /// Drops every entry, the file is emptied by [`SpillQueue::refill`].
+    pub fn clear(&mut self) {
+        self.head.clear();
+        self.spilled = 0;
+        self.spilled_from = 0;
+    }
+}
+
+pub struct Iter<'a> {
+    queue: &'a SpillQueue,
+    head: vec_deque::Iter<'a, Entry>,
+    next_spilled: usize,
+    chunk: std::vec::IntoIter<Entry>,
+}
+
+impl Iterator for Iter<'_> {
+    type Item = io::Result<Entry>;
+
+    fn next(&mut self) -> Option<io::Result<Entry>> {
+        if let Some(entry) = self.head.next() {
+            return Some(Ok(entry.clone()));
+        }
+        if let Some(entry) = self.chunk.next() {
+            return Some(Ok(entry));
+        }
+        let count = READ_CHUNK.min(self.queue.spilled - self.next_spilled);
+        if count == 0 {
+            return None;
+        }
+        // nothing is left to read after an error
+        self.next_spilled += count;
+        match self.queue.read_spilled(self.next_spilled - count, count) {
+            Ok(chunk) => {
+                self.chunk = chunk.into_iter();
+                self.chunk.next().map(Ok)
+            }
+            Err(e) => {
+                self.next_spilled = self.queue.spilled;
+                Some(Err(e))
+            }
+        }
+    }
+}
@@ -0,0 +1,29 @@
+use crate::app_state::AppState;
+use std::{sync::Arc, time::Duration};
+
+/// How often the sweeper looks for expired mutations, at most.
+const SWEEP_INTERVAL: Duration = Duration::from_secs(60);
+
+/// Spawns the task expiring the cached mutations older than `ttl`, see
+/// [`MutationManager::expire`](super::MutationManager::expire). It sweeps every minute, or every
+/// `ttl` if it is shorter, and counts the expired mutations in the metrics.
+pub fn spawn_sweeper(state: Arc<AppState>, ttl: Duration) -> tokio::task::JoinHandle<()> {
+    let interval = ttl.clamp(Duration::from_secs(1), SWEEP_INTERVAL);
+    tokio::spawn(async move {
+        loop {
+            tokio::time::sleep(interval).await;
+            let expired = match state.mutations.lock().await.expire(ttl) {
+                Ok(0) => continue,
+                Ok(expired) => expired,
+                Err(e) => {
+                    eprintln!("Failed to expire the cached mutations: {e}");
+                    continue;
+                }
+            };
+            println!("Expired {expired} cached mutations no client paginated through.");
+            if let Some(metrics) = &state.metrics {
+                metrics.record_expired_mutations(expired as u64);
+            }
+        }
+    })
+}
@@ -0,0 +1,176 @@
+use crate::{
+    app_state::AppState,
+    handlers::CompleteMessage,
+    mutation_manager::MutationManager,
+    mutation_manager::{MutationError, ServerPutUpdate},
+    repository::{OutboxKind, RepositoryResult},
+};
+use std::{sync::Arc, time::Duration};
+
+/// How many outbox entries are relayed per round trip to the database.
+const RELAY_BATCH_SIZE: usize = 1024;
+
+/// Moves the committed mutations from the outbox into the `MutationManager`, returning how many
+/// were relayed.
+///
+/// # Errors
+///
+/// This function will return an error if the outbox could not be read or acknowledged, or a
+/// mutation payload could not be written. Entries that were applied but not acknowledged are
+/// skipped by the next relay, the `MutationManager` keeps the id of the last one it relayed. An
+/// entry whose pending mutation is corrupt is logged and skipped, so it doesn't hold up the rest
+/// of the outbox.
+pub async fn relay(state: &AppState) -> RepositoryResult<usize> {
+    // holding the mutations lock for the whole relay keeps concurrent relays from applying the
+    // same entries twice, and the mutation payloads from being updated by two at once
+    relay_locked(state, &mut *state.mutations.lock().await).await
+}
+
+/// [`relay`] into `mutations`, locked by the caller, e.g. to enqueue more mutations before any
+/// other relay runs.
+///
+/// # Errors
+///
+/// See [`relay`].
+pub async fn relay_locked(
+    state: &AppState,
+    mutations: &mut MutationManager,
+) -> RepositoryResult<usize> {
+    let mut relayed = 0;
+
+    loop {
+        let entries = state.messages.outbox(RELAY_BATCH_SIZE).await?;
+        let Some(last_id) = entries.last().map(|entry| entry.id) else {
+            // also persists the pages taken since the last relay
+            mutations.sync_journal().await?;
+            return Ok(relayed);
+        };
+        let count = entries.len();
+
+        for entry in entries {
+            // applied by an earlier relay, which failed to acknowledge it
+            if entry.id <= mutations.relayed() {
+                continue;
+            }
+            let id = entry.id;
+            let result = match entry.kind {
+                OutboxKind::Post => {
+                    mutations
+                        .add_post(CompleteMessage {
+                            uuid: entry.uuid,
+                            author: entry.author.unwrap_or_default(),
+                            message: entry.message.unwrap_or_default(),
+                            parent_uuid: entry.parent_uuid,
+                            likes: entry.likes.unwrap_or_default(),
+                            // the image was already saved by the handler
+                            image: String::new(),
+                            image_metadata: None,
+                            client_timestamp: entry.client_timestamp,
+                            server_timestamp: entry.server_timestamp.unwrap_or_default(),
+                            created_at: entry.created_at.unwrap_or_default(),
+                            updated_at: entry.updated_at.unwrap_or_default(),
+                            revision: entry.revision.unwrap_or(1),
+                            reactions: entry.reactions.unwrap_or_default(),
+                        })
+                        .await
+                }
+                OutboxKind::Put => {
+                    let put = ServerPutUpdate {
+                        author: entry.author,
+                        message: entry.message,
+                        parent_uuid: entry.parent_uuid,
+                        likes: entry.likes.unwrap_or_default(),
+                        image_updated: entry.image_updated,
+                        client_timestamp: entry.client_timestamp,
+                        server_timestamp: entry.server_timestamp.unwrap_or_default(),
+                        updated_at: entry.updated_at,
+                        revision: entry.revision.unwrap_or(1),
+                        reactions: entry.reactions,
+                    };
+                    mutations.add_put(&entry.uuid, put).await
+                }
+                OutboxKind::Delete => mutations.add_delete(&entry.uuid),
+            };
+            match result {
+                Err(e @ MutationError::Corrupt { .. }) => {
+                    eprintln!("Skipping an outbox entry: {e}")
+                }
+                result => result?,
+            }
+            mutations.mark_relayed(id);
+        }
+
+        // the relayed entries must survive a restart once they are acknowledged
+        mutations.sync_journal().await?;
+        state.messages.ack_outbox(last_id).await?;
+        relayed += count;
+
+        if count < RELAY_BATCH_SIZE {
+            return Ok(relayed);
+        }
+    }
+}
+
+/// Spawns the task relaying the outbox whenever a handler commits a change, and periodically to
+/// pick up anything left over, e.g. from before a crash.
+pub fn spawn_relay(state: Arc<AppState>) -> tokio::task::JoinHandle<()> {
+    tokio::spawn(async move {
+        loop {
+            if let Err(e) = relay(&state).await {
+                eprintln!("Failed to relay the outbox: {}", e);
+            }
+            tokio::select! {
+                _ = state.outbox_notify.notified() => {}
+                _ = tokio::time::sleep(Duration::from_secs(1)) => {}
+            }
+        }
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        app_state::testing::{self, TestDir},
+        models::Message,
+    };
+
+    #[tokio::test]
+    async fn entries_relayed_before_a_restart_are_skipped() {
+        let dir = TestDir::new();
+        let state = testing::state(&dir, 10);
+        let message = Message {
+            uuid: "00000000-0000-0000-0000-000000000001".to_string(),
+            author: "author".to_string(),
+            message: "hello".to_string(),
+            parent_uuid: None,
+            likes: 0,
+            has_image: false,
+            client_timestamp: None,
+            server_timestamp: 1,
+            created_at: 1,
+            updated_at: 1,
+            deleted_at: None,
+            revision: 1,
+            reactions: Default::default(),
+        };
+        state.messages.insert(&message).await.unwrap();
+        let id = state.messages.outbox(1).await.unwrap()[0].id;
+
+        // the entry was relayed, but the server crashed before acknowledging it
+        let mut mutations = state.mutations.lock().await;
+        mutations.mark_relayed(id);
+        mutations.sync_journal().await.unwrap();
+        *mutations = MutationManager::open(&dir.path().join("mutations"), 10, 1000);
+
+        assert_eq!(relay_locked(&state, &mut mutations).await.unwrap(), 1);
+        assert!(mutations
+            .pending()
+            .unwrap()
+            .read()
+            .await
+            .unwrap()
+            .is_empty());
+        assert!(state.messages.outbox(1).await.unwrap().is_empty());
+    }
+}
@@ -0,0 +1,240 @@
+use crate::{
+    app_state::pagination::MAX_SESSIONS,
+    response::{Response, StatusCode},
+};
+use ahash::AHashMap;
+use hmac::{Hmac, Mac};
+use sha2::Sha256;
+use std::{collections::VecDeque, fmt};
+
+/// The header a page token is sent and presented back in.
+pub const PAGE_TOKEN_HEADER: &str = "X-Page-Token";
+
+/// How many served pages of each session can be replayed. Clients only retry the page they just
+/// requested, so a few are enough.
+const REPLAY_CAPACITY: usize = 4;
+
+type HmacSha256 = Hmac<Sha256>;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum PageTokenError {
+    /// The token is malformed or its signature doesn't match.
+    Invalid,
+    /// The token is genuine but its page is no longer kept, e.g. it was issued before a restart.
+    Expired,
+}
+
+impl PageTokenError {
+    /// The HTTP status this error is surfaced as.
+    pub fn status(&self) -> StatusCode {
+        match self {
+            PageTokenError::Invalid => StatusCode::BadRequest,
+            PageTokenError::Expired => StatusCode::Gone,
+        }
+    }
+}
+
+impl fmt::Display for PageTokenError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            PageTokenError::Invalid => write!(f, "Invalid page token."),
+            PageTokenError::Expired => {
+                write!(f, "Page token expired, trigger a new pagination.")
+            }
+        }
+    }
+}
+
+impl std::error::Error for PageTokenError {}
+
+/// Issues signed tokens for the pages served by the pagination and keeps the bytes of the last
+/// few of each session, so a client retrying a page after a timeout gets the identical response
+/// back instead of the next page, however many other clients paginate meanwhile. The pages of
+/// as many sessions as the pagination keeps are kept, those of the session served least
+/// recently are dropped past them.
+///
+/// A token is `<session>.<page>.<snapshot>.<signature>`, where the snapshot identifies the state
+/// the session paginates. Sessions don't survive a restart, so it is drawn once per process.
+pub struct PageTokens {
+    key: Vec<u8>,
+    snapshot: u64,
+    /// The pages served for each session, with when the session was last served.
+    served: AHashMap<u64, (u64, VecDeque<(String, Response)>)>,
+    /// Counts the pages remembered, ordering the sessions by when they were last served.
+    clock: u64,
+}
+
+impl PageTokens {
+    /// Signs the tokens with `secret`, or with a random key when `None`.
+    pub fn new(secret: Option<&[u8]>) -> Self {
+        Self {
+            key: match secret {
+                Some(secret) => secret.to_vec(),
+                None => rand::random::<[u8; 32]>().to_vec(),
+            },
+            snapshot: rand::random(),
+            served: AHashMap::new(),
+            clock: 0,
+        }
+    }
+
+    /// The token of page `page` of pagination session `session`.
+    pub fn issue(&self, session: u64, page: usize) -> String {
+        let payload = format!("{session}.{page}.{:x}", self.snapshot);
+        let signature = hex::encode(self.mac(&payload).finalize().into_bytes());
+        format!("{payload}.{signature}")
+    }
+
+    /// Keeps `response`, served with `token`, for replays. A token not issued by this server
+    /// isn't kept.
+    pub fn remember(&mut self, token: String, response: Response) {
+        let Ok((session, _)) = self.page_of(&token) else {
+            return;
+        };
+        if !self.served.contains_key(&session) && self.served.len() == MAX_SESSIONS {
+            let least_recent = self
+                .served
+                .iter()
+                .min_by_key(|(_, (served_at, _))| *served_at)
+                .map(|(&session, _)| session);
+            if let Some(least_recent) = least_recent {
+                self.served.remove(&least_recent);
+            }
+        }
+
+        self.clock += 1;
+        let (served_at, pages) = self.served.entry(session).or_default();
+        *served_at = self.clock;
+        // a refetched page replaces the one kept for its token
+        pages.retain(|(served, _)| *served != token);
+        if pages.len() == REPLAY_CAPACITY {
+            pages.pop_front();
+        }
+        pages.push_back((token, response));
+    }
+
+    /// The response served with `token`.
+    ///
+    /// # Errors
+    ///
+    /// This function will return an error if the token wasn't issued by this server, or if its
+    /// page is no longer kept.
+    pub fn replay(&self, token: &str) -> Result<Response, PageTokenError> {
+        let (session, _) = self.page_of(token)?;
+
+        self.served
+            .get(&session)
+            .into_iter()
+            .flat_map(|(_, pages)| pages)
+            .find(|(served, _)| served == token)
+            .map(|(_, response)| response.clone())
+            .ok_or(PageTokenError::Expired)
+    }
+
+    /// The session and page number `token` was issued for.
+    ///
+    /// # Errors
+    ///
+    /// This function will return an error if the token wasn't issued by this server, or if it
+    /// was issued before a restart.
+    pub fn page_of(&self, token: &str) -> Result<(u64, usize), PageTokenError> {
+        let (payload, signature) = token.rsplit_once('.').ok_or(PageTokenError::Invalid)?;
+        let signature = hex::decode(signature).map_err(|_| PageTokenError::Invalid)?;
+        self.mac(payload)
+            .verify_slice(&signature)
+            .map_err(|_| PageTokenError::Invalid)?;
+
+        let mut parts = payload.split('.');
+        let (Some(session), Some(page), Some(snapshot)) =
+            (parts.next(), parts.next(), parts.next())
+        else {
+            return Err(PageTokenError::Invalid);
+        };
+        if snapshot != format!("{:x}", self.snapshot) {
+            return Err(PageTokenError::Expired);
+        }
+        match (session.parse(), page.parse()) {
+            (Ok(session), Ok(page)) => Ok((session, page)),
+            _ => Err(PageTokenError::Invalid),
+        }
+    }
+
+    fn mac(&self, payload: &str) -> HmacSha256 {
+        let mut mac = HmacSha256::new_from_slice(&self.key).expect("HMAC accepts any key length");
+        mac.update(payload.as_bytes());
+        mac
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn page(body: &str) -> Response {
+        Response::new().body(body.to_string())
+    }
+
+    fn body(response: &Response) -> Vec<u8> {
+        response.chunks().concat()
+    }
+
+    #[test]
+    fn issued_token_verifies() {
+        let tokens = PageTokens::new(Some(b"secret"));
+        let token = tokens.issue(3, 7);
+        assert_eq!(tokens.page_of(&token), Ok((3, 7)));
+    }
+
+    #[test]
+    fn tampered_or_foreign_token_is_invalid() {
+        let tokens = PageTokens::new(Some(b"secret"));
+        let token = tokens.issue(3, 7);
+
+        let tampered = token.replacen("3.7", "3.8", 1);
+        assert_eq!(tokens.page_of(&tampered), Err(PageTokenError::Invalid));
+        let foreign = PageTokens::new(Some(b"other")).issue(3, 7);
+        assert_eq!(tokens.page_of(&foreign), Err(PageTokenError::Invalid));
+        assert_eq!(tokens.page_of("garbage"), Err(PageTokenError::Invalid));
+    }
+
+    #[test]
+    fn token_of_before_a_restart_is_expired() {
+        // the same secret, but another snapshot
+        let before = PageTokens::new(Some(b"secret"));
+        let after = PageTokens::new(Some(b"secret"));
+        assert_eq!(
+            after.page_of(&before.issue(3, 7)),
+            Err(PageTokenError::Expired)
+        );
+    }
+
+    #[test]
+    fn remembered_page_is_replayed() {
+        let mut tokens = PageTokens::new(None);
+        let token = tokens.issue(1, 1);
+        assert_eq!(tokens.replay(&token).unwrap_err(), PageTokenError::Expired);
+
+        tokens.remember(token.clone(), page("first"));
+        assert_eq!(body(&tokens.replay(&token).unwrap()), b"first");
+    }
+
+    #[test]
+    fn replays_of_a_session_outlive_other_sessions() {
+        let mut tokens = PageTokens::new(None);
+        let token = tokens.issue(1, 1);
+        tokens.remember(token.clone(), page("first"));
+        // more pages of other sessions than a session keeps
+        for session in 2..=REPLAY_CAPACITY as u64 + 2 {
+            let other = tokens.issue(session, 1);
+            tokens.remember(other, page("other"));
+        }
+        assert_eq!(body(&tokens.replay(&token).unwrap()), b"first");
+
+        // the session's own later pages push it out
+        for number in 2..=REPLAY_CAPACITY + 1 {
+            let later = tokens.issue(1, number);
+            tokens.remember(later, page("later"));
+        }
+        assert_eq!(tokens.replay(&token).unwrap_err(), PageTokenError::Expired);
+    }
+}
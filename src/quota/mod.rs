@@ -0,0 +1,247 @@
+use crate::response::StatusCode;
+use ahash::AHashMap;
+use serde::Serialize;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+/// The key used for requests without an `X-Api-Key` header.
+pub const ANONYMOUS_KEY: &str = "anonymous";
+
+/// How many keys are tracked at once. Past them, the keys storing nothing are dropped, those
+/// without a write today first.
+const MAX_TRACKED_KEYS: usize = 100_000;
+
+#[derive(Debug, Clone, Copy, Default)]
+pub struct QuotaLimits {
+    /// Writes (POST/PUT/DELETE/PATCH) allowed per key per UTC day.
+    pub daily_writes: Option<u64>,
+    /// Bytes of messages and images a key may have stored.
+    pub stored_bytes: Option<u64>,
+}
+
+#[derive(Debug, Default)]
+struct Usage {
+    day: u64,
+    writes: u64,
+    stored_bytes: u64,
+}
+
+/// The usage of a key, as reported by the usage endpoint and in quota errors.
+#[derive(Serialize, Debug)]
+pub struct UsageReport<'a> {
+    pub key: &'a str,
+    pub writes_today: u64,
+    pub daily_write_limit: Option<u64>,
+    pub stored_bytes: u64,
+    pub stored_bytes_limit: Option<u64>,
+}
+
+#[derive(Serialize, Debug)]
+#[serde(tag = "error", rename_all = "snake_case")]
+pub enum QuotaError {
+    DailyWritesExceeded { limit: u64, used: u64 },
+    StoredBytesExceeded { limit: u64, used: u64 },
+}
+
+impl QuotaError {
+    /// The HTTP status this error is surfaced as.
+    pub fn status(&self) -> StatusCode {
+        match self {
+            QuotaError::DailyWritesExceeded { .. } => StatusCode::TooManyRequests,
+            QuotaError::StoredBytesExceeded { .. } => StatusCode::PaymentRequired,
+        }
+    }
+}
+
+/// A write counted against the quotas of a key by [`QuotaTracker::reserve`] before it is made,
+/// so concurrent writes can't overrun them. It is kept with [`QuotaTracker::commit`] once the
+/// write succeeded, and given back with [`QuotaTracker::release`] otherwise.
+#[derive(Debug)]
+#[must_use]
+pub struct Reservation {
+    key: String,
+    uuid: Option<String>,
+    bytes: u64,
+}
+
+/// Tracks writes and stored bytes per API key. Stored bytes are attributed to the key that last
+/// wrote a message, and only for messages written since the server started.
+#[derive(Debug, Default)]
+pub struct QuotaTracker {
+    limits: QuotaLimits,
+    usage: AHashMap<String, Usage>,
+    /// uuid -> (key, bytes) of the messages written since startup.
+    owners: AHashMap<String, (String, u64)>,
+}
+
+impl QuotaTracker {
+    pub fn new(limits: QuotaLimits) -> Self {
+        Self {
+            limits,
+            ..Default::default()
+        }
+    }
+
+    /// Counts a write of `key` of `bytes` bytes for the message `uuid` against its quotas, if
+    /// they allow it.
+    ///
+    /// # Errors
+    ///
+    /// This function will return an error if the write would exceed one of the key's quotas,
+    /// nothing is counted then.
+    pub fn reserve(
+        &mut self,
+        key: &str,
+        uuid: Option<&str>,
+        bytes: u64,
+    ) -> Result<Reservation, QuotaError> {
+        let replaced = uuid
+            .and_then(|uuid| self.owners.get(uuid))
+            .filter(|(owner, _)| owner == key)
+            .map_or(0, |(_, size)| *size);
+        let limits = self.limits;
+        let usage = self.usage_mut(key);
+
+        if let Some(limit) = limits.daily_writes {
+            if usage.writes >= limit {
+                return Err(QuotaError::DailyWritesExceeded {
+                    limit,
+                    used: usage.writes,
+                });
+            }
+        }
+        if let Some(limit) = limits.stored_bytes {
+            if bytes > 0 && usage.stored_bytes.saturating_sub(replaced) + bytes > limit {
+                return Err(QuotaError::StoredBytesExceeded {
+                    limit,
+                    used: usage.stored_bytes,
+                });
+            }
+        }
+
+        // the previous size of the message is only given back once the write succeeded
+        usage.writes += 1;
+        usage.stored_bytes += bytes;
+        Ok(Reservation {
+            key: key.to_string(),
+            uuid: uuid.map(str::to_string),
+            bytes,
+        })
+    }
+
+    /// Keeps the write of `reservation`, which succeeded. Its bytes are the new size of its
+    /// message, `0` for deletes.
+    pub fn commit(&mut self, reservation: Reservation) {
+        let Some(uuid) = reservation.uuid else {
+            return;
+        };
+        // the previous size no longer counts against its writer
+        if let Some((owner, size)) = self.owners.remove(&uuid) {
+            let usage = self.usage_mut(&owner);
+            usage.stored_bytes = usage.stored_bytes.saturating_sub(size);
+        }
+        if reservation.bytes > 0 {
+            self.owners
+                .insert(uuid, (reservation.key, reservation.bytes));
+        }
+    }
+
+    /// Gives back the write of `reservation`, which failed.
+    pub fn release(&mut self, reservation: Reservation) {
+        let usage = self.usage_mut(&reservation.key);
+        usage.writes = usage.writes.saturating_sub(1);
+        usage.stored_bytes = usage.stored_bytes.saturating_sub(reservation.bytes);
+    }
+
+    /// Forgets all stored bytes, e.g. after the messages were cleared.
+    pub fn clear_stored(&mut self) {
+        self.owners.clear();
+        for usage in self.usage.values_mut() {
+            usage.stored_bytes = 0;
+        }
+    }
+
+    pub fn report<'a>(&mut self, key: &'a str) -> UsageReport<'a> {
+        let limits = self.limits;
+        let usage = self.usage_mut(key);
+        UsageReport {
+            key,
+            writes_today: usage.writes,
+            daily_write_limit: limits.daily_writes,
+            stored_bytes: usage.stored_bytes,
+            stored_bytes_limit: limits.stored_bytes,
+        }
+    }
+
+    /// The usage of `key`, with the write count reset if the day changed.
+    fn usage_mut(&mut self, key: &str) -> &mut Usage {
+        let today = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map_or(0, |d| d.as_secs() / 86_400);
+        if self.usage.len() >= MAX_TRACKED_KEYS && !self.usage.contains_key(key) {
+            self.usage.retain(|_, usage| {
+                usage.stored_bytes > 0 || (usage.day == today && usage.writes > 0)
+            });
+            if self.usage.len() >= MAX_TRACKED_KEYS {
+                self.usage.retain(|_, usage| usage.stored_bytes > 0);
+            }
+        }
+        let usage = self.usage.entry(key.to_string()).or_default();
+        if usage.day != today {
+            usage.day = today;
+            usage.writes = 0;
+        }
+        usage
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn tracker() -> QuotaTracker {
+        QuotaTracker::new(QuotaLimits {
+            daily_writes: Some(3),
+            stored_bytes: Some(100),
+        })
+    }
+
+    #[test]
+    fn reserved_writes_count_before_they_are_committed() {
+        let mut quotas = tracker();
+        let first = quotas.reserve("key", Some("a"), 60).unwrap();
+        // a concurrent write sees the bytes of the first one already
+        assert!(matches!(
+            quotas.reserve("key", Some("b"), 60),
+            Err(QuotaError::StoredBytesExceeded { used: 60, .. })
+        ));
+        quotas.commit(first);
+        assert_eq!(quotas.report("key").stored_bytes, 60);
+    }
+
+    #[test]
+    fn released_writes_are_given_back() {
+        let mut quotas = tracker();
+        let reservation = quotas.reserve("key", Some("a"), 60).unwrap();
+        quotas.release(reservation);
+
+        let report = quotas.report("key");
+        assert_eq!((report.writes_today, report.stored_bytes), (0, 0));
+    }
+
+    #[test]
+    fn replacing_a_message_counts_its_new_size_only() {
+        let mut quotas = tracker();
+        let post = quotas.reserve("key", Some("a"), 80).unwrap();
+        quotas.commit(post);
+        let put = quotas.reserve("key", Some("a"), 90).unwrap();
+        quotas.commit(put);
+        assert_eq!(quotas.report("key").stored_bytes, 90);
+
+        let write = quotas.reserve("key", None, 0).unwrap();
+        quotas.commit(write);
+        assert!(matches!(
+            quotas.reserve("key", None, 0),
+            Err(QuotaError::DailyWritesExceeded { limit: 3, used: 3 })
+        ));
+    }
+}
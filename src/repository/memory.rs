@@ -0,0 +1,636 @@
+use super::{
+    AuthorCount, ClearFilter, MessagePatch, MessageRepository, MessageStats, MessageUpdate,
+    OutboxEntry, OutboxKind, PageStart, PageView, RepositoryResult, SortKey, UpdateOutcome,
+    LIKE_REQUEST_TTL_MS,
+};
+use crate::models::{timestamp_now, Maybe, Message, Reactions};
+use async_trait::async_trait;
+use rand::seq::IteratorRandom;
+use std::{
+    collections::{BTreeMap, BTreeSet, HashMap, VecDeque},
+    sync::atomic::{AtomicI64, Ordering},
+};
+use tokio::sync::Mutex;
+
+/// An in-memory repository, ordered by uuid like the postgres one. Useful for exercising
+/// handlers without a database. Soft-deleted messages stay in the map, left out of the reads.
+#[derive(Default)]
+pub struct InMemoryMessageRepository {
+    messages: Mutex<BTreeMap<String, Message>>,
+    outbox: Mutex<VecDeque<OutboxEntry>>,
+    last_outbox_id: AtomicI64,
+    /// The keys of the likes applied lately, with when they were.
+    like_requests: Mutex<HashMap<String, i64>>,
+}
+
+impl InMemoryMessageRepository {
+    pub fn new() -> Self {
+        Self {
+            // the outbox ids keep growing across restarts like those of postgres, the relay
+            // skips the ids it relayed before
+            last_outbox_id: AtomicI64::new(timestamp_now() * 1000),
+            ..Self::default()
+        }
+    }
+
+    /// Records a change in the outbox, which left the message at `revision`. The author, message
+    /// and parent are only recorded if `text_changed`, the reactions and the creation and update
+    /// times only if set.
+    async fn record(
+        &self,
+        kind: OutboxKind,
+        uuid: &str,
+        update: Option<(&MessageUpdate, i64)>,
+        text_changed: bool,
+        reactions: Option<&Reactions>,
+        (created_at, updated_at): (Option<i64>, Option<i64>),
+    ) {
+        let id = self.last_outbox_id.fetch_add(1, Ordering::Relaxed) + 1;
+        let revision = update.map(|(_, revision)| revision);
+        let update = update.map(|(update, _)| update);
+        let text = update.filter(|_| text_changed);
+        self.outbox.lock().await.push_back(OutboxEntry {
+            id,
+            kind,
+            uuid: uuid.to_string(),
+            author: text.map(|u| u.author.clone()),
+            message: text.map(|u| u.message.clone()),
+            parent_uuid: text.and_then(|u| u.parent_uuid.clone()),
+            likes: update.map(|u| u.likes),
+            image_updated: update.is_some_and(|u| u.has_image.is_some()),
+            client_timestamp: update.and_then(|u| u.client_timestamp),
+            server_timestamp: update.map(|u| u.server_timestamp),
+            created_at,
+            updated_at,
+            revision,
+            reactions: reactions.cloned(),
+        });
+    }
+
+    /// Records the post of `message`, which clients see as new.
+    async fn record_post(&self, message: &Message) {
+        let update = MessageUpdate {
+            author: message.author.clone(),
+            message: message.message.clone(),
+            parent_uuid: message.parent_uuid.clone(),
+            likes: message.likes,
+            has_image: Some(message.has_image),
+            client_timestamp: message.client_timestamp,
+            server_timestamp: message.server_timestamp,
+            expected_revision: None,
+        };
+        self.record(
+            OutboxKind::Post,
+            &message.uuid,
+            Some((&update, message.revision)),
+            true,
+            Some(&message.reactions),
+            (Some(message.created_at), Some(message.updated_at)),
+        )
+        .await;
+    }
+}
+
+#[async_trait]
+impl MessageRepository for InMemoryMessageRepository {
+    async fn all_uuids(&self) -> RepositoryResult<Vec<String>> {
+        Ok(self
+            .messages
+            .lock()
+            .await
+            .values()
+            .filter(|message| message.is_live())
+            .map(|message| message.uuid.clone())
+            .collect())
+    }
+
+    async fn get(&self, uuid: &str) -> RepositoryResult<Option<Message>> {
+        Ok(self
+            .messages
+            .lock()
+            .await
+            .get(uuid)
+            .filter(|message| message.is_live())
+            .cloned())
+    }
+
+    async fn all(&self) -> RepositoryResult<Vec<Message>> {
+        Ok(self
+            .messages
+            .lock()
+            .await
+            .values()
+            .filter(|message| message.is_live())
+            .cloned()
+            .collect())
+    }
+
+    async fn page(
+        &self,
+        view: &PageView,
+        start: &PageStart,
+        limit: usize,
+    ) -> RepositoryResult<Vec<Message>> {
+        let messages = self.messages.lock().await;
+        let mut matching: Vec<_> = messages
+            .values()
+            .filter(|message| message.is_live() && view.matches(message))
+            .collect();
+        // the map is already ordered by uuid
+        if view.sort != SortKey::Uuid || view.descending {
+            matching.sort_by(|a, b| view.compare(a, b));
+        }
+        let offset = match start {
+            PageStart::Offset(offset) => *offset,
+            PageStart::After(_) if view.sort != SortKey::Uuid => {
+                return Err("pages after a uuid must be in uuid order".into());
+            }
+            PageStart::After(None) => 0,
+            PageStart::After(Some(uuid)) => {
+                matching.partition_point(|message| match view.descending {
+                    true => message.uuid.as_str() >= uuid.as_str(),
+                    false => message.uuid.as_str() <= uuid.as_str(),
+                })
+            }
+        };
+        Ok(matching
+            .into_iter()
+            .skip(offset)
+            .take(limit)
+            .cloned()
+            .collect())
+    }
+
+    async fn count_by_author(&self, author: &str) -> RepositoryResult<usize> {
+        Ok(self
+            .messages
+            .lock()
+            .await
+            .values()
+            .filter(|message| message.is_live() && message.author == author)
+            .count())
+    }
+
+    async fn stats(&self) -> RepositoryResult<MessageStats> {
+        let messages = self.messages.lock().await;
+        let live: Vec<_> = messages
+            .values()
+            .filter(|message| message.is_live())
+            .collect();
+        let authors: BTreeSet<_> = live.iter().map(|message| &message.author).collect();
+        Ok(MessageStats {
+            total: live.len(),
+            total_likes: live.iter().map(|message| message.likes as i64).sum(),
+            with_image: live.iter().filter(|message| message.has_image).count(),
+            distinct_authors: authors.len(),
+        })
+    }
+
+    async fn authors(
+        &self,
+        limit: usize,
+        offset: usize,
+    ) -> RepositoryResult<(usize, Vec<AuthorCount>)> {
+        let mut counts = BTreeMap::new();
+        for message in self.messages.lock().await.values() {
+            if !message.is_live() {
+                continue;
+            }
+            *counts.entry(message.author.clone()).or_insert(0) += 1;
+        }
+        let total = counts.len();
+        let page = counts
+            .into_iter()
+            .skip(offset)
+            .take(limit)
+            .map(|(author, messages)| AuthorCount { author, messages })
+            .collect();
+        Ok((total, page))
+    }
+
+    async fn search(
+        &self,
+        query: &str,
+        limit: usize,
+        offset: usize,
+    ) -> RepositoryResult<(usize, Vec<Message>)> {
+        // every word must occur in the author or message, matches aren't ranked
+        let words: Vec<_> = query.split_whitespace().map(str::to_lowercase).collect();
+        let matches: Vec<_> = self
+            .messages
+            .lock()
+            .await
+            .values()
+            .filter(|message| message.is_live())
+            .filter(|message| {
+                let text = format!("{} {}", message.author, message.message).to_lowercase();
+                !words.is_empty() && words.iter().all(|word| text.contains(word.as_str()))
+            })
+            .cloned()
+            .collect();
+        let total = matches.len();
+        let page = matches.into_iter().skip(offset).take(limit).collect();
+        Ok((total, page))
+    }
+
+    async fn replies(
+        &self,
+        parent_uuid: &str,
+        limit: usize,
+        offset: usize,
+    ) -> RepositoryResult<(usize, Vec<Message>)> {
+        let messages = self.messages.lock().await;
+        let replies: Vec<_> = messages
+            .values()
+            .filter(|message| {
+                message.is_live() && message.parent_uuid.as_deref() == Some(parent_uuid)
+            })
+            .collect();
+        let total = replies.len();
+        let page = replies
+            .into_iter()
+            .skip(offset)
+            .take(limit)
+            .cloned()
+            .collect();
+        Ok((total, page))
+    }
+
+    async fn sample(&self, n: usize) -> RepositoryResult<Vec<Message>> {
+        let messages = self.messages.lock().await;
+        let live = messages.values().filter(|message| message.is_live());
+        let sample = live.choose_multiple(&mut rand::thread_rng(), n);
+        Ok(sample.into_iter().cloned().collect())
+    }
+
+    async fn insert(&self, message: &Message) -> RepositoryResult<()> {
+        let mut messages = self.messages.lock().await;
+        // a deleted message is replaced, like it would be after it was purged
+        if messages.get(&message.uuid).is_some_and(Message::is_live) {
+            return Err(format!("duplicate uuid {}", message.uuid).into());
+        }
+        messages.insert(message.uuid.clone(), message.clone());
+        drop(messages);
+        self.record_post(message).await;
+        Ok(())
+    }
+
+    async fn insert_many(&self, messages: &[Message]) -> RepositoryResult<()> {
+        let mut stored = self.messages.lock().await;
+        let mut uuids = BTreeSet::new();
+        if let Some(message) = messages.iter().find(|message| {
+            stored.get(&message.uuid).is_some_and(Message::is_live) || !uuids.insert(&message.uuid)
+        }) {
+            return Err(format!("duplicate uuid {}", message.uuid).into());
+        }
+        for message in messages {
+            stored.insert(message.uuid.clone(), message.clone());
+        }
+        drop(stored);
+        for message in messages {
+            self.record_post(message).await;
+        }
+        Ok(())
+    }
+
+    async fn update(&self, uuid: &str, update: &MessageUpdate) -> RepositoryResult<UpdateOutcome> {
+        let mut messages = self.messages.lock().await;
+        let Some(message) = messages.get_mut(uuid).filter(|message| message.is_live()) else {
+            return Ok(UpdateOutcome::NotFound);
+        };
+        if update
+            .expected_revision
+            .is_some_and(|revision| revision != message.revision)
+        {
+            let revision = message.revision;
+            return Ok(UpdateOutcome::Stale { revision });
+        }
+        let text_changed = apply_update(message, update);
+        let revision = message.revision;
+        drop(messages);
+        self.record(
+            OutboxKind::Put,
+            uuid,
+            Some((update, revision)),
+            text_changed,
+            None,
+            (None, Some(update.server_timestamp)),
+        )
+        .await;
+        Ok(UpdateOutcome::Updated { revision })
+    }
+
+    async fn update_many(
+        &self,
+        updates: &[(String, MessageUpdate)],
+    ) -> RepositoryResult<Vec<UpdateOutcome>> {
+        let mut messages = self.messages.lock().await;
+        // checked against the revisions the earlier updates of the batch lead to
+        let mut revisions = BTreeMap::new();
+        let mut outcomes = Vec::with_capacity(updates.len());
+        for (uuid, update) in updates {
+            let current = revisions.get(uuid.as_str()).copied().or_else(|| {
+                messages
+                    .get(uuid)
+                    .filter(|message| message.is_live())
+                    .map(|message| message.revision)
+            });
+            outcomes.push(match current {
+                None => UpdateOutcome::NotFound,
+                Some(revision) if update.expected_revision.is_some_and(|r| r != revision) => {
+                    UpdateOutcome::Stale { revision }
+                }
+                Some(revision) => {
+                    revisions.insert(uuid.as_str(), revision + 1);
+                    UpdateOutcome::Updated {
+                        revision: revision + 1,
+                    }
+                }
+            });
+        }
+        if !outcomes
+            .iter()
+            .all(|outcome| matches!(outcome, UpdateOutcome::Updated { .. }))
+        {
+            return Ok(outcomes);
+        }
+
+        let mut applied = Vec::with_capacity(updates.len());
+        for (uuid, update) in updates {
+            let message = messages.get_mut(uuid).expect("the update was checked");
+            let text_changed = apply_update(message, update);
+            applied.push((uuid, update, message.revision, text_changed));
+        }
+        drop(messages);
+        for (uuid, update, revision, text_changed) in applied {
+            self.record(
+                OutboxKind::Put,
+                uuid,
+                Some((update, revision)),
+                text_changed,
+                None,
+                (None, Some(update.server_timestamp)),
+            )
+            .await;
+        }
+        Ok(outcomes)
+    }
+
+    async fn patch(&self, uuid: &str, patch: &MessagePatch) -> RepositoryResult<UpdateOutcome> {
+        let mut messages = self.messages.lock().await;
+        let (update, text_changed, revision) =
+            match messages.get_mut(uuid).filter(|message| message.is_live()) {
+                Some(message)
+                    if patch
+                        .expected_revision
+                        .is_some_and(|revision| revision != message.revision) =>
+                {
+                    let revision = message.revision;
+                    return Ok(UpdateOutcome::Stale { revision });
+                }
+                Some(message) => {
+                    let old = (message.author.clone(), message.message.clone());
+                    if let Maybe::Present(author) = &patch.author {
+                        message.author = author.clone();
+                    }
+                    if let Maybe::Present(text) = &patch.message {
+                        message.message = text.clone();
+                    }
+                    if let Maybe::Present(likes) = patch.likes {
+                        message.likes = likes;
+                    }
+                    if let Maybe::Present(has_image) = patch.has_image {
+                        message.has_image = has_image;
+                    }
+                    if let Maybe::Present(client_timestamp) = patch.client_timestamp {
+                        message.client_timestamp = client_timestamp;
+                    }
+                    message.server_timestamp = patch.server_timestamp;
+                    message.updated_at = patch.server_timestamp;
+                    message.revision += 1;
+                    let update = MessageUpdate {
+                        author: message.author.clone(),
+                        message: message.message.clone(),
+                        parent_uuid: message.parent_uuid.clone(),
+                        likes: message.likes,
+                        has_image: patch.has_image.present(),
+                        client_timestamp: message.client_timestamp,
+                        server_timestamp: message.server_timestamp,
+                        expected_revision: None,
+                    };
+                    let text_changed = message.author != old.0 || message.message != old.1;
+                    (update, text_changed, message.revision)
+                }
+                None => return Ok(UpdateOutcome::NotFound),
+            };
+        drop(messages);
+        self.record(
+            OutboxKind::Put,
+            uuid,
+            Some((&update, revision)),
+            text_changed,
+            None,
+            (None, Some(update.server_timestamp)),
+        )
+        .await;
+        Ok(UpdateOutcome::Updated { revision })
+    }
+
+    async fn add_likes(
+        &self,
+        uuid: &str,
+        delta: i32,
+        key: Option<&str>,
+        server_timestamp: i64,
+    ) -> RepositoryResult<Option<i32>> {
+        let mut messages = self.messages.lock().await;
+        let (update, revision) = match messages.get_mut(uuid).filter(|message| message.is_live()) {
+            Some(message) => {
+                if let Some(key) = key {
+                    let mut like_requests = self.like_requests.lock().await;
+                    like_requests.retain(|_, applied_at| {
+                        *applied_at >= server_timestamp - LIKE_REQUEST_TTL_MS
+                    });
+                    if like_requests
+                        .insert(key.to_string(), server_timestamp)
+                        .is_some()
+                    {
+                        return Ok(Some(message.likes));
+                    }
+                }
+                message.likes = message.likes.saturating_add(delta).max(0);
+                message.server_timestamp = server_timestamp;
+                message.revision += 1;
+                let update = MessageUpdate {
+                    author: message.author.clone(),
+                    message: message.message.clone(),
+                    parent_uuid: message.parent_uuid.clone(),
+                    likes: message.likes,
+                    has_image: None,
+                    client_timestamp: message.client_timestamp,
+                    server_timestamp,
+                    expected_revision: None,
+                };
+                (update, message.revision)
+            }
+            None => return Ok(None),
+        };
+        drop(messages);
+        self.record(
+            OutboxKind::Put,
+            uuid,
+            Some((&update, revision)),
+            false,
+            None,
+            (None, None),
+        )
+        .await;
+        Ok(Some(update.likes))
+    }
+
+    async fn add_reaction(
+        &self,
+        uuid: &str,
+        kind: &str,
+        delta: i32,
+        server_timestamp: i64,
+    ) -> RepositoryResult<Option<Reactions>> {
+        let mut messages = self.messages.lock().await;
+        let (update, revision, reactions) =
+            match messages.get_mut(uuid).filter(|message| message.is_live()) {
+                Some(message) => {
+                    message.reactions.add(kind, delta);
+                    message.server_timestamp = server_timestamp;
+                    message.revision += 1;
+                    let update = MessageUpdate {
+                        author: message.author.clone(),
+                        message: message.message.clone(),
+                        parent_uuid: message.parent_uuid.clone(),
+                        likes: message.likes,
+                        has_image: None,
+                        client_timestamp: message.client_timestamp,
+                        server_timestamp,
+                        expected_revision: None,
+                    };
+                    (update, message.revision, message.reactions.clone())
+                }
+                None => return Ok(None),
+            };
+        drop(messages);
+        let recorded = Some((&update, revision));
+        self.record(
+            OutboxKind::Put,
+            uuid,
+            recorded,
+            false,
+            Some(&reactions),
+            (None, None),
+        )
+        .await;
+        Ok(Some(reactions))
+    }
+
+    async fn delete(&self, uuid: &str, deleted_at: i64) -> RepositoryResult<u64> {
+        match self.messages.lock().await.get_mut(uuid) {
+            Some(message) if message.is_live() => message.deleted_at = Some(deleted_at),
+            _ => return Ok(0),
+        }
+        self.record(OutboxKind::Delete, uuid, None, false, None, (None, None))
+            .await;
+        Ok(1)
+    }
+
+    async fn restore(
+        &self,
+        uuid: &str,
+        server_timestamp: i64,
+    ) -> RepositoryResult<Option<Message>> {
+        let message = match self.messages.lock().await.get_mut(uuid) {
+            Some(message) if !message.is_live() => {
+                message.deleted_at = None;
+                message.server_timestamp = server_timestamp;
+                message.revision += 1;
+                message.clone()
+            }
+            _ => return Ok(None),
+        };
+        // clients were told the message is gone, it comes back as a new one
+        self.record_post(&message).await;
+        Ok(Some(message))
+    }
+
+    async fn purge_deleted(&self, deleted_before: i64) -> RepositoryResult<Vec<String>> {
+        let mut purged = Vec::new();
+        self.messages.lock().await.retain(|uuid, message| {
+            let purge = message
+                .deleted_at
+                .is_some_and(|deleted_at| deleted_at < deleted_before);
+            if purge {
+                purged.push(uuid.clone());
+            }
+            !purge
+        });
+        Ok(purged)
+    }
+
+    async fn clear(&self) -> RepositoryResult<()> {
+        self.messages.lock().await.clear();
+        self.outbox.lock().await.clear();
+        Ok(())
+    }
+
+    async fn clear_matching(&self, filter: &ClearFilter) -> RepositoryResult<Vec<String>> {
+        let mut deleted = Vec::new();
+        self.messages.lock().await.retain(|uuid, message| {
+            let matches = message.is_live() && filter.matches(message);
+            if matches {
+                deleted.push(uuid.clone());
+            }
+            !matches
+        });
+        for uuid in &deleted {
+            self.record(OutboxKind::Delete, uuid, None, false, None, (None, None))
+                .await;
+        }
+        Ok(deleted)
+    }
+
+    async fn outbox(&self, limit: usize) -> RepositoryResult<Vec<OutboxEntry>> {
+        Ok(self
+            .outbox
+            .lock()
+            .await
+            .iter()
+            .take(limit)
+            .cloned()
+            .collect())
+    }
+
+    async fn ack_outbox(&self, id: i64) -> RepositoryResult<()> {
+        let mut outbox = self.outbox.lock().await;
+        while outbox.front().is_some_and(|entry| entry.id <= id) {
+            outbox.pop_front();
+        }
+        Ok(())
+    }
+}
+
+/// Applies `update` to `message`, bumping its revision, and returns whether its text or parent
+/// changed.
+fn apply_update(message: &mut Message, update: &MessageUpdate) -> bool {
+    let text_changed = message.author != update.author
+        || message.message != update.message
+        || message.parent_uuid != update.parent_uuid;
+    message.author = update.author.clone();
+    message.message = update.message.clone();
+    message.parent_uuid = update.parent_uuid.clone();
+    message.likes = update.likes;
+    if let Some(has_image) = update.has_image {
+        message.has_image = has_image;
+    }
+    message.client_timestamp = update.client_timestamp;
+    message.server_timestamp = update.server_timestamp;
+    message.updated_at = update.server_timestamp;
+    message.revision += 1;
+    text_changed
+}
@@ -0,0 +1,409 @@
+mod memory;
+#[cfg(feature = "postgres")]
+mod postgres;
+
+pub use memory::InMemoryMessageRepository;
+#[cfg(feature = "postgres")]
+pub use postgres::PgMessageRepository;
+
+use crate::models::{Maybe, Message, Reactions};
+use async_trait::async_trait;
+use futures_util::{
+    stream::{self, BoxStream},
+    StreamExt, TryStreamExt,
+};
+use serde::Serialize;
+use std::{cmp::Ordering, error::Error, str::FromStr};
+#[cfg(feature = "bindings")]
+use ts_rs::TS;
+
+pub type RepositoryResult<T> = Result<T, Box<dyn Error + Send + Sync>>;
+
+/// How many messages [`MessageRepository::stream_all`] fetches at once.
+const STREAM_PAGE_SIZE: usize = 500;
+
+/// How long the key of an applied like is kept, a retry of the like within it isn't counted
+/// again.
+pub const LIKE_REQUEST_TTL_MS: i64 = 24 * 60 * 60 * 1000;
+
+/// Selects the messages removed by a partial clear. Set filters must all match.
+#[derive(Debug, Clone, Default)]
+pub struct ClearFilter {
+    pub author: Option<String>,
+    pub uuid_prefix: Option<String>,
+    /// Only the messages created before this time, in milliseconds since the epoch.
+    pub created_before: Option<i64>,
+    /// Only the messages last updated before this time, in milliseconds since the epoch.
+    pub updated_before: Option<i64>,
+}
+
+impl ClearFilter {
+    /// Whether no filter is set, i.e. every message matches.
+    pub fn is_empty(&self) -> bool {
+        self.author.is_none()
+            && self.uuid_prefix.is_none()
+            && self.created_before.is_none()
+            && self.updated_before.is_none()
+    }
+
+    pub fn matches(&self, message: &Message) -> bool {
+        self.author
+            .as_ref()
+            .is_none_or(|author| &message.author == author)
+            && self
+                .uuid_prefix
+                .as_ref()
+                .is_none_or(|prefix| message.uuid.starts_with(prefix.as_str()))
+            && self
+                .created_before
+                .is_none_or(|created_before| message.created_at < created_before)
+            && self
+                .updated_before
+                .is_none_or(|updated_before| message.updated_at < updated_before)
+    }
+}
+
+/// What the messages of fresh pages are sorted by, the uuid breaking ties.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Hash)]
+pub enum SortKey {
+    #[default]
+    Uuid,
+    Likes,
+    Author,
+    CreatedAt,
+}
+
+impl FromStr for SortKey {
+    type Err = &'static str;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "uuid" => Ok(SortKey::Uuid),
+            "likes" => Ok(SortKey::Likes),
+            "author" => Ok(SortKey::Author),
+            "created_at" => Ok(SortKey::CreatedAt),
+            _ => Err("sort must be likes, author, created_at or uuid."),
+        }
+    }
+}
+
+/// The order and filter of the messages of fresh pages. The default is every message by
+/// ascending uuid.
+#[derive(Debug, Clone, Default, PartialEq, Eq, Hash)]
+pub struct PageView {
+    pub sort: SortKey,
+    pub descending: bool,
+    /// Only the messages of this author.
+    pub author: Option<String>,
+}
+
+impl PageView {
+    pub fn is_default(&self) -> bool {
+        *self == Self::default()
+    }
+
+    pub fn matches(&self, message: &Message) -> bool {
+        self.author
+            .as_ref()
+            .is_none_or(|author| &message.author == author)
+    }
+
+    /// The order of `a` and `b` on the pages.
+    pub fn compare(&self, a: &Message, b: &Message) -> Ordering {
+        let ordering = match self.sort {
+            SortKey::Uuid => Ordering::Equal,
+            SortKey::Likes => a.likes.cmp(&b.likes),
+            SortKey::Author => a.author.cmp(&b.author),
+            SortKey::CreatedAt => a.created_at.cmp(&b.created_at),
+        }
+        .then_with(|| a.uuid.cmp(&b.uuid));
+        match self.descending {
+            true => ordering.reverse(),
+            false => ordering,
+        }
+    }
+}
+
+/// Aggregates over all the messages.
+#[derive(Serialize, Debug, Clone, Copy, Default, PartialEq, Eq)]
+#[cfg_attr(feature = "bindings", derive(TS), ts(export))]
+pub struct MessageStats {
+    pub total: usize,
+    pub total_likes: i64,
+    /// How many messages have an image.
+    pub with_image: usize,
+    pub distinct_authors: usize,
+}
+
+/// An author and how many messages they wrote.
+#[derive(Serialize, Debug, Clone, PartialEq, Eq)]
+#[cfg_attr(feature = "bindings", derive(TS), ts(export))]
+pub struct AuthorCount {
+    pub author: String,
+    pub messages: usize,
+}
+
+/// Where a fresh page starts.
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub enum PageStart {
+    /// After skipping this many messages, which the database still has to walk through.
+    Offset(usize),
+    /// Right after the message with this uuid, or at the first message when `None`. Only valid
+    /// for views in uuid order, which an index seeks to directly.
+    After(Option<String>),
+}
+
+/// The fields of a message that can be changed by an update.
+#[derive(Debug, Clone)]
+pub struct MessageUpdate {
+    pub author: String,
+    pub message: String,
+    /// The message it replies to, `None` making it start a thread.
+    pub parent_uuid: Option<String>,
+    pub likes: i32,
+    /// `None` leaves `has_image` untouched.
+    pub has_image: Option<bool>,
+    pub client_timestamp: Option<i64>,
+    pub server_timestamp: i64,
+    /// The update only applies to this revision of the message, if set.
+    pub expected_revision: Option<i64>,
+}
+
+/// How an update of a message went.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum UpdateOutcome {
+    /// The message was updated to `revision`.
+    Updated {
+        revision: i64,
+    },
+    NotFound,
+    /// The message is at `revision`, not the expected one, and was left as it is.
+    Stale {
+        revision: i64,
+    },
+}
+
+/// The fields a partial update changes, the others are left as they are.
+#[derive(Debug, Clone, Default)]
+pub struct MessagePatch {
+    pub author: Maybe<String>,
+    pub message: Maybe<String>,
+    pub likes: Maybe<i32>,
+    pub has_image: Maybe<bool>,
+    pub client_timestamp: Maybe<Option<i64>>,
+    pub server_timestamp: i64,
+    /// The patch only applies to this revision, like `If-Match`.
+    pub expected_revision: Option<i64>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum OutboxKind {
+    Post,
+    Put,
+    Delete,
+}
+
+impl OutboxKind {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            OutboxKind::Post => "post",
+            OutboxKind::Put => "put",
+            OutboxKind::Delete => "delete",
+        }
+    }
+}
+
+impl FromStr for OutboxKind {
+    type Err = &'static str;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "post" => Ok(OutboxKind::Post),
+            "put" => Ok(OutboxKind::Put),
+            "delete" => Ok(OutboxKind::Delete),
+            _ => Err("Invalid outbox kind"),
+        }
+    }
+}
+
+/// A mutation recorded in the same transaction as the change to `messages`, waiting to be
+/// relayed to the `MutationManager`.
+#[derive(Debug, Clone)]
+pub struct OutboxEntry {
+    pub id: i64,
+    pub kind: OutboxKind,
+    pub uuid: String,
+    /// `None` for deletes, and for puts that didn't change the author or message.
+    pub author: Option<String>,
+    pub message: Option<String>,
+    /// Recorded along with the author and message, `None` then meaning no parent.
+    pub parent_uuid: Option<String>,
+    pub likes: Option<i32>,
+    pub image_updated: bool,
+    /// `None` for deletes.
+    pub client_timestamp: Option<i64>,
+    pub server_timestamp: Option<i64>,
+    /// The revision of the message after the mutation, `None` for deletes.
+    pub revision: Option<i64>,
+    /// `None` but for posts.
+    pub created_at: Option<i64>,
+    /// `None` for deletes, and for puts of likes or reactions, which don't update the message.
+    pub updated_at: Option<i64>,
+    /// `None` for deletes, and for puts that didn't change the reactions.
+    pub reactions: Option<Reactions>,
+}
+
+/// Storage of the `messages` table, injected through `AppState` so handlers don't depend on a
+/// live database.
+#[async_trait]
+pub trait MessageRepository: Send + Sync {
+    /// Returns the uuids of all messages.
+    async fn all_uuids(&self) -> RepositoryResult<Vec<String>>;
+
+    /// Returns the message with `uuid`, if any.
+    async fn get(&self, uuid: &str) -> RepositoryResult<Option<Message>>;
+
+    /// Returns every message ordered by uuid.
+    async fn all(&self) -> RepositoryResult<Vec<Message>>;
+
+    /// Streams every message ordered by uuid, for dumps too large to load. The messages are
+    /// fetched a page at a time, each page continuing after the uuid the one before ended at, so
+    /// no connection is held while a slow reader takes the stream.
+    fn stream_all(&self) -> BoxStream<'_, RepositoryResult<Message>> {
+        let view = PageView::default();
+        stream::try_unfold(Some(None), move |after| {
+            let view = view.clone();
+            async move {
+                let Some(after) = after else {
+                    return RepositoryResult::Ok(None);
+                };
+                let page = self
+                    .page(&view, &PageStart::After(after), STREAM_PAGE_SIZE)
+                    .await?;
+                // a short page is the last one
+                let next = (page.len() == STREAM_PAGE_SIZE)
+                    .then(|| page.last().map(|message| message.uuid.clone()));
+                Ok(Some((stream::iter(page.into_iter().map(Ok)), next)))
+            }
+        })
+        .try_flatten()
+        .boxed()
+    }
+
+    /// Returns up to `limit` messages from `start` on, in the order and with the filter of
+    /// `view`.
+    async fn page(
+        &self,
+        view: &PageView,
+        start: &PageStart,
+        limit: usize,
+    ) -> RepositoryResult<Vec<Message>>;
+
+    /// Returns the number of messages of `author`.
+    async fn count_by_author(&self, author: &str) -> RepositoryResult<usize>;
+
+    /// Returns the aggregates over all the messages.
+    async fn stats(&self) -> RepositoryResult<MessageStats>;
+
+    /// Returns the number of distinct authors, and up to `limit` of them with their message
+    /// counts in alphabetical order, skipping the first `offset`.
+    async fn authors(
+        &self,
+        limit: usize,
+        offset: usize,
+    ) -> RepositoryResult<(usize, Vec<AuthorCount>)>;
+
+    /// Returns the total number of messages matching the full-text `query`, and up to `limit` of
+    /// them ranked by relevance, skipping the first `offset`.
+    async fn search(
+        &self,
+        query: &str,
+        limit: usize,
+        offset: usize,
+    ) -> RepositoryResult<(usize, Vec<Message>)>;
+
+    /// Returns the total number of replies to the message `parent_uuid`, and up to `limit` of
+    /// them in uuid order, skipping the first `offset`.
+    async fn replies(
+        &self,
+        parent_uuid: &str,
+        limit: usize,
+        offset: usize,
+    ) -> RepositoryResult<(usize, Vec<Message>)>;
+
+    /// Returns up to `n` live messages picked at random, in no particular order.
+    async fn sample(&self, n: usize) -> RepositoryResult<Vec<Message>>;
+
+    /// Inserts a new message and records a post in the outbox.
+    async fn insert(&self, message: &Message) -> RepositoryResult<()>;
+
+    /// Inserts new messages and records a post in the outbox for each, all of them or none.
+    async fn insert_many(&self, messages: &[Message]) -> RepositoryResult<()>;
+
+    /// Updates a message, bumping its revision and update time, and records a put in the
+    /// outbox. The author and message are left out of the put if neither changed.
+    async fn update(&self, uuid: &str, update: &MessageUpdate) -> RepositoryResult<UpdateOutcome>;
+
+    /// Applies `updates` in order, like `update`, all of them or none: if any isn't
+    /// `Updated`, none is applied and the outcomes tell which failed.
+    async fn update_many(
+        &self,
+        updates: &[(String, MessageUpdate)],
+    ) -> RepositoryResult<Vec<UpdateOutcome>>;
+
+    /// Changes the fields of a message present in `patch`, and its update time, and records a
+    /// put in the outbox with the resulting likes and timestamps. The author and message are
+    /// left out of the put if neither changed. Like `update`, the message is left as it is if it
+    /// isn't at the expected revision.
+    async fn patch(&self, uuid: &str, patch: &MessagePatch) -> RepositoryResult<UpdateOutcome>;
+
+    /// Adds `delta` to the likes of a message in a single statement, so concurrent likes don't
+    /// overwrite each other, and records a put in the outbox. The likes stay between 0 and
+    /// `i32::MAX`. A like whose `key` was applied within [`LIKE_REQUEST_TTL_MS`] is left out.
+    /// Returns the new count, the current one for a repeated key, `None` if there is no such
+    /// message.
+    async fn add_likes(
+        &self,
+        uuid: &str,
+        delta: i32,
+        key: Option<&str>,
+        server_timestamp: i64,
+    ) -> RepositoryResult<Option<i32>>;
+
+    /// Adds `delta` to the count of the `kind` reaction to a message in a single statement, like
+    /// `add_likes`, and records a put in the outbox. Returns all the reactions to the message,
+    /// `None` if there is no such message.
+    async fn add_reaction(
+        &self,
+        uuid: &str,
+        kind: &str,
+        delta: i32,
+        server_timestamp: i64,
+    ) -> RepositoryResult<Option<Reactions>>;
+
+    /// Soft-deletes a message, marking it deleted at `deleted_at` until it is restored or purged,
+    /// and records a delete in the outbox, returning the number of affected rows.
+    async fn delete(&self, uuid: &str, deleted_at: i64) -> RepositoryResult<u64>;
+
+    /// Brings back a soft-deleted message, written again at `server_timestamp`, and records a
+    /// post of it in the outbox. Returns the message, `None` if there is no such deleted message.
+    async fn restore(&self, uuid: &str, server_timestamp: i64)
+        -> RepositoryResult<Option<Message>>;
+
+    /// Permanently removes the messages soft-deleted before `deleted_before`, returning their
+    /// uuids. Clients were told about the deletes already, nothing is recorded in the outbox.
+    async fn purge_deleted(&self, deleted_before: i64) -> RepositoryResult<Vec<String>>;
+
+    /// Deletes all messages, soft-deleted ones included, along with the outbox.
+    async fn clear(&self) -> RepositoryResult<()>;
+
+    /// Deletes the live messages matching `filter` and records a delete in the outbox for each,
+    /// returning their uuids.
+    async fn clear_matching(&self, filter: &ClearFilter) -> RepositoryResult<Vec<String>>;
+
+    /// Returns up to `limit` of the oldest outbox entries.
+    async fn outbox(&self, limit: usize) -> RepositoryResult<Vec<OutboxEntry>>;
+
+    /// Removes the outbox entries up to and including `id`, once they have been relayed.
+    async fn ack_outbox(&self, id: i64) -> RepositoryResult<()>;
+}
@@ -0,0 +1,928 @@
+use super::{
+    AuthorCount, ClearFilter, MessagePatch, MessageRepository, MessageStats, MessageUpdate,
+    OutboxEntry, OutboxKind, PageStart, PageView, RepositoryResult, SortKey, UpdateOutcome,
+    LIKE_REQUEST_TTL_MS,
+};
+use crate::{
+    deadline,
+    models::{Maybe, Message, Reactions},
+};
+use async_trait::async_trait;
+use futures_util::TryStreamExt;
+use sqlx::{pool::PoolConnection, PgConnection, PgPool, Postgres, QueryBuilder, Row, Transaction};
+use std::{
+    ops::{Deref, DerefMut},
+    sync::Arc,
+};
+
+pub struct PgMessageRepository {
+    pool: Arc<PgPool>,
+}
+
+impl PgMessageRepository {
+    pub fn new(pool: Arc<PgPool>) -> Self {
+        Self { pool }
+    }
+
+    /// Begins a write transaction whose statements are cancelled by postgres once the deadline of
+    /// the current request has passed, so abandoned requests don't keep queries running.
+    async fn begin(&self) -> Result<Transaction<'static, Postgres>, sqlx::Error> {
+        let mut tx = self.pool.begin().await?;
+        if let Some(remaining) = deadline::remaining() {
+            // 0 would disable the timeout
+            let ms = remaining.as_millis().max(1);
+            sqlx::query(&format!("SET LOCAL statement_timeout = {ms}"))
+                .execute(&mut tx)
+                .await?;
+        }
+        Ok(tx)
+    }
+
+    /// Takes a connection for reads. The statement timeout of a deadline can only be scoped to a
+    /// transaction, so reads without one skip the round-trips of `BEGIN` and `COMMIT`.
+    async fn read(&self) -> Result<ReadConnection, sqlx::Error> {
+        Ok(match deadline::remaining() {
+            Some(_) => ReadConnection::Scoped(self.begin().await?),
+            None => ReadConnection::Plain(self.pool.acquire().await?),
+        })
+    }
+}
+
+/// A connection for reads, see [`PgMessageRepository::read`].
+enum ReadConnection {
+    Plain(PoolConnection<Postgres>),
+    Scoped(Transaction<'static, Postgres>),
+}
+
+impl ReadConnection {
+    /// Ends the transaction scoping the statement timeout, if there is one.
+    async fn finish(self) -> Result<(), sqlx::Error> {
+        match self {
+            Self::Plain(_) => Ok(()),
+            Self::Scoped(tx) => tx.commit().await,
+        }
+    }
+}
+
+impl Deref for ReadConnection {
+    type Target = PgConnection;
+
+    fn deref(&self) -> &PgConnection {
+        match self {
+            Self::Plain(conn) => conn,
+            Self::Scoped(tx) => tx,
+        }
+    }
+}
+
+impl DerefMut for ReadConnection {
+    fn deref_mut(&mut self) -> &mut PgConnection {
+        match self {
+            Self::Plain(conn) => conn,
+            Self::Scoped(tx) => tx,
+        }
+    }
+}
+
+#[async_trait]
+impl MessageRepository for PgMessageRepository {
+    async fn all_uuids(&self) -> RepositoryResult<Vec<String>> {
+        let uuids = sqlx::query!("SELECT uuid FROM messages WHERE deleted_at IS NULL")
+            .map(|row| row.uuid)
+            .fetch(self.pool.as_ref())
+            .try_collect()
+            .await?;
+        Ok(uuids)
+    }
+
+    async fn get(&self, uuid: &str) -> RepositoryResult<Option<Message>> {
+        let mut conn = self.read().await?;
+        let message = sqlx::query_as!(
+            Message,
+            r#"SELECT uuid, author, message, parent_uuid, likes, has_image, client_timestamp, server_timestamp, created_at, updated_at, deleted_at, revision, reactions AS "reactions: Reactions" FROM messages WHERE uuid = $1 AND deleted_at IS NULL"#,
+            uuid
+        )
+            .fetch_optional(&mut *conn)
+            .await?;
+        conn.finish().await?;
+        Ok(message)
+    }
+
+    async fn all(&self) -> RepositoryResult<Vec<Message>> {
+        let mut conn = self.read().await?;
+        let messages = sqlx::query_as!(
+            Message,
+            r#"SELECT uuid, author, message, parent_uuid, likes, has_image, client_timestamp, server_timestamp, created_at, updated_at, deleted_at, revision, reactions AS "reactions: Reactions" FROM messages WHERE deleted_at IS NULL ORDER BY uuid"#
+        )
+            .fetch_all(&mut *conn)
+            .await?;
+        conn.finish().await?;
+        Ok(messages)
+    }
+
+    async fn page(
+        &self,
+        view: &PageView,
+        start: &PageStart,
+        limit: usize,
+    ) -> RepositoryResult<Vec<Message>> {
+        if matches!(start, PageStart::After(_)) && view.sort != SortKey::Uuid {
+            return Err("pages after a uuid must be in uuid order".into());
+        }
+
+        // the sort column and direction come from the enums, only the values are bound
+        let mut query = QueryBuilder::<Postgres>::new(
+            "SELECT uuid, author, message, parent_uuid, likes, has_image, client_timestamp, server_timestamp, created_at, updated_at, deleted_at, revision, reactions FROM messages WHERE ",
+        );
+        let mut conditions = query.separated(" AND ");
+        conditions.push("deleted_at IS NULL");
+        if let Some(author) = &view.author {
+            conditions.push("author = ").push_bind_unseparated(author);
+        }
+        if let PageStart::After(Some(uuid)) = start {
+            // seeks the uuid index instead of walking through the skipped rows
+            conditions
+                .push(if view.descending {
+                    "uuid < "
+                } else {
+                    "uuid > "
+                })
+                .push_bind_unseparated(uuid);
+        }
+        let direction = match view.descending {
+            true => "DESC",
+            false => "ASC",
+        };
+        query.push(" ORDER BY ");
+        match view.sort {
+            SortKey::Uuid => (),
+            SortKey::Likes => {
+                query.push(format_args!("likes {direction}, "));
+            }
+            SortKey::Author => {
+                query.push(format_args!("author {direction}, "));
+            }
+            SortKey::CreatedAt => {
+                query.push(format_args!("created_at {direction}, "));
+            }
+        }
+        query.push(format_args!("uuid {direction} LIMIT "));
+        query.push_bind(limit as i64);
+        if let PageStart::Offset(offset) = start {
+            query.push(" OFFSET ").push_bind(*offset as i64);
+        }
+
+        let mut conn = self.read().await?;
+        let messages = query
+            .build_query_as::<Message>()
+            .fetch_all(&mut *conn)
+            .await?;
+        conn.finish().await?;
+        Ok(messages)
+    }
+
+    async fn count_by_author(&self, author: &str) -> RepositoryResult<usize> {
+        let mut conn = self.read().await?;
+        let count = sqlx::query_scalar!(
+            r#"SELECT COUNT(*) AS "count!" FROM messages WHERE author = $1 AND deleted_at IS NULL"#,
+            author
+        )
+        .fetch_one(&mut *conn)
+        .await?;
+        conn.finish().await?;
+        Ok(count as usize)
+    }
+
+    async fn stats(&self) -> RepositoryResult<MessageStats> {
+        // one scan for all the aggregates
+        let mut conn = self.read().await?;
+        let stats = sqlx::query!(
+            r#"SELECT
+                COUNT(*) AS "total!",
+                COALESCE(SUM(likes), 0)::BIGINT AS "total_likes!",
+                COUNT(*) FILTER (WHERE has_image) AS "with_image!",
+                COUNT(DISTINCT author) AS "distinct_authors!"
+            FROM messages
+            WHERE deleted_at IS NULL"#
+        )
+        .fetch_one(&mut *conn)
+        .await?;
+        conn.finish().await?;
+        Ok(MessageStats {
+            total: stats.total as usize,
+            total_likes: stats.total_likes,
+            with_image: stats.with_image as usize,
+            distinct_authors: stats.distinct_authors as usize,
+        })
+    }
+
+    async fn authors(
+        &self,
+        limit: usize,
+        offset: usize,
+    ) -> RepositoryResult<(usize, Vec<AuthorCount>)> {
+        let mut conn = self.read().await?;
+        let total = sqlx::query_scalar!(
+            r#"SELECT COUNT(DISTINCT author) AS "count!" FROM messages WHERE deleted_at IS NULL"#
+        )
+        .fetch_one(&mut *conn)
+        .await?;
+        // grouped along the (author, uuid) index
+        let authors = sqlx::query!(
+            r#"
+            SELECT author, COUNT(*) AS "messages!"
+            FROM messages
+            WHERE deleted_at IS NULL
+            GROUP BY author
+            ORDER BY author
+            LIMIT $1
+            OFFSET $2
+            "#,
+            limit as i64,
+            offset as i64
+        )
+        .fetch_all(&mut *conn)
+        .await?;
+        conn.finish().await?;
+        let authors = authors
+            .into_iter()
+            .map(|row| AuthorCount {
+                author: row.author,
+                messages: row.messages as usize,
+            })
+            .collect();
+        Ok((total as usize, authors))
+    }
+
+    async fn search(
+        &self,
+        query: &str,
+        limit: usize,
+        offset: usize,
+    ) -> RepositoryResult<(usize, Vec<Message>)> {
+        let mut conn = self.read().await?;
+        // websearch syntax accepts any input, quotes and `-` work like in search engines
+        let total = sqlx::query_scalar!(
+            r#"SELECT COUNT(*) AS "count!" FROM messages WHERE search @@ websearch_to_tsquery('english', $1) AND deleted_at IS NULL"#,
+            query
+        )
+        .fetch_one(&mut *conn)
+        .await?;
+        let messages = sqlx::query_as!(
+            Message,
+            r#"
+            SELECT uuid, author, message, parent_uuid, likes, has_image, client_timestamp, server_timestamp, created_at, updated_at, deleted_at, revision,
+                reactions AS "reactions: Reactions"
+            FROM messages, websearch_to_tsquery('english', $1) AS query
+            WHERE search @@ query AND deleted_at IS NULL
+            ORDER BY ts_rank(search, query) DESC, uuid
+            LIMIT $2
+            OFFSET $3
+            "#,
+            query,
+            limit as i64,
+            offset as i64
+        )
+        .fetch_all(&mut *conn)
+        .await?;
+        conn.finish().await?;
+        Ok((total as usize, messages))
+    }
+
+    async fn replies(
+        &self,
+        parent_uuid: &str,
+        limit: usize,
+        offset: usize,
+    ) -> RepositoryResult<(usize, Vec<Message>)> {
+        let mut conn = self.read().await?;
+        let total = sqlx::query_scalar!(
+            r#"SELECT COUNT(*) AS "count!" FROM messages WHERE parent_uuid = $1 AND deleted_at IS NULL"#,
+            parent_uuid
+        )
+        .fetch_one(&mut *conn)
+        .await?;
+        let messages = sqlx::query_as!(
+            Message,
+            r#"
+            SELECT uuid, author, message, parent_uuid, likes, has_image, client_timestamp, server_timestamp, created_at, updated_at, deleted_at, revision,
+                reactions AS "reactions: Reactions"
+            FROM messages
+            WHERE parent_uuid = $1 AND deleted_at IS NULL
+            ORDER BY uuid
+            LIMIT $2
+            OFFSET $3
+            "#,
+            parent_uuid,
+            limit as i64,
+            offset as i64
+        )
+        .fetch_all(&mut *conn)
+        .await?;
+        conn.finish().await?;
+        Ok((total as usize, messages))
+    }
+
+    async fn sample(&self, n: usize) -> RepositoryResult<Vec<Message>> {
+        // sorting by random() still scans every row, but only keeps the top `n` in memory
+        let mut conn = self.read().await?;
+        let messages = sqlx::query_as!(
+            Message,
+            r#"
+            SELECT uuid, author, message, parent_uuid, likes, has_image, client_timestamp, server_timestamp, created_at, updated_at, deleted_at, revision,
+                reactions AS "reactions: Reactions"
+            FROM messages
+            WHERE deleted_at IS NULL
+            ORDER BY random()
+            LIMIT $1
+            "#,
+            n as i64
+        )
+        .fetch_all(&mut *conn)
+        .await?;
+        conn.finish().await?;
+        Ok(messages)
+    }
+
+    async fn insert(&self, message: &Message) -> RepositoryResult<()> {
+        let mut tx = self.begin().await?;
+        // a deleted message is replaced, like it would be after it was purged
+        let inserted = sqlx::query!(
+            "INSERT INTO messages (uuid, author, message, parent_uuid, likes, has_image, client_timestamp, server_timestamp, created_at, updated_at, revision, reactions) VALUES ($1, $2, $3, $4, $5, $6, $7, $8, $9, $10, $11, $12)
+            ON CONFLICT (uuid) DO UPDATE SET author = EXCLUDED.author, message = EXCLUDED.message, parent_uuid = EXCLUDED.parent_uuid, likes = EXCLUDED.likes,
+                has_image = EXCLUDED.has_image, client_timestamp = EXCLUDED.client_timestamp,
+                server_timestamp = EXCLUDED.server_timestamp, created_at = EXCLUDED.created_at, updated_at = EXCLUDED.updated_at, deleted_at = NULL, revision = EXCLUDED.revision,
+                reactions = EXCLUDED.reactions
+            WHERE messages.deleted_at IS NOT NULL",
+            message.uuid,
+            message.author,
+            message.message,
+            message.parent_uuid,
+            message.likes,
+            message.has_image,
+            message.client_timestamp,
+            message.server_timestamp,
+            message.created_at,
+            message.updated_at,
+            message.revision,
+            message.reactions as _
+        )
+        .execute(&mut tx)
+        .await?;
+        if inserted.rows_affected() == 0 {
+            return Err(format!("duplicate uuid {}", message.uuid).into());
+        }
+        sqlx::query!(
+            "INSERT INTO outbox (kind, uuid, author, message, parent_uuid, likes, image_updated, client_timestamp, server_timestamp, created_at, updated_at, revision, reactions) VALUES ($1, $2, $3, $4, $5, $6, $7, $8, $9, $10, $11, $12, $13)",
+            OutboxKind::Post.as_str(),
+            message.uuid,
+            message.author,
+            message.message,
+            message.parent_uuid,
+            message.likes,
+            message.has_image,
+            message.client_timestamp,
+            message.server_timestamp,
+            message.created_at,
+            message.updated_at,
+            message.revision,
+            message.reactions as _
+        )
+        .execute(&mut tx)
+        .await?;
+        tx.commit().await?;
+        Ok(())
+    }
+
+    async fn insert_many(&self, messages: &[Message]) -> RepositoryResult<()> {
+        // one multi-row statement per table, with a column array per field, the nullable
+        // client timestamps are cast as the macro expects arrays of non-null values
+        let mut uuids = Vec::with_capacity(messages.len());
+        let mut authors = Vec::with_capacity(messages.len());
+        let mut texts = Vec::with_capacity(messages.len());
+        let mut parent_uuids = Vec::with_capacity(messages.len());
+        let mut likes = Vec::with_capacity(messages.len());
+        let mut has_images = Vec::with_capacity(messages.len());
+        let mut client_timestamps = Vec::with_capacity(messages.len());
+        let mut server_timestamps = Vec::with_capacity(messages.len());
+        let mut created_ats = Vec::with_capacity(messages.len());
+        let mut updated_ats = Vec::with_capacity(messages.len());
+        let mut revisions = Vec::with_capacity(messages.len());
+        let mut reactions = Vec::with_capacity(messages.len());
+        for message in messages {
+            uuids.push(message.uuid.clone());
+            authors.push(message.author.clone());
+            texts.push(message.message.clone());
+            parent_uuids.push(message.parent_uuid.clone());
+            likes.push(message.likes);
+            has_images.push(message.has_image);
+            client_timestamps.push(message.client_timestamp);
+            server_timestamps.push(message.server_timestamp);
+            created_ats.push(message.created_at);
+            updated_ats.push(message.updated_at);
+            revisions.push(message.revision);
+            reactions.push(message.reactions.clone());
+        }
+
+        let mut tx = self.begin().await?;
+        // deleted messages are replaced, like in `insert`
+        let inserted = sqlx::query!(
+            "INSERT INTO messages (uuid, author, message, parent_uuid, likes, has_image, client_timestamp, server_timestamp, created_at, updated_at, revision, reactions)
+            SELECT * FROM UNNEST($1::text[], $2::text[], $3::text[], $4::text[], $5::int[], $6::bool[], $7::bigint[], $8::bigint[], $9::bigint[], $10::bigint[], $11::bigint[], $12::jsonb[])
+            ON CONFLICT (uuid) DO UPDATE SET author = EXCLUDED.author, message = EXCLUDED.message, parent_uuid = EXCLUDED.parent_uuid, likes = EXCLUDED.likes,
+                has_image = EXCLUDED.has_image, client_timestamp = EXCLUDED.client_timestamp,
+                server_timestamp = EXCLUDED.server_timestamp, created_at = EXCLUDED.created_at, updated_at = EXCLUDED.updated_at, deleted_at = NULL, revision = EXCLUDED.revision,
+                reactions = EXCLUDED.reactions
+            WHERE messages.deleted_at IS NOT NULL",
+            &uuids,
+            &authors,
+            &texts,
+            &parent_uuids as &[Option<String>],
+            &likes,
+            &has_images,
+            &client_timestamps as &[Option<i64>],
+            &server_timestamps,
+            &created_ats,
+            &updated_ats,
+            &revisions,
+            &reactions as _
+        )
+        .execute(&mut tx)
+        .await?;
+        if inserted.rows_affected() != messages.len() as u64 {
+            return Err("duplicate uuid in the batch".into());
+        }
+        sqlx::query!(
+            "INSERT INTO outbox (kind, uuid, author, message, parent_uuid, likes, image_updated, client_timestamp, server_timestamp, created_at, updated_at, revision, reactions)
+            SELECT $1, uuid, author, message, parent_uuid, likes, image_updated, client_timestamp, server_timestamp, created_at, updated_at, revision, reactions
+            FROM UNNEST($2::text[], $3::text[], $4::text[], $5::text[], $6::int[], $7::bool[], $8::bigint[], $9::bigint[], $10::bigint[], $11::bigint[], $12::bigint[], $13::jsonb[])
+            WITH ORDINALITY AS post (uuid, author, message, parent_uuid, likes, image_updated, client_timestamp, server_timestamp, created_at, updated_at, revision, reactions, n)
+            ORDER BY n",
+            OutboxKind::Post.as_str(),
+            &uuids,
+            &authors,
+            &texts,
+            &parent_uuids as &[Option<String>],
+            &likes,
+            &has_images,
+            &client_timestamps as &[Option<i64>],
+            &server_timestamps,
+            &created_ats,
+            &updated_ats,
+            &revisions,
+            &reactions as _
+        )
+        .execute(&mut tx)
+        .await?;
+        tx.commit().await?;
+        Ok(())
+    }
+
+    async fn update(&self, uuid: &str, update: &MessageUpdate) -> RepositoryResult<UpdateOutcome> {
+        let mut tx = self.begin().await?;
+        let outcome = update_in(&mut tx, uuid, update).await?;
+        tx.commit().await?;
+        Ok(outcome)
+    }
+
+    async fn update_many(
+        &self,
+        updates: &[(String, MessageUpdate)],
+    ) -> RepositoryResult<Vec<UpdateOutcome>> {
+        let mut tx = self.begin().await?;
+        let mut outcomes = Vec::with_capacity(updates.len());
+        for (uuid, update) in updates {
+            outcomes.push(update_in(&mut tx, uuid, update).await?);
+        }
+        // dropping the transaction rolls it back
+        if outcomes
+            .iter()
+            .all(|outcome| matches!(outcome, UpdateOutcome::Updated { .. }))
+        {
+            tx.commit().await?;
+        }
+        Ok(outcomes)
+    }
+
+    async fn patch(&self, uuid: &str, patch: &MessagePatch) -> RepositoryResult<UpdateOutcome> {
+        // only the present fields are set, so the statement is built at runtime
+        let mut query = QueryBuilder::<Postgres>::new(
+            "WITH old AS (SELECT uuid, author, message, revision FROM messages WHERE uuid = ",
+        );
+        query.push_bind(uuid);
+        query.push(
+            " AND deleted_at IS NULL FOR UPDATE)
+            UPDATE messages SET revision = messages.revision + 1, server_timestamp = ",
+        );
+        query.push_bind(patch.server_timestamp);
+        query
+            .push(", updated_at = ")
+            .push_bind(patch.server_timestamp);
+        if let Maybe::Present(author) = &patch.author {
+            query.push(", author = ").push_bind(author);
+        }
+        if let Maybe::Present(message) = &patch.message {
+            query.push(", message = ").push_bind(message);
+        }
+        if let Maybe::Present(likes) = patch.likes {
+            query.push(", likes = ").push_bind(likes);
+        }
+        if let Maybe::Present(has_image) = patch.has_image {
+            query.push(", has_image = ").push_bind(has_image);
+        }
+        if let Maybe::Present(client_timestamp) = patch.client_timestamp {
+            query
+                .push(", client_timestamp = ")
+                .push_bind(client_timestamp);
+        }
+        query.push(" FROM old WHERE messages.uuid = old.uuid");
+        if let Some(revision) = patch.expected_revision {
+            query.push(" AND old.revision = ").push_bind(revision);
+        }
+        // the returned row has the new values
+        query.push(
+            "
+            RETURNING messages.author, messages.message, messages.parent_uuid, messages.likes, messages.client_timestamp,
+                messages.revision, (old.author <> messages.author OR old.message <> messages.message) AS text_changed",
+        );
+
+        let mut tx = self.begin().await?;
+        let Some(row) = query.build().fetch_optional(&mut tx).await? else {
+            // the row is still locked, it can't have changed since
+            let revision = sqlx::query_scalar!(
+                "SELECT revision FROM messages WHERE uuid = $1 AND deleted_at IS NULL",
+                uuid
+            )
+            .fetch_optional(&mut tx)
+            .await?;
+            return Ok(match revision {
+                Some(revision) => UpdateOutcome::Stale { revision },
+                None => UpdateOutcome::NotFound,
+            });
+        };
+        let revision = row.try_get::<i64, _>("revision")?;
+        let (author, message, parent_uuid) = match row.try_get("text_changed")? {
+            true => (
+                Some(row.try_get::<String, _>("author")?),
+                Some(row.try_get::<String, _>("message")?),
+                row.try_get::<Option<String>, _>("parent_uuid")?,
+            ),
+            false => (None, None, None),
+        };
+        sqlx::query!(
+            "INSERT INTO outbox (kind, uuid, author, message, parent_uuid, likes, image_updated, client_timestamp, server_timestamp, updated_at, revision) VALUES ($1, $2, $3, $4, $5, $6, $7, $8, $9, $9, $10)",
+            OutboxKind::Put.as_str(),
+            uuid,
+            author,
+            message,
+            parent_uuid,
+            row.try_get::<i32, _>("likes")?,
+            patch.has_image.is_present(),
+            row.try_get::<Option<i64>, _>("client_timestamp")?,
+            patch.server_timestamp,
+            revision
+        )
+        .execute(&mut tx)
+        .await?;
+        tx.commit().await?;
+        Ok(UpdateOutcome::Updated { revision })
+    }
+
+    async fn add_likes(
+        &self,
+        uuid: &str,
+        delta: i32,
+        key: Option<&str>,
+        server_timestamp: i64,
+    ) -> RepositoryResult<Option<i32>> {
+        let mut tx = self.begin().await?;
+        if let Some(key) = key {
+            sqlx::query!(
+                "DELETE FROM like_requests WHERE applied_at < $1",
+                server_timestamp - LIKE_REQUEST_TTL_MS
+            )
+            .execute(&mut tx)
+            .await?;
+            // waits for a concurrent like with the key to commit or roll back
+            let recorded = sqlx::query!(
+                "INSERT INTO like_requests (key, applied_at) VALUES ($1, $2) ON CONFLICT (key) DO NOTHING",
+                key,
+                server_timestamp
+            )
+            .execute(&mut tx)
+            .await?;
+            if recorded.rows_affected() == 0 {
+                let likes = sqlx::query_scalar!(
+                    "SELECT likes FROM messages WHERE uuid = $1 AND deleted_at IS NULL",
+                    uuid
+                )
+                .fetch_optional(&mut tx)
+                .await?;
+                tx.commit().await?;
+                return Ok(likes);
+            }
+        }
+        // computed in bigint, so the likes saturate instead of overflowing
+        let updated = sqlx::query!(
+            r#"UPDATE messages SET likes = LEAST(GREATEST(likes::bigint + $1, 0), 2147483647)::int, server_timestamp = $2, revision = revision + 1
+            WHERE uuid = $3 AND deleted_at IS NULL
+            RETURNING likes, client_timestamp, revision"#,
+            delta as i64,
+            server_timestamp,
+            uuid
+        )
+        .fetch_optional(&mut tx)
+        .await?;
+        let Some(updated) = updated else {
+            return Ok(None);
+        };
+        sqlx::query!(
+            "INSERT INTO outbox (kind, uuid, likes, image_updated, client_timestamp, server_timestamp, revision) VALUES ($1, $2, $3, false, $4, $5, $6)",
+            OutboxKind::Put.as_str(),
+            uuid,
+            updated.likes,
+            updated.client_timestamp,
+            server_timestamp,
+            updated.revision
+        )
+        .execute(&mut tx)
+        .await?;
+        tx.commit().await?;
+        Ok(Some(updated.likes))
+    }
+
+    async fn add_reaction(
+        &self,
+        uuid: &str,
+        kind: &str,
+        delta: i32,
+        server_timestamp: i64,
+    ) -> RepositoryResult<Option<Reactions>> {
+        let mut tx = self.begin().await?;
+        // kinds that drop to 0 are removed from the object
+        let updated = sqlx::query!(
+            r#"UPDATE messages SET reactions = CASE
+                    WHEN COALESCE((reactions ->> $1::text)::int, 0) + $2 > 0
+                    THEN jsonb_set(reactions, ARRAY[$1], to_jsonb(COALESCE((reactions ->> $1)::int, 0) + $2))
+                    ELSE reactions - $1
+                END,
+                server_timestamp = $3, revision = revision + 1
+            WHERE uuid = $4 AND deleted_at IS NULL
+            RETURNING reactions AS "reactions: Reactions", likes, client_timestamp, revision"#,
+            kind,
+            delta,
+            server_timestamp,
+            uuid
+        )
+        .fetch_optional(&mut tx)
+        .await?;
+        let Some(updated) = updated else {
+            return Ok(None);
+        };
+        sqlx::query!(
+            "INSERT INTO outbox (kind, uuid, likes, image_updated, client_timestamp, server_timestamp, revision, reactions) VALUES ($1, $2, $3, false, $4, $5, $6, $7)",
+            OutboxKind::Put.as_str(),
+            uuid,
+            updated.likes,
+            updated.client_timestamp,
+            server_timestamp,
+            updated.revision,
+            &updated.reactions as _
+        )
+        .execute(&mut tx)
+        .await?;
+        tx.commit().await?;
+        Ok(Some(updated.reactions))
+    }
+
+    async fn delete(&self, uuid: &str, deleted_at: i64) -> RepositoryResult<u64> {
+        let mut tx = self.begin().await?;
+        let result = sqlx::query!(
+            "UPDATE messages SET deleted_at = $2 WHERE uuid = $1 AND deleted_at IS NULL",
+            uuid,
+            deleted_at
+        )
+        .execute(&mut tx)
+        .await?;
+        if result.rows_affected() > 0 {
+            sqlx::query!(
+                "INSERT INTO outbox (kind, uuid, image_updated) VALUES ($1, $2, false)",
+                OutboxKind::Delete.as_str(),
+                uuid
+            )
+            .execute(&mut tx)
+            .await?;
+        }
+        tx.commit().await?;
+        Ok(result.rows_affected())
+    }
+
+    async fn restore(
+        &self,
+        uuid: &str,
+        server_timestamp: i64,
+    ) -> RepositoryResult<Option<Message>> {
+        let mut tx = self.begin().await?;
+        let message = sqlx::query_as!(
+            Message,
+            r#"UPDATE messages SET deleted_at = NULL, server_timestamp = $2, revision = revision + 1
+            WHERE uuid = $1 AND deleted_at IS NOT NULL
+            RETURNING uuid, author, message, parent_uuid, likes, has_image, client_timestamp, server_timestamp, created_at, updated_at, deleted_at, revision,
+                reactions AS "reactions: Reactions""#,
+            uuid,
+            server_timestamp
+        )
+        .fetch_optional(&mut tx)
+        .await?;
+        let Some(message) = message else {
+            return Ok(None);
+        };
+        // clients were told the message is gone, it comes back as a new one
+        sqlx::query!(
+            "INSERT INTO outbox (kind, uuid, author, message, parent_uuid, likes, image_updated, client_timestamp, server_timestamp, created_at, updated_at, revision, reactions) VALUES ($1, $2, $3, $4, $5, $6, $7, $8, $9, $10, $11, $12, $13)",
+            OutboxKind::Post.as_str(),
+            message.uuid,
+            message.author,
+            message.message,
+            message.parent_uuid,
+            message.likes,
+            message.has_image,
+            message.client_timestamp,
+            message.server_timestamp,
+            message.created_at,
+            message.updated_at,
+            message.revision,
+            message.reactions as _
+        )
+        .execute(&mut tx)
+        .await?;
+        tx.commit().await?;
+        Ok(Some(message))
+    }
+
+    async fn purge_deleted(&self, deleted_before: i64) -> RepositoryResult<Vec<String>> {
+        let mut tx = self.begin().await?;
+        let uuids = sqlx::query!(
+            "DELETE FROM messages WHERE deleted_at < $1 RETURNING uuid",
+            deleted_before
+        )
+        .map(|row| row.uuid)
+        .fetch_all(&mut tx)
+        .await?;
+        tx.commit().await?;
+        Ok(uuids)
+    }
+
+    async fn clear(&self) -> RepositoryResult<()> {
+        let mut tx = self.begin().await?;
+        sqlx::query!("DELETE FROM messages")
+            .execute(&mut tx)
+            .await?;
+        sqlx::query!("DELETE FROM outbox").execute(&mut tx).await?;
+        tx.commit().await?;
+        Ok(())
+    }
+
+    async fn clear_matching(&self, filter: &ClearFilter) -> RepositoryResult<Vec<String>> {
+        // match the prefix literally
+        let uuid_pattern = filter.uuid_prefix.as_ref().map(|prefix| {
+            let escaped = prefix
+                .replace('\\', "\\\\")
+                .replace('%', "\\%")
+                .replace('_', "\\_");
+            format!("{escaped}%")
+        });
+        let mut tx = self.begin().await?;
+        let uuids = sqlx::query!(
+            "
+            WITH deleted AS (
+                DELETE FROM messages
+                WHERE ($1::text IS NULL OR author = $1) AND ($2::text IS NULL OR uuid LIKE $2)
+                    AND ($4::bigint IS NULL OR created_at < $4) AND ($5::bigint IS NULL OR updated_at < $5)
+                    AND deleted_at IS NULL
+                RETURNING uuid
+            )
+            INSERT INTO outbox (kind, uuid, image_updated)
+            SELECT $3, uuid, false FROM deleted
+            RETURNING uuid
+            ",
+            filter.author,
+            uuid_pattern,
+            OutboxKind::Delete.as_str(),
+            filter.created_before,
+            filter.updated_before
+        )
+        .map(|row| row.uuid)
+        .fetch_all(&mut tx)
+        .await?;
+        tx.commit().await?;
+        Ok(uuids)
+    }
+
+    async fn outbox(&self, limit: usize) -> RepositoryResult<Vec<OutboxEntry>> {
+        let mut conn = self.read().await?;
+        let rows = sqlx::query!(
+            r#"SELECT id, kind, uuid, author, message, parent_uuid, likes, image_updated, client_timestamp, server_timestamp, created_at, updated_at, revision, reactions AS "reactions: Reactions" FROM outbox ORDER BY id LIMIT $1"#,
+            limit as i64
+        )
+        .fetch_all(&mut *conn)
+        .await?;
+        conn.finish().await?;
+        rows.into_iter()
+            .map(|row| {
+                Ok(OutboxEntry {
+                    id: row.id,
+                    kind: row.kind.parse()?,
+                    uuid: row.uuid,
+                    author: row.author,
+                    message: row.message,
+                    parent_uuid: row.parent_uuid,
+                    likes: row.likes,
+                    image_updated: row.image_updated,
+                    client_timestamp: row.client_timestamp,
+                    server_timestamp: row.server_timestamp,
+                    created_at: row.created_at,
+                    updated_at: row.updated_at,
+                    revision: row.revision,
+                    reactions: row.reactions,
+                })
+            })
+            .collect()
+    }
+
+    async fn ack_outbox(&self, id: i64) -> RepositoryResult<()> {
+        let mut tx = self.begin().await?;
+        sqlx::query!("DELETE FROM outbox WHERE id <= $1", id)
+            .execute(&mut tx)
+            .await?;
+        tx.commit().await?;
+        Ok(())
+    }
+}
+
+/// Updates a message within `tx`, see [`MessageRepository::update`].
+async fn update_in(
+    tx: &mut Transaction<'_, Postgres>,
+    uuid: &str,
+    update: &MessageUpdate,
+) -> RepositoryResult<UpdateOutcome> {
+    // compare against the old row so likes-only updates don't carry the text to the outbox
+    let updated = sqlx::query!(
+        r#"WITH old AS (SELECT uuid, author, message, parent_uuid, revision FROM messages WHERE uuid = $5 AND deleted_at IS NULL FOR UPDATE)
+        UPDATE messages SET author = $1, message = $2, likes = $3, has_image = COALESCE($4, messages.has_image),
+            client_timestamp = $6, server_timestamp = $7, updated_at = $7, revision = old.revision + 1, parent_uuid = $9
+        FROM old WHERE messages.uuid = old.uuid AND ($8::BIGINT IS NULL OR old.revision = $8)
+        RETURNING (old.author <> $1 OR old.message <> $2 OR old.parent_uuid IS DISTINCT FROM $9) AS "text_changed!",
+            messages.revision"#,
+        update.author,
+        update.message,
+        update.likes,
+        update.has_image,
+        uuid,
+        update.client_timestamp,
+        update.server_timestamp,
+        update.expected_revision,
+        update.parent_uuid
+    )
+    .fetch_optional(&mut *tx)
+    .await?;
+    let Some(updated) = updated else {
+        // the row is still locked, it can't have changed since
+        let revision = sqlx::query_scalar!(
+            "SELECT revision FROM messages WHERE uuid = $1 AND deleted_at IS NULL",
+            uuid
+        )
+        .fetch_optional(&mut *tx)
+        .await?;
+        return Ok(match revision {
+            Some(revision) => UpdateOutcome::Stale { revision },
+            None => UpdateOutcome::NotFound,
+        });
+    };
+    let (author, message, parent_uuid) = match updated.text_changed {
+        true => (
+            Some(&update.author),
+            Some(&update.message),
+            update.parent_uuid.as_ref(),
+        ),
+        false => (None, None, None),
+    };
+    sqlx::query!(
+        "INSERT INTO outbox (kind, uuid, author, message, parent_uuid, likes, image_updated, client_timestamp, server_timestamp, updated_at, revision) VALUES ($1, $2, $3, $4, $5, $6, $7, $8, $9, $9, $10)",
+        OutboxKind::Put.as_str(),
+        uuid,
+        author,
+        message,
+        parent_uuid,
+        update.likes,
+        update.has_image.is_some(),
+        update.client_timestamp,
+        update.server_timestamp,
+        updated.revision
+    )
+    .execute(&mut *tx)
+    .await?;
+    Ok(UpdateOutcome::Updated {
+        revision: updated.revision,
+    })
+}
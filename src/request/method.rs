@@ -1,13 +1,15 @@
-use std::str::FromStr;
+use std::{fmt, str::FromStr};
 
-#[derive(Debug, Default)]
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
 pub enum Method {
     #[default]
     Get,
+    Head,
     Post,
     Put,
     Delete,
     Patch,
+    Options,
 }
 
 impl FromStr for Method {
@@ -16,11 +18,27 @@ impl FromStr for Method {
     fn from_str(s: &str) -> Result<Self, Self::Err> {
         match s {
             "GET" => Ok(Self::Get),
+            "HEAD" => Ok(Self::Head),
             "POST" => Ok(Self::Post),
             "PUT" => Ok(Self::Put),
             "DELETE" => Ok(Self::Delete),
             "PATCH" => Ok(Self::Patch),
+            "OPTIONS" => Ok(Self::Options),
             _ => Err("Invalid method"),
         }
     }
 }
+
+impl fmt::Display for Method {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Get => write!(f, "GET"),
+            Self::Head => write!(f, "HEAD"),
+            Self::Post => write!(f, "POST"),
+            Self::Put => write!(f, "PUT"),
+            Self::Delete => write!(f, "DELETE"),
+            Self::Patch => write!(f, "PATCH"),
+            Self::Options => write!(f, "OPTIONS"),
+        }
+    }
+}
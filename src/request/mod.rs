@@ -1,63 +1,230 @@
 pub mod method;
+pub mod multipart;
 
-use std::error::Error;
-use tokio::{
-    io::{AsyncBufReadExt, AsyncReadExt, BufReader},
-    net::TcpStream,
-};
+use ahash::AHashMap;
+use std::{error::Error, fmt, io};
+use tokio::io::{AsyncBufReadExt, AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt, BufReader};
 
 use self::method::Method;
+use crate::response::StatusCode;
 
-#[derive(Default, Debug)]
+/// How large a request may be.
+#[derive(Debug, Clone, Copy)]
+pub struct RequestLimits {
+    /// Larger bodies are rejected with 413 before they are read.
+    pub max_body: usize,
+    /// More headers are rejected with 431.
+    pub max_headers: usize,
+    /// A larger request line and headers, line breaks included, are rejected with 431.
+    pub max_header_bytes: usize,
+}
+
+impl Default for RequestLimits {
+    fn default() -> Self {
+        Self {
+            max_body: 32 * 1024 * 1024,
+            max_headers: 100,
+            max_header_bytes: 16 * 1024,
+        }
+    }
+}
+
+/// A request that couldn't be read.
+#[derive(Debug)]
+pub enum RequestError {
+    /// The request line isn't `<method> <uri> HTTP/<version>`.
+    MalformedRequestLine,
+    /// A method the server doesn't implement.
+    BadMethod,
+    /// The path of the uri can't be normalized, e.g. it has a `..` segment.
+    InvalidPath,
+    /// A header line isn't `<name>: <value>` or isn't valid UTF-8.
+    MalformedHeader,
+    /// The request line and headers are over [`RequestLimits::max_header_bytes`], or there
+    /// are more than [`RequestLimits::max_headers`] headers.
+    HeadersTooLarge,
+    /// The `Content-Length` isn't a number.
+    InvalidContentLength,
+    /// The `Content-Length` is over the limit.
+    PayloadTooLarge { limit: usize },
+    /// An `Expect` header other than `100-continue`.
+    UnsupportedExpectation,
+    /// The connection was closed before the whole body was sent.
+    IncompleteBody,
+    /// The body isn't valid UTF-8, message text must not be mangled with replacement characters.
+    InvalidBody,
+    /// Reading from or writing to the stream failed.
+    Io(io::Error),
+}
+
+impl RequestError {
+    /// The HTTP status this error is surfaced as.
+    pub fn status(&self) -> StatusCode {
+        match self {
+            RequestError::MalformedRequestLine
+            | RequestError::InvalidPath
+            | RequestError::MalformedHeader
+            | RequestError::InvalidContentLength
+            | RequestError::IncompleteBody
+            | RequestError::InvalidBody => StatusCode::BadRequest,
+            RequestError::BadMethod => StatusCode::NotImplemented,
+            RequestError::HeadersTooLarge => StatusCode::RequestHeaderFieldsTooLarge,
+            RequestError::PayloadTooLarge { .. } => StatusCode::PayloadTooLarge,
+            RequestError::UnsupportedExpectation => StatusCode::ExpectationFailed,
+            RequestError::Io(_) => StatusCode::InternalServerError,
+        }
+    }
+
+    /// Whether the error was found before the body was read, which may still be coming in.
+    pub fn before_body(&self) -> bool {
+        !matches!(
+            self,
+            RequestError::IncompleteBody | RequestError::InvalidBody | RequestError::Io(_)
+        )
+    }
+}
+
+impl fmt::Display for RequestError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            RequestError::MalformedRequestLine => write!(f, "Malformed request line."),
+            RequestError::BadMethod => write!(f, "Method not implemented."),
+            RequestError::InvalidPath => write!(f, "Invalid request path."),
+            RequestError::MalformedHeader => write!(f, "Malformed header."),
+            RequestError::HeadersTooLarge => write!(f, "Request header section is too large."),
+            RequestError::InvalidContentLength => write!(f, "Invalid Content-Length."),
+            RequestError::PayloadTooLarge { limit } => {
+                write!(f, "Request body is larger than {limit} bytes.")
+            }
+            RequestError::UnsupportedExpectation => {
+                write!(f, "Only `Expect: 100-continue` is supported.")
+            }
+            RequestError::IncompleteBody => {
+                write!(f, "Request body is shorter than its Content-Length.")
+            }
+            RequestError::InvalidBody => write!(f, "Request body is not valid UTF-8."),
+            RequestError::Io(e) => write!(f, "Failed to read the request: {e}"),
+        }
+    }
+}
+
+impl From<io::Error> for RequestError {
+    fn from(e: io::Error) -> Self {
+        RequestError::Io(e)
+    }
+}
+
+impl Error for RequestError {
+    fn source(&self) -> Option<&(dyn Error + 'static)> {
+        match self {
+            RequestError::Io(e) => Some(e),
+            _ => None,
+        }
+    }
+}
+
+#[derive(Default, Debug, Clone)]
 pub struct Request {
     method: Method,
     uri: String,
+    /// The uri without its query string.
+    path: String,
+    /// Percent-decoded query parameters, the last value wins for repeated names.
+    query_params: AHashMap<String, String>,
     body: Option<String>,
+    /// The body of a `multipart/form-data` request, which may be binary unlike `body`.
+    multipart_body: Option<Vec<u8>>,
+    /// Header values keyed by lowercase name, repeated headers are joined with `, `.
+    headers: AHashMap<String, String>,
+    /// The protocol of the request line, e.g. `HTTP/1.1`.
+    version: String,
 }
 
 impl Request {
-    /// Reads data from a tcp stream and creates a new HTTP `Request`.
+    /// Reads data from a tcp stream and creates a new HTTP `Request`. Bytes past the request,
+    /// such as pipelined requests, stay buffered in `buf_reader` for the next call.
     ///
     /// # Errors
     ///
-    /// This function will return an error if the data from the stream is invalid HTTP request.
-    pub async fn from_stream(stream: &mut TcpStream) -> Result<Self, Box<dyn Error + Send + Sync>> {
-        let mut buf_reader = BufReader::new(stream);
+    /// This function will return an error if the data from the stream is invalid HTTP request,
+    /// if it is over `limits`, its body over the `body_limit` of its head, or if reading the
+    /// stream fails. Errors found before the body is read are returned before it is, see
+    /// [`RequestError::before_body`].
+    pub async fn from_stream<S: AsyncRead + AsyncWrite + Unpin>(
+        buf_reader: &mut BufReader<S>,
+        limits: &RequestLimits,
+        body_limit: impl FnOnce(&Self) -> usize,
+    ) -> Result<Self, RequestError> {
+        let mut head = HeadReader {
+            buf_reader,
+            remaining: limits.max_header_bytes,
+        };
 
-        // read status line
+        // read status line, an empty line before it is ignored
         let mut status_line = String::with_capacity(128);
-        buf_reader.read_line(&mut status_line).await?;
+        head.read_line(&mut status_line, RequestError::MalformedRequestLine)
+            .await?;
+        if status_line.trim_end().is_empty() {
+            status_line.clear();
+            head.read_line(&mut status_line, RequestError::MalformedRequestLine)
+                .await?;
+        }
         let status_line = status_line.trim_end();
 
         let mut request = Self::default();
 
-        // extract method and uri
+        // extract method, uri and version
         let mut status_line_iter = status_line.split_whitespace();
-        let method = status_line_iter.next().unwrap_or("");
-        request.set_method(method)?;
-        let uri = status_line_iter.next().unwrap_or("").to_string();
-        request.set_uri(uri);
+        let (Some(method), Some(uri), Some(version), None) = (
+            status_line_iter.next(),
+            status_line_iter.next(),
+            status_line_iter.next(),
+            status_line_iter.next(),
+        ) else {
+            return Err(RequestError::MalformedRequestLine);
+        };
+        if !version.starts_with("HTTP/") {
+            return Err(RequestError::MalformedRequestLine);
+        }
+        request
+            .set_method(method)
+            .map_err(|_| RequestError::BadMethod)?;
+        request
+            .set_uri(uri.to_string())
+            .map_err(|_| RequestError::InvalidPath)?;
+        request.version = version.to_string();
 
-        // read through header section and find content-length if any
+        // read through header section, keeping every header and the content-length if any
         let mut content_length = None;
         let mut header_line = String::with_capacity(128);
+        let mut header_count = 0;
         loop {
-            buf_reader.read_line(&mut header_line).await?;
+            head.read_line(&mut header_line, RequestError::MalformedHeader)
+                .await?;
             let trimmed = header_line.trim_end();
 
             match trimmed {
                 // end of header section
                 "" => break,
-                // find content-length
-                l if content_length.is_none() => {
-                    let mut header_line_iter = l.splitn(2, ": ");
-                    let header_name = header_line_iter.next().unwrap_or("");
-                    if header_name.eq_ignore_ascii_case("content-length") {
-                        let header_value = header_line_iter.next().unwrap_or("");
-                        content_length = Some(header_value.parse()?);
+                l => {
+                    header_count += 1;
+                    if header_count > limits.max_headers {
+                        return Err(RequestError::HeadersTooLarge);
                     }
+                    let (header_name, header_value) =
+                        l.split_once(':').ok_or(RequestError::MalformedHeader)?;
+                    let header_value = header_value.trim();
+                    if content_length.is_none()
+                        && header_name.eq_ignore_ascii_case("content-length")
+                    {
+                        content_length = Some(
+                            header_value
+                                .parse::<usize>()
+                                .map_err(|_| RequestError::InvalidContentLength)?,
+                        );
+                    }
+                    request.append_header(header_name, header_value);
                 }
-                _ => (),
             }
 
             // stream's read_line() will append read bytes to the string
@@ -65,23 +232,126 @@ impl Request {
             header_line.clear();
         }
 
+        // reject the body before it is sent when it won't be accepted anyway
+        let expect = request.header("expect");
+        if expect.is_some_and(|expect| !expect.eq_ignore_ascii_case("100-continue")) {
+            return Err(RequestError::UnsupportedExpectation);
+        }
+        // the body is only read up to its length, which must be within the limit of the route
+        let limit = body_limit(&request).min(limits.max_body);
+        if content_length.is_some_and(|len| len > limit) {
+            return Err(RequestError::PayloadTooLarge { limit });
+        }
+        // clients waiting for the interim response only send the body once they get it
+        if expect.is_some() && content_length.is_some_and(|len| len > 0) {
+            buf_reader
+                .get_mut()
+                .write_all(b"HTTP/1.1 100 Continue\r\n\r\n")
+                .await?;
+        }
+
         // read body if any
         if let Some(len) = content_length {
             let mut body = vec![0; len];
-            buf_reader.read_exact(&mut body).await?;
-            let body = String::from_utf8_lossy(&body).to_string();
-            request.set_body(Some(body));
+            buf_reader
+                .read_exact(&mut body)
+                .await
+                .map_err(|e| match e.kind() {
+                    io::ErrorKind::UnexpectedEof => RequestError::IncompleteBody,
+                    _ => RequestError::Io(e),
+                })?;
+            request.set_received_body(body)?;
         }
 
         Ok(request)
     }
 
+    /// Creates a request that wasn't read by [`Request::from_stream`], e.g. a stream of an
+    /// HTTP/2 connection, validating it the same way.
+    ///
+    /// # Errors
+    ///
+    /// This function will return an error if the method isn't implemented, the path is invalid
+    /// or the body isn't valid UTF-8 nor multipart.
+    pub fn from_parts<'a>(
+        method: &str,
+        uri: &str,
+        version: &str,
+        headers: impl IntoIterator<Item = (&'a str, &'a str)>,
+        body: Option<Vec<u8>>,
+    ) -> Result<Self, RequestError> {
+        let mut request = Self::default();
+        request
+            .set_method(method)
+            .map_err(|_| RequestError::BadMethod)?;
+        request
+            .set_uri(uri.to_string())
+            .map_err(|_| RequestError::InvalidPath)?;
+        request.version = version.to_string();
+        for (name, value) in headers {
+            request.append_header(name, value);
+        }
+        if let Some(body) = body {
+            request.set_received_body(body)?;
+        }
+        Ok(request)
+    }
+
+    /// Sets the body the client sent, which must be valid UTF-8 unless it is multipart.
+    ///
+    /// # Errors
+    ///
+    /// This function will return an error if the body isn't valid UTF-8 nor multipart.
+    pub(crate) fn set_received_body(&mut self, body: Vec<u8>) -> Result<(), RequestError> {
+        if self
+            .header("content-type")
+            .is_some_and(multipart::is_multipart)
+        {
+            self.multipart_body = Some(body);
+            return Ok(());
+        }
+        let body = String::from_utf8(body).map_err(|_| RequestError::InvalidBody)?;
+        self.set_body(Some(body));
+        Ok(())
+    }
+
     pub fn uri(&self) -> &str {
         self.uri.as_ref()
     }
 
-    pub fn set_uri(&mut self, uri: String) {
+    /// The path of the uri, without the query string.
+    pub fn path(&self) -> &str {
+        &self.path
+    }
+
+    pub fn query_params(&self) -> &AHashMap<String, String> {
+        &self.query_params
+    }
+
+    pub fn query_param(&self, name: &str) -> Option<&str> {
+        self.query_params.get(name).map(String::as_str)
+    }
+
+    /// Sets the uri of this [`Request`], splitting it into its path and query parameters. The
+    /// path is normalized: its segments are percent-decoded, and empty and `.` segments dropped.
+    ///
+    /// # Errors
+    ///
+    /// This function will return an error if the path doesn't start with `/`, has a `..`
+    /// segment, or has a segment decoding to a `/`, a `\` or invalid UTF-8.
+    pub fn set_uri(&mut self, uri: String) -> Result<(), &'static str> {
+        let (path, query) = uri.split_once('?').unwrap_or((&uri, ""));
+        self.path = normalize_path(path)?;
+        self.query_params = query
+            .split('&')
+            .filter(|pair| !pair.is_empty())
+            .map(|pair| {
+                let (name, value) = pair.split_once('=').unwrap_or((pair, ""));
+                (percent_decode(name), percent_decode(value))
+            })
+            .collect();
         self.uri = uri;
+        Ok(())
     }
 
     pub fn body(&self) -> Option<&String> {
@@ -92,6 +362,88 @@ impl Request {
         self.body = body;
     }
 
+    pub fn multipart_body(&self) -> Option<&[u8]> {
+        self.multipart_body.as_deref()
+    }
+
+    /// The body as the client sent it, multipart or not.
+    pub fn sent_body(&self) -> Option<&[u8]> {
+        self.multipart_body()
+            .or_else(|| self.body().map(String::as_bytes))
+    }
+
+    /// A copy of the request with `body`, the JSON its multipart body stands for, in place of
+    /// it.
+    pub fn with_json_body(&self, body: String) -> Self {
+        let mut request = self.clone();
+        request.multipart_body = None;
+        request
+            .headers
+            .insert("content-type".to_string(), "application/json".to_string());
+        request.body = Some(body);
+        request
+    }
+
+    /// The value of the header `name`, case-insensitively.
+    pub fn header(&self, name: &str) -> Option<&str> {
+        self.headers
+            .get(&name.to_ascii_lowercase())
+            .map(String::as_str)
+    }
+
+    /// Whether the client keeps the connection open for another request: by default on
+    /// HTTP/1.1 unless it sent `Connection: close`, only with `Connection: keep-alive` before.
+    pub fn keep_alive(&self) -> bool {
+        let has_option = |option: &str| {
+            self.header("connection").is_some_and(|value| {
+                value
+                    .split(',')
+                    .any(|token| token.trim().eq_ignore_ascii_case(option))
+            })
+        };
+        match self.version.as_str() {
+            "HTTP/1.1" => !has_option("close"),
+            _ => has_option("keep-alive"),
+        }
+    }
+
+    /// All headers, keyed by lowercase name.
+    pub fn headers(&self) -> &AHashMap<String, String> {
+        &self.headers
+    }
+
+    /// Adds a header, joining its value to the previous ones if it is repeated.
+    pub fn append_header(&mut self, name: &str, value: &str) {
+        self.headers
+            .entry(name.to_ascii_lowercase())
+            .and_modify(|values| {
+                values.push_str(", ");
+                values.push_str(value);
+            })
+            .or_insert_with(|| value.to_string());
+    }
+
+    pub fn content_range(&self) -> Option<&str> {
+        self.header("content-range")
+    }
+
+    /// The hex SHA-256 of the body the client sent, to check it arrived intact.
+    pub fn content_sha256(&self) -> Option<&str> {
+        self.header("x-content-sha256")
+    }
+
+    pub fn api_key(&self) -> Option<&str> {
+        self.header("x-api-key")
+    }
+
+    pub fn authorization(&self) -> Option<&str> {
+        self.header("authorization")
+    }
+
+    pub fn origin(&self) -> Option<&str> {
+        self.header("origin")
+    }
+
     pub fn method(&self) -> &method::Method {
         &self.method
     }
@@ -107,3 +459,147 @@ impl Request {
         Ok(())
     }
 }
+
+/// Reads the request line and the headers, at most `remaining` bytes of them.
+struct HeadReader<'a, S> {
+    buf_reader: &'a mut BufReader<S>,
+    remaining: usize,
+}
+
+impl<S: AsyncRead + Unpin> HeadReader<'_, S> {
+    /// Reads a line into `line`, a line that isn't valid UTF-8 is `malformed`.
+    async fn read_line(
+        &mut self,
+        line: &mut String,
+        malformed: RequestError,
+    ) -> Result<(), RequestError> {
+        // a line is read no further than the limit, not to buffer an endless one
+        let read = (&mut *self.buf_reader)
+            .take(self.remaining as u64)
+            .read_line(line)
+            .await
+            .map_err(|e| match e.kind() {
+                io::ErrorKind::InvalidData => malformed,
+                _ => RequestError::Io(e),
+            })?;
+        if read == self.remaining && !line.ends_with('\n') {
+            return Err(RequestError::HeadersTooLarge);
+        }
+        self.remaining -= read;
+        Ok(())
+    }
+}
+
+/// Percent-decodes the segments of `path`, dropping the empty and `.` ones, so that e.g.
+/// `//api/./messages/%61bc` is routed as `/api/messages/abc`.
+fn normalize_path(path: &str) -> Result<String, &'static str> {
+    let Some(path) = path.strip_prefix('/') else {
+        return Err("Request path must start with `/`.");
+    };
+
+    let mut normalized = String::with_capacity(path.len() + 1);
+    for segment in path.split('/') {
+        let segment = String::from_utf8(decode_escapes(segment, false))
+            .map_err(|_| "Request path must be valid UTF-8.")?;
+        match segment.as_str() {
+            "" | "." => continue,
+            ".." => return Err("Request path must not have `..` segments."),
+            // an encoded separator would reach the handlers as part of a single segment
+            s if s.contains(['/', '\\']) => {
+                return Err("Request path must not have encoded separators.")
+            }
+            s => {
+                normalized.push('/');
+                normalized.push_str(s);
+            }
+        }
+    }
+    if normalized.is_empty() {
+        normalized.push('/');
+    }
+    Ok(normalized)
+}
+
+/// Decodes `%XX` escapes and `+` as a space, leaving malformed escapes as they are.
+fn percent_decode(s: &str) -> String {
+    String::from_utf8_lossy(&decode_escapes(s, true)).into_owned()
+}
+
+/// Decodes `%XX` escapes, and `+` as a space if `plus_as_space`, leaving malformed escapes as
+/// they are.
+fn decode_escapes(s: &str, plus_as_space: bool) -> Vec<u8> {
+    fn hex(byte: u8) -> Option<u8> {
+        (byte as char).to_digit(16).map(|d| d as u8)
+    }
+
+    let bytes = s.as_bytes();
+    let mut decoded = Vec::with_capacity(bytes.len());
+    let mut i = 0;
+    while i < bytes.len() {
+        match bytes[i] {
+            b'+' if plus_as_space => decoded.push(b' '),
+            b'%' => match (
+                bytes.get(i + 1).and_then(|b| hex(*b)),
+                bytes.get(i + 2).and_then(|b| hex(*b)),
+            ) {
+                (Some(high), Some(low)) => {
+                    decoded.push(high << 4 | low);
+                    i += 2;
+                }
+                _ => decoded.push(b'%'),
+            },
+            byte => decoded.push(byte),
+        }
+        i += 1;
+    }
+    decoded
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn normalize_path_drops_empty_and_dot_segments() {
+        assert_eq!(normalize_path("/").unwrap(), "/");
+        assert_eq!(normalize_path("//").unwrap(), "/");
+        assert_eq!(
+            normalize_path("//api/./messages/").unwrap(),
+            "/api/messages"
+        );
+    }
+
+    #[test]
+    fn normalize_path_decodes_segments() {
+        assert_eq!(
+            normalize_path("/api/messages/%61bc").unwrap(),
+            "/api/messages/abc"
+        );
+        // only query strings decode `+` as a space
+        assert_eq!(normalize_path("/a+b").unwrap(), "/a+b");
+        // malformed escapes are left as they are
+        assert_eq!(normalize_path("/a%zz").unwrap(), "/a%zz");
+    }
+
+    #[test]
+    fn normalize_path_rejects_traversal_and_encoded_separators() {
+        assert!(normalize_path("api/messages").is_err());
+        assert!(normalize_path("/api/../admin").is_err());
+        assert!(normalize_path("/api/%2e%2e/admin").is_err());
+        assert!(normalize_path("/api/a%2Fb").is_err());
+        assert!(normalize_path("/api/a%5Cb").is_err());
+        assert!(normalize_path("/api/%ff").is_err());
+    }
+
+    #[test]
+    fn query_params_are_decoded() {
+        let mut request = Request::default();
+        request
+            .set_uri("/api/messages/search?q=hello+world&author=%C3%A9&flag".to_string())
+            .unwrap();
+        assert_eq!(request.query_param("q"), Some("hello world"));
+        assert_eq!(request.query_param("author"), Some("é"));
+        assert_eq!(request.query_param("flag"), Some(""));
+        assert_eq!(request.query_param("missing"), None);
+    }
+}
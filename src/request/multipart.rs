@@ -0,0 +1,138 @@
+/// A part of a `multipart/form-data` body (RFC 7578), which may be binary.
+#[derive(Debug)]
+pub struct Part<'a> {
+    /// The `name` of its `Content-Disposition`.
+    pub name: String,
+    pub data: &'a [u8],
+}
+
+/// Whether `content_type` is `multipart/form-data`.
+pub fn is_multipart(content_type: &str) -> bool {
+    let media_type = content_type.split(';').next().unwrap_or_default().trim();
+    media_type.eq_ignore_ascii_case("multipart/form-data")
+}
+
+/// The `boundary` parameter of a `multipart/form-data` content type, unquoted.
+pub fn boundary(content_type: &str) -> Option<&str> {
+    content_type.split(';').skip(1).find_map(|param| {
+        let (name, value) = param.split_once('=')?;
+        if !name.trim().eq_ignore_ascii_case("boundary") {
+            return None;
+        }
+        let value = value.trim();
+        let value = value
+            .strip_prefix('"')
+            .and_then(|v| v.strip_suffix('"'))
+            .unwrap_or(value);
+        (!value.is_empty()).then_some(value)
+    })
+}
+
+/// Splits `body` into its parts, the preamble and epilogue left out.
+///
+/// # Errors
+///
+/// This function will return an error if `body` isn't delimited by `boundary`, ends before its
+/// closing delimiter, or has a part without a name.
+pub fn parse<'a>(body: &'a [u8], boundary: &str) -> Result<Vec<Part<'a>>, &'static str> {
+    const MALFORMED: &str = "The multipart body is malformed.";
+
+    let delimiter = [b"--", boundary.as_bytes()].concat();
+    // every delimiter but the first is on a line of its own
+    let separator = [b"\r\n", delimiter.as_slice()].concat();
+
+    let start = find(body, &delimiter).ok_or(MALFORMED)?;
+    let mut rest = &body[start + delimiter.len()..];
+    let mut parts = Vec::new();
+    loop {
+        if rest.starts_with(b"--") {
+            return Ok(parts);
+        }
+        rest = rest.strip_prefix(b"\r\n").ok_or(MALFORMED)?;
+        let head_end = find(rest, b"\r\n\r\n").ok_or(MALFORMED)?;
+        let head = std::str::from_utf8(&rest[..head_end]).map_err(|_| MALFORMED)?;
+        rest = &rest[head_end + 4..];
+        let data_end = find(rest, &separator).ok_or(MALFORMED)?;
+        let name = part_name(head).ok_or("Every part must have a name.")?;
+        parts.push(Part {
+            name,
+            data: &rest[..data_end],
+        });
+        rest = &rest[data_end + separator.len()..];
+    }
+}
+
+/// The `name` of the `Content-Disposition: form-data` header among the headers of a part.
+fn part_name(head: &str) -> Option<String> {
+    let disposition = head.split("\r\n").find_map(|line| {
+        let (name, value) = line.split_once(':')?;
+        name.trim()
+            .eq_ignore_ascii_case("content-disposition")
+            .then_some(value)
+    })?;
+    let mut params = disposition.split(';').map(str::trim);
+    if !params.next()?.eq_ignore_ascii_case("form-data") {
+        return None;
+    }
+    params.find_map(|param| {
+        let (name, value) = param.split_once('=')?;
+        let value = value.trim().trim_matches('"');
+        name.trim()
+            .eq_ignore_ascii_case("name")
+            .then(|| value.to_string())
+    })
+}
+
+/// The index of the first occurrence of `needle` in `haystack`.
+fn find(haystack: &[u8], needle: &[u8]) -> Option<usize> {
+    haystack
+        .windows(needle.len())
+        .position(|window| window == needle)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const BODY: &[u8] = b"preamble\r\n--xyz\r\n\
+        Content-Disposition: form-data; name=\"message\"\r\n\
+        Content-Type: application/json\r\n\r\n\
+        {\"uuid\":\"a\"}\r\n--xyz\r\n\
+        Content-Disposition: form-data; name=\"image\"; filename=\"a.png\"\r\n\r\n\
+        \x89PNG\r\n\x00\r\n--xyz--\r\nepilogue";
+
+    #[test]
+    fn boundary_is_unquoted() {
+        assert_eq!(boundary("multipart/form-data; boundary=xyz"), Some("xyz"));
+        assert_eq!(
+            boundary("multipart/form-data; charset=utf-8; BOUNDARY=\"a b\""),
+            Some("a b")
+        );
+        assert_eq!(boundary("multipart/form-data; boundary="), None);
+        assert!(is_multipart("Multipart/Form-Data; boundary=xyz"));
+        assert!(!is_multipart("application/json"));
+    }
+
+    #[test]
+    fn parse_splits_the_parts() {
+        let parts = parse(BODY, "xyz").unwrap();
+        assert_eq!(parts.len(), 2);
+        assert_eq!(parts[0].name, "message");
+        assert_eq!(parts[0].data, b"{\"uuid\":\"a\"}");
+        // binary data, line breaks included, is kept as it is
+        assert_eq!(parts[1].name, "image");
+        assert_eq!(parts[1].data, b"\x89PNG\r\n\x00");
+    }
+
+    #[test]
+    fn parse_rejects_malformed_bodies() {
+        assert!(parse(BODY, "other").is_err());
+        // no closing delimiter
+        assert!(parse(&BODY[..BODY.len() - 16], "xyz").is_err());
+        let unnamed = b"--xyz\r\nContent-Disposition: form-data\r\n\r\ndata\r\n--xyz--";
+        assert_eq!(
+            parse(unnamed, "xyz").unwrap_err(),
+            "Every part must have a name."
+        );
+    }
+}
@@ -1,53 +1,239 @@
-use std::fmt;
+use bytes::Bytes;
+use std::{
+    borrow::Cow,
+    io,
+    sync::{Arc, Mutex},
+    time::{SystemTime, UNIX_EPOCH},
+};
+use tokio::sync::mpsc;
 
-pub(crate) struct Response<'a> {
-    pub(crate) status_line: &'a str,
-    pub(crate) headers: Vec<&'a str>,
-    pub(crate) content: Option<&'a str>,
+mod status;
+mod writer;
+
+pub use status::StatusCode;
+pub(crate) use writer::ResponseWriter;
+
+/// `Content-Type` of plain text responses.
+pub(crate) const CONTENT_TYPE_TEXT: &str = "text/plain; charset=utf-8";
+/// `Content-Type` of JSON responses.
+pub(crate) const CONTENT_TYPE_JSON: &str = "application/json; charset=utf-8";
+
+/// The `Server` header of every response.
+const SERVER: &str = concat!(env!("CARGO_PKG_NAME"), "/", env!("CARGO_PKG_VERSION"));
+
+/// The length of the status line and headers of `response`, including the blank line ending
+/// them.
+pub(crate) fn head_len(response: &[u8]) -> usize {
+    response
+        .windows(4)
+        .position(|w| w == b"\r\n\r\n")
+        .map_or(response.len(), |end| end + 4)
 }
 
-impl<'a> Response<'a> {
+/// The chunks of a body produced while it is sent, e.g. rows read from the database, until the
+/// sender is dropped. An error ends the response early, so the client can tell it is incomplete.
+pub(crate) type BodyStream = mpsc::Receiver<io::Result<Bytes>>;
+
+/// An HTTP response. The body is kept in chunks, so large bodies like pages are written out
+/// without being copied into one buffer. A body too large to keep at all is streamed after
+/// them instead, see [`Response::body_stream`].
+///
+/// The `Date`, `Server` and `Content-Length` or `Transfer-Encoding` headers are added when the
+/// head is written, so handlers never set them.
+#[derive(Debug, Clone)]
+pub struct Response {
+    status: StatusCode,
+    headers: Vec<(String, String)>,
+    body: Vec<Bytes>,
+    /// Taken by whoever sends the response first, clones share it.
+    stream: Option<Arc<Mutex<Option<BodyStream>>>>,
+}
+
+impl Response {
     pub(crate) fn new() -> Self {
         Self {
-            status_line: "HTTP/1.1 200 OK",
+            status: StatusCode::Ok,
             headers: Vec::new(),
-            content: None,
+            body: Vec::new(),
+            stream: None,
         }
     }
 
-    pub(crate) fn status_line(mut self, status_line: &'a str) -> Self {
-        self.status_line = status_line;
+    pub(crate) fn status(mut self, status: StatusCode) -> Self {
+        self.status = status;
         self
     }
 
-    pub(crate) fn set_status_line(&mut self, status_line: &'a str) {
-        self.status_line = status_line;
+    pub(crate) fn set_status(&mut self, status: StatusCode) {
+        self.status = status;
+    }
+
+    pub(crate) fn status_code(&self) -> StatusCode {
+        self.status
     }
 
-    pub(crate) fn append_header(mut self, header: &'a str) -> Self {
-        self.headers.push(header);
+    pub(crate) fn header(mut self, name: impl Into<String>, value: impl ToString) -> Self {
+        self.headers.push((name.into(), value.to_string()));
         self
     }
 
-    pub(crate) fn body(mut self, content: &'a str) -> Self {
-        self.content = Some(content);
+    pub(crate) fn body(self, body: impl Into<String>) -> Self {
+        self.body_bytes(body.into().into_bytes())
+    }
+
+    pub(crate) fn body_bytes(self, body: Vec<u8>) -> Self {
+        self.body_chunks(vec![body.into()])
+    }
+
+    pub(crate) fn body_chunks(mut self, chunks: Vec<Bytes>) -> Self {
+        self.body = chunks;
         self
     }
-}
 
-impl<'a> fmt::Display for Response<'a> {
-    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
-        let mut response = String::new();
-        response.push_str(self.status_line);
-        response.push_str("\r\n");
-        for header in self.headers.iter() {
-            response.push_str(header);
-            response.push_str("\r\n");
+    /// Streams the rest of the body from `stream` once the chunks were sent, with chunked
+    /// transfer encoding on HTTP/1.1, so its length doesn't need to be known upfront.
+    pub(crate) fn body_stream(mut self, stream: BodyStream) -> Self {
+        self.stream = Some(Arc::new(Mutex::new(Some(stream))));
+        self
+    }
+
+    /// The streamed rest of the body, `None` if there is none or it was taken already.
+    pub(crate) fn take_stream(&self) -> Option<BodyStream> {
+        self.stream.as_ref()?.lock().unwrap().take()
+    }
+
+    /// Whether the body is streamed after the chunks.
+    pub(crate) fn is_streamed(&self) -> bool {
+        self.stream.is_some()
+    }
+
+    /// Drops the body, keeping the headers describing it, to answer a `HEAD` request.
+    pub(crate) fn without_body(mut self) -> Self {
+        // the length is still the one of the body a GET gets
+        if let Some(len) = self.content_length() {
+            self = self.header("Content-Length", len);
         }
-        response.push_str("\r\n");
-        if let Some(content) = self.content {
-            response.push_str(content);
+        if self.is_streamed() {
+            self = self.header("Transfer-Encoding", "chunked");
         }
-        write!(f, "{}", response)
+        self.body.clear();
+        // dropping the stream stops producing it
+        self.stream = None;
+        self
+    }
+
+    /// The status line and headers, ending with a blank line.
+    pub(crate) fn head(&self) -> String {
+        let mut head = format!("HTTP/1.1 {}\r\n", self.status);
+        for (name, value) in self.header_fields() {
+            head.push_str(name);
+            head.push_str(": ");
+            head.push_str(&value);
+            head.push_str("\r\n");
+        }
+        head.push_str("\r\n");
+        head
+    }
+
+    /// The headers as sent, with `Date`, `Server` and `Content-Length` or `Transfer-Encoding`
+    /// added.
+    pub(crate) fn header_fields(&self) -> impl Iterator<Item = (&str, Cow<'_, str>)> {
+        let date = ("Date", Cow::Owned(http_date(SystemTime::now())));
+        let server = ("Server", Cow::Borrowed(SERVER));
+        let content_length = self
+            .content_length()
+            .map(|len| ("Content-Length", Cow::Owned(len.to_string())));
+        let transfer_encoding = self
+            .is_streamed()
+            .then_some(("Transfer-Encoding", Cow::Borrowed("chunked")));
+        [date, server]
+            .into_iter()
+            .chain(
+                self.headers
+                    .iter()
+                    .map(|(name, value)| (name.as_str(), Cow::Borrowed(value.as_str()))),
+            )
+            .chain(content_length)
+            .chain(transfer_encoding)
     }
+
+    /// The length of the body to send in `Content-Length`, `None` if the header is already set
+    /// or must not be sent.
+    fn content_length(&self) -> Option<usize> {
+        let is_set = self.headers.iter().any(|(name, _)| {
+            name.eq_ignore_ascii_case("Content-Length")
+                || name.eq_ignore_ascii_case("Transfer-Encoding")
+        });
+        // a 204 has no body to describe, the body of a 304 is the one the client already has
+        let bodiless = matches!(self.status, StatusCode::NoContent | StatusCode::NotModified);
+        (!is_set && !bodiless && !self.is_streamed()).then(|| self.body_len())
+    }
+
+    /// The length of the body, without the head or a streamed rest.
+    pub(crate) fn body_len(&self) -> usize {
+        self.body.iter().map(Bytes::len).sum()
+    }
+
+    /// The chunks of the body.
+    pub(crate) fn chunks(&self) -> &[Bytes] {
+        &self.body
+    }
+
+    /// Parses a response received from a peer, `None` if it is malformed.
+    pub(crate) fn parse(raw: Vec<u8>) -> Option<Self> {
+        let head_len = head_len(&raw);
+        let head = std::str::from_utf8(&raw[..head_len]).ok()?;
+        let mut lines = head.split("\r\n").filter(|line| !line.is_empty());
+        let code = lines.next()?.split(' ').nth(1)?.parse().ok()?;
+
+        let mut response = Self::new().status(StatusCode::from_code(code)?);
+        for line in lines {
+            let (name, value) = line.split_once(": ")?;
+            // sent again by this server, and the peer's connection isn't the client's
+            if ["Date", "Server", "Connection"]
+                .iter()
+                .any(|header| name.eq_ignore_ascii_case(header))
+            {
+                continue;
+            }
+            response = response.header(name, value);
+        }
+        let raw = Bytes::from(raw);
+        Some(response.body_chunks(vec![raw.slice(head_len..)]))
+    }
+}
+
+/// Formats `time` as an RFC 7231 IMF-fixdate, e.g. `Sun, 06 Nov 1994 08:49:37 GMT`.
+fn http_date(time: SystemTime) -> String {
+    const WEEKDAYS: [&str; 7] = ["Thu", "Fri", "Sat", "Sun", "Mon", "Tue", "Wed"];
+    const MONTHS: [&str; 12] = [
+        "Jan", "Feb", "Mar", "Apr", "May", "Jun", "Jul", "Aug", "Sep", "Oct", "Nov", "Dec",
+    ];
+
+    let secs = time
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_secs();
+    let (days, secs) = (secs / 86_400, secs % 86_400);
+
+    // the civil date of `days` since the epoch, in eras of 400 years starting in March
+    let z = days as i64 + 719_468;
+    let era = z / 146_097;
+    let day_of_era = z - era * 146_097;
+    let year_of_era =
+        (day_of_era - day_of_era / 1_460 + day_of_era / 36_524 - day_of_era / 146_096) / 365;
+    let day_of_year = day_of_era - (365 * year_of_era + year_of_era / 4 - year_of_era / 100);
+    let mp = (5 * day_of_year + 2) / 153;
+    let day = day_of_year - (153 * mp + 2) / 5 + 1;
+    let month = if mp < 10 { mp + 3 } else { mp - 9 };
+    let year = year_of_era + era * 400 + i64::from(month <= 2);
+
+    format!(
+        "{}, {day:02} {} {year} {:02}:{:02}:{:02} GMT",
+        WEEKDAYS[(days % 7) as usize],
+        MONTHS[month as usize - 1],
+        secs / 3_600,
+        secs % 3_600 / 60,
+        secs % 60
+    )
 }
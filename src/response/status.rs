@@ -0,0 +1,145 @@
+use std::fmt;
+
+/// The status codes the server responds with.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum StatusCode {
+    Ok,
+    Created,
+    NoContent,
+    NotModified,
+    BadRequest,
+    Unauthorized,
+    PaymentRequired,
+    Forbidden,
+    NotFound,
+    MethodNotAllowed,
+    RequestTimeout,
+    Conflict,
+    Gone,
+    LengthRequired,
+    PreconditionFailed,
+    PayloadTooLarge,
+    UnsupportedMediaType,
+    RangeNotSatisfiable,
+    ExpectationFailed,
+    UnprocessableEntity,
+    TooManyRequests,
+    RequestHeaderFieldsTooLarge,
+    InternalServerError,
+    NotImplemented,
+    BadGateway,
+    ServiceUnavailable,
+    InsufficientStorage,
+}
+
+impl StatusCode {
+    const ALL: [StatusCode; 27] = [
+        StatusCode::Ok,
+        StatusCode::Created,
+        StatusCode::NoContent,
+        StatusCode::NotModified,
+        StatusCode::BadRequest,
+        StatusCode::Unauthorized,
+        StatusCode::PaymentRequired,
+        StatusCode::Forbidden,
+        StatusCode::NotFound,
+        StatusCode::MethodNotAllowed,
+        StatusCode::RequestTimeout,
+        StatusCode::Conflict,
+        StatusCode::Gone,
+        StatusCode::LengthRequired,
+        StatusCode::PreconditionFailed,
+        StatusCode::PayloadTooLarge,
+        StatusCode::UnsupportedMediaType,
+        StatusCode::RangeNotSatisfiable,
+        StatusCode::ExpectationFailed,
+        StatusCode::UnprocessableEntity,
+        StatusCode::TooManyRequests,
+        StatusCode::RequestHeaderFieldsTooLarge,
+        StatusCode::InternalServerError,
+        StatusCode::NotImplemented,
+        StatusCode::BadGateway,
+        StatusCode::ServiceUnavailable,
+        StatusCode::InsufficientStorage,
+    ];
+
+    pub fn code(self) -> u16 {
+        match self {
+            StatusCode::Ok => 200,
+            StatusCode::Created => 201,
+            StatusCode::NoContent => 204,
+            StatusCode::NotModified => 304,
+            StatusCode::BadRequest => 400,
+            StatusCode::Unauthorized => 401,
+            StatusCode::PaymentRequired => 402,
+            StatusCode::Forbidden => 403,
+            StatusCode::NotFound => 404,
+            StatusCode::MethodNotAllowed => 405,
+            StatusCode::RequestTimeout => 408,
+            StatusCode::Conflict => 409,
+            StatusCode::Gone => 410,
+            StatusCode::LengthRequired => 411,
+            StatusCode::PreconditionFailed => 412,
+            StatusCode::PayloadTooLarge => 413,
+            StatusCode::UnsupportedMediaType => 415,
+            StatusCode::RangeNotSatisfiable => 416,
+            StatusCode::ExpectationFailed => 417,
+            StatusCode::UnprocessableEntity => 422,
+            StatusCode::TooManyRequests => 429,
+            StatusCode::RequestHeaderFieldsTooLarge => 431,
+            StatusCode::InternalServerError => 500,
+            StatusCode::NotImplemented => 501,
+            StatusCode::BadGateway => 502,
+            StatusCode::ServiceUnavailable => 503,
+            StatusCode::InsufficientStorage => 507,
+        }
+    }
+
+    pub fn reason(self) -> &'static str {
+        match self {
+            StatusCode::Ok => "OK",
+            StatusCode::Created => "CREATED",
+            StatusCode::NoContent => "NO CONTENT",
+            StatusCode::NotModified => "NOT MODIFIED",
+            StatusCode::BadRequest => "BAD REQUEST",
+            StatusCode::Unauthorized => "UNAUTHORIZED",
+            StatusCode::PaymentRequired => "PAYMENT REQUIRED",
+            StatusCode::Forbidden => "FORBIDDEN",
+            StatusCode::NotFound => "NOT FOUND",
+            StatusCode::MethodNotAllowed => "METHOD NOT ALLOWED",
+            StatusCode::RequestTimeout => "REQUEST TIMEOUT",
+            StatusCode::Conflict => "CONFLICT",
+            StatusCode::Gone => "GONE",
+            StatusCode::LengthRequired => "LENGTH REQUIRED",
+            StatusCode::PreconditionFailed => "PRECONDITION FAILED",
+            StatusCode::PayloadTooLarge => "PAYLOAD TOO LARGE",
+            StatusCode::UnsupportedMediaType => "UNSUPPORTED MEDIA TYPE",
+            StatusCode::RangeNotSatisfiable => "RANGE NOT SATISFIABLE",
+            StatusCode::ExpectationFailed => "EXPECTATION FAILED",
+            StatusCode::UnprocessableEntity => "UNPROCESSABLE ENTITY",
+            StatusCode::TooManyRequests => "TOO MANY REQUESTS",
+            StatusCode::RequestHeaderFieldsTooLarge => "REQUEST HEADER FIELDS TOO LARGE",
+            StatusCode::InternalServerError => "INTERNAL SERVER ERROR",
+            StatusCode::NotImplemented => "NOT IMPLEMENTED",
+            StatusCode::BadGateway => "BAD GATEWAY",
+            StatusCode::ServiceUnavailable => "SERVICE UNAVAILABLE",
+            StatusCode::InsufficientStorage => "INSUFFICIENT STORAGE",
+        }
+    }
+
+    /// Whether this is a 2xx status.
+    pub fn is_success(self) -> bool {
+        (200..300).contains(&self.code())
+    }
+
+    /// The status of `code`, `None` if the server never responds with it.
+    pub fn from_code(code: u16) -> Option<Self> {
+        Self::ALL.into_iter().find(|status| status.code() == code)
+    }
+}
+
+impl fmt::Display for StatusCode {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{} {}", self.code(), self.reason())
+    }
+}
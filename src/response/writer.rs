@@ -0,0 +1,86 @@
+use std::{io, num::NonZeroU64};
+use tokio::io::{AsyncWrite, AsyncWriteExt, BufWriter};
+
+use super::Response;
+use crate::throttle::Throttle;
+
+/// Writes a response to a stream chunk by chunk, so a large body is never copied into one
+/// buffer.
+pub(crate) struct ResponseWriter<'a, W> {
+    stream: BufWriter<&'a mut W>,
+    throttle: Option<Throttle>,
+    body_bytes: u64,
+}
+
+impl<'a, W: AsyncWrite + Unpin> ResponseWriter<'a, W> {
+    pub(crate) fn new(stream: &'a mut W) -> Self {
+        Self {
+            stream: BufWriter::new(stream),
+            throttle: None,
+            body_bytes: 0,
+        }
+    }
+
+    /// How many bytes of body were written, a streamed body included, without the head or the
+    /// chunk framing.
+    pub(crate) fn body_bytes(&self) -> u64 {
+        self.body_bytes
+    }
+
+    /// Paces the response to `bytes_per_sec`.
+    pub(crate) fn throttle(mut self, bytes_per_sec: NonZeroU64) -> Self {
+        self.throttle = Some(Throttle::new(bytes_per_sec));
+        self
+    }
+
+    /// Writes the head of `response`, then its body chunks one after the other, followed by the
+    /// streamed rest of the body if any.
+    ///
+    /// # Errors
+    ///
+    /// This function will return an error if writing to the stream fails, or if the streamed
+    /// body ends with an error, leaving the response incomplete.
+    pub(crate) async fn send(&mut self, response: &Response) -> io::Result<()> {
+        self.write(response.head().as_bytes()).await?;
+        let Some(mut body) = response.take_stream() else {
+            for chunk in response.chunks() {
+                self.write_body(chunk).await?;
+            }
+            return self.stream.flush().await;
+        };
+
+        // every part of a chunked body is framed with its length
+        for chunk in response.chunks() {
+            self.write_chunk(chunk).await?;
+        }
+        while let Some(chunk) = body.recv().await {
+            self.write_chunk(&chunk?).await?;
+        }
+        self.write(b"0\r\n\r\n").await?;
+        self.stream.flush().await
+    }
+
+    async fn write_chunk(&mut self, chunk: &[u8]) -> io::Result<()> {
+        // an empty chunk would end the body
+        if chunk.is_empty() {
+            return Ok(());
+        }
+        self.write(format!("{:x}\r\n", chunk.len()).as_bytes())
+            .await?;
+        self.write_body(chunk).await?;
+        self.write(b"\r\n").await
+    }
+
+    async fn write_body(&mut self, data: &[u8]) -> io::Result<()> {
+        self.write(data).await?;
+        self.body_bytes += data.len() as u64;
+        Ok(())
+    }
+
+    async fn write(&mut self, data: &[u8]) -> io::Result<()> {
+        match &mut self.throttle {
+            Some(throttle) => throttle.write(&mut self.stream, data).await,
+            None => self.stream.write_all(data).await,
+        }
+    }
+}
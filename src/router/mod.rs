@@ -0,0 +1,176 @@
+use crate::request::method::Method;
+
+/// The parameters captured by the `:name` segments of a matched pattern.
+#[derive(Debug, Default)]
+pub(crate) struct Params<'a> {
+    params: Vec<(&'static str, &'a str)>,
+}
+
+impl<'a> Params<'a> {
+    pub(crate) fn get(&self, name: &str) -> Option<&'a str> {
+        self.params
+            .iter()
+            .find(|(param, _)| *param == name)
+            .map(|(_, value)| *value)
+    }
+}
+
+#[derive(Debug, PartialEq, Eq)]
+pub(crate) enum RouteError {
+    NotFound,
+    /// The path is known, but not for this method.
+    MethodNotAllowed {
+        allowed: Vec<Method>,
+    },
+}
+
+/// Matches a method and path against patterns like `/api/messages/:uuid`. Routes are tried in
+/// the order they were added, so literal segments should be added before parameters. `HEAD`
+/// requests match the `GET` routes.
+pub(crate) struct Router<T> {
+    routes: Vec<(Method, Vec<&'static str>, T)>,
+}
+
+impl<T: Copy> Router<T> {
+    pub(crate) fn new() -> Self {
+        Self { routes: Vec::new() }
+    }
+
+    pub(crate) fn route(mut self, method: Method, pattern: &'static str, value: T) -> Self {
+        self.routes
+            .push((method, segments(pattern).collect(), value));
+        self
+    }
+
+    /// The values of the routes, in the order they were added.
+    pub(crate) fn values(&self) -> impl Iterator<Item = T> + '_ {
+        self.routes.iter().map(|(_, _, value)| *value)
+    }
+
+    /// Finds the route of `method` and `path`.
+    ///
+    /// # Errors
+    ///
+    /// This function will return an error if no pattern matches `path`, or if the matching
+    /// patterns are registered for other methods only.
+    pub(crate) fn find<'a>(
+        &self,
+        method: Method,
+        path: &'a str,
+    ) -> Result<(T, Params<'a>), RouteError> {
+        let path: Vec<_> = segments(path).collect();
+        let mut allowed = Vec::new();
+
+        for (route_method, pattern, value) in &self.routes {
+            let Some(params) = match_segments(pattern, &path) else {
+                continue;
+            };
+            if *route_method == method || (*route_method, method) == (Method::Get, Method::Head) {
+                return Ok((*value, params));
+            }
+            if !allowed.contains(route_method) {
+                allowed.push(*route_method);
+                if *route_method == Method::Get {
+                    allowed.push(Method::Head);
+                }
+            }
+        }
+
+        match allowed.is_empty() {
+            true => Err(RouteError::NotFound),
+            false => Err(RouteError::MethodNotAllowed { allowed }),
+        }
+    }
+}
+
+/// The segments of a path, ignoring a trailing slash.
+fn segments(path: &str) -> impl Iterator<Item = &str> {
+    path.trim_end_matches('/').split('/').skip(1)
+}
+
+fn match_segments<'a>(pattern: &[&'static str], path: &[&'a str]) -> Option<Params<'a>> {
+    if pattern.len() != path.len() {
+        return None;
+    }
+    let mut params = Params::default();
+    for (pattern, segment) in pattern.iter().zip(path) {
+        match pattern.strip_prefix(':') {
+            Some(name) if !segment.is_empty() => params.params.push((name, segment)),
+            Some(_) => return None,
+            None if pattern == segment => (),
+            None => return None,
+        }
+    }
+    Some(params)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn router() -> Router<u8> {
+        Router::new()
+            .route(Method::Get, "/api/messages", 1)
+            .route(Method::Post, "/api/messages", 2)
+            .route(Method::Get, "/api/messages/stats", 3)
+            .route(Method::Get, "/api/messages/:uuid", 4)
+            .route(Method::Delete, "/api/messages/:uuid", 5)
+            .route(Method::Put, "/api/messages/:uuid/uploads/:upload_id", 6)
+    }
+
+    #[test]
+    fn literal_routes_match_before_parameters() {
+        let router = router();
+        assert_eq!(router.find(Method::Get, "/api/messages").unwrap().0, 1);
+        assert_eq!(router.find(Method::Post, "/api/messages/").unwrap().0, 2);
+        assert_eq!(
+            router.find(Method::Get, "/api/messages/stats").unwrap().0,
+            3
+        );
+    }
+
+    #[test]
+    fn parameters_are_captured() {
+        let router = router();
+        let (route, params) = router.find(Method::Get, "/api/messages/abc").unwrap();
+        assert_eq!(route, 4);
+        assert_eq!(params.get("uuid"), Some("abc"));
+
+        let (route, params) = router
+            .find(Method::Put, "/api/messages/abc/uploads/7")
+            .unwrap();
+        assert_eq!(route, 6);
+        assert_eq!(params.get("uuid"), Some("abc"));
+        assert_eq!(params.get("upload_id"), Some("7"));
+        assert_eq!(params.get("other"), None);
+    }
+
+    #[test]
+    fn head_matches_get() {
+        assert_eq!(
+            router().find(Method::Head, "/api/messages/abc").unwrap().0,
+            4
+        );
+    }
+
+    #[test]
+    fn unmatched_paths_and_methods_are_told_apart() {
+        let router = router();
+        assert_eq!(
+            router.find(Method::Get, "/api/other").unwrap_err(),
+            RouteError::NotFound
+        );
+        assert_eq!(
+            router
+                .find(Method::Get, "/api/messages/abc/uploads")
+                .unwrap_err(),
+            RouteError::NotFound
+        );
+        assert_eq!(
+            router.find(Method::Patch, "/api/messages/abc").unwrap_err(),
+            RouteError::MethodNotAllowed {
+                allowed: vec![Method::Get, Method::Head, Method::Delete]
+            }
+        );
+    }
+}
@@ -0,0 +1,147 @@
+use ahash::AHashMap;
+use serde::Deserialize;
+use std::{
+    io,
+    path::{Path, PathBuf},
+};
+
+use crate::{
+    app_state::AppState,
+    models::{timestamp_now, Message, MessageId, Reactions},
+    repository::RepositoryResult,
+};
+
+/// A `.json` fixture, a single message or a list of them.
+#[derive(Deserialize)]
+#[serde(untagged)]
+enum Fixture {
+    One(FixtureMessage),
+    Many(Vec<FixtureMessage>),
+}
+
+#[derive(Deserialize)]
+struct FixtureMessage {
+    uuid: String,
+    author: String,
+    message: String,
+    #[serde(default)]
+    likes: i32,
+    /// Takes precedence over an image file named after the uuid.
+    image: Option<String>,
+    client_timestamp: Option<i64>,
+    /// Fixtures are imported as they are, the parent isn't checked.
+    parent_uuid: Option<String>,
+}
+
+/// Imports the fixtures of `dir`, returning the number of messages imported.
+///
+/// Every `.json` file holds a message or a list of messages. Any other file is the image of the
+/// message whose uuid is its name without the extension, e.g. `<uuid>.png`. Text files are
+/// stored as they are, like the data urls clients send, other files as a base64 data url.
+///
+/// # Errors
+///
+/// This function will return an error if a file can't be read or parsed, or if storing a
+/// message fails.
+pub async fn seed_from_dir(dir: &Path, state: &AppState) -> RepositoryResult<usize> {
+    let mut paths = std::fs::read_dir(dir)?
+        .map(|entry| entry.map(|entry| entry.path()))
+        .collect::<io::Result<Vec<_>>>()?;
+    // import in a stable order
+    paths.sort();
+    let (fixtures, images): (Vec<_>, Vec<_>) = paths
+        .into_iter()
+        .filter(|path| path.is_file())
+        .partition(|path| path.extension().is_some_and(|ext| ext == "json"));
+    let mut images: AHashMap<String, PathBuf> = images
+        .into_iter()
+        .filter_map(|path| Some((path.file_stem()?.to_str()?.to_string(), path)))
+        .collect();
+
+    let mut seeded = 0;
+    for path in fixtures {
+        let fixture: Fixture = serde_json::from_slice(&std::fs::read(&path)?)
+            .map_err(|e| format!("Invalid fixture {}: {e}", path.display()))?;
+        let messages = match fixture {
+            Fixture::One(message) => vec![message],
+            Fixture::Many(messages) => messages,
+        };
+
+        for mut message in messages {
+            // the image file is named after the uuid as the fixture has it
+            let image_name = message.uuid.clone();
+            message.uuid = MessageId::parse(message.uuid, state.id_scheme)
+                .map_err(|e| format!("Invalid fixture {}: {e}", path.display()))?
+                .into_inner();
+            message.parent_uuid = message
+                .parent_uuid
+                .map(|parent_uuid| state.id_scheme.normalize(parent_uuid));
+            if !state.all_uuids.lock().await.insert(message.uuid.clone()) {
+                eprintln!("Skipping fixture with duplicate uuid {}", message.uuid);
+                continue;
+            }
+
+            let image = match message.image {
+                Some(image) => Some(image),
+                None => images
+                    .remove(&image_name)
+                    .map(|path| read_image(&path))
+                    .transpose()?,
+            };
+            if let Some(image) = &image {
+                state.images.save(&message.uuid, image).await?;
+            }
+
+            let server_timestamp = timestamp_now();
+            let row = Message {
+                uuid: message.uuid,
+                author: message.author,
+                message: message.message,
+                parent_uuid: message.parent_uuid,
+                likes: message.likes,
+                has_image: image.is_some(),
+                client_timestamp: message.client_timestamp,
+                server_timestamp,
+                created_at: server_timestamp,
+                updated_at: server_timestamp,
+                deleted_at: None,
+                revision: 1,
+                reactions: Reactions::default(),
+            };
+            state.messages.insert(&row).await?;
+            seeded += 1;
+        }
+    }
+
+    for path in images.values() {
+        eprintln!("Skipping image {} without a message", path.display());
+    }
+    if seeded > 0 {
+        state.outbox_notify.notify_one();
+    }
+    Ok(seeded)
+}
+
+/// The image stored for the file at `path`.
+fn read_image(path: &Path) -> io::Result<String> {
+    match String::from_utf8(std::fs::read(path)?) {
+        Ok(text) => Ok(text),
+        Err(e) => {
+            let ext = path
+                .extension()
+                .and_then(|ext| ext.to_str())
+                .unwrap_or_default();
+            let mime = match ext.to_ascii_lowercase().as_str() {
+                "png" => "image/png",
+                "jpg" | "jpeg" => "image/jpeg",
+                "gif" => "image/gif",
+                "webp" => "image/webp",
+                _ => "application/octet-stream",
+            };
+            Ok(format!(
+                "data:{mime};base64,{}",
+                base64::encode(e.into_bytes())
+            ))
+        }
+    }
+}
@@ -0,0 +1,149 @@
+use crate::{models::IDEMPOTENCY_KEY_HEADER, request::Request, response::Response};
+use std::io;
+use tokio::{
+    io::{AsyncReadExt, AsyncWriteExt},
+    net::TcpStream,
+};
+
+/// The header marking a request as already forwarded by a peer, so it is always handled locally
+/// even if the peers disagree on the ring. It carries the secret shared by the nodes, the header
+/// of a client not knowing it is ignored.
+pub const FORWARDED_HEADER: &str = "X-Shard-Forwarded";
+
+/// How many points each node gets on the ring, more points spread the uuids more evenly.
+const VIRTUAL_NODES: usize = 64;
+
+/// Routes uuids to the nodes of the cluster with consistent hashing, so adding or removing a
+/// node only moves the uuids of its neighbours on the ring.
+#[derive(Debug)]
+pub struct ShardRouter {
+    self_addr: String,
+    /// Sorted by hash.
+    ring: Vec<(u64, String)>,
+    /// Authenticates the requests the nodes forward to each other.
+    secret: String,
+}
+
+impl ShardRouter {
+    /// Creates the ring from the addresses of all nodes, `self_addr` being this one's. The nodes
+    /// authenticate the requests they forward to each other with `secret`.
+    pub fn new(self_addr: String, peers: Vec<String>, secret: String) -> Self {
+        let mut ring: Vec<_> = peers
+            .iter()
+            .chain(std::iter::once(&self_addr))
+            .flat_map(|addr| (0..VIRTUAL_NODES).map(move |i| (hash(&format!("{addr}#{i}")), addr)))
+            .map(|(hash, addr)| (hash, addr.clone()))
+            .collect();
+        ring.sort();
+        ring.dedup();
+        Self {
+            self_addr,
+            ring,
+            secret,
+        }
+    }
+
+    /// The address of the node owning `uuid`.
+    pub fn owner(&self, uuid: &str) -> &str {
+        let hash = hash(uuid);
+        let i = self.ring.partition_point(|(h, _)| *h < hash);
+        // wrap around to the first point of the ring
+        &self.ring[i % self.ring.len()].1
+    }
+
+    pub fn is_local(&self, uuid: &str) -> bool {
+        self.owner(uuid) == self.self_addr
+    }
+
+    /// Whether `request` was forwarded by a peer of the ring, which must have sent the shared
+    /// secret along.
+    pub fn is_forwarded(&self, request: &Request) -> bool {
+        let Some(given) = request.header(FORWARDED_HEADER) else {
+            return false;
+        };
+        // compare in constant time so the secret can't be guessed byte by byte
+        self.secret.len() == given.len()
+            && self
+                .secret
+                .bytes()
+                .zip(given.bytes())
+                .fold(0, |diff, (a, b)| diff | (a ^ b))
+                == 0
+    }
+
+    /// Sends `request` to the node at `addr` and returns its response.
+    pub async fn forward(&self, addr: &str, request: &Request) -> io::Result<Response> {
+        let mut stream = TcpStream::connect(addr).await?;
+
+        let mut head = format!(
+            "{} {} HTTP/1.1\r\nHost: {addr}\r\n{FORWARDED_HEADER}: {}\r\nConnection: close\r\n",
+            request.method(),
+            request.uri(),
+            self.secret
+        );
+        if let Some(content_range) = request.content_range() {
+            head.push_str(&format!("Content-Range: {content_range}\r\n"));
+        }
+        if let Some(if_match) = request.header("If-Match") {
+            head.push_str(&format!("If-Match: {if_match}\r\n"));
+        }
+        if let Some(key) = request.header(IDEMPOTENCY_KEY_HEADER) {
+            head.push_str(&format!("{IDEMPOTENCY_KEY_HEADER}: {key}\r\n"));
+        }
+        if let Some(digest) = request.content_sha256() {
+            head.push_str(&format!("X-Content-SHA256: {digest}\r\n"));
+        }
+        if let Some(body) = request.body() {
+            head.push_str(&format!("Content-Length: {}\r\n", body.len()));
+        }
+        head.push_str("\r\n");
+
+        stream.write_all(head.as_bytes()).await?;
+        if let Some(body) = request.body() {
+            stream.write_all(body.as_bytes()).await?;
+        }
+
+        // the peer closes the connection after responding
+        let mut response = Vec::new();
+        stream.read_to_end(&mut response).await?;
+        Response::parse(response)
+            .ok_or_else(|| io::Error::new(io::ErrorKind::InvalidData, "Malformed response"))
+    }
+}
+
+/// 64-bit FNV-1a, stable across builds and machines unlike the hashers of the hash maps.
+///
+/// FNV alone barely changes the high bits for strings differing only in their last characters,
+/// which uuids often do, so the result is mixed with the murmur3 finalizer.
+fn hash(s: &str) -> u64 {
+    let mut hash = s.bytes().fold(0xcbf29ce484222325, |hash, byte| {
+        (hash ^ byte as u64).wrapping_mul(0x100000001b3)
+    });
+    hash ^= hash >> 33;
+    hash = hash.wrapping_mul(0xff51afd7ed558ccd);
+    hash ^= hash >> 33;
+    hash = hash.wrapping_mul(0xc4ceb9fe1a85ec53);
+    hash ^ (hash >> 33)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn only_requests_with_the_secret_are_forwarded() {
+        let router = ShardRouter::new("a:1".to_string(), vec!["b:1".to_string()], "s3cret".into());
+        let forwarded = |value: Option<&str>| {
+            let mut request = Request::default();
+            if let Some(value) = value {
+                request.append_header(FORWARDED_HEADER, value);
+            }
+            router.is_forwarded(&request)
+        };
+
+        assert!(forwarded(Some("s3cret")));
+        assert!(!forwarded(None));
+        assert!(!forwarded(Some("1")));
+        assert!(!forwarded(Some("s3cres")));
+    }
+}
@@ -0,0 +1,81 @@
+use std::{num::NonZeroU64, time::Duration};
+use tokio::{
+    io::{AsyncWrite, AsyncWriteExt},
+    time::Instant,
+};
+
+/// Paces the writes of a response to no more than `bytes_per_sec`, so a bulk download can't
+/// saturate the uplink while other connections are being served.
+#[derive(Debug)]
+pub struct Throttle {
+    bytes_per_sec: u64,
+    start: Instant,
+    sent: u64,
+}
+
+impl Throttle {
+    pub fn new(bytes_per_sec: NonZeroU64) -> Self {
+        Self {
+            bytes_per_sec: bytes_per_sec.get(),
+            start: Instant::now(),
+            sent: 0,
+        }
+    }
+
+    /// Writes `data` to `writer`, keeping the bytes written so far within the budget.
+    ///
+    /// # Errors
+    ///
+    /// This function will return an error if writing to `writer` fails.
+    pub async fn write<W: AsyncWrite + Unpin>(
+        &mut self,
+        writer: &mut W,
+        data: &[u8],
+    ) -> std::io::Result<()> {
+        // send a few chunks per second so the pacing stays smooth
+        let chunk_size = (self.bytes_per_sec / 8).clamp(1, 64 * 1024) as usize;
+
+        for chunk in data.chunks(chunk_size) {
+            writer.write_all(chunk).await?;
+            writer.flush().await?;
+            self.sent += chunk.len() as u64;
+            // wait until the bytes sent so far are within the budget
+            let due =
+                self.start + Duration::from_secs_f64(self.sent as f64 / self.bytes_per_sec as f64);
+            tokio::time::sleep_until(due).await;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn write_keeps_within_the_rate() {
+        let mut throttle = Throttle::new(NonZeroU64::new(1000).unwrap());
+        let mut written = Vec::new();
+        let start = Instant::now();
+
+        throttle.write(&mut written, &[1; 200]).await.unwrap();
+        throttle.write(&mut written, &[2; 100]).await.unwrap();
+
+        assert_eq!(written.len(), 300);
+        assert!(written[..200].iter().all(|&byte| byte == 1));
+        // 300 bytes at 1000 bytes per second
+        assert!(start.elapsed() >= Duration::from_millis(300));
+    }
+
+    #[tokio::test]
+    async fn write_of_nothing_doesnt_wait() {
+        let mut throttle = Throttle::new(NonZeroU64::new(1).unwrap());
+        let mut written = Vec::new();
+        let start = Instant::now();
+
+        throttle.write(&mut written, &[]).await.unwrap();
+
+        assert!(written.is_empty());
+        assert!(start.elapsed() < Duration::from_millis(500));
+    }
+}
@@ -0,0 +1,70 @@
+use openssl::{
+    error::ErrorStack,
+    nid::Nid,
+    ssl::{Ssl, SslAcceptor, SslFiletype, SslMethod, SslVerifyMode},
+    x509::X509Name,
+};
+use std::{io, path::Path, pin::Pin};
+use tokio::net::TcpStream;
+use tokio_openssl::SslStream;
+
+/// Terminates TLS on the admin listener and only accepts clients presenting a certificate
+/// signed by the configured CA, so machines are identified by their certificate instead of a
+/// shared bearer token.
+pub struct ClientCertAuth {
+    acceptor: SslAcceptor,
+}
+
+impl ClientCertAuth {
+    /// Loads the PEM certificate chain and key of the server, and the CA client certificates
+    /// must be signed by.
+    ///
+    /// # Errors
+    ///
+    /// This function will return an error if a file can't be read or doesn't hold a valid
+    /// certificate or key, or if the key doesn't match the certificate.
+    pub fn new(cert_chain: &Path, key: &Path, client_ca: &Path) -> Result<Self, ErrorStack> {
+        let mut builder = SslAcceptor::mozilla_intermediate_v5(SslMethod::tls_server())?;
+        builder.set_certificate_chain_file(cert_chain)?;
+        builder.set_private_key_file(key, SslFiletype::PEM)?;
+        builder.check_private_key()?;
+        builder.set_ca_file(client_ca)?;
+        // the CA names tell clients with several certificates which one to present
+        builder.set_client_ca_list(X509Name::load_client_ca_file(client_ca)?);
+        builder.set_verify(SslVerifyMode::PEER | SslVerifyMode::FAIL_IF_NO_PEER_CERT);
+        Ok(Self {
+            acceptor: builder.build(),
+        })
+    }
+
+    /// Performs the handshake on `stream`, returning the TLS stream and the identity of the
+    /// client, the common name of its certificate.
+    ///
+    /// # Errors
+    ///
+    /// This function will return an error if the handshake fails, e.g. the client has no
+    /// certificate signed by the CA, or if the certificate has no common name.
+    pub async fn accept(&self, stream: TcpStream) -> io::Result<(SslStream<TcpStream>, String)> {
+        let ssl = Ssl::new(self.acceptor.context()).map_err(io::Error::other)?;
+        let mut stream = SslStream::new(ssl, stream).map_err(io::Error::other)?;
+        Pin::new(&mut stream)
+            .accept()
+            .await
+            .map_err(io::Error::other)?;
+
+        let identity = stream
+            .ssl()
+            .peer_certificate()
+            .and_then(|cert| {
+                let entry = cert.subject_name().entries_by_nid(Nid::COMMONNAME).next()?;
+                String::from_utf8(entry.data().as_slice().to_vec()).ok()
+            })
+            .ok_or_else(|| {
+                io::Error::new(
+                    io::ErrorKind::InvalidData,
+                    "Client certificate has no common name",
+                )
+            })?;
+        Ok((stream, identity))
+    }
+}
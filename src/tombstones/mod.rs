@@ -0,0 +1,53 @@
+use ahash::AHashMap;
+use std::time::{Duration, Instant};
+
+/// Remembers recently deleted uuids so they can't be re-POSTed while clients may still hold the
+/// old version of the message.
+#[derive(Debug)]
+pub struct Tombstones {
+    window: Duration,
+    deleted: AHashMap<String, Instant>,
+    /// Expired tombstones are pruned once the map grows past this size.
+    prune_at: usize,
+}
+
+impl Tombstones {
+    /// A zero `window` disables tombstones, deleted uuids can be reused immediately.
+    pub fn new(window: Duration) -> Self {
+        Self {
+            window,
+            deleted: AHashMap::new(),
+            prune_at: 1024,
+        }
+    }
+
+    /// Records that `uuid` was just deleted.
+    pub fn bury(&mut self, uuid: &str) {
+        if self.window.is_zero() {
+            return;
+        }
+        if self.deleted.len() >= self.prune_at {
+            let window = self.window;
+            self.deleted
+                .retain(|_, deleted_at| deleted_at.elapsed() < window);
+            self.prune_at = (self.deleted.len() * 2).max(1024);
+        }
+        self.deleted.insert(uuid.to_string(), Instant::now());
+    }
+
+    /// How long `uuid` stays unavailable for reuse, `None` if it can be reused now.
+    pub fn remaining(&mut self, uuid: &str) -> Option<Duration> {
+        let deleted_at = *self.deleted.get(uuid)?;
+        match self.window.checked_sub(deleted_at.elapsed()) {
+            Some(remaining) if !remaining.is_zero() => Some(remaining),
+            _ => {
+                self.deleted.remove(uuid);
+                None
+            }
+        }
+    }
+
+    pub fn clear(&mut self) {
+        self.deleted.clear();
+    }
+}
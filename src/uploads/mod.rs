@@ -0,0 +1,300 @@
+use crate::{image::ImageStorage, response::StatusCode, try_write_perm};
+use ahash::AHashMap;
+use serde::Serialize;
+use std::{
+    fmt,
+    io::{self, Write},
+    path::PathBuf,
+    time::{Duration, Instant, SystemTime, UNIX_EPOCH},
+};
+
+mod sweeper;
+
+pub use sweeper::spawn_sweeper;
+
+/// A parsed `Content-Range: bytes <start>-<end>/<total>` header, `total` may be `*`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ContentRange {
+    pub start: usize,
+    /// Inclusive.
+    pub end: usize,
+    pub total: Option<usize>,
+}
+
+impl ContentRange {
+    pub fn parse(value: &str) -> Option<Self> {
+        let range = value.trim().strip_prefix("bytes ")?;
+        let (range, total) = range.split_once('/')?;
+        let (start, end) = range.split_once('-')?;
+        let start = start.parse().ok()?;
+        let end = end.parse().ok()?;
+        let total = match total {
+            "*" => None,
+            total => Some(total.parse().ok()?),
+        };
+        if end < start || total.is_some_and(|total| end >= total) {
+            return None;
+        }
+        Some(Self { start, end, total })
+    }
+
+    /// The number of bytes in the range.
+    pub fn size(&self) -> usize {
+        self.end - self.start + 1
+    }
+}
+
+#[derive(Serialize, Debug)]
+pub struct UploadSession {
+    #[serde(skip)]
+    uuid: String,
+    pub received: usize,
+    pub total: Option<usize>,
+    /// When the session was created or last got a chunk.
+    #[serde(skip)]
+    last_active: Instant,
+}
+
+#[derive(Debug)]
+pub enum UploadError {
+    /// There is no upload session with this id for this message.
+    NotFound,
+    /// The chunk doesn't start where the received bytes end.
+    RangeMismatch {
+        received: usize,
+    },
+    /// The chunk's length doesn't match its `Content-Range`, or the total changed.
+    InvalidRange,
+    /// The upload is committed before all bytes were received.
+    Incomplete {
+        received: usize,
+        total: Option<usize>,
+    },
+    /// The uploaded image isn't base64.
+    InvalidImage,
+    /// The image doesn't fit in the quota of the images, the session is kept to commit it later.
+    StorageFull,
+    Io(io::Error),
+}
+
+impl UploadError {
+    /// The HTTP status this error is surfaced as.
+    pub fn status(&self) -> StatusCode {
+        match self {
+            UploadError::NotFound => StatusCode::NotFound,
+            UploadError::RangeMismatch { .. } => StatusCode::RangeNotSatisfiable,
+            UploadError::InvalidRange => StatusCode::BadRequest,
+            UploadError::Incomplete { .. } => StatusCode::Conflict,
+            UploadError::InvalidImage => StatusCode::BadRequest,
+            UploadError::StorageFull => StatusCode::InsufficientStorage,
+            UploadError::Io(_) => StatusCode::InternalServerError,
+        }
+    }
+}
+
+impl fmt::Display for UploadError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            UploadError::NotFound => write!(f, "Upload session not found."),
+            UploadError::RangeMismatch { received } => {
+                write!(f, "Expected a chunk starting at byte {received}.")
+            }
+            UploadError::InvalidRange => write!(f, "Invalid Content-Range."),
+            UploadError::Incomplete { received, total } => match total {
+                Some(total) => write!(f, "Upload incomplete, received {received}/{total} bytes."),
+                None => write!(f, "Upload incomplete, total size unknown."),
+            },
+            UploadError::InvalidImage => write!(f, "The image must be base64."),
+            UploadError::StorageFull => write!(f, "The image store is full."),
+            UploadError::Io(e) => write!(f, "Upload I/O error: {e}"),
+        }
+    }
+}
+
+impl From<io::Error> for UploadError {
+    fn from(e: io::Error) -> Self {
+        UploadError::Io(e)
+    }
+}
+
+/// Keeps track of resumable image uploads. Chunks are appended to a file per session under the
+/// upload directory, and the complete file is saved as the message's image on commit.
+pub struct UploadManager {
+    upload_dir: PathBuf,
+    sessions: AHashMap<String, UploadSession>,
+    last_id: u64,
+}
+
+impl UploadManager {
+    pub fn new(upload_dir: PathBuf) -> Self {
+        std::fs::create_dir_all(&upload_dir).unwrap_or_else(|_| {
+            panic!("Failed to create the upload directory, the given path is {upload_dir:?}.")
+        });
+        // try writing and deleting a file to check if we have write permissions
+        try_write_perm(&upload_dir);
+        // sessions don't survive restarts, remove their leftover files
+        for entry in std::fs::read_dir(&upload_dir)
+            .into_iter()
+            .flatten()
+            .flatten()
+        {
+            std::fs::remove_file(entry.path()).ok();
+        }
+        Self {
+            upload_dir,
+            sessions: AHashMap::new(),
+            last_id: 0,
+        }
+    }
+
+    /// Creates an upload session for the image of `uuid`, returning its id.
+    pub fn create(&mut self, uuid: &str) -> io::Result<String> {
+        self.last_id += 1;
+        let nanos = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map_or(0, |d| d.subsec_nanos());
+        let id = format!("{:x}{:08x}", self.last_id, nanos);
+        std::fs::File::create(self.upload_file_path(&id))?;
+        self.sessions.insert(
+            id.clone(),
+            UploadSession {
+                uuid: uuid.to_string(),
+                received: 0,
+                total: None,
+                last_active: Instant::now(),
+            },
+        );
+        Ok(id)
+    }
+
+    pub fn progress(&self, id: &str, uuid: &str) -> Result<&UploadSession, UploadError> {
+        match self.sessions.get(id) {
+            Some(session) if session.uuid == uuid => Ok(session),
+            _ => Err(UploadError::NotFound),
+        }
+    }
+
+    /// Appends a chunk to the upload. Chunks must be sent in order, a client resuming an upload
+    /// should ask for the progress and continue from `received`.
+    pub fn append(
+        &mut self,
+        id: &str,
+        uuid: &str,
+        range: ContentRange,
+        chunk: &[u8],
+    ) -> Result<&UploadSession, UploadError> {
+        let path = self.upload_file_path(id);
+        let session = match self.sessions.get_mut(id) {
+            Some(session) if session.uuid == uuid => session,
+            _ => return Err(UploadError::NotFound),
+        };
+
+        if range.size() != chunk.len() {
+            return Err(UploadError::InvalidRange);
+        }
+        match (session.total, range.total) {
+            (Some(total), Some(new_total)) if total != new_total => {
+                return Err(UploadError::InvalidRange)
+            }
+            (_, Some(new_total)) => session.total = Some(new_total),
+            _ => (),
+        }
+        if range.start != session.received {
+            return Err(UploadError::RangeMismatch {
+                received: session.received,
+            });
+        }
+
+        std::fs::OpenOptions::new()
+            .append(true)
+            .open(path)?
+            .write_all(chunk)?;
+        session.received += chunk.len();
+        session.last_active = Instant::now();
+        Ok(session)
+    }
+
+    /// Ends the sessions that got no chunk within `ttl` and removes their files, returning how
+    /// many were ended. Clients abandoning their uploads would leave them behind otherwise.
+    pub fn expire(&mut self, ttl: Duration) -> usize {
+        let expired: Vec<String> = self
+            .sessions
+            .iter()
+            .filter(|(_, session)| session.last_active.elapsed() > ttl)
+            .map(|(id, _)| id.clone())
+            .collect();
+        for id in &expired {
+            self.sessions.remove(id);
+            // a file that can't be removed now is removed at the next startup
+            std::fs::remove_file(self.upload_file_path(id)).ok();
+        }
+        expired.len()
+    }
+
+    /// Atomically replaces the image of `uuid` with the decoded uploaded file and ends the
+    /// session. A session whose upload isn't base64 is ended too, its bytes can't be fixed by
+    /// uploading more.
+    pub async fn commit(
+        &mut self,
+        id: &str,
+        uuid: &str,
+        images: &dyn ImageStorage,
+    ) -> Result<(), UploadError> {
+        let session = self.progress(id, uuid)?;
+        if session.total != Some(session.received) {
+            return Err(UploadError::Incomplete {
+                received: session.received,
+                total: session.total,
+            });
+        }
+
+        let path = self.upload_file_path(id);
+        let saved = match tokio::fs::read_to_string(&path).await {
+            Ok(image) => images.save(uuid, &image).await,
+            Err(e) => Err(e),
+        };
+        match saved {
+            Err(e) if e.kind() == io::ErrorKind::InvalidData => {
+                tokio::fs::remove_file(&path).await?;
+                self.sessions.remove(id);
+                Err(UploadError::InvalidImage)
+            }
+            Err(e) if e.kind() == io::ErrorKind::StorageFull => Err(UploadError::StorageFull),
+            Err(e) => Err(e.into()),
+            Ok(()) => {
+                tokio::fs::remove_file(&path).await?;
+                self.sessions.remove(id);
+                Ok(())
+            }
+        }
+    }
+
+    fn upload_file_path(&self, id: &str) -> PathBuf {
+        self.upload_dir.join(id)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::app_state::testing::TestDir;
+
+    #[test]
+    fn idle_sessions_expire_with_their_file() {
+        let dir = TestDir::new();
+        let mut uploads = UploadManager::new(dir.path().to_path_buf());
+        let id = uploads.create("uuid").unwrap();
+        let range = ContentRange::parse("bytes 0-1/4").unwrap();
+        uploads.append(&id, "uuid", range, b"ab").unwrap();
+
+        assert_eq!(uploads.expire(Duration::from_secs(60)), 0);
+        assert_eq!(uploads.progress(&id, "uuid").unwrap().received, 2);
+
+        assert_eq!(uploads.expire(Duration::ZERO), 1);
+        assert!(matches!(
+            uploads.progress(&id, "uuid"),
+            Err(UploadError::NotFound)
+        ));
+        assert!(!dir.path().join(&id).exists());
+    }
+}
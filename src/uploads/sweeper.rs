@@ -0,0 +1,21 @@
+use crate::app_state::AppState;
+use std::{sync::Arc, time::Duration};
+
+/// How often the sweeper looks for abandoned uploads, at most.
+const SWEEP_INTERVAL: Duration = Duration::from_secs(60);
+
+/// Spawns the task ending the upload sessions idle for longer than `ttl`, see
+/// [`UploadManager::expire`](super::UploadManager::expire). It sweeps every minute, or every
+/// `ttl` if it is shorter.
+pub fn spawn_sweeper(state: Arc<AppState>, ttl: Duration) -> tokio::task::JoinHandle<()> {
+    let interval = ttl.clamp(Duration::from_secs(1), SWEEP_INTERVAL);
+    tokio::spawn(async move {
+        loop {
+            tokio::time::sleep(interval).await;
+            let expired = state.uploads.lock().await.expire(ttl);
+            if expired > 0 {
+                println!("Expired {expired} abandoned upload sessions.");
+            }
+        }
+    })
+}
@@ -0,0 +1,68 @@
+use bincode::Options;
+use serde::Serialize;
+use std::{
+    sync::atomic::{AtomicU64, Ordering},
+    time::{Duration, Instant},
+};
+
+/// Serializes `value` in the v2 wire format, bincode with variable length integers, which
+/// shrinks the lengths and numbers that dominate pages of short messages.
+pub fn serialize_v2<T: Serialize>(value: &T) -> Vec<u8> {
+    bincode::DefaultOptions::new().serialize(value).unwrap()
+}
+
+/// Shadows a fraction of the page responses with the v2 wire format: the page is serialized
+/// again in v2 and the size and latency delta is logged, while the legacy bytes are served.
+pub struct Canary {
+    fraction: f64,
+    requests: AtomicU64,
+}
+
+impl Canary {
+    /// `fraction` of the page requests are shadowed, `0.0` disables the canary.
+    pub fn new(fraction: f64) -> Self {
+        Self {
+            fraction: fraction.clamp(0.0, 1.0),
+            requests: AtomicU64::new(0),
+        }
+    }
+
+    /// Whether the current request should be shadowed, spreading the sampled requests evenly.
+    pub fn sample(&self) -> bool {
+        if self.fraction == 0.0 {
+            return false;
+        }
+        let n = self.requests.fetch_add(1, Ordering::Relaxed) as f64;
+        ((n + 1.0) * self.fraction).floor() > (n * self.fraction).floor()
+    }
+
+    /// Serializes `value` in v2 and logs how it compares to the legacy serialization that took
+    /// `legacy_time` and produced `legacy_len` bytes.
+    pub fn shadow<T: Serialize>(
+        &self,
+        label: &str,
+        value: &T,
+        legacy_len: usize,
+        legacy_time: Duration,
+    ) {
+        let start = Instant::now();
+        let v2_len = serialize_v2(value).len();
+        self.report(label, legacy_len, legacy_time, v2_len, start.elapsed());
+    }
+
+    /// Logs how a response serialized in v2 compares to the legacy serialization, for
+    /// responses serialized piece by piece.
+    pub fn report(
+        &self,
+        label: &str,
+        legacy_len: usize,
+        legacy_time: Duration,
+        v2_len: usize,
+        v2_time: Duration,
+    ) {
+        println!(
+            "wire canary {label}: legacy {legacy_len} B in {legacy_time:?}, v2 {v2_len} B in {v2_time:?} ({:+.1}% size)",
+            (v2_len as f64 - legacy_len as f64) / legacy_len.max(1) as f64 * 100.0
+        );
+    }
+}